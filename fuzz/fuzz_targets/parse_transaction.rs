@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // Errors are expected for malformed data, only panics are failures
+    let _ = fusion_producer::filter::parse_any_transaction(data);
+});
@@ -0,0 +1,109 @@
+//! Human-readable labels of addresses attached to the messages, loaded from a file
+//! which is reloaded when it changes
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use rustc_hash::FxHashMap;
+use serde::Deserialize;
+use ton_block::MsgAddressInt;
+use ton_types::UInt256;
+
+use crate::blocks_handler::BlocksHandler;
+use crate::config::AddressBookConfig;
+use crate::filter::account_key;
+use crate::types::AddressLabel;
+
+/// Loads the address book and attaches its labels to the messages of the handler
+pub fn serve(handler: BlocksHandler, config: &AddressBookConfig) -> Result<BlocksHandler> {
+    let book = Arc::new(AddressBook::open(&config.path)?);
+    tokio::spawn(watch(book.clone(), Duration::from_secs(config.reload_interval_sec.max(1))));
+    Ok(handler.with_address_book(book))
+}
+
+/// Entry of the file, a label or a label with tags
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    Label(String),
+    Labeled {
+        label: String,
+        #[serde(default)]
+        tags: Vec<String>,
+    },
+}
+
+pub struct AddressBook {
+    path: PathBuf,
+    labels: RwLock<FxHashMap<(i32, UInt256), AddressLabel>>,
+    /// Modification time of the last read file
+    modified: RwLock<Option<SystemTime>>,
+}
+
+impl AddressBook {
+    /// Reads the YAML or JSON map of raw addresses to entries
+    pub fn open(path: &Path) -> Result<Self> {
+        let book = Self {
+            path: path.to_owned(),
+            labels: Default::default(),
+            modified: Default::default(),
+        };
+        book.reload()?;
+        Ok(book)
+    }
+
+    pub fn label(&self, address: &MsgAddressInt) -> Option<AddressLabel> {
+        let key = account_key(address.workchain_id(), &address.address());
+        self.labels.read().unwrap().get(&key).cloned()
+    }
+
+    /// Reads the file if it was modified since the last read, returns whether it was
+    pub fn reload(&self) -> Result<bool> {
+        let modified = std::fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .with_context(|| format!("Failed to read {}", self.path.display()))?;
+        if self.modified.write().unwrap().replace(modified) == Some(modified) {
+            return Ok(false);
+        }
+
+        // A broken file is reported once, the previous labels are kept until it changes again
+        let labels = load(&self.path)?;
+        tracing::info!("Loaded {} address labels from {}", labels.len(), self.path.display());
+        *self.labels.write().unwrap() = labels;
+        Ok(true)
+    }
+}
+
+fn load(path: &Path) -> Result<FxHashMap<(i32, UInt256), AddressLabel>> {
+    let data = std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let entries: HashMap<String, Entry> =
+        serde_yaml::from_slice(&data).with_context(|| format!("Invalid address book {}", path.display()))?;
+    entries
+        .into_iter()
+        .map(|(address, entry)| {
+            let address = MsgAddressInt::from_str(&address)
+                .map_err(|error| anyhow::anyhow!("Invalid address {address}: {error}"))?;
+            let label = match entry {
+                Entry::Label(label) => AddressLabel { label, tags: Vec::new() },
+                Entry::Labeled { label, tags } => AddressLabel { label, tags },
+            };
+            Ok((account_key(address.workchain_id(), &address.address()), label))
+        })
+        .collect()
+}
+
+/// Reloads the book when the file changes
+async fn watch(book: Arc<AddressBook>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        if let Err(error) = book.reload() {
+            tracing::error!("Reloading address book: {:?}", error);
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use anyhow::Result;
+use rustc_hash::FxHashMap;
+use ton_block::{CommonMsgInfo, Deserializable, GetRepresentationHash, Transaction};
+use ton_types::{HashmapType, UInt256};
+
+use crate::types::{FilteredMessage, SerializeBounce};
+
+/// Number of latest matched messages waiting for a bounce
+const BOUNCE_WINDOW: usize = 100_000;
+
+/// Links bounced messages to the matched messages they return
+#[derive(Default)]
+pub struct BounceTracker {
+    inner: Mutex<TrackedMessages>,
+}
+
+#[derive(Default)]
+struct TrackedMessages {
+    messages: FxHashMap<UInt256, TrackedMessage>,
+    /// Tracked hashes, oldest first
+    order: VecDeque<UInt256>,
+}
+
+struct TrackedMessage {
+    contract_name: String,
+    filter_name: String,
+}
+
+impl BounceTracker {
+    /// Remembers a bounceable internal message of a filter with `track_bounces`
+    pub fn track(&self, message: &FilteredMessage) {
+        let bounceable = matches!(message.message.header(), CommonMsgInfo::IntMsgInfo(header) if header.bounce);
+        if !message.track_bounces || !bounceable {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.order.len() >= BOUNCE_WINDOW {
+            if let Some(oldest) = inner.order.pop_front() {
+                inner.messages.remove(&oldest);
+            }
+        }
+        inner.order.push_back(message.message_hash);
+        inner.messages.insert(message.message_hash, TrackedMessage {
+            contract_name: message.contract_name.clone(),
+            filter_name: message.filter_name.clone(),
+        });
+    }
+
+    /// Finds the bounce of a tracked message processed by the transaction.
+    /// The message is forgotten after its transaction either way
+    pub fn correlate(&self, tx: &Transaction, block_id: &UInt256) -> Result<Option<SerializeBounce>> {
+        let Some(in_msg) = tx.in_msg_cell() else {
+            return Ok(None);
+        };
+        let original_message_hash = in_msg.repr_hash();
+        let Some(tracked) = self.inner.lock().unwrap().messages.remove(&original_message_hash) else {
+            return Ok(None);
+        };
+
+        let mut bounced_message_hash = None;
+        tx.out_msgs.iterate_slices(|slice| {
+            let cell = slice.reference(0)?;
+            let message = ton_block::Message::construct_from_cell(cell.clone())?;
+            if matches!(message.header(), CommonMsgInfo::IntMsgInfo(header) if header.bounced) {
+                bounced_message_hash = Some(cell.repr_hash());
+                return Ok(false);
+            }
+            Ok(true)
+        })?;
+
+        Ok(bounced_message_hash.map(|bounced_message_hash| SerializeBounce {
+            original_message_hash,
+            bounced_message_hash,
+            block_id: *block_id,
+            transaction_id: tx.hash().unwrap_or_default(),
+            transaction_timestamp: tx.now,
+            contract_name: tracked.contract_name,
+            filter_name: tracked.filter_name,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use ton_block::{CurrencyCollection, InternalMessageHeader, Message, MsgAddressInt};
+
+    use crate::types::MessageType;
+    use super::*;
+
+    const SENDER: &str = "0:1ef42a3c649061ba446f2d5ae5219380573c78de3541fe67c742ead0cae68d0d";
+    const RECEIVER: &str = "0:e6f7da94405c55c9fb14b5be6b8f91bba1be76e678900ecb418499bfe37ada05";
+
+    fn internal_message(src: &str, dst: &str, bounce: bool, bounced: bool) -> Message {
+        let mut header = InternalMessageHeader::with_addresses_and_bounce(
+            MsgAddressInt::from_str(src).unwrap(),
+            MsgAddressInt::from_str(dst).unwrap(),
+            CurrencyCollection::with_grams(1_000_000_000),
+            bounce,
+        );
+        header.bounced = bounced;
+        Message::with_int_header(header)
+    }
+
+    fn filtered(message: &Message, track_bounces: bool) -> FilteredMessage {
+        FilteredMessage {
+            name: "transfer".to_string(),
+            message_hash: message.hash().unwrap(),
+            message: message.clone(),
+            message_type: MessageType::InternalOutbound,
+            tx: Default::default(),
+            index_in_transaction: 0,
+            contract_name: "Wallet".to_string(),
+            filter_name: "transfers".to_string(),
+            destination: None,
+            tenant: None,
+            matched_filters: Vec::new(),
+            track_bounces,
+            trace: false,
+            function_id: None,
+            expire_at: None,
+            tokens: Default::default(),
+            enrichment: Default::default(),
+            routing: Default::default(),
+            payload: Default::default(),
+        }
+    }
+
+    /// Transaction of the receiver processing the message
+    fn transaction(in_msg: &Message, out_msgs: &[Message]) -> Transaction {
+        let mut tx = Transaction::default();
+        tx.now = 1700000000;
+        tx.write_in_msg(Some(in_msg)).unwrap();
+        for message in out_msgs {
+            tx.add_out_message(message).unwrap();
+        }
+        tx
+    }
+
+    #[test]
+    fn test_correlate_bounce() {
+        let tracker = BounceTracker::default();
+        let original = internal_message(SENDER, RECEIVER, true, false);
+        let bounced = internal_message(RECEIVER, SENDER, false, true);
+        tracker.track(&filtered(&original, true));
+
+        let tx = transaction(&original, &[bounced.clone()]);
+        let block_id = UInt256::from([1; 32]);
+        let bounce = tracker.correlate(&tx, &block_id).unwrap().unwrap();
+        assert_eq!(bounce.original_message_hash, original.hash().unwrap());
+        assert_eq!(bounce.bounced_message_hash, bounced.hash().unwrap());
+        assert_eq!(bounce.block_id, block_id);
+        assert_eq!(bounce.transaction_timestamp, 1700000000);
+        assert_eq!(bounce.filter_name, "transfers");
+
+        // Forgotten after its transaction
+        assert!(tracker.correlate(&tx, &block_id).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_no_bounce() {
+        let tracker = BounceTracker::default();
+        let original = internal_message(SENDER, RECEIVER, true, false);
+        tracker.track(&filtered(&original, true));
+
+        let reply = internal_message(RECEIVER, SENDER, false, false);
+        let tx = transaction(&original, &[reply]);
+        assert!(tracker.correlate(&tx, &UInt256::default()).unwrap().is_none());
+        assert!(tracker.inner.lock().unwrap().messages.is_empty());
+    }
+
+    #[test]
+    fn test_untracked_messages() {
+        let tracker = BounceTracker::default();
+        let not_bounceable = internal_message(SENDER, RECEIVER, false, false);
+        tracker.track(&filtered(&not_bounceable, true));
+        let bounceable = internal_message(RECEIVER, SENDER, true, false);
+        tracker.track(&filtered(&bounceable, false));
+        assert!(tracker.inner.lock().unwrap().messages.is_empty());
+    }
+
+    #[test]
+    fn test_bounce_window() {
+        let tracker = BounceTracker::default();
+        let mut message = filtered(&internal_message(SENDER, RECEIVER, true, false), true);
+        for i in 0..=BOUNCE_WINDOW as u64 {
+            let mut hash = [0; 32];
+            hash[..8].copy_from_slice(&i.to_be_bytes());
+            message.message_hash = UInt256::from(hash);
+            tracker.track(&message);
+        }
+
+        let inner = tracker.inner.lock().unwrap();
+        assert_eq!(inner.messages.len(), BOUNCE_WINDOW);
+        assert_eq!(inner.order.len(), BOUNCE_WINDOW);
+        assert!(!inner.messages.contains_key(&UInt256::from([0; 32])));
+    }
+}
@@ -0,0 +1,60 @@
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rustc_hash::FxHashMap;
+
+use crate::types::FilterCounters;
+
+/// Messages and bytes handed to the transport by a filter
+#[derive(Debug, Default)]
+struct EmittedCounter {
+    messages: AtomicU64,
+    bytes: AtomicU64,
+    last_emitted_at: AtomicU32,
+}
+
+/// Counters by filter name
+static COUNTERS: OnceLock<RwLock<FxHashMap<String, Arc<EmittedCounter>>>> = OnceLock::new();
+
+/// Counts a message of the filter handed to the transport
+pub fn record_emitted(filter_name: &str, bytes: usize) {
+    let counters = COUNTERS.get_or_init(Default::default);
+    let counter = counters.read().unwrap().get(filter_name).cloned();
+    let counter = match counter {
+        Some(counter) => counter,
+        None => counters
+            .write()
+            .unwrap()
+            .entry(filter_name.to_owned())
+            .or_default()
+            .clone(),
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as u32;
+    counter.messages.fetch_add(1, Ordering::Relaxed);
+    counter.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    counter.last_emitted_at.fetch_max(now, Ordering::Relaxed);
+}
+
+/// Running counters of the filters which emitted messages, sorted by filter name
+pub fn filter_counters() -> Vec<FilterCounters> {
+    let Some(counters) = COUNTERS.get() else {
+        return Vec::new();
+    };
+    let mut counters = counters
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(filter_name, counter)| FilterCounters {
+            filter_name: filter_name.clone(),
+            messages: counter.messages.load(Ordering::Relaxed),
+            bytes: counter.bytes.load(Ordering::Relaxed),
+            last_emitted_at: counter.last_emitted_at.load(Ordering::Relaxed),
+        })
+        .collect::<Vec<_>>();
+    counters.sort_by(|a, b| a.filter_name.cmp(&b.filter_name));
+    counters
+}
@@ -0,0 +1,110 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rustc_hash::FxHashMap;
+
+/// Upper bounds of the latency buckets in seconds
+pub const LATENCY_BUCKETS: [f64; 10] = [1.0, 2.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0, 86400.0];
+
+/// Time from the block generation to handing the message to the transport
+#[derive(Debug, Default)]
+pub struct LatencyHistogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_ms: AtomicU64,
+}
+
+impl LatencyHistogram {
+    fn observe(&self, latency_ms: u64) {
+        let latency = latency_ms as f64 / 1000.0;
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            if latency <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_ms.fetch_add(latency_ms, Ordering::Relaxed);
+    }
+
+    /// Cumulative bucket counts in the order of [`LATENCY_BUCKETS`]
+    pub fn buckets(&self) -> [u64; LATENCY_BUCKETS.len()] {
+        std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed))
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum_seconds(&self) -> f64 {
+        self.sum_ms.load(Ordering::Relaxed) as f64 / 1000.0
+    }
+}
+
+/// Histograms by contract and filter names
+type Histograms = RwLock<FxHashMap<(String, String), Arc<LatencyHistogram>>>;
+
+static HISTOGRAMS: OnceLock<Histograms> = OnceLock::new();
+
+/// Records the delivery latency of a message from a block generated at `gen_utime`
+pub fn record_delivery(contract_name: &str, filter_name: &str, gen_utime: u32) {
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let latency_ms = now_ms.saturating_sub(gen_utime as u64 * 1000);
+
+    let histograms = HISTOGRAMS.get_or_init(Default::default);
+    let key = (contract_name.to_owned(), filter_name.to_owned());
+    let histogram = histograms.read().unwrap().get(&key).cloned();
+    let histogram = match histogram {
+        Some(histogram) => histogram,
+        None => histograms.write().unwrap().entry(key).or_default().clone(),
+    };
+    histogram.observe(latency_ms);
+}
+
+/// Counters of messages dropped as stale by contract and filter names
+static STALE_DROPS: OnceLock<RwLock<FxHashMap<(String, String), Arc<AtomicU64>>>> = OnceLock::new();
+
+/// Counts a message which was not sent because it is older than the allowed age
+pub fn record_stale_drop(contract_name: &str, filter_name: &str) {
+    let counters = STALE_DROPS.get_or_init(Default::default);
+    let key = (contract_name.to_owned(), filter_name.to_owned());
+    let counter = counters.read().unwrap().get(&key).cloned();
+    let counter = match counter {
+        Some(counter) => counter,
+        None => counters.write().unwrap().entry(key).or_default().clone(),
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns stale drops as `(contract_name, filter_name, count)`
+pub fn stale_drops() -> Vec<(String, String, u64)> {
+    let Some(counters) = STALE_DROPS.get() else {
+        return Vec::new();
+    };
+    counters
+        .read()
+        .unwrap()
+        .iter()
+        .map(|((contract_name, filter_name), counter)| {
+            (contract_name.clone(), filter_name.clone(), counter.load(Ordering::Relaxed))
+        })
+        .collect()
+}
+
+/// Returns histograms as `(contract_name, filter_name, histogram)`
+pub fn delivery_latencies() -> Vec<(String, String, Arc<LatencyHistogram>)> {
+    let Some(histograms) = HISTOGRAMS.get() else {
+        return Vec::new();
+    };
+    histograms
+        .read()
+        .unwrap()
+        .iter()
+        .map(|((contract_name, filter_name), histogram)| {
+            (contract_name.clone(), filter_name.clone(), histogram.clone())
+        })
+        .collect()
+}
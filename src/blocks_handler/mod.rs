@@ -1,54 +1,294 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use anyhow::Result;
+use bytes::Bytes;
+use everscale_crypto::ed25519;
 use futures_util::future::join_all;
 use once_cell::race::OnceBox;
-use rustc_hash::FxHashSet;
-use ton_block::{Deserializable, HashmapAugType, Serializable};
+use rustc_hash::{FxHashMap, FxHashSet};
+use tokio::runtime::RuntimeFlavor;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use ton_block::{AccountBlock, BlockIdExt, ConfigParams, Deserializable, HashmapAugType, Serializable};
 use ton_indexer::utils::{BlockStuff, ShardStateStuff};
-use ton_types::HashmapType;
+use ton_types::{serialize_toc, AccountId, Cell, HashmapType, UInt256};
 
 use crate::{
-    serializer::Serializer,
-    filter::filter_transaction,
-    types::SerializeMessage,
-    producer::Producer
+    error::{FusionError, FusionResult},
+    serializer::{Serializer, PROTOCOL_VERSION},
+    filter::{
+        account_key, code_hashes, config::{AccountFilter, TransactionRange}, state_budget, filter_account, filter_account_changes, filter_config_param, filter_deleted_account,
+        filter_transaction, filtered_accounts, AccountChanges, message_destinations, message_filter_channels, tracks_bounces, traces_enabled,
+        closed_aggregates,
+    },
+    types::{
+        BlockBoundary, ControlEvent, FilteredMessage, Heartbeat, RevokedBlock, StreamStats, SerializeAccountChanges, SerializeAccountState, SerializeBlock, SerializeBounce, SerializeConfigParam, SerializeTrace, SerializeAggregate,
+        SerializeMessage, SerializeMessageStatus, StreamStart,
+    },
+    control::{PauseSwitch, PayloadSampler},
+    address_book::AddressBook,
+    message_store::MessageStore,
+    producer::{Producer, Stream, Transport},
 };
 
+pub mod bounces;
+pub mod counters;
+pub mod latency;
+pub mod supervisor;
+pub mod traces;
+
+use self::bounces::BounceTracker;
+use self::traces::TraceTracker;
+
+/// Receives filtered messages instead of the serializer and transport.
+/// Called inside block processing, so it must not block
+pub type MessageSink = Arc<dyn Fn(SerializeMessage) + Send + Sync>;
+
+/// Blocks waiting to be sent with atomic emission
+const BLOCK_BATCHES_CAPACITY: usize = 16;
+
+/// Serialized message with its destination and the contract, filter and transaction time
+type OutgoingMessage = (Bytes, (Option<String>, (String, String, u32)));
+
+/// Account states of a block, sent in order after its transactions
+struct BlockStates<'a> {
+    state: &'a ShardStateStuff,
+    /// Account filters of the accounts deleted in the block
+    deleted: FxHashMap<AccountId, &'static AccountFilter>,
+    /// By account id
+    serialized: Mutex<Vec<(UInt256, Bytes)>>,
+}
+
+/// Messages of a block with its boundary frames
+struct BlockBatch {
+    begin: Bytes,
+    end: Bytes,
+    messages: Vec<OutgoingMessage>,
+}
+
+/// Provides states for filters when the scanner has no shard states
+#[async_trait::async_trait]
+pub trait StateProvider: Send + Sync {
+    /// Returns a state with at least the accounts of the block
+    async fn block_state(&self, block: &BlockStuff) -> Result<Option<ShardStateStuff>>;
+}
+
 pub struct BlocksHandler {
     pub serializer: Serializer,
     pub producer: Producer,
+    pub range: TransactionRange,
+    /// Emit block records to the blocks stream
+    pub blocks_stream: bool,
+    /// Hashes of the config params from the last key block
+    config_params: Mutex<FxHashMap<u32, UInt256>>,
+    sink: Option<MessageSink>,
+    /// Receives filtered messages along with the transport
+    observer: Option<MessageSink>,
+    /// Keeps sent messages for the pull API
+    message_store: Option<Arc<MessageStore>>,
+    /// Drops filtered messages while paused
+    pause_switch: Option<Arc<PauseSwitch>>,
+    /// Logs the payloads of a share of the messages
+    payload_sampler: Option<Arc<PayloadSampler>>,
+    /// Labels of the message addresses
+    address_book: Option<Arc<AddressBook>>,
+    state_provider: Option<Arc<dyn StateProvider>>,
+    started_at: u32,
+    /// Key signing every sent frame
+    signer: Option<Arc<ed25519::KeyPair>>,
+    /// Heartbeats task, running between `start_streams` and `end_streams`
+    heartbeat: Mutex<Option<JoinHandle<()>>>,
+    /// Set if any filter tracks bounces
+    bounces: Option<BounceTracker>,
+    /// Set if any filter assembles traces
+    traces: Option<TraceTracker>,
+    /// Accounts which can have matched messages, all if not set
+    accounts: Option<FxHashSet<(i32, UInt256)>>,
+    /// Threads sharing the account blocks of a block, serial if not above one
+    account_workers: usize,
+    /// Messages of older transactions are dropped, in seconds
+    max_message_age: Option<u32>,
+    /// Messages of older blocks are tagged as historical, in seconds
+    realtime_threshold: Option<u32>,
+    /// Output of the historical messages instead of the filter destinations
+    historical_destination: Option<String>,
+    /// Sends the messages of each block at once between boundary frames, in block order
+    block_batches: Option<mpsc::Sender<BlockBatch>>,
 }
 
 impl BlocksHandler {
-    pub fn new(serializer: Serializer, producer: Producer) -> Result<Self> {
-        tracing::debug!("New blocks handle; serializer: {:?}, producer: {:?}", serializer, producer);
-        Ok(Self {
+    pub fn new(
+        serializer: Serializer,
+        producer: Producer,
+        range: TransactionRange,
+        blocks_stream: bool,
+    ) -> FusionResult<Self> {
+        tracing::debug!("New blocks handle; serializer: {:?}, producer: {:?}, range: {:?}", serializer, producer, range);
+        if producer.requires_json() && !serializer.is_json() {
+            return Err(FusionError::config(anyhow::anyhow!("The transport requires the Json serializer")));
+        }
+        let handler = Self {
             serializer,
             producer,
-        })
+            range,
+            blocks_stream,
+            config_params: Default::default(),
+            sink: None,
+            observer: None,
+            message_store: None,
+            pause_switch: None,
+            payload_sampler: None,
+            address_book: None,
+            state_provider: None,
+            started_at: now_sec(),
+            signer: None,
+            heartbeat: Default::default(),
+            bounces: tracks_bounces().then(Default::default),
+            traces: traces_enabled().then(Default::default),
+            // Bounces and traces are found in transactions of any account
+            accounts: (!tracks_bounces() && !traces_enabled()).then(filtered_accounts).flatten(),
+            account_workers: 1,
+            max_message_age: None,
+            realtime_threshold: None,
+            historical_destination: None,
+            block_batches: None,
+        };
+        handler
+            .producer
+            .set_handshake(handler.stream_start()?, handler.serializer.schema(), handler.serializer.is_json());
+        for destination in message_destinations() {
+            handler.producer.add_destination(destination)?;
+        }
+        for (filter_name, capacity) in message_filter_channels() {
+            handler.producer.add_filter_channel(filter_name, capacity);
+        }
+        Ok(handler)
+    }
+
+    /// Pass filtered messages to the closure, bypassing serialization
+    pub fn with_sink(mut self, sink: MessageSink) -> Self {
+        self.sink = Some(sink);
+        self
+    }
+
+    /// Pass filtered messages to the closure, they are still serialized and sent
+    pub fn with_observer(mut self, observer: MessageSink) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Attach the labels of the source and destination to the messages
+    pub fn with_address_book(mut self, address_book: Arc<AddressBook>) -> Self {
+        self.address_book = Some(address_book);
+        self
+    }
+
+    /// Keep serialized messages in the store along with sending them
+    pub fn with_message_store(mut self, store: Arc<MessageStore>) -> Self {
+        self.message_store = Some(store);
+        self
+    }
+
+    /// Drop filtered messages while the switch is paused
+    pub fn with_pause_switch(mut self, switch: Arc<PauseSwitch>) -> Self {
+        self.pause_switch = Some(switch);
+        self
+    }
+
+    /// Log the serialized payloads of the messages sampled at the sampler rate
+    pub fn with_payload_sampler(mut self, sampler: Arc<PayloadSampler>) -> Self {
+        self.payload_sampler = Some(sampler);
+        self
+    }
+
+    /// Sign every frame sent to the transport, including the handshake
+    pub fn with_signer(mut self, keypair: ed25519::KeyPair) -> FusionResult<Self> {
+        if !self.producer.supports_signing() {
+            return Err(FusionError::config(anyhow::anyhow!("Signing is not supported by the transport")));
+        }
+        self.signer = Some(Arc::new(keypair));
+        self.producer
+            .set_handshake(self.stream_start()?, self.serializer.schema(), self.serializer.is_json());
+        Ok(self)
+    }
+
+    /// Spread account blocks of a block across `workers` threads.
+    /// Transactions of one account stay in order, other records may interleave
+    pub fn with_account_workers(mut self, workers: usize) -> Self {
+        self.account_workers = workers;
+        self
+    }
+
+    /// Tag messages of blocks older than `threshold_sec` as historical,
+    /// optionally sending them to a separate destination
+    pub fn with_historical(mut self, threshold_sec: u32, destination: Option<String>) -> FusionResult<Self> {
+        if let Some(destination) = &destination {
+            self.producer.add_destination(destination)?;
+        }
+        self.realtime_threshold = Some(threshold_sec);
+        self.historical_destination = destination;
+        Ok(self)
+    }
+
+    /// Send the messages of each block in order between `block_begin` and `block_end` frames,
+    /// after the messages of the previous block, bypassing the filter channels. Spawns the sending task
+    pub fn with_atomic_blocks(mut self, enabled: bool) -> Self {
+        self.block_batches = enabled.then(|| {
+            let (sender, receiver) = mpsc::channel(BLOCK_BATCHES_CAPACITY);
+            supervisor::spawn_isolated("sending block batches", send_block_batches(self.producer.clone(), receiver));
+            sender
+        });
+        self
+    }
+
+    /// Drop messages of transactions older than `max_age_sec` instead of sending them
+    pub fn with_max_message_age(mut self, max_age_sec: u32) -> Self {
+        self.max_message_age = Some(max_age_sec);
+        self
+    }
+
+    /// Use the provider for filters if the scanner passes no shard state.
+    /// Provided states are not used for the account states stream
+    pub fn with_state_provider(mut self, state_provider: Arc<dyn StateProvider>) -> Self {
+        self.state_provider = Some(state_provider);
+        self
     }
 
+    /// Handles a block whose committing masterchain block is unknown,
+    /// masterchain blocks commit themselves
     pub async fn handle_block(
         &self,
         block_stuff: &BlockStuff,
         shard_state: Option<&ShardStateStuff>
-    ) -> Result<()> {
+    ) -> FusionResult<()> {
+        let block_id = block_stuff.id();
+        let mc_seqno = block_id.shard().is_masterchain().then_some(block_id.seq_no);
+        self.handle_committed_block(block_stuff, shard_state, mc_seqno).await
+    }
+
+    /// Handles a block committed by the masterchain block `mc_seqno`
+    pub async fn handle_committed_block(
+        &self,
+        block_stuff: &BlockStuff,
+        shard_state: Option<&ShardStateStuff>,
+        mc_seqno: Option<u32>,
+    ) -> FusionResult<()> {
         let block_id = block_stuff.id();
         let block = block_stuff.block();
-        let block_extra = block.read_extra()?;
 
-        tracing::trace!("Processing block: {}", block_id);
+        // All block transactions have the same utime
+        let gen_utime = block.read_info().map_err(FusionError::scanner)?.gen_utime().as_u32();
+        let block_extra = block.read_extra().map_err(FusionError::scanner)?;
 
-        // Process transactions
         let mut changed_accounts = FxHashSet::default();
         let mut deleted_accounts = FxHashSet::default();
-
-        let workchain_id = block_id.shard_id.workchain_id();
+        let mut account_blocks = Vec::new();
 
         block_extra
-            .read_account_blocks()?
+            .read_account_blocks()
+            .map_err(FusionError::scanner)?
             .iterate_objects(|account_block| {
-                tracing::trace!("Processing account block for: {}", account_block.account_addr().as_hex_string());
-
                 let state_update = account_block.read_state_update()?;
 
                 if state_update.old_hash != state_update.new_hash {
@@ -59,74 +299,800 @@ impl BlocksHandler {
                     }
                 }
 
-                account_block
-                    .transactions()
-                    .iterate_slices(|_, raw_transaction| {
-                        let result = self.transaction(
-                            raw_transaction,
-                            &block_id.root_hash,
-                            workchain_id,
-                            shard_state,
-                        );
-                        if let Err(error) = result {
-                            tracing::error!("Transaction handler: {}", error);
-                        }
-                        Ok(true)
-                    })?;
+                account_blocks.push(account_block);
+                Ok(true)
+            })
+            .map_err(FusionError::scanner)?;
+
+        let account_changes = filter_account_changes(
+            block_id.shard().workchain_id(),
+            shard_state,
+            &changed_accounts,
+            &deleted_accounts,
+        );
+
+        // Deleted accounts are matched by the indexed code hash, so before the index is updated
+        let deleted_states = shard_state.map(|_| {
+            deleted_accounts
+                .iter()
+                .filter_map(|account_id| {
+                    let filter = filter_deleted_account(block_id.shard().workchain_id(), account_id)?;
+                    Some((account_id.clone(), filter))
+                })
+                .collect::<FxHashMap<_, _>>()
+        });
+
+        // Code hashes are matched against the state after the block,
+        // so the index follows skipped blocks too
+        let result = code_hashes::update_accounts(block_id.shard(), shard_state, &changed_accounts, &deleted_accounts);
+        if let Err(error) = result {
+            tracing::error!("Updating code hash index: {:?}", error);
+        }
+
+        if !self.range.contains_utime(gen_utime) {
+            tracing::trace!("Skipping block out of range: {}", block_id);
+            return Ok(());
+        }
+
+        tracing::trace!("Processing block: {}", block_id);
+
+        if let Some(traces) = &self.traces {
+            traces.expire(gen_utime).into_iter().for_each(|trace| self.send_trace(trace));
+        }
+        closed_aggregates(gen_utime).into_iter().for_each(|aggregate| self.send_aggregate(aggregate));
+
+        let provided_state = match (shard_state, &self.state_provider) {
+            (None, Some(provider)) => provider.block_state(block_stuff).await.unwrap_or_else(|error| {
+                tracing::error!("State provider: {:?}", error);
+                None
+            }),
+            _ => None,
+        };
+        let filter_state = shard_state.or(provided_state.as_ref());
+        if let Some(state) = filter_state {
+            // State reads of code hash filters happen on the blocking pool, not inline
+            if let Err(error) = code_hashes::prefetch(state, account_blocks.clone()).await {
+                tracing::error!("Prefetching code hashes: {:?}", error);
+            }
+        }
+
+        // Only key blocks contain the config
+        let mc_extra = block_extra.read_custom().map_err(FusionError::scanner)?;
+        if let Some(config) = mc_extra.as_ref().and_then(|extra| extra.config()) {
+            if let Err(error) = self.config_params(&block_id.root_hash, block_id.seq_no, gen_utime, config) {
+                tracing::error!("Config params handler: {}", error);
+            }
+        }
+
+        if let Err(error) = self.account_changes(block_id, gen_utime, account_changes) {
+            tracing::error!("Account changes handler: {}", error);
+        }
+
+        let workchain_id = block_id.shard_id.workchain_id();
+        // Collects the messages of the block with atomic emission
+        let batch = self.block_batches.as_ref().map(|_| Mutex::new(Vec::new()));
+        let states = shard_state.zip(deleted_states).map(|(state, deleted)| BlockStates {
+            state,
+            deleted,
+            serialized: Default::default(),
+        });
+
+        // Process transactions
+        let process = |account_blocks: &[AccountBlock]| -> (u32, u32) {
+            let mut transaction_count = 0;
+            let mut message_count = 0;
+            for account_block in account_blocks {
+                match self.account_block(account_block, block_id, mc_seqno, filter_state, states.as_ref(), batch.as_ref()) {
+                    Ok((transactions, messages)) => {
+                        transaction_count += transactions;
+                        message_count += messages;
+                    }
+                    Err(error) => tracing::error!("Account block handler: {}", error),
+                }
+            }
+            (transaction_count, message_count)
+        };
+
+        // Each account block is processed by one worker, keeping the order of its transactions
+        let (transaction_count, message_count) = if self.account_workers > 1 && account_blocks.len() > 1 {
+            process_in_parallel(&account_blocks, self.account_workers, &process)
+                .into_iter()
+                .fold((0, 0), |(transactions, messages), (worker_transactions, worker_messages)| {
+                    (transactions + worker_transactions, messages + worker_messages)
+                })
+        } else {
+            process(&account_blocks)
+        };
+        if let Some(state) = filter_state {
+            code_hashes::finish_block(state);
+            state_budget::finish_block(state);
+        }
+
+        if let (Some(batch), Some(block_batches)) = (batch, &self.block_batches) {
+            self.send_block_batch(block_id, batch.into_inner().unwrap(), block_batches).await?;
+        }
+
+        // Sent before the next block is handled, so the states of an account keep the block order
+        if let Some(states) = states {
+            let mut serialized = states.serialized.into_inner().unwrap();
+            serialized.sort_by(|(a, _), (b, _)| a.cmp(b));
+            for (_, data) in serialized {
+                if let Err(error) = self.producer.send_to(Stream::AccountStates, data).await {
+                    tracing::error!("Sending account state data: {}", error);
+                }
+            }
+        }
+
+        if self.blocks_stream {
+            let record = SerializeBlock {
+                block_id: block_id.root_hash,
+                file_hash: block_id.file_hash,
+                workchain_id,
+                shard: block_id.shard_id.shard_prefix_with_tag(),
+                seqno: block_id.seq_no,
+                gen_utime,
+                transaction_count,
+                message_count,
+            };
+            let serialized = self.seal(self.serializer.serialize_block(record)?)?;
+
+            let producer = self.producer.clone();
+            supervisor::spawn_isolated("sending blocks", async move {
+                if let Err(error) = producer.send_to(Stream::Blocks, serialized).await {
+                    tracing::error!("Sending block data: {}", error);
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Queues the messages of the block with its boundary frames, waits while the queue is full
+    async fn send_block_batch(
+        &self,
+        block_id: &BlockIdExt,
+        messages: Vec<OutgoingMessage>,
+        block_batches: &mpsc::Sender<BlockBatch>,
+    ) -> FusionResult<()> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+
+        let boundary = BlockBoundary {
+            block_id: block_id.root_hash,
+            workchain_id: block_id.shard_id.workchain_id(),
+            shard: block_id.shard_id.shard_prefix_with_tag(),
+            seqno: block_id.seq_no,
+            message_count: messages.len() as u32,
+        };
+        let batch = BlockBatch {
+            begin: self.seal(self.serializer.serialize_control(ControlEvent::BlockBegin(boundary.clone()))?)?,
+            end: self.seal(self.serializer.serialize_control(ControlEvent::BlockEnd(boundary))?)?,
+            messages,
+        };
+        block_batches
+            .send(batch)
+            .await
+            .map_err(|_| FusionError::transport(anyhow::anyhow!("Block batches are not sent")))
+    }
+
+    /// Tells consumers of all streams to roll back the records of the block
+    pub fn revoke_block(&self, block_id: &BlockIdExt) -> FusionResult<()> {
+        if self.sink.is_some() {
+            tracing::warn!("Revoked block {} is not passed to the message sink", block_id);
+            return Ok(());
+        }
+
+        let event = ControlEvent::Revoked(RevokedBlock {
+            block_id: block_id.root_hash,
+            workchain_id: block_id.shard_id.workchain_id(),
+            shard: block_id.shard_id.shard_prefix_with_tag(),
+            seqno: block_id.seq_no,
+        });
+        let serialized = self.seal(self.serializer.serialize_control(event)?)?;
+
+        let producer = self.producer.clone();
+        supervisor::spawn_isolated("sending control events", async move {
+            if let Err(error) = producer.broadcast(serialized).await {
+                tracing::error!("Sending revocation: {}", error);
+            }
+        });
+
+        Ok(())
+    }
+
+    fn send_bounce(&self, bounce: SerializeBounce) {
+        let serialized = match self.serializer.serialize_bounce(bounce).and_then(|frame| self.seal(frame)) {
+            Ok(serialized) => serialized,
+            Err(error) => {
+                tracing::error!("Serializing bounce: {}", error);
+                return;
+            }
+        };
+
+        let producer = self.producer.clone();
+        supervisor::spawn_isolated("sending bounces", async move {
+            if let Err(error) = producer.send_to(Stream::Bounces, serialized).await {
+                tracing::error!("Sending bounce: {}", error);
+            }
+        });
+    }
+
+    fn send_trace(&self, trace: SerializeTrace) {
+        let serialized = match self.serializer.serialize_trace(trace).and_then(|frame| self.seal(frame)) {
+            Ok(serialized) => serialized,
+            Err(error) => {
+                tracing::error!("Serializing trace: {}", error);
+                return;
+            }
+        };
+
+        let producer = self.producer.clone();
+        supervisor::spawn_isolated("sending traces", async move {
+            if let Err(error) = producer.send_to(Stream::Traces, serialized).await {
+                tracing::error!("Sending trace: {}", error);
+            }
+        });
+    }
+
+    fn send_aggregate(&self, aggregate: SerializeAggregate) {
+        let serialized = match self.serializer.serialize_aggregate(aggregate).and_then(|frame| self.seal(frame)) {
+            Ok(serialized) => serialized,
+            Err(error) => {
+                tracing::error!("Serializing aggregate: {}", error);
+                return;
+            }
+        };
+
+        let producer = self.producer.clone();
+        supervisor::spawn_isolated("sending aggregates", async move {
+            if let Err(error) = producer.send_to(Stream::Aggregates, serialized).await {
+                tracing::error!("Sending aggregate: {}", error);
+            }
+        });
+    }
+
+    /// Sends the status of an external message to the statuses stream
+    pub fn message_status(&self, status: SerializeMessageStatus) -> FusionResult<()> {
+        let serialized = self.seal(self.serializer.serialize_message_status(status)?)?;
+
+        let producer = self.producer.clone();
+        supervisor::spawn_isolated("sending message statuses", async move {
+            if let Err(error) = producer.send_to(Stream::MessageStatuses, serialized).await {
+                tracing::error!("Sending message status: {}", error);
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Serialized first frame of the streams
+    fn stream_start(&self) -> FusionResult<Bytes> {
+        let event = ControlEvent::StreamStart(StreamStart {
+            started_at: self.started_at,
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            protocol_version: PROTOCOL_VERSION,
+            serializer: self.serializer.name().to_owned(),
+        });
+        self.seal(self.serializer.serialize_control(event)?)
+    }
 
+    /// Signs the frame if a signing key is set
+    fn seal(&self, frame: Bytes) -> FusionResult<Bytes> {
+        seal(&self.serializer, self.signer.as_deref(), frame)
+    }
+
+    /// Spawns heartbeats. Transports without subscriptions get the stream start frame here,
+    /// http/2 subscribers always get it first
+    pub async fn start_streams(&self, heartbeat_interval: Duration) -> FusionResult<()> {
+        if !matches!(self.producer.transport, Transport::Http2 { .. }) {
+            self.producer.broadcast(self.stream_start()?).await?;
+        }
+
+        let serializer = self.serializer.clone();
+        let signer = self.signer.clone();
+        let producer = self.producer.clone();
+        let heartbeat = supervisor::spawn_isolated("sending heartbeats", async move {
+            let mut interval = tokio::time::interval(heartbeat_interval);
+            loop {
+                interval.tick().await;
+                let timestamp = now_sec();
+                let events = [
+                    ControlEvent::Heartbeat(Heartbeat { timestamp }),
+                    ControlEvent::Stats(StreamStats {
+                        timestamp,
+                        filters: counters::filter_counters(),
+                    }),
+                ];
+                for event in events {
+                    let serialized = serializer
+                        .serialize_control(event)
+                        .and_then(|frame| seal(&serializer, signer.as_deref(), frame));
+                    let result = match serialized {
+                        Ok(serialized) => producer.broadcast(serialized).await,
+                        Err(error) => Err(error),
+                    };
+                    if let Err(error) = result {
+                        tracing::error!("Sending heartbeat: {}", error);
+                    }
+                }
+            }
+        });
+        if let Some(previous) = self.heartbeat.lock().unwrap().replace(heartbeat) {
+            previous.abort();
+        }
+
+        Ok(())
+    }
+
+    /// Stops heartbeats, sends the stream end frame and waits until buffered frames are written
+    pub async fn end_streams(&self) -> FusionResult<()> {
+        let Some(heartbeat) = self.heartbeat.lock().unwrap().take() else {
+            return self.producer.flush().await;
+        };
+        heartbeat.abort();
+
+        let serialized = self.seal(self.serializer.serialize_control(ControlEvent::StreamEnd)?)?;
+        self.producer.broadcast(serialized).await?;
+        self.producer.flush().await
+    }
+
+    /// Handles the transactions and the state of one account, returns transaction and message counts
+    fn account_block(
+        &self,
+        account_block: &AccountBlock,
+        block_id: &BlockIdExt,
+        mc_seqno: Option<u32>,
+        filter_state: Option<&ShardStateStuff>,
+        states: Option<&BlockStates>,
+        batch: Option<&Mutex<Vec<OutgoingMessage>>>,
+    ) -> Result<(u32, u32)> {
+        tracing::trace!("Processing account block for: {}", account_block.account_addr().as_hex_string());
+
+        let workchain_id = block_id.shard_id.workchain_id();
+        let matching = match &self.accounts {
+            Some(accounts) => accounts.contains(&account_key(workchain_id, account_block.account_id())),
+            None => true,
+        };
+        if !matching {
+            // Skip reading the transactions, no filter matches them
+            let transaction_count = account_block.transaction_count()? as u32;
+            self.account_block_state(account_block, block_id, states);
+            return Ok((transaction_count, 0));
+        }
+
+        let mut transaction_count = 0;
+        let mut message_count = 0;
+        account_block
+            .transactions()
+            .iterate_slices(|_, raw_transaction| {
+                match self.transaction(raw_transaction, block_id, mc_seqno, filter_state, batch) {
+                    Ok(count) => message_count += count as u32,
+                    Err(error) => tracing::error!("Transaction handler: {}", error),
+                }
+                transaction_count += 1;
                 Ok(true)
             })?;
 
-        Ok(())
+        self.account_block_state(account_block, block_id, states);
+
+        Ok((transaction_count, message_count))
+    }
+
+    fn account_block_state(&self, account_block: &AccountBlock, block_id: &BlockIdExt, states: Option<&BlockStates>) {
+        let Some(states) = states else {
+            return;
+        };
+        match self.account_state(account_block, block_id, states) {
+            Ok(Some(serialized)) => {
+                let account_id = UInt256::from_slice(&account_block.account_id().get_bytestring(0));
+                states.serialized.lock().unwrap().push((account_id, serialized));
+            }
+            Ok(None) => {}
+            Err(error) => tracing::error!("Account state handler: {}", error),
+        }
     }
 
     fn transaction(
         &self,
         raw_transaction: ton_types::SliceData,
-        block_id: &ton_types::UInt256,
-        _workchain_id: i32,
+        block_id: &BlockIdExt,
+        mc_seqno: Option<u32>,
         state: Option<&ShardStateStuff>,
-    ) -> Result<()> {
+        batch: Option<&Mutex<Vec<OutgoingMessage>>>,
+    ) -> Result<usize> {
         let cell = raw_transaction.reference(0)?;
         let id = cell.repr_hash();
         let transaction = ton_block::Transaction::construct_from_cell(cell)?;
 
         tracing::trace!("Transaction handle: {}", id.as_hex_string());
 
+        Ok(self.process_transaction(transaction, block_id, mc_seqno, state, batch))
+    }
+
+    /// Filters a single transaction and sends matched messages.
+    /// `mc_seqno` is the masterchain block which committed the block, if known.
+    /// Returns the number of matched messages
+    pub fn handle_transaction(
+        &self,
+        transaction: ton_block::Transaction,
+        block_id: &BlockIdExt,
+        mc_seqno: Option<u32>,
+        state: Option<&ShardStateStuff>,
+    ) -> usize {
+        self.process_transaction(transaction, block_id, mc_seqno, state, None)
+    }
+
+    /// Filters the transaction, matched messages are added to the block batch if any
+    /// instead of being sent
+    fn process_transaction(
+        &self,
+        transaction: ton_block::Transaction,
+        block_id: &BlockIdExt,
+        mc_seqno: Option<u32>,
+        state: Option<&ShardStateStuff>,
+        batch: Option<&Mutex<Vec<OutgoingMessage>>>,
+    ) -> usize {
         let serializer = self.serializer.clone();
-        let messages = filter_transaction(transaction, state, Default::default());
+        let bounce_tx = self.bounces.as_ref().map(|_| transaction.clone());
+        let trace_tx = self.traces.as_ref().map(|_| transaction.clone());
+        let mut messages = filter_transaction(transaction, state, &self.range);
         tracing::trace!("Filtered {} messages", messages.len());
 
+        if let (Some(bounces), Some(tx)) = (&self.bounces, bounce_tx) {
+            messages.iter().for_each(|msg| bounces.track(msg));
+            match bounces.correlate(&tx, &block_id.root_hash) {
+                Ok(Some(bounce)) => self.send_bounce(bounce),
+                Ok(None) => {}
+                Err(error) => tracing::error!("Correlating bounce: {}", error),
+            }
+        }
+
+        if let (Some(traces), Some(tx)) = (&self.traces, trace_tx) {
+            match traces.process(&tx, block_id, &messages) {
+                Ok(Some(trace)) => self.send_trace(trace),
+                Ok(None) => {}
+                Err(error) => tracing::error!("Assembling trace: {}", error),
+            }
+        }
+
+        if let Some(max_age) = self.max_message_age {
+            let now = now_sec();
+            messages.retain(|msg| {
+                let fresh = now.saturating_sub(msg.tx.now) <= max_age;
+                if !fresh {
+                    latency::record_stale_drop(&msg.contract_name, &msg.filter_name);
+                }
+                fresh
+            });
+        }
+
+        if let Some(switch) = self.pause_switch.as_ref().filter(|switch| switch.is_paused()) {
+            switch.record_dropped(messages.len());
+            return 0;
+        }
+
+        if let Some(sink) = &self.sink {
+            let count = messages.len();
+            for msg in messages {
+                let msg = self.message(msg, block_id, mc_seqno);
+                let (contract_name, filter_name) = (msg.contract_name.clone(), msg.filter_name.clone());
+                let gen_utime = msg.transaction_timestamp;
+                sink(msg);
+                latency::record_delivery(&contract_name, &filter_name, gen_utime);
+            }
+            return count;
+        }
+
         let serialized = messages.into_iter()
             .map(|msg| {
-                let msg = SerializeMessage {
-                    block_id: *block_id,
-                    ..msg.into()
-                };
-                let serialized = serializer.serialize_message(msg);
-                if let Err(error) = &serialized {
-                    tracing::error!("Serializing message: {}", error);
+                let msg = self.message(msg, block_id, mc_seqno);
+                if let Some(observer) = &self.observer {
+                    observer(msg.clone());
+                }
+                let labels = (
+                    msg.destination.clone(),
+                    (msg.contract_name.clone(), msg.filter_name.clone(), msg.transaction_timestamp),
+                );
+                let filter_name = msg.filter_name.clone();
+                let sampler = self.payload_sampler.as_ref().filter(|sampler| sampler.sample());
+                let message_size = sampler.and_then(|_| msg.message.write_to_bytes().ok()).map(|boc| boc.len());
+                let serialized = serializer.serialize_message(msg).and_then(|frame| self.seal(frame));
+                if let (Some(sampler), Ok(frame)) = (sampler, &serialized) {
+                    sampler.log(&filter_name, message_size, frame);
+                }
+                match (&serialized, &self.message_store) {
+                    (Ok(frame), Some(store)) => {
+                        if let Err(error) = store.push(&filter_name, frame) {
+                            tracing::error!("Storing message: {:?}", error);
+                        }
+                    }
+                    (Ok(_), None) => {}
+                    (Err(error), _) => tracing::error!("Serializing message: {}", error),
                 }
-                serialized.unwrap_or_default()
+                (serialized.unwrap_or_default(), labels)
             })
-            .collect::<Vec<_>>();
+            .collect::<Vec<OutgoingMessage>>();
         tracing::trace!("Serialized {} messages", serialized.len());
+        let count = serialized.len();
+        if let Some(batch) = batch {
+            batch.lock().unwrap().extend(serialized);
+            return count;
+        }
         // Send to transport layer
         let producer = self.producer.clone();
-        tokio::spawn(async move {
+        supervisor::spawn_isolated("sending messages", async move {
+            let (serialized, labels): (Vec<_>, Vec<_>) = serialized.into_iter().unzip();
+            let (destinations, labels): (Vec<_>, Vec<_>) = labels.into_iter().unzip();
+            let sizes = serialized.iter().map(Bytes::len).collect::<Vec<_>>();
             let futures = serialized
                 .into_iter()
-                .map(|data| producer.send_data(data));
-            for result in join_all(futures).await {
+                .zip(destinations.iter().zip(&labels))
+                .map(|(data, (destination, (_, filter_name, _)))| {
+                    producer.send_message(filter_name, destination.as_deref(), data)
+                });
+            let results = join_all(futures).await;
+            for ((result, size), (contract_name, filter_name, gen_utime)) in results.into_iter().zip(sizes).zip(labels) {
                 tracing::trace!("Message data sent");
-                if let Err(error) = result {
-                    tracing::error!("Sending message data: {}", error);
+                match result {
+                    Ok(()) => {
+                        latency::record_delivery(&contract_name, &filter_name, gen_utime);
+                        counters::record_emitted(&filter_name, size);
+                    }
+                    Err(error) => tracing::error!("Sending message data: {}", error),
+                }
+            }
+        });
+
+        count
+    }
+
+    /// Record of the filtered message, tagged as historical if the block is old.
+    /// Messages of tenants are not moved to the historical destination
+    fn message(&self, msg: FilteredMessage, block_id: &BlockIdExt, mc_seqno: Option<u32>) -> SerializeMessage {
+        let tenant = msg.tenant.is_some();
+        let mut msg = SerializeMessage::from(msg).with_block(block_id, mc_seqno);
+        if let Some(threshold) = self.realtime_threshold {
+            msg.historical = now_sec().saturating_sub(msg.transaction_timestamp) > threshold;
+        }
+        if msg.historical && !tenant && self.historical_destination.is_some() {
+            msg.destination = self.historical_destination.clone();
+        }
+        if let Some(book) = &self.address_book {
+            msg.src_label = msg.src.as_ref().and_then(|address| book.label(address));
+            msg.dst_label = msg.dst.as_ref().and_then(|address| book.label(address));
+        }
+        msg
+    }
+
+    /// Emit config params which changed since the previous key block.
+    /// The first key block only initializes the known params
+    fn config_params(
+        &self,
+        block_id: &UInt256,
+        seqno: u32,
+        gen_utime: u32,
+        config: &ConfigParams,
+    ) -> Result<()> {
+        let mut current = FxHashMap::<u32, Cell>::default();
+        config.config_params.iterate_slices(|mut key, value| {
+            current.insert(key.get_next_u32()?, value.reference(0)?);
+            Ok(true)
+        })?;
+
+        let mut known = self.config_params.lock().unwrap();
+        let mut changed = Vec::new();
+        if !known.is_empty() {
+            for (param, cell) in &current {
+                if known.get(param) != Some(&cell.repr_hash()) {
+                    changed.push((*param, Some(cell)));
+                }
+            }
+            for param in known.keys() {
+                if !current.contains_key(param) {
+                    changed.push((*param, None));
+                }
+            }
+        }
+        *known = current
+            .iter()
+            .map(|(param, cell)| (*param, cell.repr_hash()))
+            .collect();
+        drop(known);
+
+        changed.sort_by_key(|(param, _)| *param);
+        let mut serialized = Vec::new();
+        for (param, cell) in changed {
+            tracing::debug!("Config param {} changed in block {}", param, block_id.as_hex_string());
+            let data_boc = cell.map(serialize_toc).transpose()?;
+            for filter in filter_config_param(param) {
+                let record = SerializeConfigParam {
+                    filter_name: filter.name.clone(),
+                    block_id: *block_id,
+                    seqno,
+                    gen_utime,
+                    param,
+                    data_boc: data_boc.clone(),
+                };
+                serialized.push(self.seal(self.serializer.serialize_config_param(record)?)?);
+            }
+        }
+        if serialized.is_empty() {
+            return Ok(());
+        }
+
+        let producer = self.producer.clone();
+        supervisor::spawn_isolated("sending config params", async move {
+            for data in serialized {
+                if let Err(error) = producer.send_to(Stream::ConfigParams, data).await {
+                    tracing::error!("Sending config param data: {}", error);
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Emits the changed and deleted accounts of the block per account change filter
+    fn account_changes(&self, block_id: &BlockIdExt, gen_utime: u32, changes: Vec<AccountChanges>) -> FusionResult<()> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        let mut serialized = Vec::with_capacity(changes.len());
+        for change in changes {
+            let record = SerializeAccountChanges {
+                filter_name: change.filter.name.clone(),
+                block_id: block_id.root_hash,
+                workchain_id: block_id.shard_id.workchain_id(),
+                shard: block_id.shard_id.shard_prefix_with_tag(),
+                seqno: block_id.seq_no,
+                gen_utime,
+                changed_accounts: change.changed,
+                deleted_accounts: change.deleted,
+            };
+            serialized.push(self.seal(self.serializer.serialize_account_changes(record)?)?);
+        }
+
+        let producer = self.producer.clone();
+        supervisor::spawn_isolated("sending account changes", async move {
+            for data in serialized {
+                if let Err(error) = producer.send_to(Stream::AccountChanges, data).await {
+                    tracing::error!("Sending account changes: {}", error);
                 }
             }
         });
 
         Ok(())
     }
+
+    /// Serializes the account state after the block if the account matches any account filter,
+    /// a `nonexist` state without code and data if the account was deleted in the block
+    fn account_state(&self, account_block: &AccountBlock, block_id: &BlockIdExt, states: &BlockStates) -> Result<Option<Bytes>> {
+        let account_id = account_block.account_id();
+        let workchain_id = block_id.shard_id.workchain_id();
+        let id = UInt256::from_slice(&account_id.get_bytestring(0));
+
+        let account_state = match states.deleted.get(account_id) {
+            Some(filter) => {
+                let (last_transaction_hash, last_transaction_lt) = last_transaction(account_block)?;
+                SerializeAccountState::deleted(
+                    filter.name.clone(),
+                    block_id.root_hash,
+                    workchain_id,
+                    id,
+                    last_transaction_hash,
+                    last_transaction_lt,
+                )
+            }
+            None => {
+                let Some((filter, shard_account)) = filter_account(states.state, workchain_id, account_id)? else {
+                    return Ok(None);
+                };
+                SerializeAccountState::new(filter.name.clone(), block_id.root_hash, workchain_id, id, &shard_account)?
+            }
+        };
+        Ok(Some(self.seal(self.serializer.serialize_account_state(account_state)?)?))
+    }
+}
+
+/// Hash and logical time of the last transaction of the account block
+fn last_transaction(account_block: &AccountBlock) -> Result<(UInt256, u64)> {
+    let mut last = (UInt256::default(), 0);
+    account_block.transactions().iterate_slices(|mut key, raw_transaction| {
+        let lt = key.get_next_u64()?;
+        if lt >= last.1 {
+            last = (raw_transaction.reference(0)?.repr_hash(), lt);
+        }
+        Ok(true)
+    })?;
+    Ok(last)
+}
+
+/// Splits the items into `workers` chunks, each processed in order by a scoped thread.
+/// On a multi-thread runtime the calling worker thread is handed over to other tasks while waiting
+fn process_in_parallel<T, R>(items: &[T], workers: usize, process: impl Fn(&[T]) -> R + Sync) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+{
+    let workers = workers.max(1);
+    let chunk_size = (items.len() + workers - 1) / workers;
+    let runtime = tokio::runtime::Handle::current();
+    let run = || {
+        std::thread::scope(|scope| {
+            let workers = items
+                .chunks(chunk_size.max(1))
+                .map(|chunk| {
+                    let (runtime, process) = (&runtime, &process);
+                    scope.spawn(move || {
+                        // Send tasks are spawned from the workers
+                        let _guard = runtime.enter();
+                        process(chunk)
+                    })
+                })
+                .collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+                .collect()
+        })
+    };
+    match runtime.runtime_flavor() {
+        RuntimeFlavor::MultiThread => tokio::task::block_in_place(run),
+        // Not supported by the current thread runtime, which is blocked until the workers finish
+        _ => run(),
+    }
+}
+
+/// Sends the block batches one by one. Boundary frames go to every output of the block messages.
+/// Filter channels are not used, subscribers merge them in any order, so the messages
+/// of an output share its channel with the boundary frames
+async fn send_block_batches(producer: Producer, mut receiver: mpsc::Receiver<BlockBatch>) {
+    while let Some(batch) = receiver.recv().await {
+        let mut destinations = batch
+            .messages
+            .iter()
+            .map(|(_, (destination, _))| destination.clone())
+            .collect::<Vec<_>>();
+        destinations.sort();
+        destinations.dedup();
+
+        for destination in &destinations {
+            if let Err(error) = producer.send_to_destination(destination.as_deref(), batch.begin.clone()).await {
+                tracing::error!("Sending block begin: {}", error);
+            }
+        }
+        for (data, (destination, (contract_name, filter_name, gen_utime))) in batch.messages {
+            let size = data.len();
+            match producer.send_to_destination(destination.as_deref(), data).await {
+                Ok(()) => {
+                    latency::record_delivery(&contract_name, &filter_name, gen_utime);
+                    counters::record_emitted(&filter_name, size);
+                }
+                Err(error) => tracing::error!("Sending message data: {}", error),
+            }
+        }
+        for destination in &destinations {
+            if let Err(error) = producer.send_to_destination(destination.as_deref(), batch.end.clone()).await {
+                tracing::error!("Sending block end: {}", error);
+            }
+        }
+    }
+}
+
+fn seal(serializer: &Serializer, signer: Option<&ed25519::KeyPair>, frame: Bytes) -> FusionResult<Bytes> {
+    match signer {
+        Some(keypair) => serializer.sign(&frame, keypair),
+        None => Ok(frame),
+    }
+}
+
+pub(crate) fn now_sec() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as u32
 }
 
 fn default_account_hash() -> &'static ton_types::UInt256 {
@@ -140,3 +1106,94 @@ fn default_account_hash() -> &'static ton_types::UInt256 {
         )
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use tokio::sync::mpsc;
+
+    use crate::filter::config::TransactionRange;
+    use crate::producer::tests::{http2_producer, read, subscribe};
+    use crate::serializer::Serializer;
+
+    use super::{process_in_parallel, send_block_batches, BlockBatch, BlocksHandler, OutgoingMessage};
+
+    fn message(data: &'static str, filter_name: &str) -> OutgoingMessage {
+        (Bytes::from_static(data.as_bytes()), (None, ("Contract".to_owned(), filter_name.to_owned(), 0)))
+    }
+
+    #[tokio::test]
+    async fn test_block_batch_order() {
+        let producer = http2_producer(31101);
+        producer.add_filter_channel("a", None);
+        producer.add_filter_channel("b", None);
+        // Merges the messages stream channel with the filter channels
+        let mut subscription = subscribe(31101, "/messages/data").await;
+
+        let (sender, receiver) = mpsc::channel(1);
+        let batch = BlockBatch {
+            begin: Bytes::from_static(b"<begin>"),
+            end: Bytes::from_static(b"<end>"),
+            messages: vec![message("<a1>", "a"), message("<b1>", "b"), message("<a2>", "a")],
+        };
+        sender.send(batch).await.unwrap();
+        drop(sender);
+        send_block_batches(producer, receiver).await;
+
+        let expected = b"<begin><a1><b1><a2><end>";
+        assert_eq!(read(&mut subscription, expected.len()).await, expected);
+    }
+
+    #[tokio::test]
+    async fn test_heartbeats_to_destination() {
+        let producer = http2_producer(31102);
+        let serializer = Serializer::Json {
+            address_format: Default::default(),
+            normalize_amounts: false,
+        };
+        let handler = BlocksHandler::new(serializer, producer, TransactionRange::default(), false).unwrap();
+        handler.producer.add_destination("transfers").unwrap();
+        let mut subscription = subscribe(31102, "/messages/transfers/data").await;
+
+        handler.start_streams(Duration::from_millis(50)).await.unwrap();
+        let mut data = Vec::new();
+        while !String::from_utf8_lossy(&data).contains(r#""type":"heartbeat""#) {
+            data.extend(read(&mut subscription, 1).await);
+        }
+        handler.end_streams().await.unwrap();
+        while !String::from_utf8_lossy(&data).contains(r#""type":"stream_end""#) {
+            data.extend(read(&mut subscription, 1).await);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_account_workers_order() {
+        // Transaction lts of the account blocks
+        let account_blocks = (0..10u64).map(|account| (account, (0..20).collect::<Vec<u64>>())).collect::<Vec<_>>();
+        let processed = Mutex::new(Vec::new());
+        let counts = process_in_parallel(&account_blocks, 4, |chunk: &[(u64, Vec<u64>)]| {
+            for (account, transactions) in chunk {
+                for lt in transactions {
+                    processed.lock().unwrap().push((*account, *lt));
+                    std::thread::yield_now();
+                }
+            }
+            chunk.len()
+        });
+        assert_eq!(counts.iter().sum::<usize>(), account_blocks.len());
+
+        let processed = processed.into_inner().unwrap();
+        assert_eq!(processed.len(), 200);
+        for (account, transactions) in &account_blocks {
+            let order = processed
+                .iter()
+                .filter(|(processed_account, _)| processed_account == account)
+                .map(|(_, lt)| *lt)
+                .collect::<Vec<_>>();
+            assert_eq!(&order, transactions);
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use std::any::Any;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::Duration;
+
+use anyhow::Result;
+use futures_util::{Future, FutureExt};
+use rustc_hash::FxHashMap;
+use tokio::task::JoinHandle;
+
+/// Restarts after this many panics in a row are given up
+const MAX_RESTARTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Panics caught in a component
+#[derive(Debug, Default)]
+pub struct ComponentStats {
+    pub panics: AtomicU64,
+    pub restarts: AtomicU64,
+}
+
+static COMPONENTS: OnceLock<RwLock<FxHashMap<&'static str, Arc<ComponentStats>>>> = OnceLock::new();
+
+fn component(name: &'static str) -> Arc<ComponentStats> {
+    let components = COMPONENTS.get_or_init(Default::default);
+    if let Some(stats) = components.read().unwrap().get(name) {
+        return stats.clone();
+    }
+    components.write().unwrap().entry(name).or_default().clone()
+}
+
+/// Returns stats of the components which panicked at least once
+pub fn component_stats() -> Vec<(&'static str, Arc<ComponentStats>)> {
+    let Some(components) = COMPONENTS.get() else {
+        return Vec::new();
+    };
+    let mut result = components
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(name, stats)| (*name, stats.clone()))
+        .collect::<Vec<_>>();
+    result.sort_by_key(|(name, _)| *name);
+    result
+}
+
+/// Spawns a detached task. A panic is logged and counted
+/// instead of silently dropping the task
+pub fn spawn_isolated<F>(name: &'static str, future: F) -> JoinHandle<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        if let Err(panic) = AssertUnwindSafe(future).catch_unwind().await {
+            component(name).panics.fetch_add(1, Ordering::Relaxed);
+            tracing::error!("{name} panicked: {}", panic_message(&panic));
+        }
+    })
+}
+
+/// Runs the future created by `f`, creating it again after a panic
+/// with exponential backoff. Fails after `MAX_RESTARTS` restarts
+pub async fn run_supervised<F, Fut, T>(name: &'static str, mut f: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut backoff = INITIAL_BACKOFF;
+    let mut restarts = 0;
+    loop {
+        let panic = match AssertUnwindSafe(f()).catch_unwind().await {
+            Ok(result) => return result,
+            Err(panic) => panic_message(&panic).to_owned(),
+        };
+
+        let stats = component(name);
+        stats.panics.fetch_add(1, Ordering::Relaxed);
+        if restarts == MAX_RESTARTS {
+            anyhow::bail!("{name} panicked {} times in a row: {panic}", restarts + 1);
+        }
+
+        tracing::error!("{name} panicked, restarting in {backoff:?}: {panic}");
+        tokio::time::sleep(backoff).await;
+        stats.restarts.fetch_add(1, Ordering::Relaxed);
+        restarts += 1;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+fn panic_message(panic: &Box<dyn Any + Send>) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
@@ -0,0 +1,368 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use anyhow::Result;
+use rustc_hash::{FxHashMap, FxHashSet};
+use ton_block::{BlockIdExt, CommonMsgInfo, Deserializable, GetRepresentationHash, Transaction};
+use ton_types::{HashmapType, UInt256};
+
+use crate::types::{transaction_aborted, FilteredMessage, SerializeTrace, TraceTransaction};
+
+/// Traces are emitted as incomplete after this long since the root transaction
+const TRACE_TIMEOUT_SEC: u32 = 60;
+/// New roots are not traced while this many traces are pending
+const MAX_PENDING_TRACES: usize = 10_000;
+/// Traces are emitted as incomplete once they reach this many transactions
+const MAX_TRACE_TRANSACTIONS: usize = 1_000;
+/// Number of latest untraced transactions kept in case their parent is processed later,
+/// e.g. in a block of another shard
+const RECENT_WINDOW: usize = 100_000;
+
+/// Assembles the transactions of the message chains started by matched transactions
+#[derive(Default)]
+pub struct TraceTracker {
+    inner: Mutex<Traces>,
+}
+
+#[derive(Default)]
+struct Traces {
+    traces: FxHashMap<UInt256, PendingTrace>,
+    /// Internal out-messages of the pending traces not processed yet, to the trace id
+    pending_messages: FxHashMap<UInt256, UInt256>,
+    /// Untraced transactions by the hash of their internal inbound message
+    recent: FxHashMap<UInt256, ProcessedTransaction>,
+    /// Inbound message hashes of the recent transactions, oldest first
+    recent_order: VecDeque<UInt256>,
+}
+
+struct PendingTrace {
+    contract_name: String,
+    filter_name: String,
+    started_at: u32,
+    transactions: Vec<TraceTransaction>,
+    /// Number of internal out-messages not processed yet
+    pending: usize,
+}
+
+struct ProcessedTransaction {
+    transaction: TraceTransaction,
+    /// Hashes of the internal out-messages
+    internal_messages: Vec<UInt256>,
+}
+
+impl TraceTracker {
+    /// Adds the transaction to its trace or starts a new one if a matched message is of a filter
+    /// with `trace`. Returns the trace once the transaction completes it
+    pub fn process(
+        &self,
+        tx: &Transaction,
+        block_id: &BlockIdExt,
+        messages: &[FilteredMessage],
+    ) -> Result<Option<SerializeTrace>> {
+        let processed = processed_transaction(tx, block_id)?;
+        let in_message_hash = processed.transaction.in_message_hash;
+
+        let mut inner = self.inner.lock().unwrap();
+        let parent = in_message_hash.and_then(|hash| inner.pending_messages.remove(&hash));
+        let trace_id = match parent {
+            Some(trace_id) => {
+                if let Some(trace) = inner.traces.get_mut(&trace_id) {
+                    trace.pending -= 1;
+                }
+                trace_id
+            }
+            None => match messages.iter().find(|message| message.trace) {
+                Some(root) if inner.traces.len() < MAX_PENDING_TRACES => {
+                    let trace_id = processed.transaction.transaction_id;
+                    inner.traces.insert(trace_id, PendingTrace {
+                        contract_name: root.contract_name.clone(),
+                        filter_name: root.filter_name.clone(),
+                        started_at: tx.now,
+                        transactions: Vec::new(),
+                        pending: 0,
+                    });
+                    trace_id
+                }
+                Some(_) => {
+                    tracing::warn!(
+                        "{} traces are pending, transaction {} is not traced",
+                        MAX_PENDING_TRACES,
+                        processed.transaction.transaction_id.to_hex_string()
+                    );
+                    return Ok(None);
+                }
+                None => {
+                    if let Some(hash) = in_message_hash.filter(|_| is_internal_inbound(tx)) {
+                        inner.remember(hash, processed);
+                    }
+                    return Ok(None);
+                }
+            },
+        };
+
+        Ok(inner.attach(trace_id, processed))
+    }
+
+    /// Emits the traces started before `now` by more than the timeout as incomplete
+    pub fn expire(&self, now: u32) -> Vec<SerializeTrace> {
+        let mut inner = self.inner.lock().unwrap();
+        let expired = inner
+            .traces
+            .iter()
+            .filter(|(_, trace)| trace.started_at.saturating_add(TRACE_TIMEOUT_SEC) < now)
+            .map(|(trace_id, _)| *trace_id)
+            .collect::<FxHashSet<_>>();
+        if expired.is_empty() {
+            return Vec::new();
+        }
+        inner.pending_messages.retain(|_, trace_id| !expired.contains(trace_id));
+        expired
+            .into_iter()
+            .filter_map(|trace_id| inner.traces.remove(&trace_id).map(|trace| trace.finish(trace_id, false)))
+            .collect()
+    }
+}
+
+impl Traces {
+    fn remember(&mut self, in_message_hash: UInt256, processed: ProcessedTransaction) {
+        if self.recent_order.len() >= RECENT_WINDOW {
+            if let Some(oldest) = self.recent_order.pop_front() {
+                self.recent.remove(&oldest);
+            }
+        }
+        self.recent_order.push_back(in_message_hash);
+        self.recent.insert(in_message_hash, processed);
+    }
+
+    /// Adds the transaction and the already processed transactions of its out-messages
+    /// to the trace, returns the trace if it is finished
+    fn attach(&mut self, trace_id: UInt256, processed: ProcessedTransaction) -> Option<SerializeTrace> {
+        let mut queue = vec![processed];
+        while let Some(processed) = queue.pop() {
+            let trace = self.traces.get_mut(&trace_id)?;
+            trace.transactions.push(processed.transaction);
+            for hash in processed.internal_messages {
+                match self.recent.remove(&hash) {
+                    // Its position in the order is dropped when it falls out of the window
+                    Some(child) => queue.push(child),
+                    None => {
+                        trace.pending += 1;
+                        self.pending_messages.insert(hash, trace_id);
+                    }
+                }
+            }
+        }
+
+        let trace = self.traces.get(&trace_id)?;
+        let complete = trace.pending == 0;
+        if !complete && trace.transactions.len() < MAX_TRACE_TRANSACTIONS {
+            return None;
+        }
+        if !complete {
+            self.pending_messages.retain(|_, id| *id != trace_id);
+        }
+        self.traces.remove(&trace_id).map(|trace| trace.finish(trace_id, complete))
+    }
+}
+
+impl PendingTrace {
+    fn finish(self, trace_id: UInt256, complete: bool) -> SerializeTrace {
+        SerializeTrace {
+            trace_id,
+            contract_name: self.contract_name,
+            filter_name: self.filter_name,
+            complete,
+            started_at: self.started_at,
+            transactions: self.transactions,
+        }
+    }
+}
+
+fn is_internal_inbound(tx: &Transaction) -> bool {
+    tx.read_in_msg()
+        .ok()
+        .flatten()
+        .map_or(false, |message| matches!(message.header(), CommonMsgInfo::IntMsgInfo(_)))
+}
+
+fn processed_transaction(tx: &Transaction, block_id: &BlockIdExt) -> Result<ProcessedTransaction> {
+    let mut out_message_hashes = Vec::new();
+    let mut internal_messages = Vec::new();
+    tx.out_msgs.iterate_slices(|slice| {
+        let cell = slice.reference(0)?;
+        let message = ton_block::Message::construct_from_cell(cell.clone())?;
+        let hash = cell.repr_hash();
+        if matches!(message.header(), CommonMsgInfo::IntMsgInfo(_)) {
+            internal_messages.push(hash);
+        }
+        out_message_hashes.push(hash);
+        Ok(true)
+    })?;
+
+    Ok(ProcessedTransaction {
+        transaction: TraceTransaction {
+            transaction_id: tx.hash()?,
+            block_id: block_id.root_hash,
+            workchain_id: block_id.shard().workchain_id(),
+            account_id: UInt256::from_slice(&tx.account_addr.get_bytestring(0)),
+            lt: tx.lt,
+            timestamp: tx.now,
+            in_message_hash: tx.in_msg_cell().map(|cell| cell.repr_hash()),
+            out_message_hashes,
+            aborted: transaction_aborted(tx),
+        },
+        internal_messages,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use ton_block::{CurrencyCollection, InternalMessageHeader, Message, MsgAddressInt, ShardIdent};
+    use ton_types::AccountId;
+
+    use crate::types::MessageType;
+    use super::*;
+
+    const SENDER: &str = "0:1ef42a3c649061ba446f2d5ae5219380573c78de3541fe67c742ead0cae68d0d";
+    const RECEIVER: &str = "0:e6f7da94405c55c9fb14b5be6b8f91bba1be76e678900ecb418499bfe37ada05";
+    const NOW: u32 = 1700000000;
+
+    /// Internal message, the value tells the messages apart
+    fn internal_message(value: u64) -> Message {
+        let header = InternalMessageHeader::with_addresses(
+            MsgAddressInt::from_str(SENDER).unwrap(),
+            MsgAddressInt::from_str(RECEIVER).unwrap(),
+            CurrencyCollection::with_grams(value),
+        );
+        Message::with_int_header(header)
+    }
+
+    fn transaction(lt: u64, in_msg: &Message, out_msgs: &[Message]) -> Transaction {
+        let mut tx = Transaction::default();
+        tx.account_addr = AccountId::from_raw(vec![1; 32], 256);
+        tx.lt = lt;
+        tx.now = NOW;
+        tx.write_in_msg(Some(in_msg)).unwrap();
+        for message in out_msgs {
+            tx.add_out_message(message).unwrap();
+        }
+        tx
+    }
+
+    fn block_id() -> BlockIdExt {
+        BlockIdExt::with_params(ShardIdent::full(0), 1, UInt256::from([1; 32]), UInt256::default())
+    }
+
+    fn traced(message: &Message) -> FilteredMessage {
+        FilteredMessage {
+            name: "transfer".to_string(),
+            message_hash: message.hash().unwrap(),
+            message: message.clone(),
+            message_type: MessageType::InternalInbound,
+            tx: Default::default(),
+            index_in_transaction: 0,
+            contract_name: "Wallet".to_string(),
+            filter_name: "transfers".to_string(),
+            destination: None,
+            tenant: None,
+            matched_filters: Vec::new(),
+            track_bounces: false,
+            trace: true,
+            function_id: None,
+            expire_at: None,
+            tokens: Default::default(),
+            enrichment: Default::default(),
+            routing: Default::default(),
+            payload: Default::default(),
+        }
+    }
+
+    /// Root transaction with two children, the first one has a child too
+    fn chain() -> (Vec<Transaction>, FilteredMessage) {
+        let (root_in, a, b, c) = (internal_message(1), internal_message(2), internal_message(3), internal_message(4));
+        let transactions = vec![
+            transaction(1, &root_in, &[a.clone(), b.clone()]),
+            transaction(2, &a, &[c.clone()]),
+            transaction(3, &b, &[]),
+            transaction(4, &c, &[]),
+        ];
+        (transactions, traced(&root_in))
+    }
+
+    fn ids(trace: &SerializeTrace) -> Vec<UInt256> {
+        trace.transactions.iter().map(|tx| tx.transaction_id).collect()
+    }
+
+    #[test]
+    fn test_trace_in_order() {
+        let tracker = TraceTracker::default();
+        let (transactions, root) = chain();
+
+        assert!(tracker.process(&transactions[0], &block_id(), &[root]).unwrap().is_none());
+        assert!(tracker.process(&transactions[1], &block_id(), &[]).unwrap().is_none());
+        assert!(tracker.process(&transactions[2], &block_id(), &[]).unwrap().is_none());
+        let trace = tracker.process(&transactions[3], &block_id(), &[]).unwrap().unwrap();
+
+        assert!(trace.complete);
+        assert_eq!(trace.trace_id, transactions[0].hash().unwrap());
+        assert_eq!(trace.started_at, NOW);
+        assert_eq!(trace.filter_name, "transfers");
+        let expected = transactions.iter().map(|tx| tx.hash().unwrap()).collect::<Vec<_>>();
+        assert_eq!(ids(&trace), expected);
+    }
+
+    #[test]
+    fn test_trace_children_first() {
+        let tracker = TraceTracker::default();
+        let (transactions, root) = chain();
+
+        // Children processed before the root, e.g. in a block of another shard
+        for tx in transactions[1..].iter().rev() {
+            assert!(tracker.process(tx, &block_id(), &[]).unwrap().is_none());
+        }
+        let trace = tracker.process(&transactions[0], &block_id(), &[root]).unwrap().unwrap();
+        assert!(trace.complete);
+
+        let mut ids = ids(&trace);
+        assert_eq!(ids[0], transactions[0].hash().unwrap());
+        let mut expected = transactions.iter().map(|tx| tx.hash().unwrap()).collect::<Vec<_>>();
+        ids.sort();
+        expected.sort();
+        assert_eq!(ids, expected);
+        assert!(tracker.inner.lock().unwrap().recent.is_empty());
+    }
+
+    #[test]
+    fn test_trace_expire() {
+        let tracker = TraceTracker::default();
+        let (transactions, root) = chain();
+
+        assert!(tracker.process(&transactions[0], &block_id(), &[root]).unwrap().is_none());
+        assert!(tracker.expire(NOW + TRACE_TIMEOUT_SEC).is_empty());
+
+        let expired = tracker.expire(NOW + TRACE_TIMEOUT_SEC + 1);
+        assert_eq!(expired.len(), 1);
+        assert!(!expired[0].complete);
+        assert_eq!(ids(&expired[0]), [transactions[0].hash().unwrap()]);
+
+        // Late children are not attached to the expired trace
+        assert!(tracker.process(&transactions[1], &block_id(), &[]).unwrap().is_none());
+        let inner = tracker.inner.lock().unwrap();
+        assert!(inner.traces.is_empty());
+        assert!(inner.pending_messages.is_empty());
+    }
+
+    #[test]
+    fn test_untraced_transaction() {
+        let tracker = TraceTracker::default();
+        let (transactions, root) = chain();
+        let untraced = FilteredMessage { trace: false, ..root };
+
+        assert!(tracker.process(&transactions[0], &block_id(), &[untraced]).unwrap().is_none());
+        let inner = tracker.inner.lock().unwrap();
+        assert!(inner.traces.is_empty());
+        assert_eq!(inner.recent.len(), 1);
+    }
+}
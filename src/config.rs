@@ -1,10 +1,11 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use anyhow::{Context, Result};
 use everscale_network::{adnl, dht, overlay, rldp};
 use rand::Rng;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 use ton_indexer::OldBlocksPolicy;
 
 use crate::{
@@ -17,7 +18,7 @@ use crate::{
 #[derive(Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct AppConfig {
-    /// serve states
+    /// Serve states of the node, only for `FromNetwork` and `ArchivesThenNetwork`
     #[serde(default)]
     pub rpc_config: Option<everscale_rpc_server::Config>,
 
@@ -37,6 +38,113 @@ pub struct AppConfig {
 
     /// Data transport type
     pub transport: Transport,
+
+    /// Emit per-block metadata (tx count, matched messages) to the `blocks` stream.
+    /// Allows consumers to detect gaps even when no filters match
+    #[serde(default)]
+    pub blocks_stream: bool,
+
+    /// Threads processing the account blocks of a block, serial when 0 or 1.
+    /// Transactions of one account stay in order
+    #[serde(default)]
+    pub account_workers: usize,
+
+    /// Send the messages of each block at once between block begin and end control frames
+    #[serde(default)]
+    pub atomic_blocks: bool,
+
+    /// Drop messages of transactions older than this many seconds. Disabled when not specified
+    #[serde(default)]
+    pub max_message_age_sec: Option<u32>,
+
+    /// Tag messages of old blocks as historical. Disabled when not specified
+    #[serde(default)]
+    pub historical: Option<HistoricalConfig>,
+
+    /// States for code hash filters in scanners without shard states (archives, S3, etc.)
+    #[serde(default)]
+    pub state_provider: Option<StateProviderConfig>,
+
+    /// Abort the process on the first panic instead of restarting the failed component
+    #[serde(default)]
+    pub fail_fast: bool,
+
+    /// Stream start, end and heartbeat frames. Disabled when not specified
+    #[serde(default)]
+    pub control_frames: Option<ControlFramesConfig>,
+
+    /// Sign every sent frame. Disabled when not specified
+    #[serde(default)]
+    pub signing: Option<SigningConfig>,
+
+    /// GraphQL server with a messages subscription, requires the `graphql` feature.
+    /// Disabled when not specified
+    #[serde(default)]
+    pub graphql: Option<GraphqlConfig>,
+
+    /// Short-term store of the sent messages with a pull API. Disabled when not specified
+    #[serde(default)]
+    pub message_store: Option<MessageStoreConfig>,
+
+    /// Admin API pausing and resuming the message output. Disabled when not specified
+    #[serde(default)]
+    pub control_api: Option<ControlApiConfig>,
+
+    /// Labels of the message addresses. Disabled when not specified
+    #[serde(default)]
+    pub address_book: Option<AddressBookConfig>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SigningConfig {
+    /// Hex encoded ed25519 secret key
+    pub secret_key: String,
+}
+
+impl SigningConfig {
+    pub fn keypair(&self) -> Result<everscale_crypto::ed25519::KeyPair> {
+        let bytes: [u8; 32] = hex::decode(self.secret_key.trim())
+            .context("Invalid signing key")?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Signing key must be 32 bytes"))?;
+        let secret = everscale_crypto::ed25519::SecretKey::from_bytes(bytes);
+        Ok(everscale_crypto::ed25519::KeyPair::from(&secret))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ControlFramesConfig {
+    /// Default: 10
+    #[serde(default = "default_heartbeat_interval_sec")]
+    pub heartbeat_interval_sec: u64,
+}
+
+impl ControlFramesConfig {
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_interval_sec.max(1))
+    }
+}
+
+fn default_heartbeat_interval_sec() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", deny_unknown_fields)]
+pub enum StateProviderConfig {
+    /// Fetches current account states via JSON-RPC
+    Rpc {
+        endpoint: String,
+        /// Number of cached accounts. Default: 100000
+        #[serde(default = "default_state_cache_size")]
+        cache_size: usize,
+    },
+}
+
+fn default_state_cache_size() -> usize {
+    100_000
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -50,14 +158,79 @@ pub enum ScanType {
     },
     FromArchives {
         list_path: PathBuf,
+        /// File to store the last processed archive and resume from it
+        #[serde(default)]
+        progress_path: Option<PathBuf>,
+        #[serde(default)]
+        pipeline: PipelineConfig,
+        #[serde(default)]
+        bounds: ScanBounds,
     },
+    /// Reads archive files from a local directory
+    FromDirectory(DirectoryScannerConfig),
     FromS3(S3ScannerConfig),
-    /// Reads test data from a json file
+    /// Backfills from archives, then continues from the network
+    ArchivesThenNetwork(HybridScannerConfig),
+    /// Polls transactions from a JSON-RPC endpoint without running a node
+    FromRpc(RpcScannerConfig),
+    /// Downloads new blocks from liteservers without running a node
+    FromLiteServer(LiteServerScannerConfig),
+    /// Reads test data from a json file or a directory of json files.
+    /// Accounts from the file are used as the shard state for its blocks
     TestJson {
         filename: PathBuf,
     }
 }
 
+impl ScanType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::FromNetwork { .. } => "FromNetwork",
+            Self::FromArchives { .. } => "FromArchives",
+            Self::FromDirectory(_) => "FromDirectory",
+            Self::FromS3(_) => "FromS3",
+            Self::ArchivesThenNetwork(_) => "ArchivesThenNetwork",
+            Self::FromRpc(_) => "FromRpc",
+            Self::FromLiteServer(_) => "FromLiteServer",
+            Self::TestJson { .. } => "TestJson",
+        }
+    }
+
+    /// Node settings of the scanners running a node
+    pub fn node_config_mut(&mut self) -> Option<&mut NodeConfig> {
+        match self {
+            Self::FromNetwork { node_config } => Some(node_config),
+            Self::ArchivesThenNetwork(config) => Some(&mut config.node_config),
+            _ => None,
+        }
+    }
+
+    /// Whether the scanner runs a node, whose states the RPC server needs
+    pub fn supports_rpc(&self) -> bool {
+        matches!(self, Self::FromNetwork { .. } | Self::ArchivesThenNetwork(_))
+    }
+
+    /// Rejects `rpc_config` for scanners without a node instead of ignoring it
+    pub fn check_rpc_config(
+        &self,
+        rpc_config: Option<&everscale_rpc_server::Config>,
+    ) -> Result<(), UnsupportedRpcConfig> {
+        match rpc_config {
+            Some(_) if !self.supports_rpc() => Err(UnsupportedRpcConfig {
+                scan_type: self.name(),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// `rpc_config` is specified for a scan type without a node
+#[derive(Debug, thiserror::Error)]
+#[error("`rpc_config` is not supported by the `{scan_type}` scan type, it requires a node")]
+pub struct UnsupportedRpcConfig {
+    pub scan_type: &'static str,
+}
+
 impl Default for ScanType {
     fn default() -> Self {
         Self::FromNetwork {
@@ -98,7 +271,24 @@ pub struct NodeConfig {
     /// Shard state GC options
     pub state_gc_options: Option<ton_indexer::StateGcOptions>,
 
-    pub start_from: Option<u32>,
+    /// Storage kept by the node. Default: full
+    pub retention: StorageRetention,
+
+    /// Masterchain block to sync old blocks from, a seqno or a start point.
+    /// The latest key block if not specified
+    #[serde(deserialize_with = "deserialize_start_from")]
+    pub start_from: Option<StartFrom>,
+
+    /// Process blocks only of these shards and the masterchain. All shards if empty.
+    /// Blocks of other shards are still downloaded by the node
+    pub subscriptions: Vec<ShardSubscription>,
+
+    /// Accept external messages over HTTP and broadcast them. Disabled if not specified
+    pub message_consumer: Option<MessageConsumerConfig>,
+
+    /// Delay shard blocks until a masterchain block references them,
+    /// so consumers only get effectively final blocks. Default: false
+    pub wait_for_masterchain: bool,
 
     #[serde(default)]
     pub adnl_options: adnl::NodeOptions,
@@ -112,6 +302,158 @@ pub struct NodeConfig {
     pub neighbours_options: ton_indexer::NeighboursOptions,
 }
 
+/// Shard state GC interval with the minimal retention
+const MINIMAL_STATE_GC_INTERVAL_SEC: u64 = 600;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageRetention {
+    /// As configured by `archive_options` and `state_gc_options`
+    #[default]
+    Full,
+    /// Only what following new blocks needs: archives are not kept and outdated shard states
+    /// are collected every 10 minutes, overriding `archive_options` and `state_gc_options`
+    Minimal,
+}
+
+/// Masterchain block the node syncs old blocks from
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", deny_unknown_fields)]
+pub enum StartFrom {
+    /// The latest key block, old blocks are not synced
+    Latest,
+    Seqno { seqno: u32 },
+    /// Block generated at the time, found via the liteservers
+    Utime {
+        utime: u32,
+        liteservers: Vec<LiteServerConfig>,
+    },
+    /// Block at the `start_utime` of the filter range, found via the liteservers
+    FilterRange { liteservers: Vec<LiteServerConfig> },
+}
+
+/// A plain number is the seqno
+fn deserialize_start_from<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<StartFrom>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Seqno(u32),
+        StartFrom(StartFrom),
+    }
+
+    Ok(Option::<Repr>::deserialize(deserializer)?.map(|repr| match repr {
+        Repr::Seqno(seqno) => StartFrom::Seqno { seqno },
+        Repr::StartFrom(start_from) => start_from,
+    }))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HistoricalConfig {
+    /// Messages of blocks generated earlier than this many seconds ago are historical. Default: 60
+    #[serde(default = "default_realtime_threshold_sec")]
+    pub realtime_threshold_sec: u32,
+    /// Output of the historical messages instead of the filter destinations
+    #[serde(default)]
+    pub destination: Option<String>,
+}
+
+fn default_realtime_threshold_sec() -> u32 {
+    60
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GraphqlConfig {
+    pub listen_address: SocketAddr,
+    /// Messages buffered for slow subscriptions. Default: 1024
+    #[serde(default = "default_graphql_capacity")]
+    pub capacity: usize,
+}
+
+fn default_graphql_capacity() -> usize {
+    1024
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MessageStoreConfig {
+    /// RocksDB directory of the store
+    pub path: PathBuf,
+    /// Listen address of `GET /messages`
+    pub listen_address: SocketAddr,
+    /// Messages are kept at least this long. Default: 86400
+    #[serde(default = "default_message_store_ttl_sec")]
+    pub ttl_sec: u64,
+}
+
+fn default_message_store_ttl_sec() -> u64 {
+    86400
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ControlApiConfig {
+    /// Listen address of `POST /control/pause`, `POST /control/resume`,
+    /// `POST /control/payload-logging?rate=<0..1>`, `GET /control/status`, `GET /stats`,
+    /// `GET /admin/db-usage` and `POST /admin/compact`
+    pub listen_address: SocketAddr,
+    /// Share of the matched messages whose serialized payload is logged at start. Default: 0
+    #[serde(default)]
+    pub payload_log_rate: f64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AddressBookConfig {
+    /// YAML or JSON map of raw addresses to labels
+    pub path: PathBuf,
+    /// How often the file is checked for changes. Default: 10
+    #[serde(default = "default_address_book_reload_interval_sec")]
+    pub reload_interval_sec: u64,
+}
+
+fn default_address_book_reload_interval_sec() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MessageConsumerConfig {
+    pub listen_address: SocketAddr,
+    /// Messages not included in this time are reported as expired. Default: 60
+    #[serde(default = "default_message_ttl_sec")]
+    pub message_ttl_sec: u64,
+    /// Requests must have `Authorization: Bearer <token>` if set
+    #[serde(default)]
+    pub auth_token: Option<String>,
+}
+
+fn default_message_ttl_sec() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShardSubscription {
+    pub workchain: i32,
+
+    /// Tagged shard prefix in hex, e.g. `4000000000000000`. The whole workchain if not specified
+    #[serde(default)]
+    pub shard_prefix: Option<String>,
+}
+
+impl ShardSubscription {
+    pub fn shard(&self) -> Result<ton_block::ShardIdent> {
+        let prefix = match &self.shard_prefix {
+            Some(prefix) => u64::from_str_radix(prefix, 16)
+                .with_context(|| format!("Invalid shard prefix: {prefix}"))?,
+            None => ton_block::SHARD_FULL,
+        };
+        ton_block::ShardIdent::with_tagged_prefix(self.workchain, prefix)
+    }
+}
+
 impl NodeConfig {
     pub async fn build_indexer_config(self) -> Result<ton_indexer::NodeConfig> {
         // Determine public ip
@@ -125,9 +467,23 @@ impl NodeConfig {
         // Prepare DB folder
         std::fs::create_dir_all(&self.db_path)?;
 
+        let (archive_options, state_gc_options) = match self.retention {
+            StorageRetention::Full => (self.archive_options, self.state_gc_options),
+            StorageRetention::Minimal => {
+                let state_gc_options = ton_indexer::StateGcOptions {
+                    offset_sec: 0,
+                    interval_sec: MINIMAL_STATE_GC_INTERVAL_SEC,
+                };
+                (None, Some(state_gc_options))
+            }
+        };
+
         let old_blocks_policy = match self.start_from {
-            None => OldBlocksPolicy::Ignore,
-            Some(a) => OldBlocksPolicy::Sync { from_seqno: a },
+            None | Some(StartFrom::Latest) => OldBlocksPolicy::Ignore,
+            Some(StartFrom::Seqno { seqno }) => OldBlocksPolicy::Sync { from_seqno: seqno },
+            Some(StartFrom::Utime { .. } | StartFrom::FilterRange { .. }) => {
+                anyhow::bail!("Start block is not resolved")
+            }
         };
 
         // Done
@@ -136,7 +492,7 @@ impl NodeConfig {
             adnl_keys,
             rocks_db_path: self.db_path.join("rocksdb"),
             file_db_path: self.db_path.join("files"),
-            state_gc_options: self.state_gc_options,
+            state_gc_options,
             blocks_gc_options: Some(ton_indexer::BlocksGcOptions {
                 kind: ton_indexer::BlocksGcKind::BeforePreviousKeyBlock,
                 enable_for_sync: true,
@@ -145,7 +501,7 @@ impl NodeConfig {
             shard_state_cache_options: None, // until state cache GC will be improved
             db_options: self.db_options,
             persistent_state_options: self.persistent_state_options,
-            archive_options: self.archive_options,
+            archive_options,
             sync_options: ton_indexer::SyncOptions {
                 old_blocks_policy,
                 parallel_archive_downloads: self.parallel_archive_downloads,
@@ -175,7 +531,11 @@ impl Default for NodeConfig {
                 offset_sec: rand::thread_rng().gen_range(0..3600),
                 interval_sec: 3600,
             }),
+            retention: StorageRetention::Full,
             start_from: None,
+            subscriptions: Vec::new(),
+            message_consumer: None,
+            wait_for_masterchain: false,
             adnl_options: Default::default(),
             rldp_options: Default::default(),
             dht_options: Default::default(),
@@ -185,6 +545,27 @@ impl Default for NodeConfig {
     }
 }
 
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DirectoryScannerConfig {
+    /// Directory with archive files
+    pub path: PathBuf,
+
+    /// File name pattern, e.g. `*.pack`. All files are used if not specified
+    #[serde(default)]
+    pub glob: Option<String>,
+
+    /// Scan subdirectories. Default: false
+    #[serde(default)]
+    pub recursive: bool,
+
+    /// Check the directory for new files with the interval after all files are processed.
+    /// New files are read once their size stays the same for an interval.
+    /// Stops after the first pass if not specified
+    #[serde(default)]
+    pub watch_interval_sec: Option<u64>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct S3ScannerConfig {
@@ -194,6 +575,111 @@ pub struct S3ScannerConfig {
     /// Whether to retry block handler in case of error
     #[serde(default = "default_retry_on_error")]
     pub retry_on_error: bool,
+
+    /// File to store the last processed archive and resume from it
+    #[serde(default)]
+    pub progress_path: Option<PathBuf>,
+
+    #[serde(default)]
+    pub pipeline: PipelineConfig,
+
+    #[serde(default)]
+    pub bounds: ScanBounds,
+}
+
+/// Range of blocks to process by archive scanners, all bounds are inclusive
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScanBounds {
+    /// Masterchain seqno, shard blocks use the referenced masterchain block
+    pub from_seqno: Option<u32>,
+    pub to_seqno: Option<u32>,
+
+    /// Block generation time
+    pub from_utime: Option<u32>,
+    pub to_utime: Option<u32>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HybridScannerConfig {
+    /// `FromArchives` or `FromS3` scanner
+    pub archives: Box<ScanType>,
+
+    /// Node settings, `start_from` is replaced with the block after the archives
+    #[serde(default)]
+    pub node_config: NodeConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RpcScannerConfig {
+    /// JSON-RPC endpoint, e.g. `https://jrpc.venom.foundation/rpc`
+    pub endpoint: String,
+
+    /// Accounts to poll transactions for
+    pub accounts: Vec<String>,
+
+    /// Default: 1000
+    #[serde(default = "default_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+
+    /// Transactions requested at once. Default: 50
+    #[serde(default = "default_rpc_batch_size")]
+    pub batch_size: u8,
+}
+
+fn default_poll_interval_ms() -> u64 {
+    1000
+}
+
+fn default_rpc_batch_size() -> u8 {
+    50
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LiteServerScannerConfig {
+    /// Liteservers are used in turn if one fails
+    pub liteservers: Vec<LiteServerConfig>,
+
+    /// Default: 1000
+    #[serde(default = "default_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+
+    /// Masterchain block to start from. Default: the latest one
+    #[serde(default)]
+    pub from_seqno: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LiteServerConfig {
+    pub address: SocketAddr,
+
+    /// Base64 encoded ed25519 public key
+    pub public_key: String,
+}
+
+/// Archive processing concurrency
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PipelineConfig {
+    /// Number of archives fetched and parsed ahead. Default: 1
+    pub prefetch_archives: usize,
+
+    /// Number of concurrently handled shards.
+    /// Blocks of the same shard are always handled in order. Default: 1
+    pub block_workers: usize,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            prefetch_archives: 1,
+            block_workers: 1,
+        }
+    }
 }
 
 fn default_retry_on_error() -> bool {
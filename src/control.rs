@@ -0,0 +1,256 @@
+//! Admin API pausing and resuming the message output, sampling its payloads, and maintaining
+//! the node database
+
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, Weak};
+
+use anyhow::Result;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::Serialize;
+
+use crate::blocks_handler::{counters, BlocksHandler};
+use crate::config::ControlApiConfig;
+use crate::data_scanner::db_usage::{DbUsageReport, DbUsageTracker};
+
+/// Node of the `FromNetwork` scanner, its database is served by the `/admin` endpoints
+static ENGINE: OnceLock<(Weak<ton_indexer::Engine>, Arc<DbUsageTracker>)> = OnceLock::new();
+/// Set while a compaction requested via the API runs
+static COMPACTING: AtomicBool = AtomicBool::new(false);
+
+/// Makes the node database available to the admin endpoints
+pub fn attach_engine(engine: &Arc<ton_indexer::Engine>, db_usage: Arc<DbUsageTracker>) {
+    ENGINE.set((Arc::downgrade(engine), db_usage)).ok();
+}
+
+/// Starts the control API and lets it pause and sample the messages of the handler
+pub fn serve(handler: BlocksHandler, config: &ControlApiConfig) -> Result<BlocksHandler> {
+    anyhow::ensure!(
+        (0.0..=1.0).contains(&config.payload_log_rate),
+        "payload_log_rate must be between 0 and 1"
+    );
+    let switch = Arc::new(PauseSwitch::default());
+    let sampler = Arc::new(PayloadSampler::new(config.payload_log_rate));
+    start(config.listen_address, switch.clone(), sampler.clone());
+    Ok(handler.with_pause_switch(switch).with_payload_sampler(sampler))
+}
+
+/// While paused, filtered messages are dropped and blocks are still processed
+#[derive(Debug, Default)]
+pub struct PauseSwitch {
+    paused: AtomicBool,
+    /// Messages dropped while paused
+    dropped: AtomicU64,
+}
+
+impl PauseSwitch {
+    pub fn pause(&self) {
+        if !self.paused.swap(true, Ordering::Relaxed) {
+            tracing::warn!("Message output is paused");
+        }
+    }
+
+    pub fn resume(&self) {
+        if self.paused.swap(false, Ordering::Relaxed) {
+            tracing::warn!("Message output is resumed");
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn record_dropped(&self, count: usize) {
+        self.dropped.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Logs the serialized payloads of a share of the matched messages
+#[derive(Debug, Default)]
+pub struct PayloadSampler {
+    /// Millionths of the messages to log
+    rate: AtomicU32,
+}
+
+impl PayloadSampler {
+    const SCALE: f64 = 1_000_000.0;
+
+    pub fn new(rate: f64) -> Self {
+        let sampler = Self::default();
+        sampler.set_rate(rate);
+        sampler
+    }
+
+    /// Share of the messages to log, from 0 (disabled) to 1 (every message)
+    pub fn set_rate(&self, rate: f64) {
+        let rate = (rate.clamp(0.0, 1.0) * Self::SCALE).round() as u32;
+        if self.rate.swap(rate, Ordering::Relaxed) != rate {
+            tracing::warn!("Payload logging rate is set to {}", rate as f64 / Self::SCALE);
+        }
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.rate.load(Ordering::Relaxed) as f64 / Self::SCALE
+    }
+
+    /// Whether to log the next message
+    pub fn sample(&self) -> bool {
+        match self.rate.load(Ordering::Relaxed) {
+            0 => false,
+            rate => rand::random::<u32>() % Self::SCALE as u32 < rate,
+        }
+    }
+
+    /// Logs the payload as sent to the transport, base64 encoded
+    pub fn log(&self, filter_name: &str, message_size: Option<usize>, payload: &[u8]) {
+        tracing::info!(
+            filter_name,
+            message_size,
+            payload_size = payload.len(),
+            payload = %base64::encode(payload),
+            "Sampled message payload"
+        );
+    }
+}
+
+#[derive(Serialize)]
+struct ControlStatus {
+    paused: bool,
+    dropped_messages: u64,
+    payload_log_rate: f64,
+}
+
+#[derive(Serialize)]
+struct DbUsage {
+    #[serde(flatten)]
+    report: DbUsageReport,
+    compacting: bool,
+}
+
+fn start(listen_address: std::net::SocketAddr, switch: Arc<PauseSwitch>, sampler: Arc<PayloadSampler>) {
+    tokio::spawn(async move {
+        tracing::info!("Starting control API on: {}", listen_address);
+
+        let make_service = make_service_fn(move |_| {
+            let (switch, sampler) = (switch.clone(), sampler.clone());
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let (switch, sampler) = (switch.clone(), sampler.clone());
+                    async move {
+                        let response = match req.uri().path() {
+                            path if path.starts_with("/admin/") => handle_admin_request(req),
+                            _ => handle_request(&switch, &sampler, req),
+                        };
+                        Ok::<_, Infallible>(response)
+                    }
+                }))
+            }
+        });
+        if let Err(error) = Server::bind(&listen_address).serve(make_service).await {
+            tracing::error!("Control API: {}", error);
+        }
+    });
+}
+
+fn handle_request(switch: &PauseSwitch, sampler: &PayloadSampler, req: Request<Body>) -> Response<Body> {
+    match (req.method(), req.uri().path()) {
+        (&Method::POST, "/control/pause") => switch.pause(),
+        (&Method::POST, "/control/resume") => switch.resume(),
+        (&Method::POST, "/control/payload-logging") => match query_rate(&req) {
+            Some(rate) => sampler.set_rate(rate),
+            None => return response(StatusCode::BAD_REQUEST, Body::from("Expected `rate` between 0 and 1")),
+        },
+        (&Method::GET, "/control/status") => {}
+        (&Method::GET, "/stats") => return json_response(&counters::filter_counters()),
+        (_, "/control/pause" | "/control/resume" | "/control/payload-logging" | "/control/status" | "/stats") => {
+            return response(StatusCode::METHOD_NOT_ALLOWED, Body::empty());
+        }
+        _ => return response(StatusCode::NOT_FOUND, Body::empty()),
+    }
+
+    let status = ControlStatus {
+        paused: switch.is_paused(),
+        dropped_messages: switch.dropped(),
+        payload_log_rate: sampler.rate(),
+    };
+    json_response(&status)
+}
+
+/// `rate` query parameter, 0 disables the logging
+fn query_rate(req: &Request<Body>) -> Option<f64> {
+    let rate = req
+        .uri()
+        .query()?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("rate="))?
+        .parse::<f64>()
+        .ok()?;
+    (0.0..=1.0).contains(&rate).then_some(rate)
+}
+
+fn handle_admin_request(req: Request<Body>) -> Response<Body> {
+    let Some((engine, db_usage)) = ENGINE
+        .get()
+        .and_then(|(engine, db_usage)| Some((engine.upgrade()?, db_usage)))
+    else {
+        let status = match req.uri().path() {
+            "/admin/db-usage" | "/admin/compact" => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::NOT_FOUND,
+        };
+        return response(status, Body::empty());
+    };
+
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/admin/db-usage") => {}
+        (&Method::POST, "/admin/compact") => {
+            if COMPACTING.swap(true, Ordering::AcqRel) {
+                return response(StatusCode::CONFLICT, Body::empty());
+            }
+            let engine = engine.clone();
+            tokio::spawn(async move {
+                tracing::warn!("compacting database");
+                engine.trigger_compaction().await;
+                tracing::warn!("database compacted");
+                COMPACTING.store(false, Ordering::Release);
+            });
+        }
+        (_, "/admin/db-usage" | "/admin/compact") => {
+            return response(StatusCode::METHOD_NOT_ALLOWED, Body::empty());
+        }
+        _ => return response(StatusCode::NOT_FOUND, Body::empty()),
+    }
+
+    let report = match db_usage.report(&engine) {
+        Ok(report) => report,
+        Err(error) => {
+            tracing::error!("Database usage stats: {:?}", error);
+            return response(StatusCode::INTERNAL_SERVER_ERROR, Body::empty());
+        }
+    };
+    let usage = DbUsage {
+        report,
+        compacting: COMPACTING.load(Ordering::Acquire),
+    };
+    json_response(&usage)
+}
+
+fn json_response<T: Serialize>(value: &T) -> Response<Body> {
+    let body = serde_json::to_vec(value).unwrap_or_default();
+    let mut response = response(StatusCode::OK, Body::from(body));
+    response.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
+fn response(status: StatusCode, body: Body) -> Response<Body> {
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    response
+}
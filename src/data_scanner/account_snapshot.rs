@@ -0,0 +1,61 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use ton_block::HashmapAugType;
+use ton_types::UInt256;
+
+use crate::filter::code_hashes::code_hash_filters;
+use crate::types::{AccountSnapshot, SnapshotAccount};
+
+use super::replay_scanner::StorageLoader;
+use super::shard_blocks::{top_shard_blocks, BlockLoader};
+
+/// Collects accounts of the code hash filters with balances from the states
+/// of the last applied masterchain block and its shard blocks
+pub async fn export_accounts(engine: &Arc<ton_indexer::Engine>) -> Result<AccountSnapshot> {
+    let mut filters = code_hash_filters();
+    anyhow::ensure!(!filters.is_empty(), "No filters with code hashes");
+
+    let mc_block_id = engine
+        .load_last_applied_mc_block_id()
+        .context("Failed to load last masterchain block")?;
+    let mut loader = StorageLoader { engine: engine.clone() };
+    let mc_block = loader.load_block(&mc_block_id).await?;
+
+    let mut block_ids = top_shard_blocks(&mc_block)?;
+    block_ids.push(mc_block_id.clone());
+    for block_id in block_ids {
+        let state = engine
+            .load_state(&block_id)
+            .await
+            .with_context(|| format!("State of {block_id} is not available"))?;
+        let workchain_id = block_id.shard().workchain_id();
+
+        // Reads every account of the state
+        tokio::task::block_in_place(|| {
+            state
+                .state()
+                .read_accounts()?
+                .iterate_with_keys(|account_id: UInt256, shard_account| {
+                    let account = shard_account.read_account()?;
+                    let Some(code_hash) = account.get_code_hash() else {
+                        return Ok(true);
+                    };
+                    let balance = account.balance().map(|balance| balance.grams.as_u128()).unwrap_or_default();
+                    for filter in filters.iter_mut().filter(|filter| filter.code_hash == code_hash) {
+                        filter.accounts.push(SnapshotAccount {
+                            address: format!("{workchain_id}:{}", account_id.to_hex_string()),
+                            balance,
+                        });
+                    }
+                    Ok(true)
+                })
+        })
+        .with_context(|| format!("Failed to read accounts of {block_id}"))?;
+    }
+
+    Ok(AccountSnapshot {
+        mc_seqno: mc_block_id.seq_no,
+        filters,
+    })
+}
@@ -1,31 +1,123 @@
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
 
 use anyhow::{Context, Result};
+use futures_util::StreamExt;
 use indicatif::{ProgressBar, ProgressStyle};
 
 use crate::archive::*;
 use crate::blocks_handler::*;
+use crate::config::{PipelineConfig, ScanBounds};
+
+use super::backfill_stats::BackfillStats;
+use super::bounds::archive_seqno;
+use super::handover::ScanHandover;
+use super::pipeline::BlocksPipeline;
+use super::progress::{is_processed, ScanProgress};
 
 pub struct ArchivesScanner {
     handler: Arc<BlocksHandler>,
-    list: String,
+    paths: Vec<String>,
+    progress: Option<Arc<ScanProgress>>,
+    pipeline: PipelineConfig,
+    bounds: ScanBounds,
+    stats: Arc<BackfillStats>,
 }
 
 impl ArchivesScanner {
-    pub fn new(handler: Arc<BlocksHandler>, list_path: PathBuf) -> Result<Self> {
+    pub fn new(
+        handler: Arc<BlocksHandler>,
+        list_path: PathBuf,
+        progress_path: Option<PathBuf>,
+    ) -> Result<Self> {
         let list = std::fs::read_to_string(list_path)?;
 
-        Ok(Self {
+        let mut scanner = Self::from_paths(
+            handler,
+            list.lines().map(ToOwned::to_owned).collect(),
+        );
+        if let Some(progress_path) = progress_path {
+            scanner = scanner.with_progress(ScanProgress::new(progress_path))?;
+        }
+        Ok(scanner)
+    }
+
+    /// Scan archives in the specified order
+    pub fn from_paths(handler: Arc<BlocksHandler>, paths: Vec<String>) -> Self {
+        Self {
             handler,
-            list,
-        })
+            paths,
+            progress: None,
+            pipeline: Default::default(),
+            bounds: Default::default(),
+            stats: Default::default(),
+        }
+    }
+
+    /// Skips archives up to the last processed one and records progress after each archive.
+    /// Progress stops at the first failed archive, so it is retried on resume
+    pub fn with_progress(mut self, progress: ScanProgress) -> Result<Self> {
+        if let Some(last) = progress.load()? {
+            match self.paths.iter().position(|path| path == &last) {
+                Some(index) => {
+                    tracing::info!("Resuming after archive {last}");
+                    self.paths.drain(..=index);
+                }
+                None => {
+                    tracing::warn!("Last processed archive {last} is not in the list, resuming by name");
+                    self.paths.retain(|path| !is_processed(path, &last));
+                }
+            }
+        }
+        self.progress = Some(Arc::new(progress));
+        Ok(self)
+    }
+
+    /// Shares progress counters, e.g. for metrics
+    pub fn with_stats(mut self, stats: Arc<BackfillStats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    pub fn with_pipeline(mut self, pipeline: PipelineConfig) -> Self {
+        self.pipeline = pipeline;
+        self
+    }
+
+    /// Skips archives and blocks outside the bounds.
+    /// Archives are skipped by name only if all names contain seqnos
+    pub fn with_bounds(mut self, bounds: ScanBounds) -> Self {
+        let seqnos = self
+            .paths
+            .iter()
+            .map(|path| archive_seqno(path))
+            .collect::<Option<Vec<_>>>();
+
+        if let Some(seqnos) = seqnos {
+            let mut starts = seqnos.clone();
+            starts.sort_unstable();
+
+            let mut seqnos = seqnos.into_iter();
+            self.paths.retain(|_| {
+                let start = seqnos.next().unwrap_or_default();
+                let next = starts.get(starts.partition_point(|&seqno| seqno <= start)).copied();
+                bounds.contains_archive(start, next)
+            });
+        } else if !bounds.is_empty() {
+            tracing::warn!("Archive names don't contain seqnos, all archives will be read");
+        }
+
+        self.bounds = bounds;
+        self
     }
 
     pub async fn run(self) -> Result<()> {
-        let len = self.list.lines().count();
+        self.scan().await.map(|_| ())
+    }
+
+    /// Processes all archives and returns where the scan stopped
+    pub async fn scan(self) -> Result<ScanHandover> {
+        let len = self.paths.len();
 
         let pb = ProgressBar::new(len as u64);
 
@@ -36,82 +128,67 @@ impl ArchivesScanner {
             .progress_chars("##-");
         pb.set_style(total_style);
 
-        let task_counter = Arc::new(AtomicUsize::new(0));
-
-        let (tx, rx) = tokio::sync::mpsc::channel(1000);
-
-        tokio::spawn(start_writing_blocks(
-            pb.clone(),
-            task_counter.clone(),
-            self.handler.clone(),
-            rx,
-        ));
+        let mut handover = ScanHandover::default();
+        let pipeline = BlocksPipeline::new(self.handler.clone(), self.pipeline.block_workers, false);
+
+        // Archives are read and parsed ahead on the blocking pool
+        let mut archives = futures_util::stream::iter(self.paths)
+            .map(|path| {
+                tokio::task::spawn_blocking(move || {
+                    let data = std::fs::read(&path)
+                        .with_context(|| format!("Failed reading archive {path}"))?;
+                    let blocks = parse_archive(data)
+                        .with_context(|| format!("Failed parsing archive {path}"))?;
+                    Ok::<_, anyhow::Error>((path, blocks))
+                })
+            })
+            .buffered(self.pipeline.prefetch_archives.max(1));
 
-        for task in self
-            .list
-            .lines()
-            .filter_map(|path| match std::fs::read(path) {
-                Ok(a) => Some((path.to_owned(), a)),
+        let mut failed = false;
+        while let Some(result) = archives.next().await {
+            let (path, blocks) = match result.context("Archive parser panicked")? {
+                Ok(archive) => archive,
                 Err(e) => {
-                    pb.println(format!("Failed reading archive {path}: {e:?}"));
-                    None
-                }
-            })
-            .filter_map(|(path, x)| match parse_archive(x) {
-                Ok(blocks) => {
-                    pb.println(format!("Parsed: {path}"));
+                    pb.println(format!("{e:?}"));
+                    failed = true;
                     pb.inc(1);
-                    Some(blocks)
+                    continue;
                 }
-                Err(e) => {
-                    pb.println(format!("Failed parsing archive {path}: {e:?}"));
-                    None
+            };
+            pb.println(format!("Parsed: {path}"));
+
+            handover.start_archive();
+            let mut sent_blocks = 0;
+            let mut last_utime = 0;
+            for (block_id, parsed) in blocks {
+                let (stuff, _data) = parsed.block_stuff;
+                if self.bounds.contains(&stuff)? {
+                    handover.record(&block_id);
+                    let gen_utime = stuff.block().read_info()?.gen_utime().as_u32();
+                    last_utime = last_utime.max(gen_utime);
+                    sent_blocks += 1;
+                    pipeline.send(stuff).await?;
                 }
-            })
-            .flatten()
-        {
-            task_counter.fetch_add(1, Ordering::Release);
-            tx.send(task)
-                .await
-                .map_err(|_| anyhow::anyhow!("Failed to send task"))?;
+            }
+
+            // Failed blocks are already logged by the pipeline
+            let flushed = pipeline.flush().await;
+            self.stats.archive_processed(sent_blocks, last_utime);
+            failed |= flushed.is_err();
+            if !failed {
+                if let Some(Err(e)) = self.progress.as_ref().map(|progress| progress.save(&path)) {
+                    pb.println(format!("Failed saving progress: {e:?}"));
+                }
+            }
+            pb.inc(1);
         }
 
-        // Drop tx so tasks writer will stop
-        drop(tx);
-
-        while task_counter.load(Ordering::Acquire) > 0 {
-            pb.println("Waiting tasks to complete...");
-            tokio::time::sleep(Duration::from_secs(1)).await;
+        pipeline.finish().await.ok();
+        if failed {
+            pb.println("Some archives failed, progress is kept before the first of them");
         }
+        pb.println("Complete tasks");
 
-        Ok(())
+        Ok(handover)
     }
 }
-
-async fn start_writing_blocks(
-    pb: ProgressBar,
-    counter: Arc<AtomicUsize>,
-    handler: Arc<BlocksHandler>,
-    mut rx: BlockTaskRx,
-) {
-    while let Some((block_id, parsed)) = rx.recv().await {
-        let (stuff, _data) = parsed.block_stuff;
-
-        if let Err(e) = handler
-            .handle_block(
-                &stuff,
-                None
-            )
-            .await
-            .context("Failed to handle block")
-        {
-            pb.println(format!("Failed processing block {block_id}: {e:?}"));
-        }
-        counter.fetch_sub(1, Ordering::Release);
-    }
-
-    pb.println("Complete tasks");
-}
-
-type BlockTaskRx = tokio::sync::mpsc::Receiver<BlockTask>;
-type BlockTask = (ton_block::BlockIdExt, ParsedEntry);
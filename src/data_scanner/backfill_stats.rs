@@ -0,0 +1,63 @@
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Progress of archive scanners, exported as metrics
+#[derive(Debug, Default)]
+pub struct BackfillStats {
+    pub archives_processed: AtomicU64,
+    pub blocks_processed: AtomicU64,
+    /// Generation time of the last processed block
+    pub current_utime: AtomicU32,
+    rate: Mutex<CatchUpRate>,
+}
+
+/// Moving average of block time processed per second
+#[derive(Debug, Default)]
+struct CatchUpRate {
+    last: Option<(Instant, u32)>,
+    utime_per_sec: f64,
+}
+
+impl BackfillStats {
+    pub fn archive_processed(&self, blocks: u64, last_utime: u32) {
+        self.archives_processed.fetch_add(1, Ordering::Relaxed);
+        self.blocks_processed.fetch_add(blocks, Ordering::Relaxed);
+        if last_utime == 0 {
+            return;
+        }
+        self.current_utime.fetch_max(last_utime, Ordering::Relaxed);
+
+        let now = Instant::now();
+        let mut rate = self.rate.lock().unwrap();
+        if let Some((last_time, prev_utime)) = rate.last {
+            let elapsed = now.duration_since(last_time).as_secs_f64();
+            if elapsed > 0.0 && last_utime > prev_utime {
+                let sample = (last_utime - prev_utime) as f64 / elapsed;
+                rate.utime_per_sec = match rate.utime_per_sec {
+                    r if r > 0.0 => r * (1.0 - RATE_SMOOTHING) + sample * RATE_SMOOTHING,
+                    _ => sample,
+                };
+            }
+        }
+        rate.last = Some((now, last_utime));
+    }
+
+    /// Estimated seconds until the scanner reaches the current time
+    pub fn eta_sec(&self) -> Option<u64> {
+        let utime_per_sec = self.rate.lock().unwrap().utime_per_sec;
+        let current_utime = self.current_utime.load(Ordering::Relaxed);
+        if utime_per_sec <= 0.0 || current_utime == 0 {
+            return None;
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let remaining = now.saturating_sub(current_utime as u64) as f64;
+        Some((remaining / utime_per_sec) as u64)
+    }
+}
+
+const RATE_SMOOTHING: f64 = 0.2;
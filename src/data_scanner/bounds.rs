@@ -0,0 +1,54 @@
+use anyhow::Result;
+use ton_indexer::utils::BlockStuff;
+
+use crate::config::ScanBounds;
+
+impl ScanBounds {
+    pub fn is_empty(&self) -> bool {
+        self.from_seqno.is_none()
+            && self.to_seqno.is_none()
+            && self.from_utime.is_none()
+            && self.to_utime.is_none()
+    }
+
+    /// Checks the masterchain seqno (referenced one for shard blocks) and the block time
+    pub fn contains(&self, block: &BlockStuff) -> Result<bool> {
+        if self.is_empty() {
+            return Ok(true);
+        }
+
+        let info = block.block().read_info()?;
+        let mc_seqno = match info.read_master_ref()? {
+            Some(master_ref) => master_ref.master.seq_no,
+            None => info.seq_no(),
+        };
+        let gen_utime = info.gen_utime().as_u32();
+
+        Ok(in_range(mc_seqno, self.from_seqno, self.to_seqno)
+            && in_range(gen_utime, self.from_utime, self.to_utime))
+    }
+
+    /// Checks whether an archive starting at `start_seqno` can have blocks in range.
+    /// `next_start_seqno` is the start of the following archive, if known
+    pub fn contains_archive(&self, start_seqno: u32, next_start_seqno: Option<u32>) -> bool {
+        if matches!(self.to_seqno, Some(to) if start_seqno > to) {
+            return false;
+        }
+        !matches!((self.from_seqno, next_start_seqno), (Some(from), Some(next)) if next <= from)
+    }
+}
+
+/// Extracts the starting masterchain seqno from the archive name, e.g. `archive.00100.pack`
+pub fn archive_seqno(name: &str) -> Option<u32> {
+    let file_name = name.rsplit(['/', '\\']).next()?;
+    file_name
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|part| !part.is_empty())
+        .last()?
+        .parse()
+        .ok()
+}
+
+fn in_range(value: u32, from: Option<u32>, to: Option<u32>) -> bool {
+    from.map_or(true, |from| value >= from) && to.map_or(true, |to| value <= to)
+}
@@ -0,0 +1,171 @@
+//! Growth of the node database tables and the disk space forecast
+
+use std::collections::{BTreeMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Samples are taken at most this often and kept in the database directory
+const SAMPLE_INTERVAL_SEC: u64 = 600;
+/// Growth is measured over the samples of this period
+const GROWTH_WINDOW_SEC: u64 = 24 * 3600;
+/// Growth is not estimated from samples closer than this
+const MIN_GROWTH_PERIOD_SEC: u64 = 3600;
+const HISTORY_FILE: &str = "usage_history.json";
+
+const DAY_SEC: f64 = 86400.0;
+
+/// Table sizes at a time
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Sample {
+    timestamp: u64,
+    tables: BTreeMap<String, u64>,
+}
+
+/// Sizes of the tables with their growth over the last day and the disk space forecast
+#[derive(Debug, Clone, Serialize)]
+pub struct DbUsageReport {
+    pub tables: Vec<TableUsage>,
+    pub total_bytes: u64,
+    /// Unknown until the samples cover an hour
+    pub growth_bytes_per_day: Option<f64>,
+    /// Space available on the disk of the database
+    pub free_bytes: Option<u64>,
+    /// Days until the free space is used up at the current growth
+    pub days_to_full: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TableUsage {
+    pub name: String,
+    pub keys_bytes: u64,
+    pub values_bytes: u64,
+    pub growth_bytes_per_day: Option<f64>,
+}
+
+/// Samples the table sizes of the node database, the history survives restarts
+pub struct DbUsageTracker {
+    db_path: PathBuf,
+    samples: Mutex<VecDeque<Sample>>,
+}
+
+impl DbUsageTracker {
+    /// Loads the samples kept in the database directory
+    pub fn open(db_path: &Path) -> Self {
+        let samples = load_history(&db_path.join(HISTORY_FILE)).unwrap_or_else(|error| {
+            tracing::debug!("No database usage history: {:?}", error);
+            VecDeque::new()
+        });
+        Self {
+            db_path: db_path.to_owned(),
+            samples: Mutex::new(samples),
+        }
+    }
+
+    /// Reads the current table sizes, records them if the last sample is old enough
+    pub fn report(&self, engine: &ton_indexer::Engine) -> Result<DbUsageReport> {
+        let stats = engine.db_usage_stats()?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let tables = stats
+            .iter()
+            .map(|stat| {
+                let name = stat.cf_name.to_string();
+                (name, stat.keys_total.as_u64(), stat.values_total.as_u64())
+            })
+            .collect::<Vec<_>>();
+        let current = Sample {
+            timestamp: now,
+            tables: tables
+                .iter()
+                .map(|(name, keys, values)| (name.clone(), keys + values))
+                .collect(),
+        };
+
+        let mut samples = self.samples.lock().unwrap();
+        // The oldest sample of the window
+        let base = samples
+            .iter()
+            .find(|sample| sample.timestamp + GROWTH_WINDOW_SEC >= now)
+            .filter(|sample| sample.timestamp + MIN_GROWTH_PERIOD_SEC <= now)
+            .cloned();
+        if samples.back().map_or(true, |last| last.timestamp + SAMPLE_INTERVAL_SEC <= now) {
+            samples.push_back(current.clone());
+            while samples.front().map_or(false, |first| first.timestamp + 2 * GROWTH_WINDOW_SEC < now) {
+                samples.pop_front();
+            }
+            if let Err(error) = self.save_history(&samples) {
+                tracing::warn!("Saving database usage history: {:?}", error);
+            }
+        }
+        drop(samples);
+
+        let growth = |name: &str, bytes: u64| {
+            let base = base.as_ref()?;
+            let elapsed_days = (now - base.timestamp) as f64 / DAY_SEC;
+            Some((bytes as f64 - *base.tables.get(name)? as f64) / elapsed_days)
+        };
+        let total_bytes = current.tables.values().sum::<u64>();
+        let growth_bytes_per_day = base.as_ref().map(|base| {
+            let elapsed_days = (now - base.timestamp) as f64 / DAY_SEC;
+            (total_bytes as f64 - base.tables.values().sum::<u64>() as f64) / elapsed_days
+        });
+        let free_bytes = free_space(&self.db_path);
+        let days_to_full = match (free_bytes, growth_bytes_per_day) {
+            (Some(free), Some(growth)) if growth > 0.0 => Some(free as f64 / growth),
+            _ => None,
+        };
+
+        Ok(DbUsageReport {
+            tables: tables
+                .into_iter()
+                .map(|(name, keys_bytes, values_bytes)| TableUsage {
+                    growth_bytes_per_day: growth(&name, keys_bytes + values_bytes),
+                    name,
+                    keys_bytes,
+                    values_bytes,
+                })
+                .collect(),
+            total_bytes,
+            growth_bytes_per_day,
+            free_bytes,
+            days_to_full,
+        })
+    }
+
+    fn save_history(&self, samples: &VecDeque<Sample>) -> Result<()> {
+        let path = self.db_path.join(HISTORY_FILE);
+        let data = serde_json::to_vec(samples)?;
+        std::fs::write(&path, data).with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+fn load_history(path: &Path) -> Result<VecDeque<Sample>> {
+    let data = std::fs::read(path)?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+#[cfg(unix)]
+fn free_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: the path is a valid C string and the struct is filled on success
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    // SAFETY: statvfs succeeded
+    let stat = unsafe { stat.assume_init() };
+    // Field types differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    let free = stat.f_bavail as u64 * stat.f_frsize as u64;
+    Some(free)
+}
+
+#[cfg(not(unix))]
+fn free_space(_: &Path) -> Option<u64> {
+    None
+}
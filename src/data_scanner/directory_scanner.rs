@@ -0,0 +1,145 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::blocks_handler::*;
+use crate::config::*;
+
+use super::archives_scanner::ArchivesScanner;
+use super::bounds::archive_seqno;
+
+/// Scans archive files from a local directory and optionally waits for new ones
+pub struct DirectoryScanner {
+    handler: Arc<BlocksHandler>,
+    path: PathBuf,
+    pattern: Option<glob::Pattern>,
+    recursive: bool,
+    watch_interval: Option<Duration>,
+}
+
+impl DirectoryScanner {
+    pub fn new(config: DirectoryScannerConfig, handler: Arc<BlocksHandler>) -> Result<Self> {
+        let pattern = config
+            .glob
+            .as_deref()
+            .map(glob::Pattern::new)
+            .transpose()
+            .context("Invalid glob pattern")?;
+
+        Ok(Self {
+            handler,
+            path: config.path,
+            pattern,
+            recursive: config.recursive,
+            watch_interval: config.watch_interval_sec.map(Duration::from_secs),
+        })
+    }
+
+    pub async fn run(self) -> Result<()> {
+        let mut processed = FxHashSet::default();
+        // Sizes of the new files seen in the previous pass
+        let mut growing = FxHashMap::default();
+        loop {
+            let mut paths = Vec::new();
+            self.collect_files(&self.path, &mut paths)
+                .with_context(|| format!("Failed to read directory {}", self.path.display()))?;
+            paths.retain(|path| !processed.contains(path));
+            if self.watch_interval.is_some() {
+                paths = wait_written(paths, &mut growing);
+            }
+            sort_archives(&mut paths);
+
+            if !paths.is_empty() {
+                tracing::info!("Found {} new archives", paths.len());
+                processed.extend(paths.iter().cloned());
+                ArchivesScanner::from_paths(self.handler.clone(), paths)
+                    .run()
+                    .await?;
+            }
+
+            match self.watch_interval {
+                Some(interval) => tokio::time::sleep(interval).await,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    fn collect_files(&self, dir: &Path, paths: &mut Vec<String>) -> Result<()> {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                if self.recursive {
+                    self.collect_files(&path, paths)?;
+                }
+                continue;
+            }
+
+            let matches = match (&self.pattern, path.file_name()) {
+                (Some(pattern), Some(name)) => pattern.matches(&name.to_string_lossy()),
+                (None, _) => true,
+                (_, None) => false,
+            };
+            if matches {
+                paths.push(path.to_string_lossy().into_owned());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Archive names contain the seqno, so files are processed in order.
+/// Names without it go first in path order
+fn sort_archives(paths: &mut [String]) {
+    paths.sort_by_cached_key(|path| (archive_seqno(path), path.clone()));
+}
+
+/// Returns files with the same size as in the previous pass, the rest may still be written
+fn wait_written(paths: Vec<String>, growing: &mut FxHashMap<String, u64>) -> Vec<String> {
+    let mut sizes = FxHashMap::default();
+    let mut written = Vec::new();
+    for path in paths {
+        let Ok(size) = std::fs::metadata(&path).map(|metadata| metadata.len()) else {
+            continue;
+        };
+        if growing.get(&path) == Some(&size) {
+            written.push(path);
+        } else {
+            sizes.insert(path, size);
+        }
+    }
+    *growing = sizes;
+    written
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_hash::FxHashMap;
+
+    use super::{sort_archives, wait_written};
+
+    #[test]
+    fn test_sort_archives() {
+        let mut paths = ["a/10", "a/9", "a/b/1000", "a/200"].map(ToOwned::to_owned).to_vec();
+        sort_archives(&mut paths);
+        assert_eq!(paths, ["a/9", "a/10", "a/200", "a/b/1000"]);
+    }
+
+    #[test]
+    fn test_wait_written() {
+        let dir = std::env::temp_dir().join(format!("fusion-directory-scanner-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("100").to_string_lossy().into_owned();
+        let mut growing = FxHashMap::default();
+
+        std::fs::write(&path, b"part").unwrap();
+        assert!(wait_written(vec![path.clone()], &mut growing).is_empty());
+        std::fs::write(&path, b"partial").unwrap();
+        assert!(wait_written(vec![path.clone()], &mut growing).is_empty());
+        assert_eq!(wait_written(vec![path.clone()], &mut growing), [path]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,81 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use rustc_hash::FxHashSet;
+use ton_block::BlockIdExt;
+
+use crate::blocks_handler::BlocksHandler;
+use crate::config::{HybridScannerConfig, NodeConfig, ScanType, StartFrom};
+
+use super::archives_scanner::ArchivesScanner;
+use super::s3_scanner::S3Scanner;
+
+/// Position where the archive scanner stopped.
+/// Used to continue from the network without gaps or duplicates
+#[derive(Debug, Clone, Default)]
+pub struct ScanHandover {
+    /// Last processed masterchain block
+    pub last_mc_seqno: Option<u32>,
+    /// Shard blocks of the last archive, they can be delivered by the node again
+    last_shard_blocks: FxHashSet<BlockIdExt>,
+}
+
+impl ScanHandover {
+    pub fn start_archive(&mut self) {
+        self.last_shard_blocks.clear();
+    }
+
+    pub fn record(&mut self, block_id: &BlockIdExt) {
+        if block_id.shard().is_masterchain() {
+            self.last_mc_seqno = self.last_mc_seqno.max(Some(block_id.seq_no));
+        } else {
+            self.last_shard_blocks.insert(block_id.clone());
+        }
+    }
+
+    /// Whether the block was already processed from archives
+    pub fn contains(&self, block_id: &BlockIdExt) -> bool {
+        if block_id.shard().is_masterchain() {
+            matches!(self.last_mc_seqno, Some(last) if block_id.seq_no <= last)
+        } else {
+            self.last_shard_blocks.contains(block_id)
+        }
+    }
+}
+
+/// Scans archives and returns the node config which continues right after them
+pub async fn backfill(
+    config: HybridScannerConfig,
+    handler: Arc<BlocksHandler>,
+) -> Result<(NodeConfig, ScanHandover)> {
+    let handover = match *config.archives {
+        ScanType::FromArchives {
+            list_path,
+            progress_path,
+            pipeline,
+            bounds,
+        } => ArchivesScanner::new(handler, list_path, progress_path)
+            .context("Failed to create scanner")?
+            .with_pipeline(pipeline)
+            .with_bounds(bounds)
+            .scan()
+            .await?,
+        ScanType::FromS3(scanner_config) => S3Scanner::new(scanner_config, handler)
+            .await
+            .context("Failed to create scanner")?
+            .scan()
+            .await?,
+        _ => anyhow::bail!("Only FromArchives and FromS3 can be used for backfill"),
+    };
+
+    let mut node_config = config.node_config;
+    match handover.last_mc_seqno {
+        Some(seqno) => {
+            tracing::info!("archives processed up to masterchain block {seqno}");
+            node_config.start_from = Some(StartFrom::Seqno { seqno: seqno + 1 });
+        }
+        None => tracing::warn!("no blocks found in archives"),
+    }
+
+    Ok((node_config, handover))
+}
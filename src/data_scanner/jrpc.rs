@@ -0,0 +1,60 @@
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// JSON-RPC client for everscale/venom endpoints
+#[derive(Clone)]
+pub struct JrpcClient {
+    client: reqwest::Client,
+    endpoint: String,
+}
+
+impl JrpcClient {
+    pub fn new(endpoint: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            endpoint,
+        }
+    }
+
+    pub async fn request<P, R>(&self, method: &str, params: P) -> Result<R>
+    where
+        P: Serialize,
+        R: DeserializeOwned,
+    {
+        let request = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": params,
+        });
+
+        let response: JrpcResponse<R> = self
+            .client
+            .post(&self.endpoint)
+            .json(&request)
+            .send()
+            .await
+            .context("Failed to send request")?
+            .json()
+            .await
+            .context("Invalid response")?;
+
+        match response {
+            JrpcResponse::Result { result } => Ok(result),
+            JrpcResponse::Error { error } => anyhow::bail!("RPC error: {}", error.message),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JrpcResponse<T> {
+    Result { result: T },
+    Error { error: JrpcError },
+}
+
+#[derive(Deserialize)]
+struct JrpcError {
+    message: String,
+}
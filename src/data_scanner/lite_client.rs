@@ -0,0 +1,485 @@
+//! Minimal lite-client: ADNL over TCP with a few `liteServer.*` queries
+
+use std::net::SocketAddr;
+
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{KeyIvInit, StreamCipher};
+use anyhow::{Context, Result};
+use everscale_crypto::ed25519;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use ton_block::{BlockIdExt, ShardIdent};
+use ton_types::UInt256;
+
+use crate::config::LiteServerConfig;
+
+type Aes256Ctr = ctr::Ctr128BE<aes::Aes256>;
+
+const PUB_ED25519: u32 = 0x4813b4c6;
+const ADNL_MESSAGE_QUERY: u32 = 0xb48bf97a;
+const ADNL_MESSAGE_ANSWER: u32 = 0x0fac8416;
+const LITE_SERVER_QUERY: u32 = 0x798c06df;
+const LITE_SERVER_ERROR: u32 = 0xbba9e148;
+const GET_MASTERCHAIN_INFO: u32 = 0x89b5e62e;
+const MASTERCHAIN_INFO: u32 = 0x85832881;
+const LOOKUP_BLOCK: u32 = 0xfac8f71e;
+const BLOCK_HEADER: u32 = 0x752d8219;
+const GET_BLOCK: u32 = 0x6377cf0d;
+const BLOCK_DATA: u32 = 0xa574ed6c;
+
+/// Addresses and keys of the configured liteservers
+pub fn liteserver_keys(liteservers: &[LiteServerConfig]) -> Result<Vec<(SocketAddr, ed25519::PublicKey)>> {
+    let servers = liteservers
+        .iter()
+        .map(|server| {
+            let key = base64::decode(&server.public_key)
+                .ok()
+                .and_then(|key| key.try_into().ok())
+                .and_then(ed25519::PublicKey::from_bytes)
+                .with_context(|| format!("Invalid public key of {}", server.address))?;
+            Ok((server.address, key))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    anyhow::ensure!(!servers.is_empty(), "No liteservers specified");
+    Ok(servers)
+}
+
+/// Seqno of the masterchain block at the time, trying the liteservers in turn
+pub async fn find_mc_seqno(liteservers: &[LiteServerConfig], utime: u32) -> Result<u32> {
+    let mut last_error = None;
+    for (address, key) in liteserver_keys(liteservers)? {
+        let result = async {
+            let mut client = LiteClient::connect(address, &key).await?;
+            client.lookup_block_by_utime(&ShardIdent::masterchain(), utime).await
+        };
+        match result.await {
+            Ok(block_id) => return Ok(block_id.seq_no),
+            Err(error) => {
+                tracing::warn!("liteserver {address} failed to find the block at {utime}: {error:?}");
+                last_error = Some(error);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No liteservers specified")))
+}
+
+pub struct LiteClient {
+    stream: TcpStream,
+    encrypt: Aes256Ctr,
+    decrypt: Aes256Ctr,
+}
+
+impl LiteClient {
+    pub async fn connect(address: SocketAddr, server_key: &ed25519::PublicKey) -> Result<Self> {
+        let mut stream = TcpStream::connect(address)
+            .await
+            .with_context(|| format!("Failed to connect to {address}"))?;
+
+        let mut params = [0u8; 160];
+        rand::Rng::fill(&mut rand::thread_rng(), &mut params[..]);
+
+        let keypair = ed25519::KeyPair::generate(&mut rand::thread_rng());
+        let secret = keypair.compute_shared_secret(server_key);
+        let params_hash: [u8; 32] = Sha256::digest(params).into();
+
+        // Handshake parameters are encrypted with the shared secret
+        let mut key = [0u8; 32];
+        key[..16].copy_from_slice(&secret[..16]);
+        key[16..].copy_from_slice(&params_hash[16..]);
+        let mut nonce = [0u8; 16];
+        nonce[..4].copy_from_slice(&params_hash[..4]);
+        nonce[4..].copy_from_slice(&secret[20..]);
+        let mut encrypted_params = params;
+        Aes256Ctr::new(&key.into(), &nonce.into()).apply_keystream(&mut encrypted_params);
+
+        let mut server_key_id = Sha256::new();
+        server_key_id.update(PUB_ED25519.to_le_bytes());
+        server_key_id.update(server_key.as_bytes());
+
+        let mut handshake = Vec::with_capacity(256);
+        handshake.extend_from_slice(&server_key_id.finalize());
+        handshake.extend_from_slice(keypair.public_key.as_bytes());
+        handshake.extend_from_slice(&params_hash);
+        handshake.extend_from_slice(&encrypted_params);
+        stream.write_all(&handshake).await?;
+
+        let mut client = Self {
+            stream,
+            decrypt: Aes256Ctr::new(
+                GenericArray::from_slice(&params[0..32]),
+                GenericArray::from_slice(&params[64..80]),
+            ),
+            encrypt: Aes256Ctr::new(
+                GenericArray::from_slice(&params[32..64]),
+                GenericArray::from_slice(&params[80..96]),
+            ),
+        };
+
+        // The server confirms the handshake with an empty packet
+        let confirmation = client.read_packet().await?;
+        anyhow::ensure!(confirmation.is_empty(), "Invalid handshake confirmation");
+
+        Ok(client)
+    }
+
+    /// Returns the last masterchain block id
+    pub async fn get_masterchain_info(&mut self) -> Result<BlockIdExt> {
+        let answer = self.query(&GET_MASTERCHAIN_INFO.to_le_bytes()).await?;
+        let mut reader = TlReader::new(&answer);
+        reader.expect_constructor(MASTERCHAIN_INFO)?;
+        reader.block_id_ext()
+    }
+
+    /// Finds the block id by the shard and seqno
+    pub async fn lookup_block(&mut self, shard: &ShardIdent, seqno: u32) -> Result<BlockIdExt> {
+        let mut query = Vec::with_capacity(24);
+        query.extend_from_slice(&LOOKUP_BLOCK.to_le_bytes());
+        // Lookup by seqno
+        query.extend_from_slice(&1u32.to_le_bytes());
+        query.extend_from_slice(&shard.workchain_id().to_le_bytes());
+        query.extend_from_slice(&shard.shard_prefix_with_tag().to_le_bytes());
+        query.extend_from_slice(&seqno.to_le_bytes());
+
+        let answer = self.query(&query).await?;
+        let mut reader = TlReader::new(&answer);
+        reader.expect_constructor(BLOCK_HEADER)?;
+        reader.block_id_ext()
+    }
+
+    /// Finds the id of the shard block generated at the time
+    pub async fn lookup_block_by_utime(&mut self, shard: &ShardIdent, utime: u32) -> Result<BlockIdExt> {
+        let mut query = Vec::with_capacity(28);
+        query.extend_from_slice(&LOOKUP_BLOCK.to_le_bytes());
+        // Lookup by utime
+        query.extend_from_slice(&4u32.to_le_bytes());
+        query.extend_from_slice(&shard.workchain_id().to_le_bytes());
+        query.extend_from_slice(&shard.shard_prefix_with_tag().to_le_bytes());
+        query.extend_from_slice(&0u32.to_le_bytes());
+        query.extend_from_slice(&utime.to_le_bytes());
+
+        let answer = self.query(&query).await?;
+        let mut reader = TlReader::new(&answer);
+        reader.expect_constructor(BLOCK_HEADER)?;
+        reader.block_id_ext()
+    }
+
+    /// Downloads the block data
+    pub async fn get_block(&mut self, block_id: &BlockIdExt) -> Result<Vec<u8>> {
+        let mut query = GET_BLOCK.to_le_bytes().to_vec();
+        write_block_id_ext(&mut query, block_id);
+
+        let answer = self.query(&query).await?;
+        let mut reader = TlReader::new(&answer);
+        reader.expect_constructor(BLOCK_DATA)?;
+        let id = reader.block_id_ext()?;
+        anyhow::ensure!(&id == block_id, "Unexpected block {id}");
+        Ok(reader.bytes()?.to_vec())
+    }
+
+    async fn query(&mut self, query: &[u8]) -> Result<Vec<u8>> {
+        let mut lite_query = LITE_SERVER_QUERY.to_le_bytes().to_vec();
+        write_bytes(&mut lite_query, query);
+
+        let query_id: [u8; 32] = rand::random();
+        let mut message = ADNL_MESSAGE_QUERY.to_le_bytes().to_vec();
+        message.extend_from_slice(&query_id);
+        write_bytes(&mut message, &lite_query);
+        self.write_packet(&message).await?;
+
+        loop {
+            let packet = self.read_packet().await?;
+            // Skip keepalive packets
+            if packet.is_empty() {
+                continue;
+            }
+
+            let mut reader = TlReader::new(&packet);
+            reader.expect_constructor(ADNL_MESSAGE_ANSWER)?;
+            if reader.take(32)? != query_id {
+                continue;
+            }
+            let answer = reader.bytes()?;
+
+            let mut reader = TlReader::new(answer);
+            if reader.constructor()? == LITE_SERVER_ERROR {
+                let code = reader.u32()? as i32;
+                let message = String::from_utf8_lossy(reader.bytes()?).into_owned();
+                anyhow::bail!("Liteserver error {code}: {message}");
+            }
+            return Ok(answer.to_vec());
+        }
+    }
+
+    async fn write_packet(&mut self, data: &[u8]) -> Result<()> {
+        let nonce: [u8; 32] = rand::random();
+        let mut hash = Sha256::new();
+        hash.update(nonce);
+        hash.update(data);
+
+        let mut packet = Vec::with_capacity(4 + 32 + data.len() + 32);
+        packet.extend_from_slice(&((32 + data.len() + 32) as u32).to_le_bytes());
+        packet.extend_from_slice(&nonce);
+        packet.extend_from_slice(data);
+        packet.extend_from_slice(&hash.finalize());
+
+        self.encrypt.apply_keystream(&mut packet);
+        self.stream.write_all(&packet).await?;
+        Ok(())
+    }
+
+    async fn read_packet(&mut self) -> Result<Vec<u8>> {
+        let mut size = [0u8; 4];
+        self.stream.read_exact(&mut size).await?;
+        self.decrypt.apply_keystream(&mut size);
+        let size = u32::from_le_bytes(size) as usize;
+        anyhow::ensure!((64..=MAX_PACKET_SIZE).contains(&size), "Invalid packet size: {size}");
+
+        let mut packet = vec![0u8; size];
+        self.stream.read_exact(&mut packet).await?;
+        self.decrypt.apply_keystream(&mut packet);
+
+        let (data, checksum) = packet.split_at(size - 32);
+        anyhow::ensure!(Sha256::digest(data).as_slice() == checksum, "Invalid packet checksum");
+        Ok(data[32..].to_vec())
+    }
+}
+
+const MAX_PACKET_SIZE: usize = 64 << 20;
+
+fn write_bytes(buffer: &mut Vec<u8>, data: &[u8]) {
+    let header = if data.len() < 254 {
+        buffer.push(data.len() as u8);
+        1
+    } else {
+        buffer.push(254);
+        buffer.extend_from_slice(&(data.len() as u32).to_le_bytes()[..3]);
+        4
+    };
+    buffer.extend_from_slice(data);
+    let padding = (4 - (header + data.len()) % 4) % 4;
+    buffer.extend(std::iter::repeat(0).take(padding));
+}
+
+fn write_block_id_ext(buffer: &mut Vec<u8>, block_id: &BlockIdExt) {
+    buffer.extend_from_slice(&block_id.shard().workchain_id().to_le_bytes());
+    buffer.extend_from_slice(&block_id.shard().shard_prefix_with_tag().to_le_bytes());
+    buffer.extend_from_slice(&block_id.seq_no.to_le_bytes());
+    buffer.extend_from_slice(block_id.root_hash.as_slice());
+    buffer.extend_from_slice(block_id.file_hash.as_slice());
+}
+
+struct TlReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> TlReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, offset: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let data = self
+            .data
+            .get(self.offset..self.offset + len)
+            .context("Unexpected end of data")?;
+        self.offset += len;
+        Ok(data)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn constructor(&mut self) -> Result<u32> {
+        self.u32()
+    }
+
+    fn expect_constructor(&mut self, expected: u32) -> Result<()> {
+        let constructor = self.constructor()?;
+        anyhow::ensure!(constructor == expected, "Unexpected answer: {constructor:08x}");
+        Ok(())
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let (len, header) = match self.take(1)?[0] {
+            254 => {
+                let len = self.take(3)?;
+                (u32::from_le_bytes([len[0], len[1], len[2], 0]) as usize, 4)
+            }
+            len => (len as usize, 1),
+        };
+        let data = self.take(len)?;
+        self.take((4 - (header + len) % 4) % 4)?;
+        Ok(data)
+    }
+
+    fn block_id_ext(&mut self) -> Result<BlockIdExt> {
+        let workchain_id = self.u32()? as i32;
+        let shard = self.u64()?;
+        let seqno = self.u32()?;
+        let root_hash = UInt256::from_slice(self.take(32)?);
+        let file_hash = UInt256::from_slice(self.take(32)?);
+        Ok(BlockIdExt::with_params(
+            ShardIdent::with_tagged_prefix(workchain_id, shard)?,
+            seqno,
+            root_hash,
+            file_hash,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn block_id(seqno: u32) -> BlockIdExt {
+        BlockIdExt::with_params(
+            ShardIdent::masterchain(),
+            seqno,
+            UInt256::from([1; 32]),
+            UInt256::from([2; 32]),
+        )
+    }
+
+    /// Accepts a client and completes the handshake on the server side
+    async fn accept(listener: &TcpListener, keypair: &ed25519::KeyPair) -> LiteClient {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut handshake = [0u8; 256];
+        stream.read_exact(&mut handshake).await.unwrap();
+
+        let client_key = ed25519::PublicKey::from_bytes(handshake[32..64].try_into().unwrap()).unwrap();
+        let secret = keypair.compute_shared_secret(&client_key);
+        let params_hash = &handshake[64..96];
+
+        let mut key = [0u8; 32];
+        key[..16].copy_from_slice(&secret[..16]);
+        key[16..].copy_from_slice(&params_hash[16..]);
+        let mut nonce = [0u8; 16];
+        nonce[..4].copy_from_slice(&params_hash[..4]);
+        nonce[4..].copy_from_slice(&secret[20..]);
+        let mut params: [u8; 160] = handshake[96..].try_into().unwrap();
+        Aes256Ctr::new(&key.into(), &nonce.into()).apply_keystream(&mut params);
+        assert_eq!(Sha256::digest(params).as_slice(), params_hash);
+
+        // Ciphers of the client swapped
+        let mut server = LiteClient {
+            stream,
+            encrypt: Aes256Ctr::new(
+                GenericArray::from_slice(&params[0..32]),
+                GenericArray::from_slice(&params[64..80]),
+            ),
+            decrypt: Aes256Ctr::new(
+                GenericArray::from_slice(&params[32..64]),
+                GenericArray::from_slice(&params[80..96]),
+            ),
+        };
+        server.write_packet(&[]).await.unwrap();
+        server
+    }
+
+    /// Reads a query, returns its id and the liteserver query
+    async fn read_query(server: &mut LiteClient) -> ([u8; 32], Vec<u8>) {
+        let packet = server.read_packet().await.unwrap();
+        let mut reader = TlReader::new(&packet);
+        reader.expect_constructor(ADNL_MESSAGE_QUERY).unwrap();
+        let query_id = reader.take(32).unwrap().try_into().unwrap();
+        let mut reader = TlReader::new(reader.bytes().unwrap());
+        reader.expect_constructor(LITE_SERVER_QUERY).unwrap();
+        (query_id, reader.bytes().unwrap().to_vec())
+    }
+
+    async fn answer(server: &mut LiteClient, query_id: &[u8; 32], answer: &[u8]) {
+        let mut message = ADNL_MESSAGE_ANSWER.to_le_bytes().to_vec();
+        message.extend_from_slice(query_id);
+        write_bytes(&mut message, answer);
+        server.write_packet(&message).await.unwrap();
+    }
+
+    #[test]
+    fn test_tl_bytes() {
+        for len in [0, 1, 3, 253, 254, 1000] {
+            let data = vec![7u8; len];
+            let mut buffer = Vec::new();
+            write_bytes(&mut buffer, &data);
+            buffer.extend_from_slice(&42u32.to_le_bytes());
+            assert_eq!(buffer.len() % 4, 0, "{len}");
+
+            let mut reader = TlReader::new(&buffer);
+            assert_eq!(reader.bytes().unwrap(), data.as_slice());
+            assert_eq!(reader.u32().unwrap(), 42);
+        }
+        assert!(TlReader::new(&[5, 1, 2]).bytes().is_err());
+    }
+
+    #[test]
+    fn test_tl_block_id() {
+        let block_id = block_id(100);
+        let mut buffer = Vec::new();
+        write_block_id_ext(&mut buffer, &block_id);
+        assert_eq!(TlReader::new(&buffer).block_id_ext().unwrap(), block_id);
+    }
+
+    #[tokio::test]
+    async fn test_queries() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let keypair = ed25519::KeyPair::generate(&mut rand::thread_rng());
+
+        let server = tokio::spawn(async move {
+            let mut server = accept(&listener, &keypair).await;
+
+            let (query_id, query) = read_query(&mut server).await;
+            assert_eq!(query, GET_MASTERCHAIN_INFO.to_le_bytes());
+            // A keepalive and an answer to another query are skipped
+            server.write_packet(&[]).await.unwrap();
+            answer(&mut server, &[0; 32], &LITE_SERVER_ERROR.to_le_bytes()).await;
+            let mut info = MASTERCHAIN_INFO.to_le_bytes().to_vec();
+            write_block_id_ext(&mut info, &block_id(100));
+            answer(&mut server, &query_id, &info).await;
+
+            let (query_id, _) = read_query(&mut server).await;
+            let mut error = LITE_SERVER_ERROR.to_le_bytes().to_vec();
+            error.extend_from_slice(&651u32.to_le_bytes());
+            write_bytes(&mut error, b"block not found");
+            answer(&mut server, &query_id, &error).await;
+        });
+
+        let mut client = LiteClient::connect(address, &keypair.public_key).await.unwrap();
+        assert_eq!(client.get_masterchain_info().await.unwrap(), block_id(100));
+
+        let error = client.lookup_block(&ShardIdent::masterchain(), 200).await.unwrap_err();
+        assert_eq!(error.to_string(), "Liteserver error 651: block not found");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_wrong_server_key() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let keypair = ed25519::KeyPair::generate(&mut rand::thread_rng());
+        let other = ed25519::KeyPair::generate(&mut rand::thread_rng());
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0u8; 256];
+            stream.read_exact(&mut handshake).await.unwrap();
+
+            // The key id doesn't match, the server drops the connection
+            let mut key_id = Sha256::new();
+            key_id.update(PUB_ED25519.to_le_bytes());
+            key_id.update(keypair.public_key.as_bytes());
+            assert_ne!(&handshake[..32], key_id.finalize().as_slice());
+        });
+
+        assert!(LiteClient::connect(address, &other.public_key).await.is_err());
+        server.await.unwrap();
+    }
+}
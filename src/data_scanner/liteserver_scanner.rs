@@ -0,0 +1,119 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use everscale_crypto::ed25519;
+use ton_block::{BlockIdExt, ShardIdent};
+use ton_indexer::utils::BlockStuff;
+
+use crate::blocks_handler::*;
+use crate::config::*;
+
+use super::lite_client::{liteserver_keys, LiteClient};
+use super::shard_blocks::{BlockLoader, ShardBlocksTracker};
+
+/// Follows new blocks via liteservers without running a full node.
+/// Shard states are not available, so account states are not produced
+pub struct LiteServerScanner {
+    handler: Arc<BlocksHandler>,
+    servers: Vec<(SocketAddr, ed25519::PublicKey)>,
+    poll_interval: Duration,
+    from_seqno: Option<u32>,
+}
+
+#[derive(Default)]
+struct ScanState {
+    next_mc_seqno: Option<u32>,
+    shard_blocks: ShardBlocksTracker,
+}
+
+impl LiteServerScanner {
+    pub fn new(config: LiteServerScannerConfig, handler: Arc<BlocksHandler>) -> Result<Self> {
+        let servers = liteserver_keys(&config.liteservers)?;
+
+        Ok(Self {
+            handler,
+            servers,
+            poll_interval: Duration::from_millis(config.poll_interval_ms),
+            from_seqno: config.from_seqno,
+        })
+    }
+
+    pub async fn run(self) -> Result<()> {
+        let mut state = ScanState {
+            next_mc_seqno: self.from_seqno,
+            ..Default::default()
+        };
+        let mut server_index = 0;
+        let mut client = None;
+
+        loop {
+            if client.is_none() {
+                let (address, key) = &self.servers[server_index % self.servers.len()];
+                match LiteClient::connect(*address, key).await {
+                    Ok(connected) => {
+                        tracing::info!("connected to liteserver {address}");
+                        client = Some(connected);
+                    }
+                    Err(e) => {
+                        tracing::error!("failed to connect to liteserver {address}: {e:?}");
+                        server_index += 1;
+                        tokio::time::sleep(self.poll_interval).await;
+                        continue;
+                    }
+                }
+            }
+            let Some(lite_client) = client.as_mut() else {
+                continue;
+            };
+
+            match self.poll(lite_client, &mut state).await {
+                Ok(()) => tokio::time::sleep(self.poll_interval).await,
+                Err(e) => {
+                    // Try the next liteserver
+                    tracing::error!("liteserver error: {e:?}");
+                    client = None;
+                    server_index += 1;
+                }
+            }
+        }
+    }
+
+    /// Processes masterchain blocks up to the latest one with their shard blocks
+    async fn poll(&self, client: &mut LiteClient, state: &mut ScanState) -> Result<()> {
+        let last = client.get_masterchain_info().await?;
+        let next_seqno = *state.next_mc_seqno.get_or_insert(last.seq_no);
+
+        for seqno in next_seqno..=last.seq_no {
+            let mc_id = if seqno == last.seq_no {
+                last.clone()
+            } else {
+                client.lookup_block(&ShardIdent::masterchain(), seqno).await?
+            };
+            let mc_block = client.load_block(&mc_id).await?;
+
+            // Shard blocks go first, they are committed by the masterchain block
+            let shard_blocks = state.shard_blocks.new_blocks(client, &mc_block).await?;
+            for block in &shard_blocks {
+                self.handler
+                    .handle_committed_block(block, None, Some(seqno))
+                    .await?;
+            }
+            self.handler.handle_block(&mc_block, None).await?;
+
+            state.next_mc_seqno = Some(seqno + 1);
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl BlockLoader for LiteClient {
+    async fn load_block(&mut self, block_id: &BlockIdExt) -> Result<BlockStuff> {
+        let data = self.get_block(block_id).await?;
+        BlockStuff::deserialize_checked(block_id.clone(), &data)
+            .with_context(|| format!("Invalid block {block_id}"))
+    }
+}
@@ -0,0 +1,258 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use hyper::body::HttpBody;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use rustc_hash::FxHashMap;
+use ton_block::{Deserializable, GetRepresentationHash, HashmapAugType};
+use ton_indexer::utils::BlockStuff;
+use ton_types::{HashmapType, UInt256};
+
+use crate::blocks_handler::*;
+use crate::config::*;
+use crate::types::{MessageStatus, SerializeMessageStatus};
+
+/// Accepts external messages over HTTP and broadcasts them to the network.
+/// Statuses of the accepted messages are sent to the `statuses` stream
+pub struct MessageConsumer {
+    engine: Weak<ton_indexer::Engine>,
+    handler: Arc<BlocksHandler>,
+    listen_address: SocketAddr,
+    message_ttl: Duration,
+    auth_token: Option<String>,
+    /// Expiration time of the broadcasted messages by hash
+    pending: Mutex<FxHashMap<UInt256, u32>>,
+}
+
+impl MessageConsumer {
+    pub fn new(
+        engine: &Arc<ton_indexer::Engine>,
+        handler: Arc<BlocksHandler>,
+        config: MessageConsumerConfig,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            engine: Arc::downgrade(engine),
+            handler,
+            listen_address: config.listen_address,
+            message_ttl: Duration::from_secs(config.message_ttl_sec),
+            auth_token: config.auth_token,
+            pending: Default::default(),
+        })
+    }
+
+    /// Spawns the HTTP server and the expiration of pending messages
+    pub fn start(self: &Arc<Self>) {
+        let consumer = self.clone();
+        tokio::spawn(async move {
+            tracing::info!("Starting message consumer on: {}", consumer.listen_address);
+
+            let listen_address = consumer.listen_address;
+            let make_service = make_service_fn(move |_| {
+                let consumer = consumer.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req| {
+                        let consumer = consumer.clone();
+                        async move { Ok::<_, Infallible>(consumer.handle_request(req).await) }
+                    }))
+                }
+            });
+            if let Err(error) = Server::bind(&listen_address).serve(make_service).await {
+                tracing::error!("Message consumer: {}", error);
+            }
+        });
+
+        let consumer = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                match consumer.upgrade() {
+                    Some(consumer) => consumer.expire_messages(),
+                    None => break,
+                }
+            }
+        });
+    }
+
+    async fn handle_request(&self, req: Request<Body>) -> Response<Body> {
+        if req.method() != Method::POST || req.uri().path() != "/messages" {
+            return response(StatusCode::NOT_FOUND, String::new());
+        }
+        if !self.is_authorized(&req) {
+            return response(StatusCode::UNAUTHORIZED, String::new());
+        }
+        if self.pending.lock().unwrap().len() >= MAX_PENDING {
+            return response(StatusCode::TOO_MANY_REQUESTS, String::new());
+        }
+
+        let result = match read_body(req.into_body()).await {
+            Ok(Some(body)) => self.send_message(&body),
+            Ok(None) => return response(StatusCode::PAYLOAD_TOO_LARGE, String::new()),
+            Err(error) => Err(error),
+        };
+        match result {
+            Ok(hash) => response(
+                StatusCode::OK,
+                serde_json::json!({ "message_hash": hash.to_hex_string() }).to_string(),
+            ),
+            Err(error) => response(StatusCode::BAD_REQUEST, format!("{error:?}")),
+        }
+    }
+
+    fn is_authorized(&self, req: &Request<Body>) -> bool {
+        let Some(token) = &self.auth_token else {
+            return true;
+        };
+        req.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map_or(false, |value| value == token)
+    }
+
+    /// Broadcasts a base64 encoded external message
+    fn send_message(&self, body: &[u8]) -> Result<UInt256> {
+        let boc = base64::decode(String::from_utf8_lossy(body).trim()).context("Invalid base64")?;
+        let message = ton_block::Message::construct_from_bytes(&boc).context("Invalid message")?;
+        let header = message
+            .ext_in_header()
+            .context("Not an external inbound message")?;
+        let hash = message.hash()?;
+
+        let engine = self.engine.upgrade().context("Engine is not running")?;
+        engine.broadcast_external_message(header.dst.workchain_id(), &boc)?;
+
+        let expire_at = now_sec() + self.message_ttl.as_secs() as u32;
+        self.pending.lock().unwrap().insert(hash, expire_at);
+        self.send_status(SerializeMessageStatus {
+            message_hash: hash,
+            status: MessageStatus::Accepted,
+            block_id: None,
+            transaction_id: None,
+        });
+        Ok(hash)
+    }
+
+    /// Reports pending messages included in the block
+    pub fn process_block(&self, block_stuff: &BlockStuff) -> Result<()> {
+        if self.pending.lock().unwrap().is_empty() {
+            return Ok(());
+        }
+
+        let mut included = Vec::new();
+        block_stuff
+            .block()
+            .read_extra()?
+            .read_account_blocks()?
+            .iterate_objects(|account_block| {
+                account_block.transactions().iterate_slices(|_, raw_transaction| {
+                    let cell = raw_transaction.reference(0)?;
+                    let transaction = ton_block::Transaction::construct_from_cell(cell.clone())?;
+                    if let Some(in_msg) = transaction.in_msg_cell() {
+                        included.push((in_msg.repr_hash(), cell.repr_hash()));
+                    }
+                    Ok(true)
+                })?;
+                Ok(true)
+            })?;
+
+        let included = {
+            let mut pending = self.pending.lock().unwrap();
+            included.retain(|(hash, _)| pending.remove(hash).is_some());
+            included
+        };
+        for (message_hash, transaction_id) in included {
+            self.send_status(SerializeMessageStatus {
+                message_hash,
+                status: MessageStatus::Included,
+                block_id: Some(block_stuff.id().root_hash),
+                transaction_id: Some(transaction_id),
+            });
+        }
+        Ok(())
+    }
+
+    fn expire_messages(&self) {
+        let now = now_sec();
+        let mut expired = Vec::new();
+        self.pending.lock().unwrap().retain(|hash, expire_at| {
+            if *expire_at < now {
+                expired.push(*hash);
+                return false;
+            }
+            true
+        });
+
+        for message_hash in expired {
+            self.send_status(SerializeMessageStatus {
+                message_hash,
+                status: MessageStatus::Expired,
+                block_id: None,
+                transaction_id: None,
+            });
+        }
+    }
+
+    fn send_status(&self, status: SerializeMessageStatus) {
+        if let Err(error) = self.handler.message_status(status) {
+            tracing::error!("Message status: {}", error);
+        }
+    }
+}
+
+/// Base64 of the largest external message the node accepts, with some margin
+const MAX_BODY_SIZE: usize = 128 * 1024;
+
+/// Broadcasted messages waiting for inclusion, new ones are rejected above it
+const MAX_PENDING: usize = 100_000;
+
+/// Reads the request body, `None` if it is larger than `MAX_BODY_SIZE`
+async fn read_body(mut body: Body) -> Result<Option<Vec<u8>>> {
+    if body.size_hint().lower() > MAX_BODY_SIZE as u64 {
+        return Ok(None);
+    }
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if data.len() + chunk.len() > MAX_BODY_SIZE {
+            return Ok(None);
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(Some(data))
+}
+
+fn response(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder().status(status).body(Body::from(body)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::Body;
+
+    use super::{read_body, MAX_BODY_SIZE};
+
+    #[tokio::test]
+    async fn test_body_limit() {
+        let body = read_body(Body::from(vec![b'A'; MAX_BODY_SIZE])).await.unwrap();
+        assert_eq!(body.map(|body| body.len()), Some(MAX_BODY_SIZE));
+
+        let body = read_body(Body::from(vec![b'A'; MAX_BODY_SIZE + 1])).await.unwrap();
+        assert!(body.is_none());
+
+        // Without a known length the body is limited while reading
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for _ in 0..3 {
+                if sender.send_data(vec![b'A'; MAX_BODY_SIZE / 2].into()).await.is_err() {
+                    break;
+                }
+            }
+        });
+        assert!(read_body(body).await.unwrap().is_none());
+    }
+}
@@ -1,4 +1,21 @@
+pub mod account_snapshot;
 pub mod archives_scanner;
+pub mod backfill_stats;
+pub mod bounds;
+pub mod db_usage;
+pub mod directory_scanner;
+pub mod handover;
+mod jrpc;
+mod lite_client;
+pub mod liteserver_scanner;
+pub mod message_consumer;
 pub mod network_scanner;
+pub mod pipeline;
+pub mod progress;
+pub mod replay_scanner;
+pub mod rpc_scanner;
 pub mod s3_scanner;
+pub mod shard_blocks;
+pub mod state_provider;
+pub mod synthetic_state;
 pub mod test_scanner;
@@ -1,26 +1,66 @@
-use std::sync::Arc;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
 
 use anyhow::{Context, Result};
 use everscale_rpc_server::RpcState;
+use rustc_hash::FxHashMap;
+use ton_block::{BlockIdExt, ShardIdent};
 use ton_indexer::utils::*;
 use ton_indexer::ProcessBlockContext;
 
 use crate::blocks_handler::*;
 use crate::config::*;
+use crate::filter::code_hashes;
+use crate::filter::config::TransactionRange;
+
+use super::db_usage::{DbUsageReport, DbUsageTracker};
+use super::handover::ScanHandover;
+use super::lite_client::find_mc_seqno;
+use super::message_consumer::MessageConsumer;
+use super::shard_blocks::top_shard_blocks;
 
 pub struct NetworkScanner {
     indexer: Arc<ton_indexer::Engine>,
+    db_usage: Arc<DbUsageTracker>,
+    gaps: Arc<GapMetrics>,
+    message_consumer: Option<Arc<MessageConsumer>>,
+}
+
+/// Shard seqno gaps and forks found in the received blocks
+#[derive(Debug, Default)]
+pub struct GapMetrics {
+    pub detected: AtomicU64,
+    pub repaired: AtomicU64,
+    /// Blocks replaced by another block at the same seqno
+    pub forks: AtomicU64,
 }
 
 impl NetworkScanner {
     pub async fn new(
-        node_settings: NodeConfig,
+        mut node_settings: NodeConfig,
         global_config: ton_indexer::GlobalConfig,
         handler: Arc<BlocksHandler>,
         rpc_state: Option<Arc<RpcState>>,
+        handover: Option<ScanHandover>,
     ) -> Result<Arc<Self>> {
-        let subscriber: Arc<dyn ton_indexer::Subscriber> = BlocksSubscriber::new(handler, rpc_state)?;
+        let shards = node_settings
+            .subscriptions
+            .iter()
+            .map(ShardSubscription::shard)
+            .collect::<Result<Vec<_>>>()
+            .context("Invalid shard subscription")?;
+        let message_consumer_config = node_settings.message_consumer.take();
+        node_settings.start_from = resolve_start_from(node_settings.start_from.take(), &handler.range).await?;
+        let subscriber = BlocksSubscriber::new(
+            handler.clone(),
+            rpc_state,
+            handover,
+            shards,
+            node_settings.wait_for_masterchain,
+        )?;
         println!("Indexer staring...");
+        let db_path = node_settings.db_path.clone();
 
         let indexer = ton_indexer::Engine::new(
             node_settings
@@ -28,55 +68,101 @@ impl NetworkScanner {
                 .await
                 .context("Failed to build node config")?,
             global_config,
-            subscriber,
+            subscriber.clone() as Arc<dyn ton_indexer::Subscriber>,
         )
             .await
             .context("Failed to start node")?;
+        subscriber.engine.set(Arc::downgrade(&indexer)).ok();
+        let db_usage = Arc::new(DbUsageTracker::open(&db_path));
+        crate::control::attach_engine(&indexer, db_usage.clone());
 
-        // let message_consumer = if let Some(config) = unimplemented!() {
-        //     Some(
-        //         MessageConsumer::new(indexer.clone(), config)
-        //             .context("Failed to create message consumer")?,
-        //     )
-        // } else {
-        //     None
-        // };
+        let message_consumer = message_consumer_config
+            .map(|config| MessageConsumer::new(&indexer, handler, config));
+        if let Some(consumer) = &message_consumer {
+            subscriber.message_consumer.set(consumer.clone()).ok();
+        }
 
         Ok(Arc::new(Self {
             indexer,
-            /* message_consumer */
+            db_usage,
+            gaps: subscriber.gaps.clone(),
+            message_consumer,
         }))
     }
 
     pub async fn start(self: &Arc<Self>) -> Result<()> {
         self.indexer.start().await?;
-        /* if let Some(consumer) = &self.message_consumer {
+        if let Some(consumer) = &self.message_consumer {
             consumer.start();
-        } */
+        }
         Ok(())
     }
 
     pub fn indexer(&self) -> &Arc<ton_indexer::Engine> {
         &self.indexer
     }
+
+    pub fn gap_metrics(&self) -> &GapMetrics {
+        &self.gaps
+    }
+
+    /// Table sizes of the node database with their growth
+    pub fn db_usage(&self) -> Result<DbUsageReport> {
+        self.db_usage.report(&self.indexer)
+    }
 }
 
 struct BlocksSubscriber {
     handler: Arc<BlocksHandler>,
-    rpc_state: Option<Arc<RpcState>>
+    rpc_state: Option<Arc<RpcState>>,
+    /// Blocks already processed from archives
+    handover: Option<ScanHandover>,
+    /// Used to load missing blocks, set after the engine is created
+    engine: OnceLock<Weak<ton_indexer::Engine>>,
+    /// Tracks inclusion of the sent external messages, if enabled
+    message_consumer: OnceLock<Arc<MessageConsumer>>,
+    last_seqnos: Mutex<FxHashMap<ShardIdent, u32>>,
+    /// Ids of the latest blocks of each shard by seqno, used to detect forks
+    recent_blocks: Mutex<FxHashMap<ShardIdent, BTreeMap<u32, BlockIdExt>>>,
+    gaps: Arc<GapMetrics>,
+    /// Subscribed shards, all if empty
+    shards: Vec<ShardIdent>,
+    /// Shard blocks waiting for a masterchain block, if enabled
+    uncommitted: Option<Mutex<Vec<BlockStuff>>>,
 }
 
 impl BlocksSubscriber {
-    fn new(handler: Arc<BlocksHandler>, rpc_state: Option<Arc<RpcState>>) -> Result<Arc<Self>> {
+    fn new(
+        handler: Arc<BlocksHandler>,
+        rpc_state: Option<Arc<RpcState>>,
+        handover: Option<ScanHandover>,
+        shards: Vec<ShardIdent>,
+        wait_for_masterchain: bool,
+    ) -> Result<Arc<Self>> {
 
         Ok(Arc::new(Self {
             handler,
-            rpc_state
+            rpc_state,
+            handover,
+            engine: OnceLock::new(),
+            message_consumer: OnceLock::new(),
+            last_seqnos: Default::default(),
+            recent_blocks: Default::default(),
+            gaps: Default::default(),
+            shards,
+            uncommitted: wait_for_masterchain.then(Default::default),
         }))
     }
 }
 
 impl BlocksSubscriber {
+    /// Masterchain blocks are always processed, they commit shard blocks and carry the config
+    fn is_subscribed(&self, shard: &ShardIdent) -> bool {
+        self.shards.is_empty()
+            || shard.is_masterchain()
+            || self.shards.iter().any(|s| s.intersect_with(shard))
+    }
+
     async fn handle_block(
         &self,
         block_stuff: &BlockStuff,
@@ -88,24 +174,234 @@ impl BlocksSubscriber {
                 .context("Failed to update RPC state")?;
         }
 
+        if let Some(consumer) = self.message_consumer.get() {
+            if let Err(e) = consumer.process_block(block_stuff) {
+                tracing::error!("failed to track sent messages: {e:?}");
+            }
+        }
+
+        if matches!(&self.handover, Some(handover) if handover.contains(block_stuff.id())) {
+            return Ok(());
+        }
+
+        if !self.is_subscribed(block_stuff.id().shard()) {
+            return Ok(());
+        }
+
+        if !self.revoke_forked(block_stuff.id())? {
+            return Ok(());
+        }
+        self.repair_gap(block_stuff).await?;
+
+        self.emit_block(block_stuff, shard_state).await
+    }
+
+    /// Passes the block to the handler. With `wait_for_masterchain`,
+    /// shard blocks are handled after the masterchain block referencing them
+    async fn emit_block(
+        &self,
+        block_stuff: &BlockStuff,
+        shard_state: Option<&ShardStateStuff>,
+    ) -> Result<()> {
+        if let Some(uncommitted) = &self.uncommitted {
+            if !block_stuff.id().shard().is_masterchain() {
+                let overflow = {
+                    let mut uncommitted = uncommitted.lock().unwrap();
+                    uncommitted.push(block_stuff.clone());
+                    let excess = uncommitted.len().saturating_sub(MAX_UNCOMMITTED);
+                    uncommitted.drain(..excess).collect::<Vec<_>>()
+                };
+                for block in overflow {
+                    tracing::warn!("no masterchain block references {}, emitting it uncommitted", block.id());
+                    self.handler
+                        .handle_committed_block(&block, None, None)
+                        .await
+                        .context("Failed to handle block")?;
+                }
+                return Ok(());
+            }
+            self.emit_committed(block_stuff).await?;
+        }
+
         self.handler
             .handle_block(block_stuff, shard_state)
             .await
             .context("Failed to handle block")
     }
+
+    /// Handles waiting shard blocks referenced by the masterchain block.
+    /// States are loaded from the node storage
+    async fn emit_committed(&self, mc_block: &BlockStuff) -> Result<()> {
+        let Some(uncommitted) = &self.uncommitted else {
+            return Ok(());
+        };
+
+        let top_blocks = top_shard_blocks(mc_block)?;
+        let committed = {
+            let mut uncommitted = uncommitted.lock().unwrap();
+            let (committed, rest) = uncommitted.drain(..).partition::<Vec<_>, _>(|block| {
+                let id = block.id();
+                top_blocks
+                    .iter()
+                    .any(|top| top.shard().intersect_with(id.shard()) && top.seq_no >= id.seq_no)
+            });
+            *uncommitted = rest;
+            committed
+        };
+
+        let engine = self.engine.get().and_then(Weak::upgrade);
+        for block in committed {
+            let state = match &engine {
+                Some(engine) => engine.load_state(block.id()).await.map_err(|e| {
+                    tracing::warn!("state of {} is not available: {e:?}", block.id());
+                }).ok(),
+                None => None,
+            };
+            self.handler
+                .handle_committed_block(&block, state.as_deref(), Some(mc_block.id().seq_no))
+                .await
+                .context("Failed to handle block")?;
+        }
+        Ok(())
+    }
+
+    /// Revokes the previous block at the same seqno if it differs from the new one.
+    /// Not yet emitted blocks are just dropped. Returns false if the block was already handled
+    fn revoke_forked(&self, block_id: &BlockIdExt) -> Result<bool> {
+        let replaced = {
+            let mut recent_blocks = self.recent_blocks.lock().unwrap();
+            let blocks = recent_blocks.entry(block_id.shard().clone()).or_default();
+            let replaced = blocks.insert(block_id.seq_no, block_id.clone());
+            *blocks = blocks.split_off(&block_id.seq_no.saturating_sub(FORK_DEPTH));
+            replaced
+        };
+        let Some(replaced) = replaced else {
+            return Ok(true);
+        };
+        if &replaced == block_id {
+            return Ok(false);
+        }
+
+        self.gaps.forks.fetch_add(1, Ordering::Relaxed);
+        tracing::warn!("fork detected: {replaced} replaced with {block_id}");
+
+        if let Some(uncommitted) = &self.uncommitted {
+            let mut uncommitted = uncommitted.lock().unwrap();
+            let len = uncommitted.len();
+            uncommitted.retain(|block| block.id() != &replaced);
+            if uncommitted.len() < len {
+                return Ok(true);
+            }
+        }
+
+        self.handler
+            .revoke_block(&replaced)
+            .context("Failed to revoke block")?;
+        Ok(true)
+    }
+
+    /// Handles skipped blocks of the shard before the current one.
+    /// Shard states are not available for them
+    async fn repair_gap(&self, block_stuff: &BlockStuff) -> Result<()> {
+        let block_id = block_stuff.id();
+        let last_seqno = {
+            let mut last_seqnos = self.last_seqnos.lock().unwrap();
+            let last_seqno = last_seqnos.entry(block_id.shard().clone()).or_insert(block_id.seq_no);
+            // A late block must not move the shard back, its successors are already handled
+            let previous = *last_seqno;
+            *last_seqno = previous.max(block_id.seq_no);
+            (previous != block_id.seq_no).then_some(previous)
+        };
+        let Some(last_seqno) = last_seqno else {
+            return Ok(());
+        };
+        if block_id.seq_no <= last_seqno + 1 {
+            return Ok(());
+        }
+
+        self.gaps.detected.fetch_add(1, Ordering::Relaxed);
+        tracing::warn!(
+            "gap detected in shard {}: {} after {last_seqno}",
+            block_id.shard(),
+            block_id.seq_no
+        );
+
+        let blocks = match self.load_previous_blocks(block_stuff, last_seqno).await {
+            Ok(blocks) => blocks,
+            Err(e) => {
+                tracing::error!("failed to load missing blocks: {e:?}");
+                return Ok(());
+            }
+        };
+        for block in &blocks {
+            // Recorded first, so the original block arriving late is not emitted again
+            if !self.revoke_forked(block.id())? {
+                continue;
+            }
+            self.emit_block(block, None)
+                .await
+                .context("Failed to handle missing block")?;
+        }
+
+        self.gaps.repaired.fetch_add(1, Ordering::Relaxed);
+        tracing::info!("gap repaired in shard {}: {} blocks", block_id.shard(), blocks.len());
+        Ok(())
+    }
+
+    /// Loads blocks after `last_seqno` by following previous block references
+    async fn load_previous_blocks(
+        &self,
+        block_stuff: &BlockStuff,
+        last_seqno: u32,
+    ) -> Result<Vec<BlockStuff>> {
+        let engine = self
+            .engine
+            .get()
+            .and_then(Weak::upgrade)
+            .context("Engine is not running")?;
+        let storage = engine.storage();
+
+        let shard = block_stuff.id().shard().clone();
+        let mut info = block_stuff.block().read_info()?;
+        let mut blocks = Vec::new();
+        while info.seq_no() > last_seqno + 1 {
+            anyhow::ensure!(
+                !info.after_merge() && !info.after_split(),
+                "Shard topology changed within the gap"
+            );
+            let prev = info.read_prev_ref()?.prev1()?;
+            let prev_id =
+                BlockIdExt::with_params(shard.clone(), prev.seq_no, prev.root_hash, prev.file_hash);
+
+            let handle = storage
+                .block_handle_storage()
+                .load_handle(&prev_id)?
+                .with_context(|| format!("Block {prev_id} not found"))?;
+            let block = storage.block_storage().load_block_data(&handle).await?;
+
+            info = block.block().read_info()?;
+            blocks.push(block);
+        }
+
+        blocks.reverse();
+        Ok(blocks)
+    }
 }
 
 #[async_trait::async_trait]
 impl ton_indexer::Subscriber for BlocksSubscriber {
     async fn process_block(&self, ctx: ProcessBlockContext<'_>) -> Result<()> {
-        self.handle_block(
-            ctx.block_stuff(),
-            ctx.shard_state_stuff(),
-        )
-        .await
+        let (block_stuff, shard_state) = (ctx.block_stuff(), ctx.shard_state_stuff());
+        supervisor::run_supervised("block processing", || self.handle_block(block_stuff, shard_state))
+            .await
     }
 
     async fn process_full_state(&self, state: Arc<ShardStateStuff>) -> Result<()> {
+        let indexed_state = state.clone();
+        tokio::task::spawn_blocking(move || code_hashes::index_full_state(&indexed_state))
+            .await?
+            .context("Failed to index code hashes")?;
+
         if let Some(rpc_state) = &self.rpc_state {
             rpc_state
                 .process_full_state(state)
@@ -126,3 +422,76 @@ impl ton_indexer::Subscriber for BlocksSubscriber {
         Ok(())
     }
 }
+
+/// Number of latest seqnos per shard checked for forks
+const FORK_DEPTH: u32 = 1000;
+
+/// Shard blocks kept waiting for a masterchain block, older ones are emitted without it
+const MAX_UNCOMMITTED: usize = 10_000;
+
+/// Replaces the time based start points with the seqno of the masterchain block at that time
+async fn resolve_start_from(start_from: Option<StartFrom>, range: &TransactionRange) -> Result<Option<StartFrom>> {
+    let (utime, liteservers) = match start_from {
+        Some(StartFrom::Utime { utime, liteservers }) => (utime, liteservers),
+        Some(StartFrom::FilterRange { liteservers }) => {
+            let utime = range
+                .start_utime
+                .context("`FilterRange` start requires `start_utime` in the filter range")?;
+            (utime, liteservers)
+        }
+        start_from => return Ok(start_from),
+    };
+    let seqno = find_mc_seqno(&liteservers, utime)
+        .await
+        .context("Failed to find the start block")?;
+    tracing::info!("starting from masterchain block {seqno} generated at {utime}");
+    Ok(Some(StartFrom::Seqno { seqno }))
+}
+
+#[cfg(test)]
+mod tests {
+    use ton_types::UInt256;
+
+    use crate::filter::config::TransactionRange;
+    use crate::producer::tests::http2_producer;
+    use crate::serializer::Serializer;
+
+    use super::*;
+
+    fn subscriber(port: u16, shards: Vec<ShardIdent>) -> Arc<BlocksSubscriber> {
+        let serializer = Serializer::Json {
+            address_format: Default::default(),
+            normalize_amounts: false,
+        };
+        let handler = BlocksHandler::new(serializer, http2_producer(port), TransactionRange::default(), false).unwrap();
+        BlocksSubscriber::new(Arc::new(handler), None, None, shards, true).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_masterchain_with_workchain_subscription() {
+        let shard = ShardIdent::with_tagged_prefix(0, 0x4000_0000_0000_0000).unwrap();
+        let subscriber = subscriber(31103, vec![shard]);
+        assert!(subscriber.uncommitted.is_some());
+
+        // Otherwise waiting shard blocks are never committed
+        assert!(subscriber.is_subscribed(&ShardIdent::masterchain()));
+        assert!(subscriber.is_subscribed(&ShardIdent::full(0)));
+        assert!(!subscriber.is_subscribed(&ShardIdent::with_tagged_prefix(0, 0xc000_0000_0000_0000).unwrap()));
+        assert!(!subscriber.is_subscribed(&ShardIdent::full(1)));
+    }
+
+    #[tokio::test]
+    async fn test_repeated_block_is_skipped() {
+        let subscriber = subscriber(31104, Vec::new());
+        let block_id = |seq_no, hash| {
+            BlockIdExt::with_params(ShardIdent::full(0), seq_no, UInt256::from([hash; 32]), UInt256::default())
+        };
+
+        // A block emitted by gap repair, then received from the network
+        assert!(subscriber.revoke_forked(&block_id(5, 1)).unwrap());
+        assert!(!subscriber.revoke_forked(&block_id(5, 1)).unwrap());
+        // A fork at the same seqno is still handled
+        assert!(subscriber.revoke_forked(&block_id(5, 2)).unwrap());
+        assert_eq!(subscriber.gaps.forks.load(Ordering::Relaxed), 1);
+    }
+}
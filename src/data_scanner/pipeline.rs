@@ -0,0 +1,129 @@
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use rustc_hash::FxHasher;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use ton_indexer::utils::BlockStuff;
+
+use crate::blocks_handler::{supervisor, BlocksHandler};
+
+/// Handles blocks on several workers. Blocks of the same shard
+/// always go to the same worker, so their order is preserved
+pub struct BlocksPipeline {
+    workers: Vec<mpsc::Sender<WorkerTask>>,
+    tasks: Vec<JoinHandle<()>>,
+    error: Arc<Mutex<Option<anyhow::Error>>>,
+}
+
+enum WorkerTask {
+    Block(BlockStuff),
+    Flush(oneshot::Sender<()>),
+}
+
+impl BlocksPipeline {
+    /// Failed blocks are retried if `retry_on_error` is set,
+    /// otherwise the first error is returned from [`BlocksPipeline::flush`]
+    pub fn new(handler: Arc<BlocksHandler>, workers: usize, retry_on_error: bool) -> Self {
+        let error = Arc::new(Mutex::new(None));
+        let (workers, tasks) = (0..workers.max(1))
+            .map(|_| {
+                let (tx, rx) = mpsc::channel(WORKER_QUEUE_CAPACITY);
+                let task = tokio::spawn(run_worker(
+                    handler.clone(),
+                    rx,
+                    retry_on_error,
+                    error.clone(),
+                ));
+                (tx, task)
+            })
+            .unzip();
+
+        Self {
+            workers,
+            tasks,
+            error,
+        }
+    }
+
+    pub async fn send(&self, block: BlockStuff) -> Result<()> {
+        let mut hasher = FxHasher::default();
+        block.id().shard().hash(&mut hasher);
+        let worker = &self.workers[hasher.finish() as usize % self.workers.len()];
+
+        worker
+            .send(WorkerTask::Block(block))
+            .await
+            .map_err(|_| anyhow::anyhow!("Blocks worker stopped"))
+    }
+
+    /// Waits until all sent blocks are handled
+    pub async fn flush(&self) -> Result<()> {
+        let mut waiters = Vec::with_capacity(self.workers.len());
+        for worker in &self.workers {
+            let (tx, rx) = oneshot::channel();
+            worker
+                .send(WorkerTask::Flush(tx))
+                .await
+                .map_err(|_| anyhow::anyhow!("Blocks worker stopped"))?;
+            waiters.push(rx);
+        }
+        for rx in waiters {
+            rx.await.context("Blocks worker stopped")?;
+        }
+
+        match self.error.lock().unwrap().take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Handles the remaining blocks and stops workers
+    pub async fn finish(self) -> Result<()> {
+        let result = self.flush().await;
+        drop(self.workers);
+        for task in self.tasks {
+            task.await.context("Blocks worker panicked")?;
+        }
+        result
+    }
+}
+
+async fn run_worker(
+    handler: Arc<BlocksHandler>,
+    mut rx: mpsc::Receiver<WorkerTask>,
+    retry_on_error: bool,
+    error: Arc<Mutex<Option<anyhow::Error>>>,
+) {
+    while let Some(task) = rx.recv().await {
+        let block = match task {
+            WorkerTask::Block(block) => block,
+            WorkerTask::Flush(tx) => {
+                tx.send(()).ok();
+                continue;
+            }
+        };
+
+        let (handler, block) = (&handler, &block);
+        loop {
+            let result = supervisor::run_supervised("block processing", || async move {
+                handler.handle_block(block, None).await.map_err(anyhow::Error::from)
+            })
+            .await;
+            match result {
+                Ok(()) => break,
+                Err(e) => {
+                    let e = e.context(format!("Failed processing block {}", block.id()));
+                    tracing::error!("{e:?}");
+                    if !retry_on_error {
+                        error.lock().unwrap().get_or_insert(e);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+const WORKER_QUEUE_CAPACITY: usize = 1000;
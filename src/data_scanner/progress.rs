@@ -0,0 +1,62 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+
+use super::bounds::archive_seqno;
+
+/// Stores the name of the last fully processed archive,
+/// so that a restarted scanner can continue after it
+pub struct ScanProgress {
+    path: PathBuf,
+}
+
+impl ScanProgress {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Returns the last processed archive, if any
+    pub fn load(&self) -> Result<Option<String>> {
+        match std::fs::read_to_string(&self.path) {
+            Ok(data) => {
+                let name = data.trim();
+                Ok((!name.is_empty()).then(|| name.to_owned()))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| {
+                format!("Failed to read progress file {}", self.path.display())
+            }),
+        }
+    }
+
+    pub fn save(&self, archive: &str) -> Result<()> {
+        // Write to a temporary file first so a crash never leaves a partial record
+        let temp_path = self.path.with_extension("tmp");
+        std::fs::write(&temp_path, archive)
+            .and_then(|_| std::fs::rename(&temp_path, &self.path))
+            .with_context(|| format!("Failed to write progress file {}", self.path.display()))
+    }
+}
+
+/// Whether the archive comes at or before the last processed one.
+/// Compares seqnos in the names if both have them, so the last archive needn't be listed
+pub fn is_processed(archive: &str, last: &str) -> bool {
+    match (archive_seqno(archive), archive_seqno(last)) {
+        (Some(seqno), Some(last_seqno)) => seqno <= last_seqno,
+        _ => archive <= last,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_processed;
+
+    #[test]
+    fn test_is_processed() {
+        assert!(is_processed("archives/900", "archives/1000"));
+        assert!(is_processed("archives/1000", "archives/1000"));
+        // The last archive was removed from the listing
+        assert!(!is_processed("archives/1100", "archives/1050"));
+        assert!(!is_processed("archive.b", "archive.a"));
+    }
+}
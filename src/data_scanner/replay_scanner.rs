@@ -0,0 +1,105 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use ton_block::BlockIdExt;
+use ton_indexer::utils::BlockStuff;
+
+use crate::blocks_handler::*;
+
+use super::shard_blocks::{BlockLoader, ShardBlocksTracker};
+
+/// Processes blocks already stored in the local node database.
+/// Shard states are not available for old blocks, so account states are not produced
+pub struct ReplayScanner {
+    engine: Arc<ton_indexer::Engine>,
+    handler: Arc<BlocksHandler>,
+    from_seqno: u32,
+    to_seqno: Option<u32>,
+}
+
+impl ReplayScanner {
+    pub fn new(
+        engine: Arc<ton_indexer::Engine>,
+        handler: Arc<BlocksHandler>,
+        from_seqno: u32,
+        to_seqno: Option<u32>,
+    ) -> Self {
+        Self {
+            engine,
+            handler,
+            from_seqno,
+            to_seqno,
+        }
+    }
+
+    pub async fn run(self) -> Result<()> {
+        let mut loader = StorageLoader {
+            engine: self.engine.clone(),
+        };
+
+        // Masterchain blocks can only be found by following the chain back from the last one
+        let last = self
+            .engine
+            .load_last_applied_mc_block_id()
+            .context("Failed to load last masterchain block")?;
+        let to_seqno = self.to_seqno.unwrap_or(last.seq_no).min(last.seq_no);
+        anyhow::ensure!(self.from_seqno <= to_seqno, "Nothing to replay");
+
+        let mut mc_ids = Vec::new();
+        let mut block_id = last;
+        while block_id.seq_no >= self.from_seqno {
+            let prev = if block_id.seq_no > self.from_seqno {
+                let block = loader.load_block(&block_id).await?;
+                let prev = block.block().read_info()?.read_prev_ref()?.prev1()?;
+                Some(BlockIdExt::with_params(
+                    block_id.shard().clone(),
+                    prev.seq_no,
+                    prev.root_hash,
+                    prev.file_hash,
+                ))
+            } else {
+                None
+            };
+            if block_id.seq_no <= to_seqno {
+                mc_ids.push(block_id);
+            }
+            match prev {
+                Some(prev) => block_id = prev,
+                None => break,
+            }
+        }
+        mc_ids.reverse();
+        tracing::info!("replaying {} masterchain blocks", mc_ids.len());
+
+        let mut shard_blocks = ShardBlocksTracker::default();
+        for mc_id in mc_ids {
+            let mc_block = loader.load_block(&mc_id).await?;
+            for block in shard_blocks.new_blocks(&mut loader, &mc_block).await? {
+                self.handler
+                    .handle_committed_block(&block, None, Some(mc_block.id().seq_no))
+                    .await?;
+            }
+            self.handler.handle_block(&mc_block, None).await?;
+            tracing::debug!("replayed masterchain block {}", mc_id.seq_no);
+        }
+
+        tracing::info!("replay complete");
+        Ok(())
+    }
+}
+
+pub(super) struct StorageLoader {
+    pub(super) engine: Arc<ton_indexer::Engine>,
+}
+
+#[async_trait::async_trait]
+impl BlockLoader for StorageLoader {
+    async fn load_block(&mut self, block_id: &BlockIdExt) -> Result<BlockStuff> {
+        let storage = self.engine.storage();
+        let handle = storage
+            .block_handle_storage()
+            .load_handle(block_id)?
+            .with_context(|| format!("Block {block_id} not found"))?;
+        storage.block_storage().load_block_data(&handle).await
+    }
+}
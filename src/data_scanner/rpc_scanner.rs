@@ -0,0 +1,131 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use rustc_hash::FxHashMap;
+use ton_block::{BlockIdExt, Deserializable, MsgAddressInt};
+
+use crate::blocks_handler::*;
+use crate::config::*;
+
+use super::jrpc::JrpcClient;
+
+/// Polls transactions of the specified accounts from a JSON-RPC endpoint.
+/// Blocks are not available via RPC, so messages have an empty block id
+/// and account states are not produced
+pub struct RpcScanner {
+    handler: Arc<BlocksHandler>,
+    client: JrpcClient,
+    accounts: Vec<MsgAddressInt>,
+    poll_interval: Duration,
+    batch_size: u8,
+}
+
+impl RpcScanner {
+    pub fn new(config: RpcScannerConfig, handler: Arc<BlocksHandler>) -> Result<Self> {
+        let accounts = config
+            .accounts
+            .iter()
+            .map(|account| {
+                MsgAddressInt::from_str(account)
+                    .map_err(|e| anyhow::anyhow!("Invalid account {account}: {e}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        anyhow::ensure!(!accounts.is_empty(), "No accounts to poll");
+        anyhow::ensure!(config.batch_size > 0, "`batch_size` must be positive");
+
+        Ok(Self {
+            handler,
+            client: JrpcClient::new(config.endpoint),
+            accounts,
+            poll_interval: Duration::from_millis(config.poll_interval_ms),
+            batch_size: config.batch_size,
+        })
+    }
+
+    pub async fn run(self) -> Result<()> {
+        // Only transactions after the first poll are processed
+        let mut last_lts = FxHashMap::default();
+        for account in &self.accounts {
+            let last_lt = self
+                .fetch_transactions(account, None)
+                .await?
+                .first()
+                .map(|transaction| transaction.lt)
+                .unwrap_or_default();
+            last_lts.insert(account.clone(), last_lt);
+        }
+
+        loop {
+            tokio::time::sleep(self.poll_interval).await;
+
+            for account in &self.accounts {
+                let last_lt = last_lts.entry(account.clone()).or_default();
+                match self.new_transactions(account, *last_lt).await {
+                    Ok(transactions) => {
+                        for transaction in transactions {
+                            *last_lt = transaction.lt;
+                            self.handler
+                                .handle_transaction(transaction, &BlockIdExt::default(), None, None);
+                        }
+                    }
+                    Err(e) => tracing::error!("Failed to poll account {account}: {e:?}"),
+                }
+            }
+        }
+    }
+
+    /// Returns transactions after `last_lt` from the oldest to the newest
+    async fn new_transactions(
+        &self,
+        account: &MsgAddressInt,
+        last_lt: u64,
+    ) -> Result<Vec<ton_block::Transaction>> {
+        let mut result = Vec::new();
+        let mut before_lt = None;
+        loop {
+            let batch = self.fetch_transactions(account, before_lt).await?;
+            let is_full = !batch.is_empty() && batch.len() == self.batch_size as usize;
+
+            let mut reached = !is_full;
+            for transaction in batch {
+                if transaction.lt <= last_lt {
+                    reached = true;
+                    break;
+                }
+                before_lt = Some(transaction.lt - 1);
+                result.push(transaction);
+            }
+            if reached {
+                break;
+            }
+        }
+
+        result.reverse();
+        Ok(result)
+    }
+
+    /// Returns transactions starting from `from_lt` (or the latest) to older ones
+    async fn fetch_transactions(
+        &self,
+        account: &MsgAddressInt,
+        from_lt: Option<u64>,
+    ) -> Result<Vec<ton_block::Transaction>> {
+        let params = serde_json::json!({
+            "account": account.to_string(),
+            "limit": self.batch_size,
+            "lastTransactionLt": from_lt.map(|lt| lt.to_string()),
+        });
+        let transactions: Vec<String> = self.client.request("getTransactionsList", params).await?;
+
+        transactions
+            .iter()
+            .map(|boc| {
+                let bytes = base64::decode(boc)?;
+                ton_block::Transaction::construct_from_bytes(&bytes)
+                    .context("Failed to parse transaction")
+            })
+            .collect()
+    }
+}
@@ -9,10 +9,20 @@ use crate::archive::*;
 use crate::blocks_handler::*;
 use crate::config::*;
 
+use super::backfill_stats::BackfillStats;
+use super::bounds::archive_seqno;
+use super::handover::ScanHandover;
+use super::pipeline::BlocksPipeline;
+use super::progress::{is_processed, ScanProgress};
+
 pub struct S3Scanner {
     handler: Arc<BlocksHandler>,
     downloader: ArchiveDownloader,
     retry_on_error: bool,
+    progress: Option<ScanProgress>,
+    pipeline: PipelineConfig,
+    bounds: ScanBounds,
+    stats: Arc<BackfillStats>,
 }
 
 impl S3Scanner {
@@ -25,51 +35,114 @@ impl S3Scanner {
             handler,
             downloader,
             retry_on_error: config.retry_on_error,
+            progress: config.progress_path.map(ScanProgress::new),
+            pipeline: config.pipeline,
+            bounds: config.bounds,
+            stats: Default::default(),
         })
     }
 
+    /// Shares progress counters, e.g. for metrics
+    pub fn with_stats(mut self, stats: Arc<BackfillStats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
     pub async fn run(self) -> Result<()> {
+        self.scan().await.map(|_| ())
+    }
+
+    /// Processes all archives and returns where the scan stopped
+    pub async fn scan(self) -> Result<ScanHandover> {
         let pb = ProgressBar::new_spinner();
 
         let total_style = ProgressStyle::default_bar()
             .template("Archives processed: {pos}. Speed: {per_sec}. {msg}")?;
         pb.set_style(total_style);
 
-        let mut stream = self.downloader.archives_stream();
-        while let Some(item) = stream.next().await {
-            let (archive_name, archive): (String, Vec<u8>) =
-                item.context("Failed to fetch archive")?;
+        // Archives up to and including this one are skipped
+        let resume_after = match &self.progress {
+            Some(progress) => progress.load()?,
+            None => None,
+        };
+        if let Some(last) = &resume_after {
+            pb.println(format!("Resuming after archive {last}"));
+        }
 
-            let parsed = parse_archive(archive).context("Invalid archive")?;
-            for (block_id, parsed) in parsed {
-                let (stuff, _data) = parsed.block_stuff;
+        let skip_until = resume_after.clone();
+        let bounds = self.bounds;
+        let mut archives = self
+            .downloader
+            .archives_stream()
+            .filter(move |item| {
+                let processed = match (&skip_until, item) {
+                    (Some(last), Ok((archive_name, _))) => is_processed(archive_name, last),
+                    _ => false,
+                };
+                futures_util::future::ready(!processed)
+            })
+            // Archives are listed in order, so the rest is after `to_seqno` too
+            .take_while(move |item| {
+                let in_bounds = match item {
+                    Ok((archive_name, _)) => archive_seqno(archive_name)
+                        .map_or(true, |start| bounds.contains_archive(start, None)),
+                    Err(_) => true,
+                };
+                futures_util::future::ready(in_bounds)
+            })
+            // Archives are parsed ahead on the blocking pool
+            .map(|item| async move {
+                let (archive_name, archive): (String, Vec<u8>) =
+                    item.context("Failed to fetch archive")?;
+                let blocks = tokio::task::spawn_blocking(move || parse_archive(archive))
+                    .await
+                    .context("Archive parser panicked")?
+                    .context("Invalid archive")?;
+                Ok::<_, anyhow::Error>((archive_name, blocks))
+            })
+            .buffered(self.pipeline.prefetch_archives.max(1));
 
-                loop {
-                    match self
-                        .handler
-                        .handle_block(
-                            &stuff,
-                            None
-                        )
-                        .await
-                        .context("Failed to handle block")
-                    {
-                        Ok(()) => break,
-                        Err(e) => {
-                            pb.println(format!("Failed processing block {block_id}: {e:?}"));
-                            if !self.retry_on_error {
-                                return Err(e);
-                            }
-                        }
-                    }
+        let pipeline = BlocksPipeline::new(
+            self.handler.clone(),
+            self.pipeline.block_workers,
+            self.retry_on_error,
+        );
+
+        let mut handover = ScanHandover::default();
+        let mut processed = 0usize;
+        while let Some(item) = archives.next().await {
+            let (archive_name, blocks) = item?;
+
+            handover.start_archive();
+            let mut sent_blocks = 0;
+            let mut last_utime = 0;
+            for (block_id, parsed) in blocks {
+                let (stuff, _data) = parsed.block_stuff;
+                if self.bounds.contains(&stuff)? {
+                    handover.record(&block_id);
+                    let gen_utime = stuff.block().read_info()?.gen_utime().as_u32();
+                    last_utime = last_utime.max(gen_utime);
+                    sent_blocks += 1;
+                    pipeline.send(stuff).await?;
                 }
             }
+            pipeline.flush().await?;
+            self.stats.archive_processed(sent_blocks, last_utime);
 
+            if let Some(progress) = &self.progress {
+                progress.save(&archive_name)?;
+            }
+
+            processed += 1;
             pb.inc(1);
             pb.println(archive_name);
         }
+        pipeline.finish().await?;
 
+        if let (Some(last), 0) = (resume_after, processed) {
+            pb.println(format!("No archives found after {last}"));
+        }
         pb.println("Done");
-        Ok(())
+        Ok(handover)
     }
 }
@@ -0,0 +1,122 @@
+use anyhow::{Context, Result};
+use rustc_hash::{FxHashMap, FxHashSet};
+use ton_block::{BlockIdExt, ShardIdent};
+use ton_indexer::utils::BlockStuff;
+
+/// Source of blocks by id
+#[async_trait::async_trait]
+pub trait BlockLoader: Send {
+    async fn load_block(&mut self, block_id: &BlockIdExt) -> Result<BlockStuff>;
+}
+
+/// Finds shard blocks committed by each next masterchain block
+#[derive(Default)]
+pub struct ShardBlocksTracker {
+    /// Last processed block of each shard
+    shard_seqnos: FxHashMap<ShardIdent, u32>,
+}
+
+impl ShardBlocksTracker {
+    /// Loads shard blocks from the top blocks of the masterchain block back to the
+    /// last processed ones. Only top blocks are returned for the first masterchain block
+    pub async fn new_blocks<L: BlockLoader>(
+        &mut self,
+        loader: &mut L,
+        mc_block: &BlockStuff,
+    ) -> Result<Vec<BlockStuff>> {
+        let top_blocks = top_shard_blocks(mc_block)?;
+
+        let mut blocks = Vec::new();
+        for top_block in &top_blocks {
+            self.collect(loader, top_block, &mut blocks).await?;
+        }
+        blocks.sort_by_key(|block| block.id().seq_no);
+
+        for top_block in top_blocks {
+            self.shard_seqnos
+                .retain(|shard, _| !shard.intersect_with(top_block.shard()));
+            self.shard_seqnos
+                .insert(top_block.shard().clone(), top_block.seq_no);
+        }
+
+        Ok(blocks)
+    }
+
+    async fn collect<L: BlockLoader>(
+        &self,
+        loader: &mut L,
+        top_block: &BlockIdExt,
+        blocks: &mut Vec<BlockStuff>,
+    ) -> Result<()> {
+        let is_processed = |block_id: &BlockIdExt| {
+            self.shard_seqnos.iter().any(|(shard, &seqno)| {
+                shard.intersect_with(block_id.shard()) && seqno >= block_id.seq_no
+            })
+        };
+
+        let mut visited = FxHashSet::default();
+        let mut pending = vec![top_block.clone()];
+        while let Some(block_id) = pending.pop() {
+            if is_processed(&block_id) || !visited.insert(block_id.clone()) {
+                continue;
+            }
+            anyhow::ensure!(visited.len() <= MAX_SHARD_BLOCKS, "Too many missing shard blocks");
+
+            let block = loader.load_block(&block_id).await?;
+            if !self.shard_seqnos.is_empty() {
+                let info = block.block().read_info()?;
+                let prev_ref = info.read_prev_ref()?;
+                let shard = block_id.shard();
+
+                let prev1 = prev_ref.prev1()?;
+                let prev1_shard = if info.after_merge() {
+                    shard.split()?.0
+                } else if info.after_split() {
+                    shard.merge()?
+                } else {
+                    shard.clone()
+                };
+                pending.push(BlockIdExt::with_params(
+                    prev1_shard,
+                    prev1.seq_no,
+                    prev1.root_hash,
+                    prev1.file_hash,
+                ));
+
+                if let Some(prev2) = prev_ref.prev2()? {
+                    pending.push(BlockIdExt::with_params(
+                        shard.split()?.1,
+                        prev2.seq_no,
+                        prev2.root_hash,
+                        prev2.file_hash,
+                    ));
+                }
+            }
+            blocks.push(block);
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the latest shard blocks referenced by the masterchain block
+pub fn top_shard_blocks(mc_block: &BlockStuff) -> Result<Vec<BlockIdExt>> {
+    let mut top_blocks = Vec::new();
+    let mc_extra = mc_block
+        .block()
+        .read_extra()?
+        .read_custom()?
+        .context("Masterchain block without extra")?;
+    mc_extra.shards().iterate_shards(|shard, descr| {
+        top_blocks.push(BlockIdExt::with_params(
+            shard,
+            descr.seq_no,
+            descr.root_hash,
+            descr.file_hash,
+        ));
+        Ok(true)
+    })?;
+    Ok(top_blocks)
+}
+
+const MAX_SHARD_BLOCKS: usize = 1000;
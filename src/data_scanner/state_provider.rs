@@ -0,0 +1,106 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use rustc_hash::FxHashMap;
+use serde::Deserialize;
+use ton_block::{Account, Deserializable, HashmapAugType, MsgAddressInt};
+use ton_indexer::utils::{BlockStuff, ShardStateStuff};
+use ton_types::{AccountId, UInt256};
+
+use crate::blocks_handler::StateProvider;
+use crate::config::StateProviderConfig;
+
+use super::jrpc::JrpcClient;
+use super::synthetic_state::shard_state_from_accounts;
+
+pub fn from_config(config: StateProviderConfig) -> Arc<dyn StateProvider> {
+    match config {
+        StateProviderConfig::Rpc { endpoint, cache_size } => {
+            Arc::new(RpcStateProvider::new(endpoint, cache_size))
+        }
+    }
+}
+
+/// Builds states from the current account states fetched via JSON-RPC.
+/// Code hashes rarely change, so the latest state is enough for code hash filters
+pub struct RpcStateProvider {
+    client: JrpcClient,
+    cache: Mutex<FxHashMap<(i32, AccountId), Option<Account>>>,
+    cache_size: usize,
+}
+
+impl RpcStateProvider {
+    pub fn new(endpoint: String, cache_size: usize) -> Self {
+        Self {
+            client: JrpcClient::new(endpoint),
+            cache: Default::default(),
+            cache_size,
+        }
+    }
+
+    async fn fetch_account(&self, workchain_id: i32, account_id: &AccountId) -> Result<Option<Account>> {
+        let address = MsgAddressInt::with_standart(None, workchain_id as i8, account_id.clone())?;
+        let state: ContractState = self
+            .client
+            .request("getContractState", serde_json::json!({ "address": address.to_string() }))
+            .await?;
+
+        match state {
+            ContractState::Exists { account } => Account::construct_from_base64(&account)
+                .map(Some)
+                .with_context(|| format!("Invalid account state of {address}")),
+            ContractState::NotExists { .. } => Ok(None),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl StateProvider for RpcStateProvider {
+    async fn block_state(&self, block: &BlockStuff) -> Result<Option<ShardStateStuff>> {
+        let workchain_id = block.id().shard().workchain_id();
+
+        let mut account_ids = Vec::new();
+        block
+            .block()
+            .read_extra()?
+            .read_account_blocks()?
+            .iterate_objects(|account_block| {
+                account_ids.push(account_block.account_id().clone());
+                Ok(true)
+            })?;
+        if account_ids.is_empty() {
+            return Ok(None);
+        }
+
+        let mut accounts = Vec::with_capacity(account_ids.len());
+        for account_id in account_ids {
+            let key = (workchain_id, account_id);
+            let cached = self.cache.lock().unwrap().get(&key).cloned();
+            let account = match cached {
+                Some(account) => account,
+                None => {
+                    let account = self.fetch_account(workchain_id, &key.1).await?;
+                    let mut cache = self.cache.lock().unwrap();
+                    if cache.len() >= self.cache_size {
+                        cache.clear();
+                    }
+                    cache.insert(key, account.clone());
+                    account
+                }
+            };
+            accounts.extend(account);
+        }
+
+        let accounts = accounts
+            .iter()
+            .map(|account| (account, UInt256::default(), account.last_tr_time().unwrap_or_default()));
+        shard_state_from_accounts(workchain_id, accounts).map(Some)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum ContractState {
+    Exists { account: String },
+    NotExists {},
+}
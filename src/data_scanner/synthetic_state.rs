@@ -0,0 +1,25 @@
+use anyhow::Result;
+use ton_block::{Account, BlockIdExt, Serializable, ShardAccounts, ShardIdent, ShardStateUnsplit};
+use ton_indexer::utils::{MinRefMcStateTracker, ShardStateStuff};
+use ton_types::UInt256;
+
+/// Builds a shard state with only the specified accounts.
+/// Each account is passed with its last transaction hash and lt
+pub fn shard_state_from_accounts<'a, I>(workchain_id: i32, accounts: I) -> Result<ShardStateStuff>
+where
+    I: IntoIterator<Item = (&'a Account, UInt256, u64)>,
+{
+    let mut shard_accounts = ShardAccounts::default();
+    for (account, last_trans_hash, last_trans_lt) in accounts {
+        shard_accounts.insert(0, account, last_trans_hash, last_trans_lt)?;
+    }
+
+    let mut state = ShardStateUnsplit::default();
+    state.write_accounts(&shard_accounts)?;
+
+    let block_id = BlockIdExt {
+        shard_id: ShardIdent::with_tagged_prefix(workchain_id, ton_block::SHARD_FULL)?,
+        ..Default::default()
+    };
+    ShardStateStuff::new(block_id, state.serialize()?, &MinRefMcStateTracker::default())
+}
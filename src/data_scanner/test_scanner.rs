@@ -1,17 +1,20 @@
 use std::fs::File;
 use std::io::BufReader;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
 
 use anyhow::{Context, Result};
 use serde::Deserialize;
-use ton_block::{BlockIdExt, ShardIdent};
-use ton_indexer::utils::BlockStuff;
-use ton_types::UInt256;
+use rustc_hash::FxHashMap;
+use ton_block::{Account, Block, BlockIdExt, Deserializable, HashmapAugType, ShardIdent};
+use ton_indexer::utils::{BlockStuff, ShardStateStuff};
+use ton_types::{serialize_toc, HashmapType, UInt256};
 
 use crate::blocks_handler::*;
 
+use super::synthetic_state::shard_state_from_accounts;
+
 /// Reads a json data about blocks and accounts for testing purposes
 pub struct TestScanner {
     handler: Arc<BlocksHandler>,
@@ -28,6 +31,7 @@ struct BlockData {
     boc: String,
 }
 
+/// Only `boc` is used, the rest is derived from it
 #[allow(dead_code)]
 #[derive(Debug, Clone, Deserialize)]
 struct AccountData {
@@ -53,12 +57,35 @@ impl TestScanner {
         Ok(Self { handler, filename })
     }
 
+    /// `filename` can be a directory, its json files are processed in name order
     pub async fn run(self) -> Result<()> {
-        let file = File::open(self.filename)?;
+        let files = if self.filename.is_dir() {
+            let mut files = std::fs::read_dir(&self.filename)?
+                .map(|entry| Ok(entry?.path()))
+                .collect::<Result<Vec<_>>>()?;
+            files.retain(|path| path.extension().map_or(false, |ext| ext == "json"));
+            files.sort();
+            files
+        } else {
+            vec![self.filename.clone()]
+        };
+
+        for file in files {
+            tracing::info!("Processing fixture {}", file.display());
+            self.process_file(&file)
+                .await
+                .with_context(|| format!("Failed to process {}", file.display()))?;
+        }
+
+        Ok(())
+    }
+
+    async fn process_file(&self, filename: &Path) -> Result<()> {
+        let file = File::open(filename)?;
         let reader = BufReader::new(file);
         let block_json: BlocksJson = serde_json::from_reader(reader)?;
         let blocks = block_json.data.blocks;
-        let _accounts = block_json.data.accounts;
+        let states = shard_states(&block_json.data.accounts)?;
 
         for block_data in blocks {
             let block_id = BlockIdExt {
@@ -78,7 +105,7 @@ impl TestScanner {
                 .handler
                 .handle_block(
                     &block_stuff,
-                    None
+                    states.get(&block_id.shard_id.workchain_id())
                 )
                 .await
                 .context("Failed to handle block")
@@ -90,3 +117,47 @@ impl TestScanner {
         Ok(())
     }
 }
+
+/// Returns BOCs of all transactions of the fixture blocks, e.g. for benchmarks
+pub fn fixture_transactions(json: &str) -> Result<Vec<Vec<u8>>> {
+    let block_json: BlocksJson = serde_json::from_str(json)?;
+
+    let mut transactions = Vec::new();
+    for block_data in block_json.data.blocks {
+        let block = Block::construct_from_base64(&block_data.boc)
+            .with_context(|| format!("Invalid block {}", block_data.id))?;
+        block
+            .read_extra()?
+            .read_account_blocks()?
+            .iterate_objects(|account_block| {
+                account_block.transactions().iterate_slices(|_, raw_transaction| {
+                    transactions.push(serialize_toc(&raw_transaction.reference(0)?)?);
+                    Ok(true)
+                })?;
+                Ok(true)
+            })?;
+    }
+    Ok(transactions)
+}
+
+/// Builds a state per workchain from the fixture accounts
+fn shard_states(accounts: &[AccountData]) -> Result<FxHashMap<i32, ShardStateStuff>> {
+    let mut by_workchain = FxHashMap::<i32, Vec<Account>>::default();
+    for data in accounts {
+        let account = Account::construct_from_base64(&data.boc)
+            .with_context(|| format!("Invalid account {}", data.id))?;
+        let workchain_id = account.get_addr().map(|addr| addr.workchain_id()).unwrap_or_default();
+        by_workchain.entry(workchain_id).or_default().push(account);
+    }
+
+    by_workchain
+        .into_iter()
+        .map(|(workchain_id, accounts)| {
+            let accounts = accounts.iter().map(|account| {
+                let last_trans_lt = account.last_tr_time().unwrap_or_default();
+                (account, UInt256::default(), last_trans_lt)
+            });
+            Ok((workchain_id, shard_state_from_accounts(workchain_id, accounts)?))
+        })
+        .collect()
+}
@@ -0,0 +1,47 @@
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Errors returned by the public library API.
+/// The inner error keeps the original cause and can be downcasted
+#[derive(Debug, thiserror::Error)]
+pub enum FusionError {
+    #[error("Invalid config: {0}")]
+    Config(BoxError),
+    #[error("Failed to load ABI: {0}")]
+    Abi(BoxError),
+    #[error("Filter error: {0}")]
+    Filter(BoxError),
+    #[error("Serialization error: {0}")]
+    Serialization(BoxError),
+    #[error("Transport error: {0}")]
+    Transport(BoxError),
+    #[error("Scanner error: {0}")]
+    Scanner(BoxError),
+}
+
+pub type FusionResult<T> = std::result::Result<T, FusionError>;
+
+impl FusionError {
+    pub fn config<E: Into<anyhow::Error>>(error: E) -> Self {
+        Self::Config(error.into().into())
+    }
+
+    pub fn abi<E: Into<anyhow::Error>>(error: E) -> Self {
+        Self::Abi(error.into().into())
+    }
+
+    pub fn filter<E: Into<anyhow::Error>>(error: E) -> Self {
+        Self::Filter(error.into().into())
+    }
+
+    pub fn serialization<E: Into<anyhow::Error>>(error: E) -> Self {
+        Self::Serialization(error.into().into())
+    }
+
+    pub fn transport<E: Into<anyhow::Error>>(error: E) -> Self {
+        Self::Transport(error.into().into())
+    }
+
+    pub fn scanner<E: Into<anyhow::Error>>(error: E) -> Self {
+        Self::Scanner(error.into().into())
+    }
+}
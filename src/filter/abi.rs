@@ -0,0 +1,269 @@
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use ton_types::UInt256;
+
+use super::utils::deserialize_optional_from_str;
+
+static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Client for downloading ABIs, startup must not hang on an unresponsive server
+fn client() -> Result<&'static reqwest::Client> {
+    if let Some(client) = CLIENT.get() {
+        return Ok(client);
+    }
+    let client = reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .context("Failed to build ABI client")?;
+    // Another thread may have set it first, both clients are the same
+    Ok(CLIENT.get_or_init(|| client))
+}
+
+fn is_https(url: &str) -> bool {
+    url.get(..8).map_or(false, |scheme| scheme.eq_ignore_ascii_case("https://"))
+}
+
+/// Contract ABI or a list of its historical versions
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum ContractAbi {
+    Single(AbiSource),
+    /// ABI versions tried in order
+    Versions(Vec<AbiVersion>),
+    /// ABI of the `abis` registry, shared by all filters referencing it
+    Named {
+        #[serde(rename = "ref")]
+        name: String,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct AbiVersion {
+    pub abi: AbiSource,
+    /// Use this version only for accounts with the specified code hash
+    #[serde(default, deserialize_with = "deserialize_optional_from_str")]
+    pub code_hash: Option<UInt256>,
+}
+
+impl ContractAbi {
+    /// Named ABIs must be resolved beforehand
+    pub fn into_versions(self) -> Result<Vec<AbiVersion>> {
+        match self {
+            Self::Single(abi) => Ok(vec![AbiVersion {
+                abi,
+                code_hash: None,
+            }]),
+            Self::Versions(versions) => Ok(versions),
+            Self::Named { name } => bail!("ABI `{name}` is not resolved"),
+        }
+    }
+
+    /// Downloads all remote ABI versions. Named ABIs are kept as is
+    pub async fn fetch(self) -> Result<Self> {
+        if let Self::Named { .. } = self {
+            return Ok(self);
+        }
+        let mut versions = Vec::new();
+        for version in self.into_versions()? {
+            versions.push(AbiVersion {
+                abi: version.abi.fetch().await?,
+                code_hash: version.code_hash,
+            });
+        }
+        Ok(Self::Versions(versions))
+    }
+}
+
+impl From<AbiSource> for ContractAbi {
+    fn from(abi: AbiSource) -> Self {
+        Self::Single(abi)
+    }
+}
+
+/// Contract ABI location
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum AbiSource {
+    /// Path to the ABI file on the local filesystem
+    Path(String),
+    /// ABI JSON embedded into the config (either as a string or as a YAML structure)
+    Inline {
+        #[serde(deserialize_with = "deserialize_json_string")]
+        inline: String,
+    },
+    /// ABI downloaded at startup
+    Url {
+        url: String,
+        /// Expected hex encoded SHA-256 of the ABI file, required for non-https URLs
+        #[serde(default)]
+        sha256: Option<String>,
+    },
+}
+
+impl AbiSource {
+    /// Downloads remote ABI and replaces the source with its content
+    pub async fn fetch(self) -> Result<Self> {
+        let (url, sha256) = match self {
+            Self::Url { url, sha256 } => (url, sha256),
+            other => return Ok(other),
+        };
+        if sha256.is_none() && !is_https(&url) {
+            bail!("ABI from {url} must be fetched over https or have sha256");
+        }
+
+        tracing::info!("Fetching ABI from {}", url);
+        let response = client()?
+            .get(&url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("Failed to fetch ABI from {url}"))?;
+        let data = response.bytes().await?;
+
+        if let Some(expected) = sha256 {
+            let actual = hex::encode(Sha256::digest(&data));
+            if !actual.eq_ignore_ascii_case(expected.trim()) {
+                bail!("ABI checksum mismatch for {url}: expected {expected}, got {actual}");
+            }
+        }
+
+        let inline = String::from_utf8(data.to_vec()).context("Invalid ABI encoding")?;
+        Ok(Self::Inline { inline })
+    }
+
+    /// Returns ABI JSON. Remote sources must be fetched beforehand
+    pub fn read(&self) -> Result<String> {
+        match self {
+            Self::Path(path) => {
+                std::fs::read_to_string(path).with_context(|| format!("Failed to read ABI {path}"))
+            }
+            Self::Inline { inline } => Ok(inline.clone()),
+            Self::Url { url, .. } => bail!("ABI from {url} was not fetched"),
+        }
+    }
+}
+
+/// Source of ABIs for contract filters without `abi`, looked up by the code hashes of the entries
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", deny_unknown_fields)]
+pub enum AbiDiscovery {
+    /// Verified contracts service
+    Api {
+        /// `{code_hash}` is replaced with the hex code hash, must be https
+        url: String,
+        /// JSON pointer to the ABI in the response, e.g. `/abi`. The whole response if not specified
+        #[serde(default)]
+        abi_pointer: Option<String>,
+        /// Downloaded ABIs are stored here as `<code_hash>.abi.json` and not downloaded again
+        #[serde(default)]
+        cache_dir: Option<PathBuf>,
+    },
+    /// Directory with `<code_hash>.abi.json` files
+    Directory { path: PathBuf },
+}
+
+impl AbiDiscovery {
+    /// Returns the ABI of the contract with the code hash
+    pub async fn discover(&self, code_hash: &UInt256) -> Result<AbiSource> {
+        let code_hash = code_hash.to_hex_string();
+        let file_name = format!("{code_hash}.abi.json");
+        match self {
+            Self::Directory { path } => {
+                let path = path.join(file_name);
+                anyhow::ensure!(path.is_file(), "No ABI for code hash {code_hash} in {}", path.display());
+                Ok(AbiSource::Path(path.to_string_lossy().into_owned()))
+            }
+            Self::Api { url, abi_pointer, cache_dir } => {
+                let cached = cache_dir.as_ref().map(|dir| dir.join(&file_name));
+                if let Some(path) = cached.as_ref().filter(|path| path.is_file()) {
+                    return Ok(AbiSource::Path(path.to_string_lossy().into_owned()));
+                }
+
+                let url = url.replace("{code_hash}", &code_hash);
+                anyhow::ensure!(is_https(&url), "ABI discovery URL must be https: {url}");
+                tracing::info!("Discovering ABI from {}", url);
+                let response = client()?
+                    .get(&url)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .with_context(|| format!("Failed to fetch ABI for code hash {code_hash}"))?;
+                let abi = match abi_pointer {
+                    Some(pointer) => {
+                        let response: serde_json::Value = response.json().await?;
+                        match response.pointer(pointer) {
+                            Some(serde_json::Value::String(abi)) => abi.clone(),
+                            Some(abi) => abi.to_string(),
+                            None => bail!("No `{pointer}` in the ABI response for code hash {code_hash}"),
+                        }
+                    }
+                    None => response.text().await?,
+                };
+                ton_abi::Contract::load(&abi)
+                    .with_context(|| format!("Invalid ABI for code hash {code_hash}"))?;
+
+                if let Some(path) = cached {
+                    std::fs::create_dir_all(path.parent().unwrap_or(&path))
+                        .and_then(|_| std::fs::write(&path, &abi))
+                        .with_context(|| format!("Failed to cache ABI at {}", path.display()))?;
+                }
+                Ok(AbiSource::Inline { inline: abi })
+            }
+        }
+    }
+}
+
+fn deserialize_json_string<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(s) => Ok(s),
+        value => Ok(value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_https() {
+        assert!(is_https("https://example.com/abi.json"));
+        assert!(is_https("HTTPS://example.com/abi.json"));
+        assert!(!is_https("http://example.com/abi.json"));
+        assert!(!is_https("ftp://example.com"));
+        assert!(!is_https("https:"));
+    }
+
+    #[tokio::test]
+    async fn test_plain_http_requires_checksum() {
+        let source = AbiSource::Url {
+            url: "http://127.0.0.1:1/abi.json".to_string(),
+            sha256: None,
+        };
+        let error = source.fetch().await.unwrap_err();
+        assert!(error.to_string().contains("sha256"));
+    }
+
+    #[tokio::test]
+    async fn test_discovery_requires_https() {
+        let discovery = AbiDiscovery::Api {
+            url: "http://127.0.0.1:1/{code_hash}".to_string(),
+            abi_pointer: None,
+            cache_dir: None,
+        };
+        let error = discovery.discover(&UInt256::default()).await.unwrap_err();
+        assert!(error.to_string().contains("https"));
+    }
+}
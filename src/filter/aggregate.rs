@@ -0,0 +1,208 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use num_bigint::BigInt;
+use serde::Deserialize;
+
+use super::expression::{FilterExpression, Value};
+use crate::types::{FilteredMessage, SerializeAggregate};
+
+/// Windows are closed once a block this long past their end is handled,
+/// so messages of other shards can still arrive
+const WINDOW_GRACE_SEC: u32 = 10;
+
+/// Tumbling windows of the matched messages by transaction time, summarized to the aggregates stream
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Aggregation {
+    /// Window length in seconds. Default: 60
+    #[serde(default = "default_window_sec")]
+    pub window_sec: u32,
+    /// Integer expression summed over the window, e.g. `args.amount` or `value`
+    pub sum: Option<FilterExpression>,
+    #[serde(skip)]
+    windows: Mutex<Windows>,
+}
+
+#[derive(Debug, Default)]
+struct Windows {
+    /// Open windows by their start
+    open: BTreeMap<u32, Window>,
+    /// Windows starting before this are closed
+    closed_until: u32,
+}
+
+#[derive(Debug, Default)]
+struct Window {
+    count: u64,
+    sum: BigInt,
+}
+
+fn default_window_sec() -> u32 {
+    60
+}
+
+impl Aggregation {
+    /// Counts the message in the window of its transaction
+    pub fn add(&self, message: &FilteredMessage) {
+        let sum = match &self.sum {
+            Some(sum) => match sum.value(message) {
+                Ok(Value::Int(value)) => value,
+                Ok(Value::Null) => BigInt::default(),
+                Ok(_) | Err(_) => {
+                    tracing::debug!("Aggregation sum `{}` is not an integer, the message is skipped", sum.source());
+                    return;
+                }
+            },
+            None => BigInt::default(),
+        };
+
+        let window_sec = self.window_sec.max(1);
+        let start = message.tx.now - message.tx.now % window_sec;
+        let mut windows = self.windows.lock().unwrap();
+        if start < windows.closed_until {
+            tracing::debug!("Message {} is late for its aggregation window", message.message_hash.to_hex_string());
+            return;
+        }
+        let window = windows.open.entry(start).or_default();
+        window.count += 1;
+        window.sum += sum;
+    }
+
+    /// Closes the windows which ended before `now` by more than the grace period
+    pub fn close(&self, now: u32, contract_name: &str, filter_name: &str) -> Vec<SerializeAggregate> {
+        let window_sec = self.window_sec.max(1);
+        let Some(closed_until) = now.checked_sub(WINDOW_GRACE_SEC + window_sec) else {
+            return Vec::new();
+        };
+        let closed_until = closed_until - closed_until % window_sec + window_sec;
+
+        let mut windows = self.windows.lock().unwrap();
+        windows.closed_until = windows.closed_until.max(closed_until);
+        let open = windows.open.split_off(&windows.closed_until);
+        std::mem::replace(&mut windows.open, open)
+            .into_iter()
+            .map(|(window_start, window)| SerializeAggregate {
+                contract_name: contract_name.to_string(),
+                filter_name: filter_name.to_string(),
+                window_start,
+                window_sec,
+                count: window.count,
+                sum: self.sum.as_ref().map(|_| window.sum.to_string()),
+            })
+            .collect()
+    }
+}
+
+impl Clone for Aggregation {
+    /// Clones the config with no open windows
+    fn clone(&self) -> Self {
+        Self {
+            window_sec: self.window_sec,
+            sum: self.sum.clone(),
+            windows: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use ton_block::{CurrencyCollection, InternalMessageHeader, Message, MsgAddressInt};
+    use ton_types::UInt256;
+
+    use crate::types::MessageType;
+    use super::*;
+
+    const ADDRESS: &str = "0:e6f7da94405c55c9fb14b5be6b8f91bba1be76e678900ecb418499bfe37ada05";
+    /// Start of a minute
+    const START: u32 = 1700000040;
+
+    fn aggregation(config: &str) -> Aggregation {
+        serde_yaml::from_str(config).unwrap()
+    }
+
+    fn message(now: u32, value: u64) -> FilteredMessage {
+        let address = MsgAddressInt::from_str(ADDRESS).unwrap();
+        let header = InternalMessageHeader::with_addresses(address.clone(), address, CurrencyCollection::with_grams(value));
+        let mut tx = ton_block::Transaction::default();
+        tx.now = now;
+        FilteredMessage {
+            name: "transfer".to_string(),
+            message_hash: UInt256::default(),
+            message: Message::with_int_header(header),
+            message_type: MessageType::InternalInbound,
+            tx,
+            index_in_transaction: 0,
+            contract_name: Default::default(),
+            filter_name: Default::default(),
+            destination: None,
+            tenant: None,
+            matched_filters: Vec::new(),
+            track_bounces: false,
+            trace: false,
+            function_id: None,
+            expire_at: None,
+            tokens: Default::default(),
+            enrichment: Default::default(),
+            routing: Default::default(),
+            payload: Default::default(),
+        }
+    }
+
+    fn summary(aggregates: &[SerializeAggregate]) -> Vec<(u32, u64, Option<&str>)> {
+        aggregates
+            .iter()
+            .map(|aggregate| (aggregate.window_start, aggregate.count, aggregate.sum.as_deref()))
+            .collect()
+    }
+
+    #[test]
+    fn test_tumbling_windows() {
+        let aggregation = aggregation("sum: value");
+        assert_eq!(aggregation.window_sec, 60);
+
+        aggregation.add(&message(START, 10));
+        aggregation.add(&message(START + 59, 20));
+        aggregation.add(&message(START + 60, 5));
+
+        // Still within the grace period of the first window
+        assert!(aggregation.close(START + 60 + WINDOW_GRACE_SEC - 1, "Wallet", "transfers").is_empty());
+
+        let closed = aggregation.close(START + 60 + WINDOW_GRACE_SEC, "Wallet", "transfers");
+        assert_eq!(summary(&closed), [(START, 2, Some("30"))]);
+        assert_eq!(closed[0].filter_name, "transfers");
+        assert_eq!(closed[0].window_sec, 60);
+
+        let closed = aggregation.close(START + 120 + WINDOW_GRACE_SEC, "Wallet", "transfers");
+        assert_eq!(summary(&closed), [(START + 60, 1, Some("5"))]);
+    }
+
+    #[test]
+    fn test_late_messages() {
+        let aggregation = aggregation("window_sec: 10");
+        aggregation.add(&message(START, 1));
+        let closed = aggregation.close(START + 10 + WINDOW_GRACE_SEC, "Wallet", "transfers");
+        assert_eq!(summary(&closed), [(START, 1, None)]);
+
+        // The window is closed already
+        aggregation.add(&message(START + 9, 1));
+        assert!(aggregation.close(START + 100, "Wallet", "transfers").is_empty());
+    }
+
+    #[test]
+    fn test_non_integer_sum() {
+        let aggregation = aggregation("sum: src");
+        aggregation.add(&message(START, 1));
+        assert!(aggregation.close(START + 100, "Wallet", "transfers").is_empty());
+    }
+
+    #[test]
+    fn test_clone_without_windows() {
+        let aggregation = aggregation("window_sec: 10");
+        aggregation.add(&message(START, 1));
+        assert!(aggregation.clone().close(START + 100, "Wallet", "transfers").is_empty());
+        assert_eq!(aggregation.close(START + 100, "Wallet", "transfers").len(), 1);
+    }
+}
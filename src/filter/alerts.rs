@@ -0,0 +1,314 @@
+use std::collections::VecDeque;
+use std::sync::{Mutex, OnceLock};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::expression::FilterExpression;
+use crate::types::FilteredMessage;
+
+static RULES: OnceLock<Vec<AlertRule>> = OnceLock::new();
+static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+/// Notification sent when a filter matches enough messages within a window
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertRule {
+    /// Name of the rule in the notifications
+    pub name: String,
+    /// Name of the message filter
+    pub filter: String,
+    /// Matched messages count only if the expression is true, e.g. `args.amount > 1000000000`
+    pub condition: Option<FilterExpression>,
+    /// Number of messages within the window which triggers the alert. Default: 1
+    #[serde(default = "default_threshold")]
+    pub threshold: usize,
+    /// Sliding window of transaction time. Default: 60
+    #[serde(default = "default_window_sec")]
+    pub window_sec: u32,
+    /// The rule is silent for this long after an alert, the window length if not set
+    pub cooldown_sec: Option<u32>,
+    pub notify: AlertTarget,
+    #[serde(skip)]
+    state: Mutex<RuleState>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum AlertTarget {
+    /// POST of the alert as JSON
+    Webhook { url: String },
+    /// Message of a Telegram bot
+    Telegram { bot_token: String, chat_id: String },
+}
+
+#[derive(Debug, Default)]
+struct RuleState {
+    /// Transaction times of the counted messages, oldest first
+    matched: VecDeque<u32>,
+    silent_until: u32,
+}
+
+/// Body of the webhook requests
+#[derive(Debug, Clone, Serialize)]
+pub struct Alert {
+    pub rule: String,
+    pub contract_name: String,
+    pub filter_name: String,
+    /// Messages within the window
+    pub count: usize,
+    pub window_sec: u32,
+    /// The message which triggered the alert
+    pub message_hash: String,
+    pub transaction_timestamp: u32,
+}
+
+fn default_threshold() -> usize {
+    1
+}
+
+fn default_window_sec() -> u32 {
+    60
+}
+
+pub(super) fn init(rules: Vec<AlertRule>) -> Result<()> {
+    RULES
+        .set(rules)
+        .map_err(|_| anyhow::anyhow!("Unable to initialize alert rules"))
+}
+
+/// Counts the message of the filter in its alert rules, sends the triggered alerts
+pub(super) fn observe(contract_name: &str, filter_name: &str, message: &FilteredMessage) {
+    let rules = RULES.get().into_iter().flatten().filter(|rule| rule.filter == filter_name);
+    for rule in rules {
+        if let Some(alert) = rule.observe(contract_name, message) {
+            notify(&rule.notify, alert);
+        }
+    }
+}
+
+impl AlertRule {
+    fn observe(&self, contract_name: &str, message: &FilteredMessage) -> Option<Alert> {
+        if let Some(condition) = &self.condition {
+            let matched = condition.evaluate(message).unwrap_or_else(|error| {
+                tracing::debug!("Error during alert condition `{}` evaluation: {}", condition.source(), error);
+                false
+            });
+            if !matched {
+                return None;
+            }
+        }
+
+        let now = message.tx.now;
+        let mut state = self.state.lock().unwrap();
+        state.matched.push_back(now);
+        while state.matched.front().map_or(false, |time| time.saturating_add(self.window_sec) <= now) {
+            state.matched.pop_front();
+        }
+        if state.matched.len() < self.threshold.max(1) || now < state.silent_until {
+            return None;
+        }
+
+        let count = state.matched.len();
+        state.matched.clear();
+        state.silent_until = now.saturating_add(self.cooldown_sec.unwrap_or(self.window_sec));
+        Some(Alert {
+            rule: self.name.clone(),
+            contract_name: contract_name.to_string(),
+            filter_name: self.filter.clone(),
+            count,
+            window_sec: self.window_sec,
+            message_hash: message.message_hash.to_hex_string(),
+            transaction_timestamp: now,
+        })
+    }
+}
+
+impl Clone for AlertRule {
+    /// Clones the rule with no counted messages
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            filter: self.filter.clone(),
+            condition: self.condition.clone(),
+            threshold: self.threshold,
+            window_sec: self.window_sec,
+            cooldown_sec: self.cooldown_sec,
+            notify: self.notify.clone(),
+            state: Default::default(),
+        }
+    }
+}
+
+fn notify(target: &AlertTarget, alert: Alert) {
+    tracing::warn!(
+        "Alert `{}`: {} messages of {} / {} within {} s",
+        alert.rule,
+        alert.count,
+        alert.contract_name,
+        alert.filter_name,
+        alert.window_sec
+    );
+    // Filters also run in the offline tools, which only log alerts
+    let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+        return;
+    };
+    let target = target.clone();
+    runtime.spawn(async move {
+        if let Err(error) = send(&target, &alert).await {
+            tracing::error!("Sending alert `{}`: {:?}", alert.rule, error);
+        }
+    });
+}
+
+async fn send(target: &AlertTarget, alert: &Alert) -> Result<()> {
+    let client = CLIENT.get_or_init(reqwest::Client::new);
+    let request = match target {
+        AlertTarget::Webhook { url } => client.post(url).json(alert),
+        AlertTarget::Telegram { bot_token, chat_id } => {
+            let text = format!(
+                "Alert {}: {} messages of {} / {} within {} s, last message {}",
+                alert.rule, alert.count, alert.contract_name, alert.filter_name, alert.window_sec, alert.message_hash
+            );
+            client
+                .post(format!("https://api.telegram.org/bot{bot_token}/sendMessage"))
+                .json(&serde_json::json!({ "chat_id": chat_id, "text": text }))
+        }
+    };
+    // Telegram URLs contain the bot token
+    request
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(reqwest::Error::without_url)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use ton_block::{CurrencyCollection, InternalMessageHeader, Message, MsgAddressInt};
+    use ton_types::UInt256;
+
+    use super::*;
+    use crate::types::MessageType;
+
+    const ADDRESS: &str = "0:e6f7da94405c55c9fb14b5be6b8f91bba1be76e678900ecb418499bfe37ada05";
+    const NOW: u32 = 1700000000;
+
+    fn rule(config: &str) -> AlertRule {
+        let config = format!("name: whales\nfilter: transfers\nnotify:\n  kind: webhook\n  url: http://127.0.0.1:1\n{config}");
+        serde_yaml::from_str(&config).unwrap()
+    }
+
+    fn message(now: u32, value: u64) -> FilteredMessage {
+        let address = MsgAddressInt::from_str(ADDRESS).unwrap();
+        let header = InternalMessageHeader::with_addresses(address.clone(), address, CurrencyCollection::with_grams(value));
+        let mut tx = ton_block::Transaction::default();
+        tx.now = now;
+        FilteredMessage {
+            name: "transfer".to_string(),
+            message_hash: UInt256::default(),
+            message: Message::with_int_header(header),
+            message_type: MessageType::InternalInbound,
+            tx,
+            index_in_transaction: 0,
+            contract_name: Default::default(),
+            filter_name: Default::default(),
+            destination: None,
+            tenant: None,
+            matched_filters: Vec::new(),
+            track_bounces: false,
+            trace: false,
+            function_id: None,
+            expire_at: None,
+            tokens: Default::default(),
+            enrichment: Default::default(),
+            routing: Default::default(),
+            payload: Default::default(),
+        }
+    }
+
+    /// Counts of the alerts triggered by the messages at the offsets from `NOW`
+    fn alerts(rule: &AlertRule, offsets: &[u32]) -> Vec<Option<usize>> {
+        offsets
+            .iter()
+            .map(|offset| rule.observe("Wallet", &message(NOW + offset, 1)).map(|alert| alert.count))
+            .collect()
+    }
+
+    #[test]
+    fn test_threshold_and_cooldown() {
+        let rule = rule("threshold: 3");
+        assert_eq!(alerts(&rule, &[0, 10, 20]), [None, None, Some(3)]);
+        // Silent for the window length after the alert
+        assert_eq!(alerts(&rule, &[30, 40, 50, 80]), [None, None, None, Some(4)]);
+    }
+
+    #[test]
+    fn test_sliding_window() {
+        let rule = rule("threshold: 2\nwindow_sec: 60\ncooldown_sec: 0");
+        assert_eq!(alerts(&rule, &[0, 60, 119]), [None, None, Some(2)]);
+    }
+
+    #[test]
+    fn test_condition() {
+        let rule = rule("condition: value > 100");
+        assert!(rule.observe("Wallet", &message(NOW, 100)).is_none());
+
+        let alert = rule.observe("Wallet", &message(NOW, 101)).unwrap();
+        assert_eq!(alert.rule, "whales");
+        assert_eq!(alert.contract_name, "Wallet");
+        assert_eq!(alert.filter_name, "transfers");
+        assert_eq!(alert.transaction_timestamp, NOW);
+    }
+
+    #[test]
+    fn test_clone_without_state() {
+        let rule = rule("threshold: 2");
+        assert!(rule.observe("Wallet", &message(NOW, 1)).is_none());
+        assert!(rule.clone().observe("Wallet", &message(NOW, 1)).is_none());
+        assert!(rule.observe("Wallet", &message(NOW, 1)).is_some());
+    }
+
+    /// Accepts one request and answers with the status, returns the request
+    async fn webhook(listener: TcpListener, status: &'static str) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buffer = [0; 4096];
+        // The body is a JSON object, complete once it is closed
+        while !request.ends_with(b"}") {
+            let len = stream.read(&mut buffer).await.unwrap();
+            assert!(len > 0, "The request is truncated");
+            request.extend_from_slice(&buffer[..len]);
+        }
+        let response = format!("HTTP/1.1 {status}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n");
+        stream.write_all(response.as_bytes()).await.unwrap();
+        String::from_utf8(request).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_send_webhook() {
+        let alert = rule("").observe("Wallet", &message(NOW, 1)).unwrap();
+
+        for (status, ok) in [("200 OK", true), ("500 Internal Server Error", false)] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}/alerts", listener.local_addr().unwrap());
+            let server = tokio::spawn(webhook(listener, status));
+
+            let result = send(&AlertTarget::Webhook { url }, &alert).await;
+            assert_eq!(result.is_ok(), ok, "{status}: {result:?}");
+
+            let request = server.await.unwrap();
+            assert!(request.starts_with("POST /alerts "));
+            let body = request.split("\r\n\r\n").nth(1).unwrap();
+            let body: serde_json::Value = serde_json::from_str(body).unwrap();
+            assert_eq!(body["rule"], "whales");
+            assert_eq!(body["count"], 1);
+            assert_eq!(body["transaction_timestamp"], NOW);
+        }
+    }
+}
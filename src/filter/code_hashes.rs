@@ -0,0 +1,286 @@
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+
+use anyhow::Result;
+use rustc_hash::{FxHashMap, FxHashSet};
+use ton_block::{AccountBlock, BlockIdExt, Deserializable, HashmapAugType, Message, ShardAccounts, ShardIdent, Transaction};
+use ton_indexer::utils::ShardStateStuff;
+use ton_types::{AccountId, HashmapType, UInt256};
+
+use crate::types::CodeHashAccounts;
+
+use super::account_key;
+use super::config::AddressOrCodeHash;
+use super::parser::{get_account_filters, try_get_parsers};
+use super::state_budget;
+use super::utils::account_code_hash;
+
+static CODE_HASHES: OnceLock<CodeHashIndex> = OnceLock::new();
+/// Code hashes read ahead from the states of the blocks being processed, by the block of the state
+static PREFETCHED: OnceLock<Mutex<FxHashMap<BlockIdExt, Arc<PrefetchedCodeHashes>>>> = OnceLock::new();
+
+type PrefetchedCodeHashes = FxHashMap<(i32, UInt256), Option<UInt256>>;
+
+/// Accounts with the code hashes of message filters, kept up to date from processed blocks
+/// so matching a code hash doesn't read the shard state
+struct CodeHashIndex {
+    /// Code hashes of the filters and versioned ABIs
+    code_hashes: FxHashSet<UInt256>,
+    inner: RwLock<IndexedAccounts>,
+}
+
+#[derive(Default)]
+struct IndexedAccounts {
+    /// Shards with an indexed full state, accounts of other shards are read from states
+    shards: Vec<ShardIdent>,
+    /// Code hashes of the indexed accounts
+    accounts: FxHashMap<(i32, UInt256), UInt256>,
+}
+
+impl IndexedAccounts {
+    fn covers(&self, workchain_id: i32, account_id: &AccountId) -> bool {
+        self.shards.iter().any(|shard| {
+            shard.workchain_id() == workchain_id && shard.contains_account(account_id.clone()).unwrap_or(false)
+        })
+    }
+}
+
+/// Creates the index if any message filter uses code hashes
+pub(super) fn init(code_hashes: FxHashSet<UInt256>) {
+    if code_hashes.is_empty() {
+        return;
+    }
+    CODE_HASHES.get_or_init(|| CodeHashIndex {
+        code_hashes,
+        inner: Default::default(),
+    });
+}
+
+/// Code hash of the account if it is one of the filter code hashes, `None` in the inner option otherwise.
+/// Returns `None` if the account is not indexed
+pub(super) fn lookup(workchain_id: i32, account_id: &AccountId) -> Option<Option<UInt256>> {
+    let index = CODE_HASHES.get()?;
+    let inner = index.inner.read().unwrap();
+    if !inner.covers(workchain_id, account_id) {
+        return None;
+    }
+    Some(inner.accounts.get(&account_key(workchain_id, account_id)).copied())
+}
+
+/// Code hash of the account from the index or the state.
+/// Hashes of indexed accounts are known only if they are filter code hashes.
+/// `None` if the state read is skipped by the state read budget
+pub(super) fn filter_code_hash(
+    state: &ShardStateStuff,
+    workchain_id: i32,
+    account_id: &AccountId,
+) -> Result<Option<UInt256>> {
+    if let Some(code_hash) = lookup(workchain_id, account_id) {
+        return Ok(code_hash);
+    }
+    let prefetched = PREFETCHED
+        .get()
+        .and_then(|prefetched| prefetched.lock().unwrap().get(state.block_id()).cloned());
+    if let Some(code_hash) = prefetched.and_then(|prefetched| prefetched.get(&account_key(workchain_id, account_id)).copied()) {
+        return Ok(code_hash);
+    }
+    Ok(state_budget::read_state(state.block_id(), || account_code_hash(state, account_id))?.flatten())
+}
+
+/// Reads the code hashes of the accounts of the block transactions and their messages
+/// from the state on the blocking pool, so filtering the block doesn't read the state inline.
+/// Kept until [`finish_block`]
+pub async fn prefetch(state: &ShardStateStuff, account_blocks: Vec<AccountBlock>) -> Result<()> {
+    if CODE_HASHES.get().is_none() || account_blocks.is_empty() {
+        return Ok(());
+    }
+    let shard = state.shard().clone();
+    let shard_accounts = state.state().read_accounts()?;
+    let block_id = state.block_id().clone();
+
+    let code_hashes = {
+        let block_id = block_id.clone();
+        tokio::task::spawn_blocking(move || read_code_hashes(&block_id, &shard, &shard_accounts, &account_blocks))
+            .await??
+    };
+    PREFETCHED
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .insert(block_id, Arc::new(code_hashes));
+    Ok(())
+}
+
+/// Drops the code hashes read ahead for the block with this state
+pub fn finish_block(state: &ShardStateStuff) {
+    if let Some(prefetched) = PREFETCHED.get() {
+        prefetched.lock().unwrap().remove(state.block_id());
+    }
+}
+
+fn read_code_hashes(
+    block_id: &BlockIdExt,
+    shard: &ShardIdent,
+    shard_accounts: &ShardAccounts,
+    account_blocks: &[AccountBlock],
+) -> Result<PrefetchedCodeHashes> {
+    let workchain_id = shard.workchain_id();
+    let mut accounts = FxHashSet::default();
+    for account_block in account_blocks {
+        account_block.transactions().iterate_slices(|_, raw_transaction| {
+            let tx = Transaction::construct_from_cell(raw_transaction.reference(0)?)?;
+            accounts.insert(tx.account_addr.clone());
+            if let Some(message) = tx.in_msg.as_ref().map(|message| message.read_struct()).transpose()? {
+                accounts.extend(message.src_ref().into_iter().chain(message.dst_ref()).filter_map(|address| {
+                    (address.workchain_id() == workchain_id).then(|| address.address())
+                }));
+            }
+            tx.out_msgs.iterate_slices(|slice| {
+                let message = Message::construct_from_cell(slice.reference(0)?)?;
+                if let Some(address) = message.dst_ref().filter(|address| address.workchain_id() == workchain_id) {
+                    accounts.insert(address.address());
+                }
+                Ok(true)
+            })?;
+            Ok(true)
+        })?;
+    }
+
+    let mut code_hashes = PrefetchedCodeHashes::default();
+    for account_id in accounts {
+        if lookup(workchain_id, &account_id).is_some() || !shard.contains_account(account_id.clone()).unwrap_or(false) {
+            continue;
+        }
+        let read = state_budget::read_state(block_id, || match shard_accounts.account(&account_id)? {
+            Some(account) => Ok(account.read_account()?.get_code_hash()),
+            None => Ok(None),
+        });
+        match read {
+            Ok(Some(code_hash)) => {
+                code_hashes.insert(account_key(workchain_id, &account_id), code_hash);
+            }
+            // The block ran out of the state read budget
+            Ok(None) => break,
+            // Read again while filtering, which reports the error
+            Err(_) => {}
+        }
+    }
+    Ok(code_hashes)
+}
+
+/// Indexes all accounts of the full shard state
+pub fn index_full_state(state: &ShardStateStuff) -> Result<()> {
+    let Some(index) = CODE_HASHES.get() else {
+        return Ok(());
+    };
+    let shard = state.shard().clone();
+    let workchain_id = shard.workchain_id();
+
+    let mut accounts = FxHashMap::default();
+    state
+        .state()
+        .read_accounts()?
+        .iterate_with_keys(|account_id: UInt256, shard_account| {
+            if let Some(code_hash) = shard_account.read_account()?.get_code_hash() {
+                if index.code_hashes.contains(&code_hash) {
+                    accounts.insert((workchain_id, account_id), code_hash);
+                }
+            }
+            Ok(true)
+        })?;
+
+    tracing::info!("Indexed {} accounts with filter code hashes in shard {}", accounts.len(), shard);
+    let mut inner = index.inner.write().unwrap();
+    inner.accounts.extend(accounts);
+    if !inner.shards.contains(&shard) {
+        inner.shards.push(shard);
+    }
+    Ok(())
+}
+
+/// Applies account changes of a block from the state after it.
+/// Without the state the shard of the block is no longer indexed
+pub fn update_accounts(
+    shard: &ShardIdent,
+    state: Option<&ShardStateStuff>,
+    changed_accounts: &FxHashSet<AccountId>,
+    deleted_accounts: &FxHashSet<AccountId>,
+) -> Result<()> {
+    let Some(index) = CODE_HASHES.get() else {
+        return Ok(());
+    };
+    let workchain_id = shard.workchain_id();
+
+    let Some(state) = state else {
+        let mut inner = index.inner.write().unwrap();
+        if !inner.shards.is_empty() {
+            tracing::warn!("No state for block in shard {}, code hash index is dropped", shard);
+            *inner = Default::default();
+        }
+        return Ok(());
+    };
+
+    let updates = changed_accounts
+        .iter()
+        .map(|account_id| {
+            let code_hash = account_code_hash(state, account_id)?.filter(|hash| index.code_hashes.contains(hash));
+            Ok((account_key(workchain_id, account_id), code_hash))
+        })
+        .collect::<Result<Vec<_>>>();
+
+    let mut inner = index.inner.write().unwrap();
+    let updates = match updates {
+        Ok(updates) => updates,
+        Err(error) => {
+            *inner = Default::default();
+            return Err(error.context("Code hash index is dropped"));
+        }
+    };
+    for account_id in deleted_accounts {
+        inner.accounts.remove(&account_key(workchain_id, account_id));
+    }
+    for (key, code_hash) in updates {
+        match code_hash {
+            Some(code_hash) => inner.accounts.insert(key, code_hash),
+            None => inner.accounts.remove(&key),
+        };
+    }
+    Ok(())
+}
+
+/// Message and account filters with code hashes, without accounts
+pub fn code_hash_filters() -> Vec<CodeHashAccounts> {
+    let message_filters = try_get_parsers()
+        .into_iter()
+        .flatten()
+        .flat_map(|parser| parser.filters.iter().map(move |filter| (parser, filter)))
+        .flat_map(|(parser, filter)| {
+            [&filter.sender, &filter.receiver]
+                .into_iter()
+                .flatten()
+                .map(move |account| (Some(&parser.name), &filter.name, account))
+        });
+    let account_filters = get_account_filters()
+        .iter()
+        .map(|filter| (None, &filter.name, &filter.account));
+
+    let mut filters: Vec<CodeHashAccounts> = Vec::new();
+    for (contract_name, filter_name, account) in message_filters.chain(account_filters) {
+        let AddressOrCodeHash::CodeHash(code_hash) = account else {
+            continue;
+        };
+        let known = filters.iter().any(|filter| {
+            filter.contract_name.as_ref() == contract_name
+                && &filter.filter_name == filter_name
+                && &filter.code_hash == code_hash
+        });
+        if !known {
+            filters.push(CodeHashAccounts {
+                contract_name: contract_name.cloned(),
+                filter_name: filter_name.clone(),
+                code_hash: *code_hash,
+                accounts: Vec::new(),
+            });
+        }
+    }
+    filters
+}
@@ -1,8 +1,23 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use rand::Rng;
 use serde::Deserialize;
 use ton_block::MsgAddressInt;
 use ton_types::UInt256;
 
+use crate::error::{FusionError, FusionResult};
 use crate::types::MessageType;
+use super::abi::{AbiDiscovery, AbiVersion, ContractAbi};
+use super::expression::FilterExpression;
+use super::getters::Getter;
+use super::aggregate::Aggregation;
+use super::alerts::AlertRule;
+use super::limiter::RateLimit;
+use super::plugin::WasmPlugin;
+use super::state_budget::StateReadBudget;
+use super::stats::MatchCounter;
 use super::utils::deserialize_from_str;
 
 #[derive(Debug, Clone, Deserialize, PartialEq, Eq, Hash)]
@@ -12,15 +27,71 @@ pub enum FilterType {
     Contract {
         /// Contract name, must be unique
         name: String,
-        /// Path to contract ABI
-        abi_path: String,
+        /// Contract ABI: a file path, inline JSON or a remote URL,
+        /// or a list of ABI versions tried in order.
+        /// Discovered by the code hashes of the entries if not specified
+        #[serde(default, alias = "abi_path")]
+        abi: Option<ContractAbi>,
+        /// Nekoton parser options
+        #[serde(default)]
+        parser_options: ParserOptions,
     },
     /// Filter messages with empty body
     NativeTransfer,
+    /// Inbound messages of transactions which deployed, froze, unfroze or deleted an account.
+    /// The message name is one of: `deployed`, `frozen`, `unfrozen`, `deleted`
+    AccountStatusChange,
+    /// Normalized TIP-3.1 token transfers, mints and burns.
+    /// The message name is one of: `transfer`, `mint`, `burn`
+    Tip3Transfer,
+    /// Multisig wallet custodian calls.
+    /// The message name is one of: `send`, `submit`, `confirm`
+    Multisig,
+    /// DePool participant calls and stake events. The message name is one of:
+    /// `add_stake`, `withdraw`, `withdraw_all`, `round_stake_accepted`, `round_stake_rejected`
+    Depool,
+    /// TIP-4.1 NFT mints, transfers and burns.
+    /// The message name is one of: `mint`, `transfer`, `burn`
+    NftTransfer,
     /// Pass all messages
     AnyMessage,
 }
 
+/// Controls which parts of the contract ABI are used to parse transactions
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq, Hash)]
+#[serde(default, deny_unknown_fields)]
+pub struct ParserOptions {
+    /// Parse contract events. Default: true
+    pub events: bool,
+    /// Parse inbound function calls. Default: true
+    pub functions: bool,
+    /// Parse function outputs. Default: false
+    pub function_outputs: bool,
+    /// Parse bounced function calls. Default: false
+    pub bounced: bool,
+    /// Parse external inbound messages. Default: true
+    pub external_in: bool,
+    /// Require message bodies to fully match function inputs. Default: true
+    pub strict: bool,
+    /// Use only functions and events referenced by the filter entries.
+    /// Ignored if any entry doesn't specify a message. Default: false
+    pub only_referenced: bool,
+}
+
+impl Default for ParserOptions {
+    fn default() -> Self {
+        Self {
+            events: true,
+            functions: true,
+            function_outputs: false,
+            bounced: false,
+            external_in: true,
+            strict: true,
+            only_referenced: false,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Contract {
@@ -41,9 +112,311 @@ pub struct FilterRecord {
 #[derive(Debug, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct FilterConfig {
+    /// Files with more filters: paths, directories of `.yaml` files or glob patterns
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// ABIs referenced by name from contract filters, e.g. `abi: { ref: TokenWallet }`
+    #[serde(default)]
+    pub abis: HashMap<String, ContractAbi>,
+    /// Source of ABIs for contract filters without `abi`
+    #[serde(default)]
+    pub abi_discovery: Option<AbiDiscovery>,
+    #[serde(default)]
+    pub message_filters: Vec<FilterRecord>,
+    /// Accounts which states are emitted to the accounts stream
+    #[serde(default)]
+    pub account_filters: Vec<AccountFilter>,
+    /// Masterchain config params which changes are emitted to the config stream
+    #[serde(default)]
+    pub config_filters: Vec<ConfigParamFilter>,
+    /// Accounts which changes and deletions are summarized per block to the account changes stream
+    #[serde(default)]
+    pub account_change_filters: Vec<AccountChangeFilter>,
+    /// Which matching filters of a filter type emit a message
+    #[serde(default)]
+    pub match_mode: MatchMode,
+    /// Emit a message matched by several filter types once
+    #[serde(default)]
+    pub dedup: Option<DedupConfig>,
+    /// Time budget of the shard state reads of code hash filters, unlimited if not set
+    #[serde(default)]
+    pub state_read_budget: Option<StateReadBudget>,
+    /// Notifications about matched messages of the message filters
+    #[serde(default)]
+    pub alerts: Vec<AlertRule>,
+    /// Filter sets with isolated outputs, evaluated for every transaction together with the filters above
+    #[serde(default)]
+    pub tenants: Vec<TenantConfig>,
+    /// Process only transactions within the range
+    #[serde(default)]
+    pub range: TransactionRange,
+}
+
+/// Which matching filters of a filter type emit a message
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchMode {
+    /// Only the matching filter with the highest priority, the first one among equal priorities
+    #[default]
+    First,
+    /// Every matching filter emits its own message, which lists the names of all matching filters
+    All,
+}
+
+/// Dedup of messages matched by several filter types, within a transaction and a tenant
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DedupConfig {
+    /// Filter type which records are kept. Default: decoded
+    pub prefer: DedupPrecedence,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DedupPrecedence {
+    /// Contract and semantic filters win over the filters without a decoded body,
+    /// then the config order
+    #[default]
+    Decoded,
+    /// The filter type listed first in the config
+    First,
+}
+
+/// Message filters of a team served by the node. Their messages go only to the tenant destination
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TenantConfig {
+    /// Tenant name, must be unique
+    pub name: String,
+    /// Output of all messages of the tenant, interpreted by the transport like filter destinations
+    pub destination: String,
+    #[serde(default)]
     pub message_filters: Vec<FilterRecord>,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccountFilter {
+    /// Custom name for a filter
+    pub name: String,
+    /// Account address or code hash
+    pub account: AddressOrCodeHash,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigParamFilter {
+    /// Custom name for a filter
+    pub name: String,
+    /// Config param indices to watch, all params if empty
+    #[serde(default)]
+    pub params: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccountChangeFilter {
+    /// Custom name for a filter
+    pub name: String,
+    /// Account addresses or code hashes, all accounts if empty
+    #[serde(default)]
+    pub accounts: Vec<AddressOrCodeHash>,
+}
+
+impl ConfigParamFilter {
+    pub fn matches(&self, param: u32) -> bool {
+        self.params.is_empty() || self.params.contains(&param)
+    }
+}
+
+/// Transaction time and logical time bounds, all inclusive
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransactionRange {
+    pub start_utime: Option<u32>,
+    pub end_utime: Option<u32>,
+    pub start_lt: Option<u64>,
+    pub end_lt: Option<u64>,
+}
+
+impl TransactionRange {
+    pub fn contains_utime(&self, utime: u32) -> bool {
+        self.start_utime.map(|start| utime >= start).unwrap_or(true)
+            && self.end_utime.map(|end| utime <= end).unwrap_or(true)
+    }
+
+    pub fn contains_lt(&self, lt: u64) -> bool {
+        self.start_lt.map(|start| lt >= start).unwrap_or(true)
+            && self.end_lt.map(|end| lt <= end).unwrap_or(true)
+    }
+
+    pub fn contains(&self, tx: &ton_block::Transaction) -> bool {
+        self.contains_utime(tx.now) && self.contains_lt(tx.lt)
+    }
+}
+
+/// Filters of an included file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FilterInclude {
+    #[serde(default)]
+    abis: HashMap<String, ContractAbi>,
+    #[serde(default)]
+    message_filters: Vec<FilterRecord>,
+    #[serde(default)]
+    account_filters: Vec<AccountFilter>,
+    #[serde(default)]
+    config_filters: Vec<ConfigParamFilter>,
+    #[serde(default)]
+    account_change_filters: Vec<AccountChangeFilter>,
+    #[serde(default)]
+    tenants: Vec<TenantConfig>,
+}
+
+impl FilterConfig {
+    /// Appends filters from the included files in alphabetical order
+    pub fn load_includes(&mut self) -> FusionResult<()> {
+        for pattern in std::mem::take(&mut self.include) {
+            let paths = include_paths(&pattern).map_err(FusionError::config)?;
+            if paths.is_empty() {
+                tracing::warn!("No filter files found for {}", pattern);
+            }
+            for path in paths {
+                let include = read_include(&path).map_err(FusionError::config)?;
+                for (name, abi) in include.abis {
+                    if self.abis.contains_key(&name) {
+                        return Err(FusionError::config(anyhow::anyhow!(
+                            "Duplicate ABI `{name}` in {}",
+                            path.display()
+                        )));
+                    }
+                    self.abis.insert(name, abi);
+                }
+                self.message_filters.extend(include.message_filters);
+                self.account_filters.extend(include.account_filters);
+                self.config_filters.extend(include.config_filters);
+                self.account_change_filters.extend(include.account_change_filters);
+                self.tenants.extend(include.tenants);
+            }
+        }
+        Ok(())
+    }
+
+    /// Download all remote ABIs, so parsers can be built synchronously.
+    /// ABIs of the registry are downloaded once
+    pub async fn fetch_remote_abis(&mut self) -> FusionResult<()> {
+        for abi in self.abis.values_mut() {
+            *abi = abi.clone().fetch().await.map_err(FusionError::abi)?;
+        }
+        self.resolve_abis()?;
+        let tenant_filters = self.tenants.iter_mut().flat_map(|tenant| &mut tenant.message_filters);
+        for record in self.message_filters.iter_mut().chain(tenant_filters) {
+            match &mut record.filter_type {
+                FilterType::Contract { abi: Some(abi), .. } => {
+                    *abi = abi.clone().fetch().await.map_err(FusionError::abi)?;
+                }
+                FilterType::Contract { name, abi: abi @ None, .. } => {
+                    let discovery = self.abi_discovery.as_ref().ok_or_else(|| {
+                        FusionError::abi(anyhow::anyhow!("{name}: no `abi` and `abi_discovery` is not configured"))
+                    })?;
+                    let discovered = discover_abi(discovery, &record.entries)
+                        .await
+                        .with_context(|| format!("{name}: failed to discover ABI"))
+                        .map_err(FusionError::abi)?;
+                    *abi = Some(discovered);
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces references to the `abis` registry with the ABIs
+    pub fn resolve_abis(&mut self) -> FusionResult<()> {
+        let tenant_filters = self.tenants.iter_mut().flat_map(|tenant| &mut tenant.message_filters);
+        for record in self.message_filters.iter_mut().chain(tenant_filters) {
+            let FilterType::Contract { name, abi, .. } = &mut record.filter_type else {
+                continue;
+            };
+            let Some(ContractAbi::Named { name: abi_name }) = abi else {
+                continue;
+            };
+            match self.abis.get(abi_name.as_str()) {
+                Some(ContractAbi::Named { .. }) => {
+                    return Err(FusionError::abi(anyhow::anyhow!("ABI `{abi_name}` references another ABI")))
+                }
+                Some(resolved) => *abi = Some(resolved.clone()),
+                None => return Err(FusionError::abi(anyhow::anyhow!("{name}: unknown ABI `{abi_name}`"))),
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves message filters of the tenants to the other message filters,
+    /// their entries are bound to the tenant and its destination
+    pub fn merge_tenants(&mut self) {
+        for tenant in std::mem::take(&mut self.tenants) {
+            for mut record in tenant.message_filters {
+                for entry in &mut record.entries {
+                    entry.tenant = Some(tenant.name.clone());
+                    entry.destination = Some(tenant.destination.clone());
+                }
+                self.message_filters.push(record);
+            }
+        }
+    }
+}
+
+/// ABI versions for the code hashes of the entries
+async fn discover_abi(discovery: &AbiDiscovery, entries: &[FilterEntry]) -> anyhow::Result<ContractAbi> {
+    let mut code_hashes = Vec::new();
+    for entry in entries {
+        for account in [&entry.sender, &entry.receiver].into_iter().flatten() {
+            if let AddressOrCodeHash::CodeHash(code_hash) = account {
+                if !code_hashes.contains(code_hash) {
+                    code_hashes.push(*code_hash);
+                }
+            }
+        }
+    }
+    anyhow::ensure!(!code_hashes.is_empty(), "No code hashes in the entries");
+
+    let mut versions = Vec::new();
+    for code_hash in code_hashes {
+        versions.push(AbiVersion {
+            abi: discovery.discover(&code_hash).await?,
+            code_hash: Some(code_hash),
+        });
+    }
+    Ok(ContractAbi::Versions(versions))
+}
+
+fn include_paths(pattern: &str) -> anyhow::Result<Vec<PathBuf>> {
+    let mut paths = if Path::new(pattern).is_dir() {
+        std::fs::read_dir(pattern)?
+            .map(|entry| Ok(entry?.path()))
+            .filter(|path| {
+                !matches!(path, Ok(path) if !matches!(
+                    path.extension().and_then(|ext| ext.to_str()),
+                    Some("yaml" | "yml")
+                ))
+            })
+            .collect::<std::io::Result<Vec<_>>>()?
+    } else {
+        glob::glob(pattern)
+            .with_context(|| format!("Invalid include pattern {pattern}"))?
+            .collect::<Result<Vec<_>, _>>()?
+    };
+    paths.sort();
+    Ok(paths)
+}
+
+fn read_include(path: &Path) -> anyhow::Result<FilterInclude> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("Failed to open filter file {}", path.display()))?;
+    serde_yaml::from_reader(file).with_context(|| format!("Invalid filter file {}", path.display()))
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
 pub enum AddressOrCodeHash {
@@ -86,12 +459,88 @@ impl AddressOrCodeHash {
 pub struct FilterEntry {
     /// Custom name for a filter
     pub name: String,
+    /// Entries with higher priority are matched first. Default: 0
+    #[serde(default)]
+    pub priority: i32,
     /// Message source by address or code hash
     pub sender: Option<AddressOrCodeHash>,
     /// Message destination by address or code hash
     pub receiver: Option<AddressOrCodeHash>,
     /// Array of messages to match
     pub message: Option<MessageFilter>,
+    /// Additional expression evaluated for each extracted message,
+    /// e.g. `src == dst || args.amount > value / 2`
+    pub condition: Option<FilterExpression>,
+    /// WASM module which accepts or rejects matched messages and can attach additional fields
+    pub plugin: Option<WasmPlugin>,
+    /// Fraction of matched messages to emit, from 0.0 to 1.0
+    #[serde(default, deserialize_with = "deserialize_sample_rate")]
+    pub sample_rate: Option<f64>,
+    /// Maximum number of emitted messages per second
+    pub max_messages_per_second: Option<RateLimit>,
+    /// Attach the inbound and outbound message hashes of the transaction to internal messages
+    #[serde(default)]
+    pub include_routing: bool,
+    /// Separate output of the matched messages, interpreted by the transport.
+    /// The messages stream is used if not specified
+    pub destination: Option<String>,
+    /// Capacity of the separate channel of the filter in the messages stream,
+    /// the transport capacity if not specified
+    #[serde(default)]
+    pub channel_capacity: Option<usize>,
+    /// Get-methods run on the matched accounts, their outputs are attached to the message
+    #[serde(default)]
+    pub getters: Vec<Getter>,
+    /// Emit a record to the bounces stream when a matched bounceable message bounces
+    #[serde(default)]
+    pub track_bounces: bool,
+    /// Emit a record to the traces stream with the transactions of the message chains
+    /// started by the transactions with matched messages
+    #[serde(default)]
+    pub trace: bool,
+    /// Summarize the matched messages in tumbling windows to the aggregates stream
+    #[serde(default)]
+    pub aggregate: Option<Aggregation>,
+    /// Attach the message body as a generic cell tree, only for `any_message` filters
+    #[serde(default)]
+    pub body_tree: bool,
+    /// Attach the decoded ABI parameters to protobuf messages
+    #[serde(default)]
+    pub include_decoded: bool,
+    /// Tenant of the filter, set when the tenants are merged
+    #[serde(skip)]
+    pub tenant: Option<String>,
+    /// Number of emitted messages
+    #[serde(skip)]
+    pub matched: MatchCounter,
+}
+
+impl FilterEntry {
+    /// Applies sampling and rate limiting to the matched message
+    pub fn admit(&self) -> bool {
+        if let Some(sample_rate) = self.sample_rate {
+            if !rand::thread_rng().gen_bool(sample_rate) {
+                return false;
+            }
+        }
+        match &self.max_messages_per_second {
+            Some(limit) => limit.try_acquire(),
+            None => true,
+        }
+    }
+}
+
+fn deserialize_sample_rate<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let sample_rate: Option<f64> = Deserialize::deserialize(deserializer)?;
+    match sample_rate {
+        Some(rate) if !(0.0..=1.0).contains(&rate) => Err(serde::de::Error::custom(format!(
+            "sample_rate must be within 0.0 and 1.0, got {rate}"
+        ))),
+        sample_rate => Ok(sample_rate),
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -115,3 +564,30 @@ impl std::hash::Hash for Contract {
         self.name.hash(state);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(fields: &str) -> Result<FilterEntry, serde_yaml::Error> {
+        serde_yaml::from_str(&format!("name: test\n{fields}"))
+    }
+
+    #[test]
+    fn test_sample_rate() {
+        assert!(entry("sample_rate: 1.5").is_err());
+        assert!(entry("sample_rate: -0.1").is_err());
+        assert!(entry("sample_rate: .nan").is_err());
+        assert!(entry("sample_rate: .inf").is_err());
+
+        assert!(entry("").unwrap().sample_rate.is_none());
+        assert!((0..100).all(|_| entry("sample_rate: 1.0").unwrap().admit()));
+        assert!((0..100).all(|_| !entry("sample_rate: 0.0").unwrap().admit()));
+    }
+
+    #[test]
+    fn test_sampling_with_rate_limit() {
+        let entry = entry("sample_rate: 1.0\nmax_messages_per_second: 2").unwrap();
+        assert_eq!((0..10).filter(|_| entry.admit()).count(), 2);
+    }
+}
@@ -0,0 +1,566 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use num_bigint::{BigInt, Sign};
+use serde::Deserialize;
+use ton_abi::{Token, TokenValue};
+use ton_block::{CommonMsgInfo, GetRepresentationHash};
+
+use crate::types::FilteredMessage;
+
+/// Boolean expression evaluated against every extracted message.
+///
+/// Supported syntax:
+/// - literals: integers, `"strings"`, `true`, `false`, `null`
+/// - variables: `name`, `type`, `src`, `dst`, `value`, `bounced`,
+///   `tx.lt`, `tx.now`, `tx.hash`, `tx.aborted`, `tx.total_fees`,
+///   `args.<name>` (decoded ABI tokens, nested tuples via `.`)
+/// - operators: `||`, `&&`, `!`, `==`, `!=`, `<`, `<=`, `>`, `>=`, `+`, `-`, `*`, `/`, `%`
+///
+/// Example: `src == dst || args.amount > value / 2`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct FilterExpression {
+    source: String,
+    root: Expr,
+}
+
+impl FilterExpression {
+    /// Evaluates the expression for the message. Non-boolean results are an error
+    pub fn evaluate(&self, message: &FilteredMessage) -> Result<bool> {
+        let context = MessageContext::new(message);
+        match self.root.evaluate(&|path| context.resolve(path))? {
+            Value::Bool(value) => Ok(value),
+            other => bail!("Filter expression `{}` returned {}", self.source, other.kind()),
+        }
+    }
+
+    /// Evaluates the expression for the message to a value of any type
+    pub fn value(&self, message: &FilteredMessage) -> Result<Value> {
+        let context = MessageContext::new(message);
+        self.root.evaluate(&|path| context.resolve(path))
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+}
+
+impl FromStr for FilterExpression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let tokens = tokenize(s)?;
+        let mut parser = ExprParser { tokens, position: 0 };
+        let root = parser.parse_or()?;
+        if let Some(token) = parser.peek() {
+            bail!("Unexpected token `{token:?}` in filter expression `{s}`");
+        }
+        Ok(Self {
+            source: s.to_string(),
+            root,
+        })
+    }
+}
+
+impl TryFrom<String> for FilterExpression {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        Self::from_str(&value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(BigInt),
+    Str(String),
+    Map(BTreeMap<String, Value>),
+}
+
+impl Value {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Null => "null",
+            Self::Bool(_) => "bool",
+            Self::Int(_) => "int",
+            Self::Str(_) => "string",
+            Self::Map(_) => "map",
+        }
+    }
+
+    fn get(&self, key: &str) -> Value {
+        match self {
+            Self::Map(map) => map.get(key).cloned().unwrap_or(Value::Null),
+            _ => Value::Null,
+        }
+    }
+
+    /// Integers are represented as strings to preserve precision
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Self::Null => serde_json::Value::Null,
+            Self::Bool(value) => serde_json::Value::Bool(*value),
+            Self::Int(value) => serde_json::Value::String(value.to_string()),
+            Self::Str(value) => serde_json::Value::String(value.clone()),
+            Self::Map(map) => serde_json::Value::Object(
+                map.iter()
+                    .map(|(key, value)| (key.clone(), value.to_json()))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl From<&TokenValue> for Value {
+    fn from(value: &TokenValue) -> Self {
+        match value {
+            TokenValue::Uint(value) => Self::Int(BigInt::from_biguint(Sign::Plus, value.number.clone())),
+            TokenValue::Int(value) => Self::Int(value.number.clone()),
+            TokenValue::VarUint(_, value) => Self::Int(BigInt::from_biguint(Sign::Plus, value.clone())),
+            TokenValue::VarInt(_, value) => Self::Int(value.clone()),
+            TokenValue::Bool(value) => Self::Bool(*value),
+            TokenValue::Tuple(tokens) => tokens_to_value(tokens),
+            TokenValue::Address(address) => Self::Str(address.to_string()),
+            TokenValue::String(value) => Self::Str(value.clone()),
+            TokenValue::Bytes(value) | TokenValue::FixedBytes(value) => Self::Str(hex::encode(value)),
+            TokenValue::Token(grams) => Self::Int(BigInt::from(grams.as_u128())),
+            TokenValue::Time(value) => Self::Int(BigInt::from(*value)),
+            TokenValue::Expire(value) => Self::Int(BigInt::from(*value)),
+            TokenValue::Optional(_, value) => value.as_deref().map(Value::from).unwrap_or(Value::Null),
+            TokenValue::Ref(value) => Value::from(value.as_ref()),
+            _ => Self::Null,
+        }
+    }
+}
+
+pub fn tokens_to_value(tokens: &[Token]) -> Value {
+    Value::Map(
+        tokens
+            .iter()
+            .map(|token| (token.name.clone(), Value::from(&token.value)))
+            .collect(),
+    )
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Null => f.write_str("null"),
+            Self::Bool(value) => write!(f, "{value}"),
+            Self::Int(value) => write!(f, "{value}"),
+            Self::Str(value) => write!(f, "{value:?}"),
+            Self::Map(map) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in map.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{key}: {value}")?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+/// Lazily resolves expression variables from the extracted message
+struct MessageContext<'a> {
+    message: &'a FilteredMessage,
+}
+
+impl<'a> MessageContext<'a> {
+    fn new(message: &'a FilteredMessage) -> Self {
+        Self { message }
+    }
+
+    fn resolve(&self, path: &[String]) -> Result<Value> {
+        let message = self.message;
+        let Some((head, tail)) = path.split_first() else {
+            bail!("Empty variable path");
+        };
+
+        let value = match (head.as_str(), tail) {
+            ("name", []) => Value::Str(message.name.clone()),
+            ("type", []) => Value::Str(message.message_type.as_str().to_string()),
+            ("src", []) => message
+                .message
+                .src_ref()
+                .map(|address| Value::Str(address.to_string()))
+                .unwrap_or(Value::Null),
+            ("dst", []) => message
+                .message
+                .dst_ref()
+                .map(|address| Value::Str(address.to_string()))
+                .unwrap_or(Value::Null),
+            ("value", []) => match message.message.header() {
+                CommonMsgInfo::IntMsgInfo(header) => Value::Int(BigInt::from(header.value.grams.as_u128())),
+                _ => Value::Int(BigInt::default()),
+            },
+            ("bounced", []) => match message.message.header() {
+                CommonMsgInfo::IntMsgInfo(header) => Value::Bool(header.bounced),
+                _ => Value::Bool(false),
+            },
+            ("tx", [field]) => match field.as_str() {
+                "lt" => Value::Int(BigInt::from(message.tx.lt)),
+                "now" => Value::Int(BigInt::from(message.tx.now)),
+                "hash" => Value::Str(message.tx.hash()?.to_hex_string()),
+                "aborted" => Value::Bool(message.tx.read_description()?.is_aborted()),
+                "total_fees" => Value::Int(BigInt::from(message.tx.total_fees.grams.as_u128())),
+                _ => bail!("Unknown transaction field `{field}`"),
+            },
+            ("args", fields) => fields
+                .iter()
+                .fold(tokens_to_value(&message.tokens), |value, field| value.get(field)),
+            _ => bail!("Unknown variable `{}`", path.join(".")),
+        };
+        Ok(value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UnaryOp {
+    Not,
+    Neg,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Literal(Value),
+    Variable(Vec<String>),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn evaluate<F>(&self, resolve: &F) -> Result<Value>
+    where
+        F: Fn(&[String]) -> Result<Value>,
+    {
+        match self {
+            Self::Literal(value) => Ok(value.clone()),
+            Self::Variable(path) => resolve(path),
+            Self::Unary(op, expr) => match (op, expr.evaluate(resolve)?) {
+                (UnaryOp::Not, Value::Bool(value)) => Ok(Value::Bool(!value)),
+                (UnaryOp::Neg, Value::Int(value)) => Ok(Value::Int(-value)),
+                (op, value) => bail!("Operator {op:?} is not applicable to {}", value.kind()),
+            },
+            // Logical operators are short-circuited
+            Self::Binary(BinaryOp::Or, left, right) => {
+                if expect_bool(left.evaluate(resolve)?)? {
+                    return Ok(Value::Bool(true));
+                }
+                Ok(Value::Bool(expect_bool(right.evaluate(resolve)?)?))
+            }
+            Self::Binary(BinaryOp::And, left, right) => {
+                if !expect_bool(left.evaluate(resolve)?)? {
+                    return Ok(Value::Bool(false));
+                }
+                Ok(Value::Bool(expect_bool(right.evaluate(resolve)?)?))
+            }
+            Self::Binary(op, left, right) => {
+                let left = left.evaluate(resolve)?;
+                let right = right.evaluate(resolve)?;
+                evaluate_binary(*op, left, right)
+            }
+        }
+    }
+}
+
+fn expect_bool(value: Value) -> Result<bool> {
+    match value {
+        Value::Bool(value) => Ok(value),
+        other => bail!("Expected bool, got {}", other.kind()),
+    }
+}
+
+fn evaluate_binary(op: BinaryOp, left: Value, right: Value) -> Result<Value> {
+    let value = match (op, left, right) {
+        (BinaryOp::Eq, left, right) => Value::Bool(left == right),
+        (BinaryOp::Ne, left, right) => Value::Bool(left != right),
+        (BinaryOp::Lt, Value::Int(l), Value::Int(r)) => Value::Bool(l < r),
+        (BinaryOp::Le, Value::Int(l), Value::Int(r)) => Value::Bool(l <= r),
+        (BinaryOp::Gt, Value::Int(l), Value::Int(r)) => Value::Bool(l > r),
+        (BinaryOp::Ge, Value::Int(l), Value::Int(r)) => Value::Bool(l >= r),
+        (BinaryOp::Lt, Value::Str(l), Value::Str(r)) => Value::Bool(l < r),
+        (BinaryOp::Le, Value::Str(l), Value::Str(r)) => Value::Bool(l <= r),
+        (BinaryOp::Gt, Value::Str(l), Value::Str(r)) => Value::Bool(l > r),
+        (BinaryOp::Ge, Value::Str(l), Value::Str(r)) => Value::Bool(l >= r),
+        (BinaryOp::Add, Value::Int(l), Value::Int(r)) => Value::Int(l + r),
+        (BinaryOp::Add, Value::Str(l), Value::Str(r)) => Value::Str(l + &r),
+        (BinaryOp::Sub, Value::Int(l), Value::Int(r)) => Value::Int(l - r),
+        (BinaryOp::Mul, Value::Int(l), Value::Int(r)) => Value::Int(l * r),
+        (BinaryOp::Div | BinaryOp::Rem, Value::Int(_), Value::Int(r)) if r == BigInt::default() => {
+            bail!("Division by zero")
+        }
+        (BinaryOp::Div, Value::Int(l), Value::Int(r)) => Value::Int(l / r),
+        (BinaryOp::Rem, Value::Int(l), Value::Int(r)) => Value::Int(l % r),
+        (op, left, right) => bail!(
+            "Operator {op:?} is not applicable to {} and {}",
+            left.kind(),
+            right.kind()
+        ),
+    };
+    Ok(value)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ExprToken {
+    Int(BigInt),
+    Str(String),
+    Ident(String),
+    Dot,
+    LParen,
+    RParen,
+    Op(&'static str),
+}
+
+fn tokenize(s: &str) -> Result<Vec<ExprToken>> {
+    const OPERATORS: [&str; 17] = [
+        "||", "&&", "==", "!=", "<=", ">=", "<", ">", "!", "+", "-", "*", "/", "%", "(", ")", ".",
+    ];
+
+    let mut tokens = Vec::new();
+    let mut chars = s.char_indices().peekable();
+
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() {
+            let mut end = start;
+            while let Some(&(i, c)) = chars.peek() {
+                if !c.is_ascii_digit() && c != '_' {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            let digits = s[start..end].replace('_', "");
+            tokens.push(ExprToken::Int(BigInt::from_str(&digits)?));
+        } else if c.is_alphabetic() || c == '_' {
+            let mut end = start;
+            while let Some(&(i, c)) = chars.peek() {
+                if !c.is_alphanumeric() && c != '_' {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            tokens.push(ExprToken::Ident(s[start..end].to_string()));
+        } else if c == '"' || c == '\'' {
+            chars.next();
+            let mut value = String::new();
+            loop {
+                match chars.next() {
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, escaped)) => value.push(escaped),
+                        None => bail!("Unterminated string in filter expression `{s}`"),
+                    },
+                    Some((_, ch)) if ch == c => break,
+                    Some((_, ch)) => value.push(ch),
+                    None => bail!("Unterminated string in filter expression `{s}`"),
+                }
+            }
+            tokens.push(ExprToken::Str(value));
+        } else {
+            let rest = &s[start..];
+            let op = OPERATORS
+                .iter()
+                .find(|op| rest.starts_with(**op))
+                .ok_or_else(|| anyhow!("Unexpected character `{c}` in filter expression `{s}`"))?;
+            for _ in 0..op.len() {
+                chars.next();
+            }
+            tokens.push(match *op {
+                "(" => ExprToken::LParen,
+                ")" => ExprToken::RParen,
+                "." => ExprToken::Dot,
+                op => ExprToken::Op(op),
+            });
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Recursive descent parser, from the lowest precedence to the highest
+struct ExprParser {
+    tokens: Vec<ExprToken>,
+    position: usize,
+}
+
+impl ExprParser {
+    fn peek(&self) -> Option<&ExprToken> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<ExprToken> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat_op(&mut self, ops: &[&'static str]) -> Option<&'static str> {
+        match self.peek() {
+            Some(ExprToken::Op(op)) if ops.contains(op) => {
+                let op = *op;
+                self.position += 1;
+                Some(op)
+            }
+            _ => None,
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr> {
+        let mut left = self.parse_and()?;
+        while self.eat_op(&["||"]).is_some() {
+            let right = self.parse_and()?;
+            left = Expr::Binary(BinaryOp::Or, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr> {
+        let mut left = self.parse_comparison()?;
+        while self.eat_op(&["&&"]).is_some() {
+            let right = self.parse_comparison()?;
+            left = Expr::Binary(BinaryOp::And, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr> {
+        let left = self.parse_sum()?;
+        let op = match self.eat_op(&["==", "!=", "<", "<=", ">", ">="]) {
+            Some("==") => BinaryOp::Eq,
+            Some("!=") => BinaryOp::Ne,
+            Some("<") => BinaryOp::Lt,
+            Some("<=") => BinaryOp::Le,
+            Some(">") => BinaryOp::Gt,
+            Some(">=") => BinaryOp::Ge,
+            _ => return Ok(left),
+        };
+        let right = self.parse_sum()?;
+        Ok(Expr::Binary(op, Box::new(left), Box::new(right)))
+    }
+
+    fn parse_sum(&mut self) -> Result<Expr> {
+        let mut left = self.parse_product()?;
+        while let Some(op) = self.eat_op(&["+", "-"]) {
+            let op = if op == "+" { BinaryOp::Add } else { BinaryOp::Sub };
+            let right = self.parse_product()?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_product(&mut self) -> Result<Expr> {
+        let mut left = self.parse_unary()?;
+        while let Some(op) = self.eat_op(&["*", "/", "%"]) {
+            let op = match op {
+                "*" => BinaryOp::Mul,
+                "/" => BinaryOp::Div,
+                _ => BinaryOp::Rem,
+            };
+            let right = self.parse_unary()?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr> {
+        match self.eat_op(&["!", "-"]) {
+            Some("!") => Ok(Expr::Unary(UnaryOp::Not, Box::new(self.parse_unary()?))),
+            Some(_) => Ok(Expr::Unary(UnaryOp::Neg, Box::new(self.parse_unary()?))),
+            None => self.parse_primary(),
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(ExprToken::Int(value)) => Ok(Expr::Literal(Value::Int(value))),
+            Some(ExprToken::Str(value)) => Ok(Expr::Literal(Value::Str(value))),
+            Some(ExprToken::Ident(ident)) => match ident.as_str() {
+                "true" => Ok(Expr::Literal(Value::Bool(true))),
+                "false" => Ok(Expr::Literal(Value::Bool(false))),
+                "null" => Ok(Expr::Literal(Value::Null)),
+                _ => {
+                    let mut path = vec![ident];
+                    while self.peek() == Some(&ExprToken::Dot) {
+                        self.position += 1;
+                        match self.next() {
+                            Some(ExprToken::Ident(ident)) => path.push(ident),
+                            token => bail!("Expected field name after `.`, got {token:?}"),
+                        }
+                    }
+                    Ok(Expr::Variable(path))
+                }
+            },
+            Some(ExprToken::LParen) => {
+                let expr = self.parse_or()?;
+                match self.next() {
+                    Some(ExprToken::RParen) => Ok(expr),
+                    token => bail!("Expected `)`, got {token:?}"),
+                }
+            }
+            token => bail!("Unexpected token {token:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(source: &str) -> Result<Value> {
+        let expr = FilterExpression::from_str(source)?;
+        expr.root.evaluate(&|path: &[String]| {
+            Ok(match path.join(".").as_str() {
+                "src" => Value::Str("0:01".to_string()),
+                "dst" => Value::Str("0:01".to_string()),
+                "value" => Value::Int(BigInt::from(100)),
+                "args.amount" => Value::Int(BigInt::from(60)),
+                _ => Value::Null,
+            })
+        })
+    }
+
+    #[test]
+    fn test_expression_evaluation() {
+        assert_eq!(eval("src == dst").unwrap(), Value::Bool(true));
+        assert_eq!(eval("args.amount > value / 2").unwrap(), Value::Bool(true));
+        assert_eq!(eval("args.amount * 2 >= value + 21").unwrap(), Value::Bool(false));
+        assert_eq!(eval("!(src != dst) && args.missing == null").unwrap(), Value::Bool(true));
+        assert_eq!(eval("-value + 1_000 % 7").unwrap(), Value::Int(BigInt::from(-94)));
+        assert_eq!(eval("false || \"a\" < 'b'").unwrap(), Value::Bool(true));
+    }
+
+    #[test]
+    fn test_expression_errors() {
+        assert!(FilterExpression::from_str("src ==").is_err());
+        assert!(FilterExpression::from_str("(src == dst").is_err());
+        assert!(FilterExpression::from_str("src # dst").is_err());
+        assert!(eval("value / 0 == 1").is_err());
+        assert!(eval("src > 1").is_err());
+    }
+}
@@ -0,0 +1,71 @@
+use std::sync::OnceLock;
+
+use anyhow::Result;
+use ton_block::{Deserializable, Transaction};
+
+use crate::serializer::{AddressFormat, Serializer};
+
+use super::parser::{AbiParser, InnerParser};
+use super::semantic::{SemanticKind, SemanticParser};
+use super::utils;
+
+/// Every parser kind, ABI parsers use the built-in ABIs
+static PARSERS: OnceLock<Vec<InnerParser>> = OnceLock::new();
+
+fn parsers() -> &'static [InnerParser] {
+    PARSERS.get_or_init(|| {
+        let mut parsers = vec![
+            InnerParser::EmptyMessage,
+            InnerParser::RawBodyMessageParser,
+            InnerParser::AccountStatusChange,
+        ];
+        for kind in SemanticKind::ALL {
+            let parser = SemanticParser::new(kind).expect("Built-in ABIs are valid");
+            parsers.push(InnerParser::Nekoton(vec![AbiParser {
+                code_hash: None,
+                parser: parser.transaction_parser().clone(),
+            }]));
+            parsers.push(InnerParser::Semantic(parser));
+        }
+        parsers
+    })
+}
+
+fn serializers() -> Vec<Serializer> {
+    vec![
+        #[cfg(feature = "serialize-protobuf")]
+        Serializer::Protobuf {
+            legacy_amounts: true,
+            address_format: Some(AddressFormat::Base64),
+            normalize_amounts: true,
+        },
+        #[cfg(feature = "serialize-json")]
+        Serializer::Json {
+            address_format: AddressFormat::Base64,
+            normalize_amounts: true,
+        },
+    ]
+}
+
+/// Parses arbitrary bytes as a transaction BOC with every parser kind, without the configured
+/// filters, and serializes the extracted messages with every serializer. Malformed data must
+/// fail with an error, a panic is a bug. Returns the number of extracted messages
+pub fn parse_any_transaction(data: &[u8]) -> Result<usize> {
+    let tx = Transaction::construct_from_bytes(data)?;
+    let mut count = 0;
+    for parser in parsers() {
+        let Ok(messages) = parser.parse(&tx, None) else {
+            continue;
+        };
+        for message in messages {
+            if let (InnerParser::RawBodyMessageParser, Some(body)) = (parser, message.message.body()) {
+                utils::cell_tree(&body).ok();
+            }
+            for serializer in serializers() {
+                serializer.serialize_message(message.clone().into()).ok();
+            }
+            count += 1;
+        }
+    }
+    Ok(count)
+}
@@ -0,0 +1,137 @@
+use anyhow::{bail, Context, Result};
+use nekoton_abi::FunctionExt;
+use nekoton_utils::ConstClock;
+use serde::Deserialize;
+use ton_abi::token::Detokenizer;
+use ton_block::MsgAddressInt;
+use ton_indexer::utils::ShardStateStuff;
+
+use crate::types::FilteredMessage;
+
+use super::abi::AbiSource;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GetterConfig {
+    /// Get-method name
+    pub method: String,
+    /// ABI with the get-method, a file path or inline JSON
+    pub abi: AbiSource,
+    /// Account to run the get-method on. Default: receiver
+    #[serde(default)]
+    pub account: GetterAccount,
+    /// Key of the result in the message enrichment. Default: the method name
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GetterAccount {
+    Sender,
+    #[default]
+    Receiver,
+}
+
+/// Get-method executed on a side of matched messages, its outputs are attached to the message
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "GetterConfig")]
+pub struct Getter {
+    name: String,
+    account: GetterAccount,
+    function: ton_abi::Function,
+    /// Takes only `answerId`
+    responsible: bool,
+}
+
+impl TryFrom<GetterConfig> for Getter {
+    type Error = anyhow::Error;
+
+    fn try_from(config: GetterConfig) -> Result<Self> {
+        let abi = ton_abi::Contract::load(&config.abi.read()?)?;
+        let function = abi
+            .function(&config.method)
+            .with_context(|| format!("No get-method {}", config.method))?
+            .clone();
+        let responsible = match function.inputs.as_slice() {
+            [] => false,
+            [answer_id] if answer_id.name == "answerId" => true,
+            _ => bail!("Get-method {} must have no inputs besides `answerId`", config.method),
+        };
+        Ok(Self {
+            name: config.name.unwrap_or(config.method),
+            account: config.account,
+            function,
+            responsible,
+        })
+    }
+}
+
+impl Getter {
+    /// Runs the get-method on the account from the state, `None` if the account is not there
+    fn run(&self, state: &ShardStateStuff, address: &MsgAddressInt, now: u32) -> Result<Option<serde_json::Value>> {
+        let Some(tokens) = run_get_method(&self.function, self.responsible, state, address, now)? else {
+            return Ok(None);
+        };
+        Ok(Some(Detokenizer::detokenize_to_json_value(&tokens)?))
+    }
+}
+
+/// Runs the get-method with the local executor on the account from the state,
+/// `None` if the account is not there. Responsible methods take only `answerId`
+pub(crate) fn run_get_method(
+    function: &ton_abi::Function,
+    responsible: bool,
+    state: &ShardStateStuff,
+    address: &MsgAddressInt,
+    now: u32,
+) -> Result<Option<Vec<ton_abi::Token>>> {
+    let accounts = state.state().read_accounts()?;
+    let Some(shard_account) = accounts.account(&address.address())? else {
+        return Ok(None);
+    };
+    let ton_block::Account::Account(account) = shard_account.read_account()? else {
+        return Ok(None);
+    };
+
+    let clock = ConstClock::from_secs(now);
+    let output = if responsible {
+        let answer_id = ton_abi::Token::new("answerId", ton_abi::TokenValue::Uint(ton_abi::Uint::new(0, 32)));
+        function.run_local_responsible(&clock, account, &[answer_id])?
+    } else {
+        function.run_local(&clock, account, &[])?
+    };
+    match output.tokens {
+        Some(tokens) => Ok(Some(tokens)),
+        None => bail!("{} failed with exit code {}", function.name, output.result_code),
+    }
+}
+
+/// Attaches outputs of the getters to the message. The state is the one after the block,
+/// so results reflect the end of the block rather than the transaction
+pub fn run_getters(getters: &[Getter], state: &ShardStateStuff, message: &mut FilteredMessage) {
+    for getter in getters {
+        let address = match getter.account {
+            GetterAccount::Sender => message.message.src_ref(),
+            GetterAccount::Receiver => message.message.dst_ref(),
+        };
+        // Accounts of other shards are not in the state
+        let shard = state.shard();
+        let address = address.filter(|address| {
+            shard.workchain_id() == address.workchain_id()
+                && shard.contains_account(address.address()).unwrap_or(false)
+        });
+        let Some(address) = address else {
+            continue;
+        };
+        match getter.run(state, address, message.tx.now) {
+            Ok(Some(value)) => {
+                message.enrichment.insert(getter.name.clone(), value);
+            }
+            Ok(None) => {}
+            Err(error) => {
+                tracing::warn!("Get-method {} of {}: {:?}", getter.function.name, address, error);
+            }
+        }
+    }
+}
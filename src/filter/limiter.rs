@@ -0,0 +1,88 @@
+use std::sync::Mutex;
+use std::time::Instant;
+
+use serde::Deserialize;
+
+/// Token bucket which limits the number of messages per second
+#[derive(Debug, Deserialize)]
+#[serde(from = "u32")]
+pub struct RateLimit {
+    max_per_second: u32,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl RateLimit {
+    pub fn new(max_per_second: u32) -> Self {
+        Self {
+            max_per_second,
+            bucket: Mutex::new(Bucket {
+                tokens: max_per_second as f64,
+                updated_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a token from the bucket. Returns `false` if the limit is exceeded
+    pub fn try_acquire(&self) -> bool {
+        let capacity = self.max_per_second as f64;
+        let mut bucket = self.bucket.lock().unwrap();
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * capacity).min(capacity);
+        bucket.updated_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl From<u32> for RateLimit {
+    fn from(max_per_second: u32) -> Self {
+        Self::new(max_per_second)
+    }
+}
+
+impl Clone for RateLimit {
+    /// Clones the limit with the current state of the bucket
+    fn clone(&self) -> Self {
+        Self {
+            max_per_second: self.max_per_second,
+            bucket: Mutex::new(*self.bucket.lock().unwrap()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit() {
+        let limit = RateLimit::new(3);
+        assert!((0..3).all(|_| limit.try_acquire()));
+        assert!(!limit.try_acquire());
+
+        // The clone continues with the drained bucket
+        assert!(!limit.clone().try_acquire());
+
+        std::thread::sleep(std::time::Duration::from_millis(400));
+        assert!(limit.try_acquire());
+    }
+
+    #[test]
+    fn test_zero_rate_limit() {
+        let limit = RateLimit::new(0);
+        assert!(!limit.try_acquire());
+    }
+}
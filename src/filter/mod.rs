@@ -1,40 +1,51 @@
-use crate::types::FilteredMessage;
+use crate::types::{Enrichment, FilteredMessage, MessageRouting, SerializeAggregate};
 
 use self::{
-    config::{AddressOrCodeHash, FilterEntry},
-    parser::get_parsers,
+    config::{
+        AccountChangeFilter, AccountFilter, AddressOrCodeHash, ConfigParamFilter, DedupPrecedence, FilterEntry, MatchMode,
+        TransactionRange,
+    },
+    parser::{
+        get_account_change_filters, get_account_filters, get_config_filters, get_dedup, get_match_mode,
+        get_parsers, try_get_parsers, InnerParser, Parser,
+    },
+    plugin::PluginVerdict,
 };
 use anyhow::Result;
-use chrono::{NaiveDate, NaiveDateTime};
-use ton_block::{MsgAddressInt, Transaction};
+use rustc_hash::{FxHashMap, FxHashSet};
+use ton_block::{CommonMsgInfo, MsgAddressInt, ShardAccount, Transaction};
 use ton_indexer::utils::ShardStateStuff;
-use ton_types::UInt256;
+use ton_types::{AccountId, UInt256};
 
+pub mod abi;
+pub mod aggregate;
+pub mod alerts;
+pub mod code_hashes;
 pub mod config;
+pub mod expression;
+mod fuzz;
+pub mod getters;
+pub mod limiter;
 mod parser;
+pub mod plugin;
+mod semantic;
+pub mod state_budget;
+pub mod stats;
 mod utils;
+mod validation;
 
+pub use fuzz::parse_any_transaction;
 pub use parser::init_parsers;
+pub use validation::validate_filters;
 
-/// Read state and check account's code hash
+/// Check account's code hash in the code hash index or the state
 fn match_code_hash(
     state: &ShardStateStuff,
     filter_hash: &UInt256,
     account: &MsgAddressInt,
 ) -> Result<bool> {
-    let shard_accounts = state.state().read_accounts()?;
-    let Some(account) = shard_accounts.account(&account.address())? else {
-        tracing::trace!(
-            "match_code_hash: account not found in the shard: {}",
-            state.shard()
-        );
-        return Ok(false);
-    };
-    let account = account.read_account()?;
-    Ok(account
-        .get_code_hash()
-        .map(|account_hash| account_hash == filter_hash)
-        .unwrap_or(false))
+    let code_hash = code_hashes::filter_code_hash(state, account.workchain_id(), &account.address())?;
+    Ok(code_hash.as_ref() == Some(filter_hash))
 }
 
 /// Match the filter with an account
@@ -81,80 +92,486 @@ fn match_filter(
         Some(filter) => filter.message_name == ext.name && filter.message_type == ext.message_type,
         None => true
     };
-    src_match && dst_match && event_match
+    if !(src_match && dst_match && event_match) {
+        return false;
+    }
+    // Evaluate the custom expression last, since it is the most expensive check
+    match &filter.condition {
+        Some(condition) => condition.evaluate(ext).unwrap_or_else(|err| {
+            tracing::debug!("Error during filter condition `{}` evaluation: {}", condition.source(), err);
+            false
+        }),
+        None => true,
+    }
+}
+
+/// Run the filter plugin if any. Returns `None` if the message was rejected
+fn apply_plugin(filter: &FilterEntry, ext: &FilteredMessage) -> Option<Enrichment> {
+    let Some(plugin) = &filter.plugin else {
+        return Some(Default::default());
+    };
+    match plugin.apply(ext) {
+        Ok(PluginVerdict::Accept(enrichment)) => Some(enrichment),
+        Ok(PluginVerdict::Reject) => None,
+        Err(err) => {
+            tracing::error!("Error during plugin execution for filter {}: {}", filter.name, err);
+            None
+        }
+    }
+}
+
+/// Transaction message hashes for internal messages
+fn message_routing(ext: &FilteredMessage) -> Option<MessageRouting> {
+    if !matches!(ext.message.header(), CommonMsgInfo::IntMsgInfo(_)) {
+        return None;
+    }
+    match MessageRouting::from_transaction(&ext.tx) {
+        Ok(routing) => Some(routing),
+        Err(error) => {
+            tracing::error!("Reading message routing: {}", error);
+            None
+        }
+    }
+}
+
+/// Destinations of all message filters, none before the filters are initialized
+pub fn message_destinations() -> impl Iterator<Item = &'static str> {
+    try_get_parsers()
+        .into_iter()
+        .flatten()
+        .flat_map(|parser| &parser.filters)
+        .filter_map(|filter| filter.destination.as_deref())
+}
+
+/// Names and channel capacities of the message filters sent to the messages stream
+pub fn message_filter_channels() -> impl Iterator<Item = (&'static str, Option<usize>)> {
+    try_get_parsers()
+        .into_iter()
+        .flatten()
+        .flat_map(|parser| &parser.filters)
+        .filter(|filter| filter.destination.is_none())
+        .map(|filter| (filter.name.as_str(), filter.channel_capacity))
+}
+
+/// Whether any message filter tracks bounces
+pub fn tracks_bounces() -> bool {
+    try_get_parsers()
+        .into_iter()
+        .flatten()
+        .flat_map(|parser| &parser.filters)
+        .any(|filter| filter.track_bounces)
+}
+
+/// Closes the aggregation windows of all filters which ended before `now`
+pub fn closed_aggregates(now: u32) -> Vec<SerializeAggregate> {
+    try_get_parsers()
+        .into_iter()
+        .flatten()
+        .flat_map(|parser| parser.filters.iter().map(move |filter| (parser, filter)))
+        .filter_map(|(parser, filter)| Some((parser, filter, filter.aggregate.as_ref()?)))
+        .flat_map(|(parser, filter, aggregation)| aggregation.close(now, &parser.name, &filter.name))
+        .collect()
+}
+
+/// Whether any filter assembles traces
+pub fn traces_enabled() -> bool {
+    try_get_parsers()
+        .into_iter()
+        .flatten()
+        .flat_map(|parser| &parser.filters)
+        .any(|filter| filter.trace)
+}
+
+/// Accounts whose transactions can contain matched messages, `None` if some filter
+/// can match messages of any account. Inbound messages are read from transactions
+/// of their receivers and outbound ones from their senders, so every side a parser
+/// reads must be constrained to an address
+pub fn filtered_accounts() -> Option<FxHashSet<(i32, UInt256)>> {
+    let mut accounts = FxHashSet::default();
+    for parser in try_get_parsers()? {
+        for filter in &parser.filters {
+            let sides = [
+                (parser.inner_parser.reads_in_message(), &filter.receiver),
+                (parser.inner_parser.reads_out_messages(), &filter.sender),
+            ];
+            for (reads, account) in sides {
+                match account {
+                    _ if !reads => {}
+                    Some(AddressOrCodeHash::Address(address)) => {
+                        accounts.insert(account_key(address.workchain_id(), &address.address()));
+                    }
+                    _ => return None,
+                }
+            }
+        }
+    }
+    Some(accounts)
+}
+
+/// Key of an account in `filtered_accounts`
+pub fn account_key(workchain_id: i32, account_id: &AccountId) -> (i32, UInt256) {
+    (workchain_id, UInt256::from_slice(&account_id.get_bytestring(0)))
+}
+
+/// Find config filters which watch the param
+pub fn filter_config_param(param: u32) -> impl Iterator<Item = &'static ConfigParamFilter> {
+    get_config_filters()
+        .iter()
+        .filter(move |filter| filter.matches(param))
+}
+
+/// Changed and deleted account ids of a block matching an account change filter
+pub struct AccountChanges {
+    pub filter: &'static AccountChangeFilter,
+    pub changed: Vec<UInt256>,
+    pub deleted: Vec<UInt256>,
+}
+
+/// Matches changed and deleted accounts of a block with the account change filters.
+/// Changed accounts are matched by the code hash after the block, so code hash filters need the state.
+/// Deleted accounts are matched by the indexed code hash, so it must be called before the index is updated
+pub fn filter_account_changes(
+    workchain_id: i32,
+    state: Option<&ShardStateStuff>,
+    changed_accounts: &FxHashSet<AccountId>,
+    deleted_accounts: &FxHashSet<AccountId>,
+) -> Vec<AccountChanges> {
+    let filters = get_account_change_filters();
+    if filters.is_empty() {
+        return Vec::new();
+    }
+    let with_code_hashes = filters.iter().any(|filter| {
+        filter
+            .accounts
+            .iter()
+            .any(|account| matches!(account, AddressOrCodeHash::CodeHash(_)))
+    });
+
+    let mut changes = filters
+        .iter()
+        .map(|filter| AccountChanges {
+            filter,
+            changed: Vec::new(),
+            deleted: Vec::new(),
+        })
+        .collect::<Vec<_>>();
+
+    for (account_id, deleted) in changed_accounts
+        .iter()
+        .map(|account_id| (account_id, false))
+        .chain(deleted_accounts.iter().map(|account_id| (account_id, true)))
+    {
+        let code_hash = match (with_code_hashes, deleted, state) {
+            (false, _, _) => None,
+            (true, true, _) => code_hashes::lookup(workchain_id, account_id).flatten(),
+            (true, false, Some(state)) => utils::account_code_hash(state, account_id).unwrap_or_else(|error| {
+                tracing::error!("Reading code hash of changed account: {:?}", error);
+                None
+            }),
+            (true, false, None) => None,
+        };
+
+        for change in &mut changes {
+            let accounts = &change.filter.accounts;
+            let matched = accounts.is_empty()
+                || accounts.iter().any(|account| match account {
+                    AddressOrCodeHash::Address(address) => {
+                        address.workchain_id() == workchain_id && &address.address() == account_id
+                    }
+                    AddressOrCodeHash::CodeHash(filter_hash) => code_hash.as_ref() == Some(filter_hash),
+                });
+            if matched {
+                let (_, key) = account_key(workchain_id, account_id);
+                match deleted {
+                    true => change.deleted.push(key),
+                    false => change.changed.push(key),
+                }
+            }
+        }
+    }
+
+    changes.retain(|change| !change.changed.is_empty() || !change.deleted.is_empty());
+    for change in &mut changes {
+        change.changed.sort();
+        change.deleted.sort();
+    }
+    changes
+}
+
+/// Finds the first account filter matching the account and reads its state
+pub fn filter_account(
+    state: &ShardStateStuff,
+    workchain_id: i32,
+    account_id: &AccountId,
+) -> Result<Option<(&'static AccountFilter, ShardAccount)>> {
+    let filters = get_account_filters();
+    if filters.is_empty() {
+        return Ok(None);
+    }
+
+    let Some(shard_account) = state.state().read_accounts()?.account(account_id)? else {
+        return Ok(None);
+    };
+
+    let mut code_hash = None;
+    for filter in filters {
+        let matched = match &filter.account {
+            AddressOrCodeHash::Address(address) => {
+                address.workchain_id() == workchain_id && &address.address() == account_id
+            }
+            AddressOrCodeHash::CodeHash(filter_hash) => {
+                if code_hash.is_none() {
+                    code_hash = Some(shard_account.read_account()?.get_code_hash());
+                }
+                code_hash.as_ref().and_then(Option::as_ref) == Some(filter_hash)
+            }
+        };
+        if matched {
+            return Ok(Some((filter, shard_account)));
+        }
+    }
+    Ok(None)
+}
+
+/// Finds the first account filter matching the deleted account. Code hashes are looked up
+/// in the index, so it must be called before the index is updated
+pub fn filter_deleted_account(workchain_id: i32, account_id: &AccountId) -> Option<&'static AccountFilter> {
+    let mut code_hash = None;
+    get_account_filters().iter().find(|filter| match &filter.account {
+        AddressOrCodeHash::Address(address) => {
+            address.workchain_id() == workchain_id && &address.address() == account_id
+        }
+        AddressOrCodeHash::CodeHash(filter_hash) => {
+            let code_hash = code_hash.get_or_insert_with(|| code_hashes::lookup(workchain_id, account_id).flatten());
+            code_hash.as_ref() == Some(filter_hash)
+        }
+    })
+}
+
+/// Attaches the body of a message without ABI as a cell tree
+fn attach_body_tree(message: &mut FilteredMessage) {
+    let Some(body) = message.message.body() else {
+        return;
+    };
+    match utils::cell_tree(&body) {
+        Ok(tree) => {
+            message.enrichment.insert("body_tree".to_string(), tree);
+        }
+        Err(error) => tracing::warn!("Failed to build body tree of {}: {:?}", message.message_hash.to_hex_string(), error),
+    }
+}
+
+/// Message of the matched filter with the filter fields and attachments.
+/// Decoded tokens are kept for `emitted_message`
+fn matched_message(
+    parser: &Parser,
+    filter: &FilterEntry,
+    enrichment: Enrichment,
+    state: Option<&ShardStateStuff>,
+    ext: FilteredMessage,
+) -> FilteredMessage {
+    let routing = filter
+        .include_routing
+        .then(|| message_routing(&ext))
+        .flatten();
+    let mut message = FilteredMessage {
+        contract_name: parser.name.clone(),
+        filter_name: filter.name.clone(),
+        destination: filter.destination.clone(),
+        tenant: filter.tenant.clone(),
+        track_bounces: filter.track_bounces,
+        trace: filter.trace,
+        enrichment,
+        routing,
+        ..ext
+    };
+    if filter.body_tree && matches!(parser.inner_parser, InnerParser::RawBodyMessageParser) {
+        attach_body_tree(&mut message);
+    }
+    if let (Some(state), false) = (state, filter.getters.is_empty()) {
+        getters::run_getters(&filter.getters, state, &mut message);
+    }
+    message
+}
+
+/// Counts the message which passed dedup in the filter metrics, aggregation and alerts
+fn emitted_message(parser: &Parser, filter: &FilterEntry, mut message: FilteredMessage) -> FilteredMessage {
+    filter.matched.increment();
+    if let Some(aggregation) = &filter.aggregate {
+        aggregation.add(&message);
+    }
+    alerts::observe(&parser.name, &filter.name, &message);
+    if !filter.include_decoded {
+        message.tokens = Vec::new();
+    }
+    message
 }
 
 /// Filters transaction by source, destination and/or abi action name
 pub fn filter_transaction(
     tx: Transaction,
     state: Option<&ShardStateStuff>,
-    start_date: NaiveDate,
+    range: &TransactionRange,
 ) -> Vec<FilteredMessage> {
     let mut filtered = vec![];
-    let tx_now = NaiveDateTime::from_timestamp_opt(tx.now.into(), 0);
-    if tx_now.is_none() || tx_now.unwrap().date() < start_date {
+    if !range.contains(&tx) {
         return vec![];
     }
-    for parser in get_parsers().iter() {
-        if let Ok(extracted) = parser.inner_parser.parse(&tx) {
-            let mut extracted = extracted.into_iter().filter_map(|ext| {
-                let (src, dst) = (ext.message.src_ref(), ext.message.dst_ref());
-                // find a first filter match
-                let match_filter = parser.filters.iter()
-                    .find(|filter| match_filter(state, filter, src, dst, &ext));
-                // fill parser and filter names in the 
-                match_filter.map(|filter| {
-                    FilteredMessage {
-                        contract_name: parser.name.clone(),
-                        filter_name: filter.name.clone(),
-                        ..ext
-                    }
-                })
+    let match_mode = get_match_mode();
+    let parsers = get_parsers();
+    for (index, parser) in parsers.iter().enumerate() {
+        let Ok(extracted) = parser.inner_parser.parse(&tx, state) else {
+            continue;
+        };
+        for ext in extracted {
+            let (src, dst) = (ext.message.src_ref(), ext.message.dst_ref());
+            // filters are sorted by priority
+            let mut matched = parser.filters.iter().filter_map(|filter| {
+                if !match_filter(state, filter, src, dst, &ext) {
+                    return None;
+                }
+                apply_plugin(filter, &ext).map(|enrichment| (filter, enrichment))
             });
-            filtered.extend(&mut extracted);
+            let (matched, matched_filters) = match match_mode {
+                MatchMode::First => (matched.next().into_iter().collect::<Vec<_>>(), Vec::new()),
+                MatchMode::All => {
+                    let matched = matched.collect::<Vec<_>>();
+                    let names = matched.iter().map(|(filter, _)| filter.name.clone()).collect();
+                    (matched, names)
+                }
+            };
+            // drop sampled out and throttled messages
+            let mut admitted = matched
+                .into_iter()
+                .filter(|(filter, _)| filter.admit())
+                .collect::<Vec<_>>();
+            let Some((last_filter, last_enrichment)) = admitted.pop() else {
+                continue;
+            };
+            for (filter, enrichment) in admitted {
+                filtered.push((matched_message(parser, filter, enrichment, state, ext.clone()), index, filter));
+            }
+            let ext = FilteredMessage { matched_filters, ..ext };
+            filtered.push((matched_message(parser, last_filter, last_enrichment, state, ext), index, last_filter));
         }
     }
+    let filtered = match get_dedup() {
+        Some(dedup) if filtered.len() > 1 => dedup_messages(filtered, dedup.prefer),
+        _ => filtered,
+    };
     filtered
+        .into_iter()
+        .map(|(message, index, filter)| emitted_message(&parsers[index], filter, message))
+        .collect()
+}
+
+/// Matched message with the index of its parser and its filter
+type MatchedMessage<'a> = (FilteredMessage, usize, &'a FilterEntry);
+
+/// Keeps the records of one parser for every message hash and tenant
+fn dedup_messages(messages: Vec<MatchedMessage<'_>>, prefer: DedupPrecedence) -> Vec<MatchedMessage<'_>> {
+    let parsers = get_parsers();
+    let rank = |index: usize| match prefer {
+        DedupPrecedence::Decoded => (!parsers[index].inner_parser.decodes_body(), index),
+        DedupPrecedence::First => (false, index),
+    };
+
+    let mut best = FxHashMap::default();
+    for (message, source, _) in &messages {
+        let rank = rank(*source);
+        best.entry((message.tenant.as_deref(), message.message_hash))
+            .and_modify(|best: &mut (bool, usize)| *best = (*best).min(rank))
+            .or_insert(rank);
+    }
+    let keep = messages
+        .iter()
+        .map(|(message, source, _)| best[&(message.tenant.as_deref(), message.message_hash)] == rank(*source))
+        .collect::<Vec<_>>();
+
+    messages
+        .into_iter()
+        .zip(keep)
+        .filter_map(|(message, keep)| keep.then_some(message))
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use std::{str::FromStr, sync::Once};
 
-    use chrono::NaiveDate;
     use ton_block::{Deserializable, MsgAddressInt, Transaction};
     use ton_types::UInt256;
 
-    use crate::types::MessageType;
+    use crate::types::{FilteredMessage, MessageType};
 
     use super::{
-        config::{FilterType, FilterEntry, FilterConfig, MessageFilter, FilterRecord},
-        parser::init_parsers, filter_transaction,
+        abi::AbiSource,
+        config::{DedupPrecedence, FilterType, FilterEntry, FilterConfig, MessageFilter, FilterRecord, TransactionRange},
+        parser::{get_parsers, init_parsers}, dedup_messages, filter_transaction,
     };
 
     static TEST_INIT: Once = Once::new();
 
+    const SEPTEMBER_1_2023: u32 = 1693526400;
+    const SEPTEMBER_20_2023: u32 = 1695168000;
+
     fn test_filter_config(src: Option<MsgAddressInt>, dst: Option<MsgAddressInt>) -> FilterConfig {
         let contract = FilterType::Contract {
             name: "TokenWallet".to_string(),
-            abi_path: "./test/abi/TokenWallet.abi.json".to_string(),
+            abi: Some(AbiSource::Path("./test/abi/TokenWallet.abi.json".to_string()).into()),
+            parser_options: Default::default(),
         };
         let contract_filter = FilterEntry {
             name: "tip3 transfer".to_string(),
+            priority: 0,
             sender: src.map(Into::into),
             receiver: dst.clone().map(Into::into),
             message: Some(MessageFilter {
                 message_name: "transfer".to_string(),
                 message_type: MessageType::InternalInbound,
             }),
+            condition: None,
+            plugin: None,
+            sample_rate: None,
+            max_messages_per_second: None,
+            include_routing: false,
+            destination: None,
+            channel_capacity: None,
+            getters: Vec::new(),
+            track_bounces: false,
+            trace: false,
+            aggregate: None,
+            body_tree: false,
+            include_decoded: false,
+            tenant: None,
+            matched: Default::default(),
         };
         let native_transfer_filter = FilterEntry {
             name: "native trasnfer".to_string(),
+            priority: 0,
             sender: dst.map(Into::into),
             receiver: None,
             message: None,
+            condition: None,
+            plugin: None,
+            sample_rate: None,
+            max_messages_per_second: None,
+            include_routing: false,
+            destination: None,
+            channel_capacity: None,
+            getters: Vec::new(),
+            track_bounces: false,
+            trace: false,
+            aggregate: None,
+            body_tree: false,
+            include_decoded: false,
+            tenant: None,
+            matched: Default::default(),
         };
         FilterConfig {
+            include: Default::default(),
+            abis: Default::default(),
+            abi_discovery: None,
             message_filters: Vec::from([
                 FilterRecord {
                     filter_type: contract,
@@ -165,6 +582,15 @@ mod tests {
                     entries: vec![native_transfer_filter],
                 }
             ]),
+            account_filters: Default::default(),
+            config_filters: Default::default(),
+            account_change_filters: Default::default(),
+            match_mode: Default::default(),
+            dedup: None,
+            state_read_budget: None,
+            alerts: Vec::new(),
+            tenants: Default::default(),
+            range: Default::default(),
         }
     }
 
@@ -189,9 +615,12 @@ mod tests {
         // Tip3 token transfer
         let tx = transfer_token_tx();
         let message_hash = UInt256::from_str("3b1c0c89be14e92f4d9465911b2ac28ce5588f1616994b7a2e94da50d6e22fa4").unwrap();
-        let start_date = NaiveDate::from_ymd_opt(2023, 09, 1).unwrap();
+        let range = TransactionRange {
+            start_utime: Some(SEPTEMBER_1_2023),
+            ..Default::default()
+        };
 
-        let filtered = filter_transaction(tx, None, start_date);
+        let filtered = filter_transaction(tx, None, &range);
         assert_eq!(filtered.len(), 1);
         assert_eq!(filtered[0].message_hash, message_hash);
     }
@@ -201,9 +630,12 @@ mod tests {
         init();
         // Tip3 token transfer
         let tx = Transaction::construct_from_base64("te6ccgECNAEACA0AA7V5bRdQ3GcnryHQqzoVz0tjr0SeiUgyi/8DhzFk1ME0KnAAAiIbowaUF0/n9tGdnzo376LvizSy7ImBMwg+5pNJqW446iYg8leQAAIiG3vs0BZQmb7gANR3fpSoBQQBAhkMgNiJBEXMZxh1zUyRAwIAb8mKcBJMNht8AAAAAAAOAAIAAAANIiXVOTNvmEiIpm7IWphppVDf+mYCxFebj6STkCiHFmhHESfEAKBgM2ssPQkAAAAAAAAAAAe/AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAACCcgSH2vYmURp5KqRpajGI37O3PtnHt3pc6V6xWMeYrLdN765jA+6TmlYiM8VK0pId87W4DlzCmOwmSbUci9E7nScCAeAsBgIB2RYHAgFIDQgBASAJAY3gBLaLqG4zk9eQ6FWdCuelsdeiT0SkGUX/gcOYsmpgmhU4AABEQ3Rg0o7KEzfcJnx2gQAAAAFAAAAAAAAAAAAVeqVvc6y7YAoCA8/ADAsAIQAAAAAAAAAAADRVyA/Vp58gACEAAAAAAAAAAAAAAlVOB1rG4AEBIA4BsWgBLaLqG4zk9eQ6FWdCuelsdeiT0SkGUX/gcOYsmpgmhU8AB70KjxkkGG6RG8tWuUhk4BXPHjeNUH+Z8dC6tDK5o0NQOiiAxAYHKNQAAERDdGDSjMoTN9zADwObCpj/owAAAAAAAAAAAAAAAAAPaVCAC+mEPdFkJ195tCFyk8cnEKshyD4gVEBAhHkAKxIjVyVAAAAAAAAAAAAAAAAAvrwgAAAAACgAAAAkFBEQAEOAC+mEPdFkJ195tCFyk8cnEKshyD4gVEBAhHkAKxIjVyVIAgPPwBMSAEMgAWHRf7Ih17oOcynXJ3lkLhapVO/CSiXfCmuBYYmO0fikAEMgAQI4c1NxnVNLEx2rgTBGtPGYvhHfkGF8kNnGssRiqrAcAgTIBhwVAEOAC+mEPdFkJ195tCFyk8cnEKshyD4gVEBAhHkAKxIjVyVQAgEgIBcCASAdGAEBIBkBsWgBLaLqG4zk9eQ6FWdCuelsdeiT0SkGUX/gcOYsmpgmhU8APFZjRjVXype5QphxutnYoAh4S3H6+Rr6QlnIQwe3ibDQBMS0AAYEUb4AAERDdGDSisoTN9zAGgGLc+IhQwAAAAAAAAAAAAAAAVq5L3KAEYI6bXJ+tVvVDkt18OawILWbu/0ojBJrQChoE1ByKuOAAAAAAAAAAAAAAAAAAAAAEBsBQ4AL6YQ90WQnX3m0IXKTxycQqyHIPiBUQECEeQArEiNXJUgcAAABASAeAa9IAS2i6huM5PXkOhVnQrnpbHXok9EpBlF/4HDmLJqYJoVPABfTCHuiyE6+82hC5SeOTiFWQ5B8QKiAgQjyAFYkRq5KjmJaBAYDN/gAAERDdGDSiMoTN9zAHwB5BONBUAAAAAA9F4AAAAAAAAAAAAAAAAAAVq5L3IAAAAAAAAAAAAAAAABCkiYAAAAAAAAAAAAAAAAAA9pUIAIBICMhAQEgIgDt4AS2i6huM5PXkOhVnQrnpbHXok9EpBlF/4HDmLJqYJoVOAAAREN0YNKGyhM33DoE5tKyhM33AAAAAAAAAAAAAAAAAAAHijmG9fyslIraVwM4yL8rzAGAAAAAAAAAAAAAAA99blsCO4ZC8qaTz2x//LmQiQrPs8ABASAkAV3gBLaLqG4zk9eQ6FWdCuelsdeiT0SkGUX/gcOYsmpgmhU4AABEQ3Rg0oTKEzfcwCUBS1AciqeAC+mEPdFkJ195tCFyk8cnEKshyD4gVEBAhHkAKxIjVyVQJgFDgAvphD3RZCdfebQhcpPHJxCrIcg+IFRAQIR5ACsSI1clUCcBY4AFh0X+yIde6DnMp1yd5ZC4WqVTvwkol3wprgWGJjtH4oAAAAAAAAAAAAAAACtXJe5QKAFrgAQI4c1NxnVNLEx2rgTBGtPGYvhHfkGF8kNnGssRiqrAYAAAAAAAAAAAAAAAAAHtKgAAAAA4KQED0EAqAYOABYdF/siHXug5zKdcneWQuFqlU78JKJd8Ka4FhiY7R+KAAAAAAAAAAAAAAAAAIUkTAAAAAAAAAAAAAAAAAAAAABArAEOAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAQAbFIAeKzGjGqvlS9yhTDjdbOxQBDwluP18jX0hLOQhg9vE2HACW0XUNxnJ68h0Ks6Fc9LY69EnolIMov/A4cxZNTBNCp0ERcxnAGCEGQAABEQ3QjyYbKEzfcwC0Ba3DYn8mABYdF/siHXug5zKdcneWQuFqlU78JKJd8Ka4FhiY7R+KAAAAAAAAAAAAAAAArVyXuUC4BQ4AL6YQ90WQnX3m0IXKTxycQqyHIPiBUQECEeQArEiNXJVAvAUOAEGlXrvLZsKUGZveJNRaMERcQtlpzwDMun4KVr0K/tpYwMAFDgAvphD3RZCdfebQhcpPHJxCrIcg+IFRAQIR5ACsSI1clUDECtwYAAAAAPReAAAAAAAAAAAAAAAAAAAX14QCAC+mEPdFkJ195tCFyk8cnEKshyD4gVEBAhHkAKxIjVyVQAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAACMzIAYwAAAAAAAAAAAAAAAAAOpAyAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAQAAFA").unwrap();
-        let start_date = NaiveDate::from_ymd_opt(2023, 09, 1).unwrap();
+        let range = TransactionRange {
+            start_utime: Some(SEPTEMBER_1_2023),
+            ..Default::default()
+        };
 
-        let filtered = filter_transaction(tx, None, start_date);
+        let filtered = filter_transaction(tx, None, &range);
         assert!(filtered.is_empty());
     }
 
@@ -212,22 +644,88 @@ mod tests {
         init();
         // Tip3 token transfer
         let tx = transfer_token_tx();
-        let start_date = NaiveDate::from_ymd_opt(2023, 09, 20).unwrap();
+        let range = TransactionRange {
+            start_utime: Some(SEPTEMBER_20_2023),
+            ..Default::default()
+        };
+
+        let filtered = filter_transaction(tx, None, &range);
+        assert!(filtered.is_empty());
+    }
+
+    #[test]
+    fn test_filter_early_date() {
+        init();
+        // Tip3 token transfer
+        let tx = transfer_token_tx();
+        let range = TransactionRange {
+            end_utime: Some(SEPTEMBER_1_2023),
+            ..Default::default()
+        };
 
-        let filtered = filter_transaction(tx, None, start_date);
+        let filtered = filter_transaction(tx, None, &range);
         assert!(filtered.is_empty());
     }
 
+    #[test]
+    fn test_filter_lt_range() {
+        init();
+        // Tip3 token transfer
+        let tx = transfer_token_tx();
+        let lt = tx.lt;
+
+        let range = TransactionRange {
+            start_lt: Some(lt),
+            end_lt: Some(lt),
+            ..Default::default()
+        };
+        assert_eq!(filter_transaction(tx.clone(), None, &range).len(), 1);
+
+        let range = TransactionRange {
+            start_lt: Some(lt + 1),
+            ..Default::default()
+        };
+        assert!(filter_transaction(tx, None, &range).is_empty());
+    }
+
     #[test]
     fn test_native_transfer_filter() {
         init();
         // native coin transfer
         let tx = Transaction::construct_from_base64("te6ccgECDAEAAlwAA7V+b32pRAXFXJ+xS1vmuPkbuhvnbmeJAOy0GEmb/jetoFAAAimeamUMEUZcH4ZeMycxqFO+Qtx1wKHL1ZnFvEX2BNOxTljTIEwgAAIpnmaUfBZQw21wADRkmWkIBQQBAhcEQQkAQdSKGGSJIhEDAgBtyYDDUEoI0AAAAAAABAACAAAAAi/Kw/8gXD0ilOnrDoFOdOyIzavNfU+KreaCt9HmIQZaQFAVzACeRzeMCfvIAAAAAAAAAADgAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAACCctuYHxy64icPHxb/ZoNfCbM4wf3G5cnqnU+oqPab2wX9w0CH75dIy0g1PN/p++fOBmCKgxwYAmH0PbeVmK2UXQ4CAeAIBgEB3wcAr0gBze+1KIC4q5P2KWt81x8jd0N87czxIB2Wgwkzf8b1tAsAM5srDrbAJr3vUfScydyZm/JmFwE+AlDnlOoZFCIsyxdPhgngBgII2AAARTPNTKGEyhhtrkABsWgBEYWcwWlbdPqMPu1crIumVsKzoJK22anTJ/x2cIL+s0UAOb32pRAXFXJ+xS1vmuPkbuhvnbmeJAOy0GEmb/jetoFQBB1IoAYEDxQAAEUzzQ+YlMoYbazACQFrZ6C5XwAAAAAAAAAAAAAAAABO1QSAEtouobjOT15DoVZ0K56Wx16JPRKQZRf+Bw5iyamCaFTwCgFDgBnNlYdbYBNe96j6TmTuTM35MwuAnwEoc8p1DIoRFmWLqAsAAA==").unwrap();
         let message_hash = UInt256::from_str("4a81042d202c35cc123015bd6d1656ff1eab66674b2f6368bd9ded8670829bca").unwrap();
-        let start_date = NaiveDate::from_ymd_opt(2023, 09, 1).unwrap();
+        let range = TransactionRange {
+            start_utime: Some(SEPTEMBER_1_2023),
+            ..Default::default()
+        };
 
-        let filtered = filter_transaction(tx, None, start_date);
+        let filtered = filter_transaction(tx, None, &range);
         assert_eq!(filtered.len(), 1);
         assert_eq!(filtered[0].message_hash, message_hash);
     }
+
+    #[test]
+    fn test_dedup_messages() {
+        init();
+        let message = filter_transaction(transfer_token_tx(), None, &TransactionRange::default()).remove(0);
+        let tenant_message = FilteredMessage {
+            tenant: Some("tenant".to_string()),
+            ..message.clone()
+        };
+        // The contract parser decodes the body, the native transfer parser doesn't
+        let parsers = get_parsers();
+        let (contract, native) = (&parsers[0].filters[0], &parsers[1].filters[0]);
+        let messages = vec![
+            (message.clone(), 1, native),
+            (message.clone(), 0, contract),
+            (tenant_message, 1, native),
+        ];
+
+        let kept = dedup_messages(messages, DedupPrecedence::Decoded);
+        let kept = kept
+            .iter()
+            .map(|(message, index, _)| (message.tenant.as_deref(), *index))
+            .collect::<Vec<_>>();
+        assert_eq!(kept, [(None, 0), (Some("tenant"), 1)]);
+    }
 }
@@ -1,18 +1,57 @@
+use std::collections::HashSet;
 use std::sync::OnceLock;
 
 use anyhow::{anyhow, Context, Result};
+use rustc_hash::{FxHashMap, FxHashSet};
 use ton_block::Deserializable;
+use ton_indexer::utils::ShardStateStuff;
+use ton_types::UInt256;
 
+use crate::error::{FusionError, FusionResult};
 use crate::types::{FilteredMessage, message_type_from};
 
-use super::config::{FilterConfig, FilterEntry, FilterRecord, FilterType};
+use super::abi::{AbiSource, ContractAbi};
+use super::semantic::{SemanticKind, SemanticParser};
+use super::code_hashes;
+use super::alerts;
+use super::state_budget;
+use super::config::{AccountChangeFilter, AccountFilter, AddressOrCodeHash, ConfigParamFilter, DedupConfig, FilterConfig, FilterEntry, FilterRecord, FilterType, MatchMode, ParserOptions};
 
 static PARSERS: OnceLock<Vec<Parser>> = OnceLock::new();
+static ACCOUNT_FILTERS: OnceLock<Vec<AccountFilter>> = OnceLock::new();
+static CONFIG_FILTERS: OnceLock<Vec<ConfigParamFilter>> = OnceLock::new();
+static ACCOUNT_CHANGE_FILTERS: OnceLock<Vec<AccountChangeFilter>> = OnceLock::new();
+static MATCH_MODE: OnceLock<MatchMode> = OnceLock::new();
+static DEDUP: OnceLock<Option<DedupConfig>> = OnceLock::new();
 
 pub fn get_parsers<'a>() -> &'a Vec<Parser> {
     PARSERS.get().unwrap()
 }
 
+pub fn try_get_parsers<'a>() -> Option<&'a Vec<Parser>> {
+    PARSERS.get()
+}
+
+pub fn get_account_filters<'a>() -> &'a [AccountFilter] {
+    ACCOUNT_FILTERS.get().map(Vec::as_slice).unwrap_or_default()
+}
+
+pub fn get_config_filters<'a>() -> &'a [ConfigParamFilter] {
+    CONFIG_FILTERS.get().map(Vec::as_slice).unwrap_or_default()
+}
+
+pub fn get_account_change_filters<'a>() -> &'a [AccountChangeFilter] {
+    ACCOUNT_CHANGE_FILTERS.get().map(Vec::as_slice).unwrap_or_default()
+}
+
+pub fn get_match_mode() -> MatchMode {
+    MATCH_MODE.get().copied().unwrap_or_default()
+}
+
+pub fn get_dedup() -> Option<DedupConfig> {
+    DEDUP.get().copied().flatten()
+}
+
 #[derive(Debug)]
 pub struct Parser {
     pub name: String,
@@ -33,39 +72,164 @@ impl Parser {
 }
 
 /// Intialize parsers object
-pub fn init_parsers(config: FilterConfig) -> Result<()> {
-    let v = init_all_parsers(config)?;
+pub fn init_parsers(mut config: FilterConfig) -> FusionResult<()> {
+    config.resolve_abis()?;
+    config.merge_tenants();
+    ACCOUNT_FILTERS
+        .set(std::mem::take(&mut config.account_filters))
+        .map_err(|_| FusionError::filter(anyhow!("Unable to initialize account filters")))?;
+    CONFIG_FILTERS
+        .set(std::mem::take(&mut config.config_filters))
+        .map_err(|_| FusionError::filter(anyhow!("Unable to initialize config filters")))?;
+    ACCOUNT_CHANGE_FILTERS
+        .set(std::mem::take(&mut config.account_change_filters))
+        .map_err(|_| FusionError::filter(anyhow!("Unable to initialize account change filters")))?;
+    MATCH_MODE
+        .set(config.match_mode)
+        .map_err(|_| FusionError::filter(anyhow!("Unable to initialize match mode")))?;
+    DEDUP
+        .set(config.dedup)
+        .map_err(|_| FusionError::filter(anyhow!("Unable to initialize dedup")))?;
+    state_budget::init(config.state_read_budget).map_err(FusionError::filter)?;
+    alerts::init(std::mem::take(&mut config.alerts)).map_err(FusionError::filter)?;
+
+    let v = init_all_parsers(config).map_err(FusionError::abi)?;
+    // Deleted accounts are matched by the code hash from the index
+    let mut index_code_hashes = parser_code_hashes(&v);
+    for filter in get_account_change_filters() {
+        for account in &filter.accounts {
+            if let AddressOrCodeHash::CodeHash(code_hash) = account {
+                index_code_hashes.insert(*code_hash);
+            }
+        }
+    }
+    for filter in get_account_filters() {
+        if let AddressOrCodeHash::CodeHash(code_hash) = &filter.account {
+            index_code_hashes.insert(*code_hash);
+        }
+    }
+    code_hashes::init(index_code_hashes);
 
     PARSERS
         .set(v)
-        .map_err(|_| anyhow!("Unable to initialize parsers and handlers"))
+        .map_err(|_| FusionError::filter(anyhow!("Unable to initialize parsers and handlers")))
 }
 
-/// Construct nekoton parser from abi file
-fn get_abi_parser(abi_path: &str) -> Result<InnerParser> {
-    let abi_json = std::fs::read_to_string(abi_path)?;
-    let abi = ton_abi::Contract::load(&abi_json)?;
+/// Code hashes of the message filters and versioned ABIs
+fn parser_code_hashes(parsers: &[Parser]) -> FxHashSet<UInt256> {
+    let mut code_hashes = FxHashSet::default();
+    for parser in parsers {
+        for filter in &parser.filters {
+            for account in [&filter.sender, &filter.receiver].into_iter().flatten() {
+                if let AddressOrCodeHash::CodeHash(code_hash) = account {
+                    code_hashes.insert(*code_hash);
+                }
+            }
+        }
+        if let InnerParser::Nekoton(abi_parsers) = &parser.inner_parser {
+            code_hashes.extend(abi_parsers.iter().filter_map(|abi_parser| abi_parser.code_hash));
+        }
+    }
+    code_hashes
+}
+
+/// Loaded ABIs by source, so ABIs shared by filters are parsed once
+type AbiCache = FxHashMap<AbiSource, ton_abi::Contract>;
+
+/// Construct nekoton parser from abi
+fn get_abi_parser(
+    abi: &AbiSource,
+    options: &ParserOptions,
+    names: Option<&HashSet<&str>>,
+    cache: &mut AbiCache,
+) -> Result<nekoton_abi::TransactionParser> {
+    if !cache.contains_key(abi) {
+        let contract = ton_abi::Contract::load(&abi.read()?)?;
+        cache.insert(abi.clone(), contract);
+    }
+    let abi = &cache[abi];
+
+    let is_referenced = |name: &str| names.map(|names| names.contains(name)).unwrap_or(true);
+    let events = abi
+        .events
+        .values()
+        .filter(|event| is_referenced(&event.name))
+        .cloned()
+        .collect::<Vec<_>>();
+    let funs = abi
+        .functions
+        .values()
+        .filter(|function| is_referenced(&function.name))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let allow_partial_match = !options.strict;
+    let mut builder = nekoton_abi::TransactionParser::builder();
+    if options.functions {
+        builder = builder.function_in_list(funs.clone(), allow_partial_match);
+    }
+    if options.function_outputs {
+        builder = builder.function_out_list(funs.clone(), allow_partial_match);
+    }
+    if options.bounced {
+        builder = builder.function_bounce_list(funs);
+    }
+    if options.events {
+        builder = builder.events_list(events);
+    }
+
+    if options.external_in {
+        builder.build_with_external_in()
+    } else {
+        builder.build()
+    }
+}
+
+/// Construct parsers for all ABI versions of the contract
+fn get_versioned_abi_parser(
+    abi: ContractAbi,
+    options: &ParserOptions,
+    entries: &[FilterEntry],
+    cache: &mut AbiCache,
+) -> Result<InnerParser> {
+    // Collect message names only if every entry is bound to a message
+    let names = options
+        .only_referenced
+        .then(|| {
+            entries
+                .iter()
+                .map(|entry| entry.message.as_ref().map(|m| m.message_name.as_str()))
+                .collect::<Option<HashSet<_>>>()
+        })
+        .flatten();
 
-    let events = abi.events.into_values();
-    let funs = abi.functions.into_values();
-    Ok(
-        InnerParser::Nekoton(
-            nekoton_abi::TransactionParser::builder()
-                .function_in_list(funs, false)
-                .events_list(events)
-                .build_with_external_in()?
-        )
-    )
+    let parsers = abi
+        .into_versions()?
+        .into_iter()
+        .map(|version| {
+            Ok(AbiParser {
+                code_hash: version.code_hash,
+                parser: get_abi_parser(&version.abi, options, names.as_ref(), cache)?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(InnerParser::Nekoton(parsers))
 }
 
 /// Initialize parsers from config
 fn init_all_parsers(config: FilterConfig) -> Result<Vec<Parser>> {
     let mut parsers = vec![];
+    let mut abi_cache = AbiCache::default();
     for record in config.message_filters.into_iter() {
-        let FilterRecord { filter_type, entries } = record;
+        let FilterRecord { filter_type, mut entries } = record;
+        // Stable, so entries of equal priority keep the config order
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.priority));
         let parser = match filter_type {
-            FilterType::Contract { name, abi_path } => {
-                let inner_parser = get_abi_parser(&abi_path)?;
+            FilterType::Contract { name, abi, parser_options } => {
+                let inner_parser = abi
+                    .context("No ABI")
+                    .and_then(|abi| get_versioned_abi_parser(abi, &parser_options, &entries, &mut abi_cache))
+                    .with_context(|| format!("Failed to load ABI for {name}"))?;
                 Parser::new(
                     name,
                     entries,
@@ -77,6 +241,31 @@ fn init_all_parsers(config: FilterConfig) -> Result<Vec<Parser>> {
                 filters: entries,
                 inner_parser: InnerParser::EmptyMessage
             },
+            FilterType::AccountStatusChange => Parser {
+                name: "AccountStatusChange".to_string(),
+                filters: entries,
+                inner_parser: InnerParser::AccountStatusChange,
+            },
+            FilterType::Tip3Transfer => Parser {
+                name: "Tip3Transfer".to_string(),
+                filters: entries,
+                inner_parser: InnerParser::Semantic(SemanticParser::new(SemanticKind::Tip3)?),
+            },
+            FilterType::Multisig => Parser {
+                name: "Multisig".to_string(),
+                filters: entries,
+                inner_parser: InnerParser::Semantic(SemanticParser::new(SemanticKind::Multisig)?),
+            },
+            FilterType::Depool => Parser {
+                name: "Depool".to_string(),
+                filters: entries,
+                inner_parser: InnerParser::Semantic(SemanticParser::new(SemanticKind::Depool)?),
+            },
+            FilterType::NftTransfer => Parser {
+                name: "NftTransfer".to_string(),
+                filters: entries,
+                inner_parser: InnerParser::Semantic(SemanticParser::new(SemanticKind::Nft)?),
+            },
             FilterType::AnyMessage => Parser {
                 name: "RawMessage".to_string(),
                 filters: entries,
@@ -90,23 +279,109 @@ fn init_all_parsers(config: FilterConfig) -> Result<Vec<Parser>> {
 
 #[derive(Debug, Clone)]
 pub enum InnerParser {
-    Nekoton(nekoton_abi::TransactionParser),
+    /// ABI parsers for all known contract versions
+    Nekoton(Vec<AbiParser>),
     EmptyMessage,
     RawBodyMessageParser,
+    AccountStatusChange,
+    /// Built-in decoders of standard contracts
+    Semantic(SemanticParser),
+}
+
+#[derive(Debug, Clone)]
+pub struct AbiParser {
+    /// Use this parser only for accounts with the specified code hash
+    pub code_hash: Option<UInt256>,
+    pub parser: nekoton_abi::TransactionParser,
 }
 
 impl InnerParser {
-    pub fn parse<'tx>(&'tx self, tx: &'tx ton_block::Transaction) -> Result<Vec<FilteredMessage>> {
+    /// Whether extracted messages can be the inbound message of the transaction
+    pub fn reads_in_message(&self) -> bool {
+        !matches!(self, Self::EmptyMessage)
+    }
+
+    /// Whether extracted messages can be outbound messages of the transaction
+    pub fn reads_out_messages(&self) -> bool {
+        !matches!(self, Self::AccountStatusChange)
+    }
+
+    /// Whether message bodies are decoded by an ABI or a standard contract decoder
+    pub fn decodes_body(&self) -> bool {
+        matches!(self, Self::Nekoton(_) | Self::Semantic(_))
+    }
+
+    pub fn parse<'tx>(
+        &'tx self,
+        tx: &'tx ton_block::Transaction,
+        state: Option<&ShardStateStuff>,
+    ) -> Result<Vec<FilteredMessage>> {
         match self {
-            Self::Nekoton(parser) => parser
-                .parse(tx)
-                .map(|v| v.iter().map(FilteredMessage::from).collect()),
+            Self::Nekoton(parsers) => parse_versioned(parsers, tx, state),
             Self::EmptyMessage => EmptyMessageParser::parse_empty_messages(tx),
             Self::RawBodyMessageParser => RawMessageParser::parse_raw_messages(tx),
+            Self::AccountStatusChange => AccountStatusParser::parse_status_change(tx),
+            Self::Semantic(parser) => parser.parse(tx, state),
         }
     }
 }
 
+/// Try ABI versions in order and return the first non-empty result.
+/// Versions bound to a code hash are skipped if the account state is unknown
+fn parse_versioned(
+    parsers: &[AbiParser],
+    tx: &ton_block::Transaction,
+    state: Option<&ShardStateStuff>,
+) -> Result<Vec<FilteredMessage>> {
+    // Account code hash is read lazily, only once per transaction
+    let mut account_hash: Option<Option<UInt256>> = None;
+    let mut versions = Vec::with_capacity(parsers.len());
+    for AbiParser { code_hash, parser } in parsers {
+        if let Some(code_hash) = code_hash {
+            let Some(state) = state else {
+                continue;
+            };
+            if account_hash.is_none() {
+                let workchain_id = state.shard().workchain_id();
+                account_hash = Some(code_hashes::filter_code_hash(state, workchain_id, &tx.account_addr)?);
+            }
+            if account_hash.as_ref().and_then(Option::as_ref) != Some(code_hash) {
+                continue;
+            }
+        }
+        versions.push(parser);
+    }
+
+    let results = versions.into_iter().map(|parser| {
+        let extracted = parser.parse(tx).map_err(|error| {
+            let error = anyhow::Error::from(error);
+            tracing::debug!("ABI version failed to parse transaction {} of {}: {:?}", tx.lt, tx.account_addr.as_hex_string(), error);
+            error
+        })?;
+        Ok(extracted.iter().map(FilteredMessage::from).collect())
+    });
+    first_parsed(results)
+}
+
+/// First non-empty result of the ABI versions, evaluated lazily. A failed version
+/// is skipped, e.g. an older version decodes historical messages of an upgraded contract,
+/// so an error is returned only if every version failed
+fn first_parsed<T>(results: impl Iterator<Item = Result<Vec<T>>>) -> Result<Vec<T>> {
+    let mut last_error = None;
+    let mut parsed = false;
+    for result in results {
+        match result {
+            Ok(extracted) if !extracted.is_empty() => return Ok(extracted),
+            Ok(_) => parsed = true,
+            Err(error) => last_error = Some(error),
+        }
+    }
+    match last_error {
+        Some(error) if !parsed => Err(error),
+        _ => Ok(Vec::new()),
+    }
+}
+
 pub struct EmptyMessageParser {}
 
 impl EmptyMessageParser{
@@ -132,7 +407,18 @@ impl EmptyMessageParser{
                         tx: tx.clone(),
                         index_in_transaction,
                         contract_name: Default::default(),
-                        filter_name: Default::default()
+                        filter_name: Default::default(),
+                        destination: Default::default(),
+                        tenant: None,
+                        matched_filters: Vec::new(),
+                        track_bounces: false,
+                        trace: false,
+                        function_id: None,
+                        expire_at: None,
+                        tokens: Default::default(),
+                        enrichment: Default::default(),
+                        routing: Default::default(),
+                        payload: Default::default(),
                     }
                 );
             }
@@ -167,7 +453,18 @@ impl RawMessageParser{
                     tx: tx.clone(),
                     index_in_transaction: 0,
                     contract_name: Default::default(),
-                    filter_name: Default::default()
+                    filter_name: Default::default(),
+                    destination: Default::default(),
+                    tenant: None,
+                    matched_filters: Vec::new(),
+                    track_bounces: false,
+                    trace: false,
+                    function_id: None,
+                    expire_at: None,
+                    tokens: Default::default(),
+                    enrichment: Default::default(),
+                    routing: Default::default(),
+                    payload: Default::default(),
                 }
             );
         }
@@ -188,7 +485,18 @@ impl RawMessageParser{
                     tx: tx.clone(),
                     index_in_transaction,
                     contract_name: Default::default(),
-                    filter_name: Default::default()
+                    filter_name: Default::default(),
+                    destination: Default::default(),
+                    tenant: None,
+                    matched_filters: Vec::new(),
+                    track_bounces: false,
+                    trace: false,
+                    function_id: None,
+                    expire_at: None,
+                    tokens: Default::default(),
+                    enrichment: Default::default(),
+                    routing: Default::default(),
+                    payload: Default::default(),
                 }
             );
 
@@ -199,3 +507,89 @@ impl RawMessageParser{
         Ok(output)
     }
 }
+
+// Emits the inbound message of transactions which changed the account status
+pub struct AccountStatusParser {}
+
+impl AccountStatusParser {
+    pub fn parse_status_change(tx: &ton_block::Transaction) -> Result<Vec<FilteredMessage>> {
+        use ton_block::AccountStatus;
+
+        let name = match (&tx.orig_status, &tx.end_status) {
+            (AccountStatus::AccStateUninit | AccountStatus::AccStateNonexist, AccountStatus::AccStateActive) => {
+                ACCOUNT_DEPLOYED
+            }
+            (AccountStatus::AccStateFrozen, AccountStatus::AccStateActive) => ACCOUNT_UNFROZEN,
+            (orig, AccountStatus::AccStateFrozen) if orig != &AccountStatus::AccStateFrozen => ACCOUNT_FROZEN,
+            (orig, AccountStatus::AccStateNonexist) if orig != &AccountStatus::AccStateNonexist => {
+                ACCOUNT_DELETED
+            }
+            _ => return Ok(Vec::new()),
+        };
+
+        // Tick-tock transactions have no inbound message
+        let Some(message) = &tx.in_msg else {
+            return Ok(Vec::new());
+        };
+        let message_hash = message.hash();
+        let message = message.read_struct().context("Failed reading in msg")?;
+        let message_type = message_type_from(message.header(), true);
+
+        Ok(vec![FilteredMessage {
+            name: name.to_string(),
+            message_hash,
+            message,
+            message_type,
+            tx: tx.clone(),
+            index_in_transaction: 0,
+            contract_name: Default::default(),
+            filter_name: Default::default(),
+            destination: Default::default(),
+            tenant: None,
+            matched_filters: Vec::new(),
+            track_bounces: false,
+            trace: false,
+            function_id: None,
+            expire_at: None,
+            tokens: Default::default(),
+            enrichment: Default::default(),
+            routing: Default::default(),
+            payload: Default::default(),
+        }])
+    }
+}
+
+pub const ACCOUNT_DEPLOYED: &str = "deployed";
+pub const ACCOUNT_FROZEN: &str = "frozen";
+pub const ACCOUNT_UNFROZEN: &str = "unfrozen";
+pub const ACCOUNT_DELETED: &str = "deleted";
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{anyhow, Result};
+
+    use super::first_parsed;
+
+    #[test]
+    fn test_first_parsed_skips_failed_versions() {
+        let results: Vec<Result<Vec<u32>>> = vec![Err(anyhow!("Newer version")), Ok(vec![1])];
+        assert_eq!(first_parsed(results.into_iter()).unwrap(), [1]);
+
+        let results: Vec<Result<Vec<u32>>> = vec![Err(anyhow!("Newer version")), Ok(Vec::new())];
+        assert!(first_parsed(results.into_iter()).unwrap().is_empty());
+
+        let results: Vec<Result<Vec<u32>>> = vec![Err(anyhow!("Newer version")), Err(anyhow!("Older version"))];
+        assert_eq!(first_parsed(results.into_iter()).unwrap_err().to_string(), "Older version");
+    }
+
+    #[test]
+    fn test_first_parsed_is_lazy() {
+        let mut evaluated = 0;
+        let results = (0..3).map(|version| {
+            evaluated += 1;
+            Ok(vec![version])
+        });
+        assert_eq!(first_parsed(results).unwrap(), [0]);
+        assert_eq!(evaluated, 1);
+    }
+}
@@ -0,0 +1,277 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::types::{Enrichment, FilteredMessage};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WasmPluginConfig {
+    /// Path to the compiled WASM module
+    pub path: PathBuf,
+    /// Fuel limit for a single invocation. Unlimited if not specified
+    #[serde(default)]
+    pub fuel: Option<u64>,
+}
+
+/// Plugin decision for the extracted message
+#[derive(Debug, Clone)]
+pub enum PluginVerdict {
+    Reject,
+    Accept(Enrichment),
+}
+
+/// User-provided WASM filter/transform stage.
+///
+/// The module must export:
+/// - `memory`
+/// - `alloc(len: i32) -> i32`, which reserves an input buffer
+/// - `filter(ptr: i32, len: i32) -> i64`, which receives the message as a JSON object
+///   and returns a negative value to reject the message, zero to accept it unchanged,
+///   or `(ptr << 32) | len` of a JSON object with fields to attach to the message
+///
+/// Instances are reused between messages. Their memory is restored to the state
+/// right after instantiation once each call is done
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "WasmPluginConfig")]
+pub struct WasmPlugin {
+    config: WasmPluginConfig,
+    #[cfg(feature = "wasm-plugins")]
+    instance_pre: wasmtime::InstancePre<()>,
+    /// Idle instances, one is taken by each worker for a call
+    #[cfg(feature = "wasm-plugins")]
+    instances: std::sync::Arc<std::sync::Mutex<Vec<PluginInstance>>>,
+}
+
+impl std::fmt::Debug for WasmPlugin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WasmPlugin")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+#[cfg(feature = "wasm-plugins")]
+impl TryFrom<WasmPluginConfig> for WasmPlugin {
+    type Error = anyhow::Error;
+
+    fn try_from(config: WasmPluginConfig) -> Result<Self> {
+        use anyhow::Context;
+
+        let mut engine_config = wasmtime::Config::new();
+        engine_config.consume_fuel(config.fuel.is_some());
+        let engine = wasmtime::Engine::new(&engine_config)?;
+
+        let module = wasmtime::Module::from_file(&engine, &config.path)
+            .with_context(|| format!("Failed to load WASM plugin {}", config.path.display()))?;
+        let instance_pre = wasmtime::Linker::new(&engine).instantiate_pre(&module)?;
+
+        // Checks the exports right away, the instance is kept for the first call
+        let instance = PluginInstance::new(&instance_pre, config.fuel)
+            .with_context(|| format!("Invalid WASM plugin {}", config.path.display()))?;
+
+        Ok(Self {
+            config,
+            instance_pre,
+            instances: std::sync::Arc::new(std::sync::Mutex::new(vec![instance])),
+        })
+    }
+}
+
+#[cfg(not(feature = "wasm-plugins"))]
+impl TryFrom<WasmPluginConfig> for WasmPlugin {
+    type Error = anyhow::Error;
+
+    fn try_from(config: WasmPluginConfig) -> Result<Self> {
+        anyhow::bail!(
+            "Unable to load WASM plugin {}: built without `wasm-plugins` feature",
+            config.path.display()
+        )
+    }
+}
+
+impl WasmPlugin {
+    #[cfg(feature = "wasm-plugins")]
+    pub fn apply(&self, message: &FilteredMessage) -> Result<PluginVerdict> {
+        let input = serde_json::to_vec(&message_to_json(message))?;
+        self.call(&input)
+    }
+
+    /// Runs the plugin on a JSON input with an idle instance or a new one.
+    /// Instances which failed are dropped
+    #[cfg(feature = "wasm-plugins")]
+    fn call(&self, input: &[u8]) -> Result<PluginVerdict> {
+        let idle = self.instances.lock().unwrap().pop();
+        let mut instance = match idle {
+            Some(instance) => instance,
+            None => PluginInstance::new(&self.instance_pre, self.config.fuel)?,
+        };
+
+        let verdict = instance.call(input)?;
+        instance.reset(self.config.fuel)?;
+        self.instances.lock().unwrap().push(instance);
+        Ok(verdict)
+    }
+
+    #[cfg(not(feature = "wasm-plugins"))]
+    pub fn apply(&self, _: &FilteredMessage) -> Result<PluginVerdict> {
+        Ok(PluginVerdict::Accept(Default::default()))
+    }
+}
+
+#[cfg(feature = "wasm-plugins")]
+struct PluginInstance {
+    store: wasmtime::Store<()>,
+    memory: wasmtime::Memory,
+    alloc: wasmtime::TypedFunc<i32, i32>,
+    filter: wasmtime::TypedFunc<(i32, i32), i64>,
+    /// Memory right after instantiation
+    snapshot: Vec<u8>,
+    /// Total fuel added to the store
+    fuel_added: u64,
+}
+
+#[cfg(feature = "wasm-plugins")]
+impl PluginInstance {
+    fn new(instance_pre: &wasmtime::InstancePre<()>, fuel: Option<u64>) -> Result<Self> {
+        use anyhow::Context;
+
+        let mut store = wasmtime::Store::new(instance_pre.module().engine(), ());
+        let fuel_added = fuel.unwrap_or_default();
+        if let Some(fuel) = fuel {
+            store.add_fuel(fuel)?;
+        }
+
+        let instance = instance_pre.instantiate(&mut store)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .context("WASM plugin doesn't export memory")?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+        let filter = instance.get_typed_func::<(i32, i32), i64>(&mut store, "filter")?;
+        let snapshot = memory.data(&store).to_vec();
+
+        let mut instance = Self {
+            store,
+            memory,
+            alloc,
+            filter,
+            snapshot,
+            fuel_added,
+        };
+        instance.reset(fuel)?;
+        Ok(instance)
+    }
+
+    fn call(&mut self, input: &[u8]) -> Result<PluginVerdict> {
+        let ptr = self.alloc.call(&mut self.store, input.len() as i32)?;
+        self.memory.write(&mut self.store, ptr as usize, input)?;
+
+        let verdict = match self.filter.call(&mut self.store, (ptr, input.len() as i32))? {
+            result if result < 0 => PluginVerdict::Reject,
+            0 => PluginVerdict::Accept(Default::default()),
+            result => {
+                let ptr = (result >> 32) as usize;
+                let len = (result & 0xffff_ffff) as usize;
+                let mut output = vec![0; len];
+                self.memory.read(&self.store, ptr, &mut output)?;
+                PluginVerdict::Accept(serde_json::from_slice(&output)?)
+            }
+        };
+        Ok(verdict)
+    }
+
+    /// Restores the memory and tops the fuel up to the limit
+    fn reset(&mut self, fuel: Option<u64>) -> Result<()> {
+        self.memory.data_mut(&mut self.store)[..self.snapshot.len()].copy_from_slice(&self.snapshot);
+
+        if let Some(fuel) = fuel {
+            let consumed = self.store.fuel_consumed().unwrap_or_default();
+            let remaining = self.fuel_added.saturating_sub(consumed);
+            let refill = fuel.saturating_sub(remaining);
+            self.store.add_fuel(refill)?;
+            self.fuel_added += refill;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "wasm-plugins")]
+fn message_to_json(message: &FilteredMessage) -> serde_json::Value {
+    use ton_block::GetRepresentationHash;
+
+    use super::expression::tokens_to_value;
+
+    serde_json::json!({
+        "name": message.name,
+        "type": message.message_type.as_str(),
+        "message_hash": message.message_hash.to_hex_string(),
+        "src": message.message.src_ref().map(ToString::to_string),
+        "dst": message.message.dst_ref().map(ToString::to_string),
+        "value": message.message.get_value().map(|value| value.grams.as_u128().to_string()),
+        "tx": {
+            "hash": message.tx.hash().unwrap_or_default().to_hex_string(),
+            "lt": message.tx.lt,
+            "now": message.tx.now,
+        },
+        "index_in_transaction": message.index_in_transaction,
+        "contract_name": message.contract_name,
+        "filter_name": message.filter_name,
+        "args": tokens_to_value(&message.tokens).to_json(),
+    })
+}
+
+#[cfg(all(test, feature = "wasm-plugins"))]
+mod tests {
+    use super::*;
+
+    fn plugin(name: &str, fuel: Option<u64>) -> Result<WasmPlugin> {
+        WasmPlugin::try_from(WasmPluginConfig {
+            path: PathBuf::from(format!("./test/plugins/{name}.wat")),
+            fuel,
+        })
+    }
+
+    fn enrichment(verdict: PluginVerdict) -> Option<Enrichment> {
+        match verdict {
+            PluginVerdict::Accept(enrichment) => Some(enrichment),
+            PluginVerdict::Reject => None,
+        }
+    }
+
+    #[test]
+    fn test_return_convention() {
+        let plugin = plugin("counter", None).unwrap();
+
+        assert_eq!(enrichment(plugin.call(b"{}").unwrap()), Some(Enrichment::new()));
+        assert_eq!(enrichment(plugin.call(b"null").unwrap()), None);
+
+        let expected = Enrichment::from([("calls".to_string(), serde_json::json!(1))]);
+        assert_eq!(enrichment(plugin.call(br#"{"a":1}"#).unwrap()), Some(expected));
+    }
+
+    #[test]
+    fn test_instance_reuse() {
+        let plugin = plugin("counter", Some(1000)).unwrap();
+        let expected = Enrichment::from([("calls".to_string(), serde_json::json!(1))]);
+
+        // Each call sees the initial memory and full fuel of the same instance
+        for _ in 0..200 {
+            assert_eq!(enrichment(plugin.call(br#"{"a":1}"#).unwrap()), Some(expected.clone()));
+        }
+        assert_eq!(plugin.instances.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_malformed_plugin() {
+        assert!(plugin("no_filter", None).is_err());
+        assert!(plugin("missing", None).is_err());
+
+        let plugin = plugin("malformed", None).unwrap();
+        assert!(plugin.call(b"{}").is_err());
+        assert!(plugin.instances.lock().unwrap().is_empty());
+
+        assert!(plugin.call(b"null").is_err());
+        assert!(plugin.call(br#"{"a":1}"#).is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use anyhow::Result;
+
+use crate::types::{DepoolOperation, FilteredMessage, SemanticPayload};
+
+use super::{find_u64, find_uint};
+
+pub const DEPOOL_ADD_STAKE: &str = "add_stake";
+pub const DEPOOL_WITHDRAW: &str = "withdraw";
+pub const DEPOOL_WITHDRAW_ALL: &str = "withdraw_all";
+pub const DEPOOL_STAKE_ACCEPTED: &str = "round_stake_accepted";
+pub const DEPOOL_STAKE_REJECTED: &str = "round_stake_rejected";
+
+/// Participant calls and elector related events of the DePool contract
+pub const ABI: &str = r#"{
+    "ABI version": 2,
+    "header": ["time", "expire"],
+    "functions": [
+        {
+            "name": "addOrdinaryStake",
+            "inputs": [
+                {"name": "stake", "type": "uint64"}
+            ],
+            "outputs": []
+        },
+        {
+            "name": "withdrawFromPoolingRound",
+            "inputs": [
+                {"name": "withdrawValue", "type": "uint64"}
+            ],
+            "outputs": []
+        },
+        {
+            "name": "withdrawPart",
+            "inputs": [
+                {"name": "withdrawValue", "type": "uint64"}
+            ],
+            "outputs": []
+        },
+        {
+            "name": "withdrawAll",
+            "inputs": [],
+            "outputs": []
+        }
+    ],
+    "data": [],
+    "events": [
+        {
+            "name": "RoundStakeIsAccepted",
+            "inputs": [
+                {"name": "queryId", "type": "uint64"},
+                {"name": "comment", "type": "uint32"}
+            ]
+        },
+        {
+            "name": "RoundStakeIsRejected",
+            "inputs": [
+                {"name": "queryId", "type": "uint64"},
+                {"name": "comment", "type": "uint32"}
+            ]
+        }
+    ]
+}"#;
+
+pub fn decode(message: &FilteredMessage) -> Result<Option<SemanticPayload>> {
+    let tokens = &message.tokens;
+    let participant = || message.message.src_ref().cloned();
+    let operation = match message.name.as_str() {
+        "addOrdinaryStake" => DepoolOperation {
+            action: DEPOOL_ADD_STAKE,
+            participant: participant(),
+            amount: find_uint(tokens, "stake"),
+            query_id: None,
+        },
+        "withdrawFromPoolingRound" | "withdrawPart" => DepoolOperation {
+            action: DEPOOL_WITHDRAW,
+            participant: participant(),
+            amount: find_uint(tokens, "withdrawValue"),
+            query_id: None,
+        },
+        "withdrawAll" => DepoolOperation {
+            action: DEPOOL_WITHDRAW_ALL,
+            participant: participant(),
+            amount: None,
+            query_id: None,
+        },
+        "RoundStakeIsAccepted" => DepoolOperation {
+            action: DEPOOL_STAKE_ACCEPTED,
+            participant: None,
+            amount: None,
+            query_id: find_u64(tokens, "queryId"),
+        },
+        "RoundStakeIsRejected" => DepoolOperation {
+            action: DEPOOL_STAKE_REJECTED,
+            participant: None,
+            amount: None,
+            query_id: find_u64(tokens, "queryId"),
+        },
+        _ => return Ok(None),
+    };
+    Ok(Some(SemanticPayload::Depool(operation)))
+}
@@ -0,0 +1,135 @@
+use anyhow::Result;
+use num_bigint::BigUint;
+use ton_abi::{Token, TokenValue};
+use ton_block::{MsgAddress, MsgAddressInt};
+use ton_indexer::utils::ShardStateStuff;
+
+use crate::types::{FilteredMessage, SemanticPayload};
+
+mod depool;
+mod multisig;
+mod nft;
+mod tip3;
+mod token_metadata;
+
+/// Standard contracts recognized by the semantic decoders
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SemanticKind {
+    Tip3,
+    Multisig,
+    Depool,
+    Nft,
+}
+
+impl SemanticKind {
+    pub const ALL: [SemanticKind; 4] = [Self::Tip3, Self::Multisig, Self::Depool, Self::Nft];
+
+    fn abi(&self) -> &'static str {
+        match self {
+            Self::Tip3 => tip3::ABI,
+            Self::Multisig => multisig::ABI,
+            Self::Depool => depool::ABI,
+            Self::Nft => nft::ABI,
+        }
+    }
+
+    /// Normalized message names produced by the decoder
+    pub fn message_names(&self) -> &'static [&'static str] {
+        match self {
+            Self::Tip3 => &[tip3::TOKEN_TRANSFER, tip3::TOKEN_MINT, tip3::TOKEN_BURN],
+            Self::Multisig => &[
+                multisig::MULTISIG_SEND,
+                multisig::MULTISIG_SUBMIT,
+                multisig::MULTISIG_CONFIRM,
+            ],
+            Self::Depool => &[
+                depool::DEPOOL_ADD_STAKE,
+                depool::DEPOOL_WITHDRAW,
+                depool::DEPOOL_WITHDRAW_ALL,
+                depool::DEPOOL_STAKE_ACCEPTED,
+                depool::DEPOOL_STAKE_REJECTED,
+            ],
+            Self::Nft => &[nft::NFT_MINT, nft::NFT_TRANSFER, nft::NFT_BURN],
+        }
+    }
+
+    fn decode(
+        &self,
+        message: &FilteredMessage,
+        state: Option<&ShardStateStuff>,
+    ) -> Result<Option<SemanticPayload>> {
+        match self {
+            Self::Tip3 => tip3::decode(message, state),
+            Self::Multisig => multisig::decode(message),
+            Self::Depool => depool::decode(message),
+            Self::Nft => nft::decode(message),
+        }
+    }
+}
+
+/// Decodes messages of standard contracts with a built-in ABI
+/// and replaces raw calls with normalized [`SemanticPayload`] records
+#[derive(Debug, Clone)]
+pub struct SemanticParser {
+    kind: SemanticKind,
+    parser: nekoton_abi::TransactionParser,
+}
+
+impl SemanticParser {
+    pub fn new(kind: SemanticKind) -> Result<Self> {
+        let abi = ton_abi::Contract::load(kind.abi())?;
+        let functions = abi.functions.into_values().collect::<Vec<_>>();
+        let events = abi.events.into_values().collect::<Vec<_>>();
+        let parser = nekoton_abi::TransactionParser::builder()
+            .function_in_list(functions, false)
+            .events_list(events)
+            .build_with_external_in()?;
+        Ok(Self { kind, parser })
+    }
+
+    /// Parser of the functions and events of the built-in ABI
+    pub fn transaction_parser(&self) -> &nekoton_abi::TransactionParser {
+        &self.parser
+    }
+
+    pub fn parse(
+        &self,
+        tx: &ton_block::Transaction,
+        state: Option<&ShardStateStuff>,
+    ) -> Result<Vec<FilteredMessage>> {
+        let mut output = Vec::new();
+        for ext in self.parser.parse(tx)?.iter() {
+            let mut message = FilteredMessage::from(ext);
+            let Some(payload) = self.kind.decode(&message, state)? else {
+                continue;
+            };
+            message.name = payload.name().to_string();
+            message.payload = Some(payload);
+            output.push(message);
+        }
+        Ok(output)
+    }
+}
+
+fn find_token<'a>(tokens: &'a [Token], name: &str) -> Option<&'a TokenValue> {
+    tokens.iter().find(|token| token.name == name).map(|token| &token.value)
+}
+
+fn find_uint(tokens: &[Token], name: &str) -> Option<BigUint> {
+    match find_token(tokens, name)? {
+        TokenValue::Uint(value) => Some(value.number.clone()),
+        _ => None,
+    }
+}
+
+fn find_u64(tokens: &[Token], name: &str) -> Option<u64> {
+    find_uint(tokens, name).and_then(|value| u64::try_from(value).ok())
+}
+
+fn find_address(tokens: &[Token], name: &str) -> Option<MsgAddressInt> {
+    match find_token(tokens, name)? {
+        TokenValue::Address(MsgAddress::AddrStd(address)) => Some(MsgAddressInt::AddrStd(address.clone())),
+        TokenValue::Address(MsgAddress::AddrVar(address)) => Some(MsgAddressInt::AddrVar(address.clone())),
+        _ => None,
+    }
+}
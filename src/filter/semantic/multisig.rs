@@ -0,0 +1,76 @@
+use anyhow::Result;
+
+use crate::types::{FilteredMessage, MultisigTransaction, SemanticPayload};
+
+use super::{find_address, find_u64, find_uint};
+
+pub const MULTISIG_SEND: &str = "send";
+pub const MULTISIG_SUBMIT: &str = "submit";
+pub const MULTISIG_CONFIRM: &str = "confirm";
+
+/// Custodian calls of SafeMultisig / SetcodeMultisig wallets
+pub const ABI: &str = r#"{
+    "ABI version": 2,
+    "header": ["pubkey", "time", "expire"],
+    "functions": [
+        {
+            "name": "sendTransaction",
+            "inputs": [
+                {"name": "dest", "type": "address"},
+                {"name": "value", "type": "uint128"},
+                {"name": "bounce", "type": "bool"},
+                {"name": "flags", "type": "uint8"},
+                {"name": "payload", "type": "cell"}
+            ],
+            "outputs": []
+        },
+        {
+            "name": "submitTransaction",
+            "inputs": [
+                {"name": "dest", "type": "address"},
+                {"name": "value", "type": "uint128"},
+                {"name": "bounce", "type": "bool"},
+                {"name": "allBalance", "type": "bool"},
+                {"name": "payload", "type": "cell"}
+            ],
+            "outputs": [
+                {"name": "transId", "type": "uint64"}
+            ]
+        },
+        {
+            "name": "confirmTransaction",
+            "inputs": [
+                {"name": "transactionId", "type": "uint64"}
+            ],
+            "outputs": []
+        }
+    ],
+    "data": [],
+    "events": []
+}"#;
+
+pub fn decode(message: &FilteredMessage) -> Result<Option<SemanticPayload>> {
+    let tokens = &message.tokens;
+    let transaction = match message.name.as_str() {
+        "sendTransaction" => MultisigTransaction {
+            action: MULTISIG_SEND,
+            transaction_id: None,
+            dest: find_address(tokens, "dest"),
+            value: find_uint(tokens, "value"),
+        },
+        "submitTransaction" => MultisigTransaction {
+            action: MULTISIG_SUBMIT,
+            transaction_id: None,
+            dest: find_address(tokens, "dest"),
+            value: find_uint(tokens, "value"),
+        },
+        "confirmTransaction" => MultisigTransaction {
+            action: MULTISIG_CONFIRM,
+            transaction_id: find_u64(tokens, "transactionId"),
+            dest: None,
+            value: None,
+        },
+        _ => return Ok(None),
+    };
+    Ok(Some(SemanticPayload::Multisig(transaction)))
+}
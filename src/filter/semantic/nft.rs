@@ -0,0 +1,80 @@
+use anyhow::Result;
+
+use crate::types::{FilteredMessage, NftTransfer, SemanticPayload};
+
+use super::{find_address, find_uint};
+
+pub const NFT_MINT: &str = "mint";
+pub const NFT_TRANSFER: &str = "transfer";
+pub const NFT_BURN: &str = "burn";
+
+/// TIP-4.1 collection and NFT events
+pub const ABI: &str = r#"{
+    "ABI version": 2,
+    "version": "2.2",
+    "header": ["pubkey", "time", "expire"],
+    "functions": [],
+    "data": [],
+    "events": [
+        {
+            "name": "NftCreated",
+            "inputs": [
+                {"name": "id", "type": "uint256"},
+                {"name": "nft", "type": "address"},
+                {"name": "owner", "type": "address"},
+                {"name": "manager", "type": "address"},
+                {"name": "creator", "type": "address"}
+            ]
+        },
+        {
+            "name": "NftBurned",
+            "inputs": [
+                {"name": "id", "type": "uint256"},
+                {"name": "nft", "type": "address"},
+                {"name": "owner", "type": "address"},
+                {"name": "manager", "type": "address"}
+            ]
+        },
+        {
+            "name": "OwnerChanged",
+            "inputs": [
+                {"name": "oldOwner", "type": "address"},
+                {"name": "newOwner", "type": "address"}
+            ]
+        }
+    ]
+}"#;
+
+pub fn decode(message: &FilteredMessage) -> Result<Option<SemanticPayload>> {
+    let tokens = &message.tokens;
+    // Events are emitted either by the collection or by the NFT itself
+    let emitter = || message.message.src_ref().cloned();
+    let transfer = match message.name.as_str() {
+        "NftCreated" => NftTransfer {
+            kind: NFT_MINT,
+            collection: emitter(),
+            nft: find_address(tokens, "nft"),
+            id: find_uint(tokens, "id"),
+            from_owner: None,
+            to_owner: find_address(tokens, "owner"),
+        },
+        "NftBurned" => NftTransfer {
+            kind: NFT_BURN,
+            collection: emitter(),
+            nft: find_address(tokens, "nft"),
+            id: find_uint(tokens, "id"),
+            from_owner: find_address(tokens, "owner"),
+            to_owner: None,
+        },
+        "OwnerChanged" => NftTransfer {
+            kind: NFT_TRANSFER,
+            collection: None,
+            nft: emitter(),
+            id: None,
+            from_owner: find_address(tokens, "oldOwner"),
+            to_owner: find_address(tokens, "newOwner"),
+        },
+        _ => return Ok(None),
+    };
+    Ok(Some(SemanticPayload::Nft(transfer)))
+}
@@ -0,0 +1,252 @@
+use anyhow::Result;
+use ton_block::{Deserializable, MsgAddressInt};
+use ton_indexer::utils::ShardStateStuff;
+use ton_types::{AccountId, SliceData};
+
+use crate::filter::utils::account_data;
+use crate::types::{FilteredMessage, SemanticPayload, TokenTransfer};
+
+use super::token_metadata::token_metadata;
+use super::{find_address, find_uint};
+
+pub const TOKEN_TRANSFER: &str = "transfer";
+pub const TOKEN_MINT: &str = "mint";
+pub const TOKEN_BURN: &str = "burn";
+
+/// Inbound functions of TIP-3.1 token wallets and roots, which finalize token movements
+pub const ABI: &str = r#"{
+    "ABI version": 2,
+    "version": "2.2",
+    "header": ["pubkey", "time", "expire"],
+    "functions": [
+        {
+            "name": "acceptTransfer",
+            "id": "0x67A0B95F",
+            "inputs": [
+                {"name": "amount", "type": "uint128"},
+                {"name": "sender", "type": "address"},
+                {"name": "remainingGasTo", "type": "address"},
+                {"name": "notify", "type": "bool"},
+                {"name": "payload", "type": "cell"}
+            ],
+            "outputs": []
+        },
+        {
+            "name": "acceptMint",
+            "id": "0x4384F298",
+            "inputs": [
+                {"name": "amount", "type": "uint128"},
+                {"name": "remainingGasTo", "type": "address"},
+                {"name": "notify", "type": "bool"},
+                {"name": "payload", "type": "cell"}
+            ],
+            "outputs": []
+        },
+        {
+            "name": "acceptBurn",
+            "id": "0x192B51B1",
+            "inputs": [
+                {"name": "amount", "type": "uint128"},
+                {"name": "walletOwner", "type": "address"},
+                {"name": "remainingGasTo", "type": "address"},
+                {"name": "callbackTo", "type": "address"},
+                {"name": "payload", "type": "cell"}
+            ],
+            "outputs": []
+        }
+    ],
+    "data": [],
+    "events": []
+}"#;
+
+/// Wallet owners and token roots, which are not present in the message bodies,
+/// are read from the wallet state, so they are known only when the shard state is available.
+/// So is the metadata of the root
+pub fn decode(
+    message: &FilteredMessage,
+    state: Option<&ShardStateStuff>,
+) -> Result<Option<SemanticPayload>> {
+    let Some(amount) = find_uint(&message.tokens, "amount") else {
+        return Ok(None);
+    };
+
+    // Reads the receiving wallet only if needed
+    let wallet = || -> Result<Option<(MsgAddressInt, MsgAddressInt)>> {
+        match state {
+            Some(state) => wallet_root_and_owner(state, &message.tx.account_addr),
+            None => Ok(None),
+        }
+    };
+
+    let mut transfer = match message.name.as_str() {
+        "acceptTransfer" => {
+            let wallet = wallet()?;
+            TokenTransfer {
+                kind: TOKEN_TRANSFER,
+                token_root: wallet.as_ref().map(|(root, _)| root.clone()),
+                from_owner: find_address(&message.tokens, "sender"),
+                to_owner: wallet.map(|(_, owner)| owner),
+                amount,
+                symbol: None,
+                decimals: None,
+                amount_normalized: None,
+            }
+        }
+        "acceptMint" => TokenTransfer {
+            kind: TOKEN_MINT,
+            token_root: message.message.src_ref().cloned(),
+            from_owner: None,
+            to_owner: wallet()?.map(|(_, owner)| owner),
+            amount,
+            symbol: None,
+            decimals: None,
+            amount_normalized: None,
+        },
+        "acceptBurn" => TokenTransfer {
+            kind: TOKEN_BURN,
+            token_root: message.message.dst_ref().cloned(),
+            from_owner: find_address(&message.tokens, "walletOwner"),
+            to_owner: None,
+            amount,
+            symbol: None,
+            decimals: None,
+            amount_normalized: None,
+        },
+        _ => return Ok(None),
+    };
+    if let (Some(state), Some(root)) = (state, &transfer.token_root) {
+        if let Some(metadata) = token_metadata(state, root, message.tx.now) {
+            transfer.symbol = Some(metadata.symbol);
+            transfer.decimals = Some(metadata.decimals);
+        }
+    }
+    Ok(Some(SemanticPayload::TokenTransfer(transfer)))
+}
+
+/// Reads `root_` and `owner_` fields of the TIP-3.1 reference wallet
+fn wallet_root_and_owner(
+    state: &ShardStateStuff,
+    account_id: &AccountId,
+) -> Result<Option<(MsgAddressInt, MsgAddressInt)>> {
+    let Some(data) = account_data(state, account_id)? else {
+        return Ok(None);
+    };
+    let mut slice = SliceData::load_cell(data)?;
+    // Skip `_pubkey`, `_timestamp` and `_constructorFlag`
+    slice.move_by(256 + 64 + 1)?;
+    let root = MsgAddressInt::construct_from(&mut slice)?;
+    let owner = MsgAddressInt::construct_from(&mut slice)?;
+    Ok(Some((root, owner)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use num_bigint::BigUint;
+    use ton_abi::{Token, TokenValue, Uint};
+    use ton_block::{CurrencyCollection, InternalMessageHeader, Message, MsgAddress};
+    use ton_types::UInt256;
+
+    use crate::types::MessageType;
+    use super::*;
+
+    const ROOT: &str = "0:1ef42a3c649061ba446f2d5ae5219380573c78de3541fe67c742ead0cae68d0d";
+    const WALLET: &str = "0:e6f7da94405c55c9fb14b5be6b8f91bba1be76e678900ecb418499bfe37ada05";
+    const OWNER: &str = "0:0000000000000000000000000000000000000000000000000000000000000001";
+
+    fn address(address: &str) -> MsgAddressInt {
+        MsgAddressInt::from_str(address).unwrap()
+    }
+
+    fn address_token(name: &str, address: &str) -> Token {
+        let address = match self::address(address) {
+            MsgAddressInt::AddrStd(address) => MsgAddress::AddrStd(address),
+            MsgAddressInt::AddrVar(address) => MsgAddress::AddrVar(address),
+        };
+        Token::new(name, TokenValue::Address(address))
+    }
+
+    fn amount_token(amount: u128) -> Token {
+        Token::new("amount", TokenValue::Uint(Uint::new(amount, 128)))
+    }
+
+    fn message(name: &str, src: &str, dst: &str, tokens: Vec<Token>) -> FilteredMessage {
+        let header = InternalMessageHeader::with_addresses(address(src), address(dst), CurrencyCollection::default());
+        FilteredMessage {
+            name: name.to_string(),
+            message_hash: UInt256::default(),
+            message: Message::with_int_header(header),
+            message_type: MessageType::InternalInbound,
+            tx: Default::default(),
+            index_in_transaction: 0,
+            contract_name: Default::default(),
+            filter_name: Default::default(),
+            destination: Default::default(),
+            tenant: None,
+            matched_filters: Vec::new(),
+            track_bounces: false,
+            trace: false,
+            function_id: None,
+            expire_at: None,
+            tokens,
+            enrichment: Default::default(),
+            routing: Default::default(),
+            payload: Default::default(),
+        }
+    }
+
+    fn transfer(message: &FilteredMessage) -> TokenTransfer {
+        match decode(message, None).unwrap() {
+            Some(SemanticPayload::TokenTransfer(transfer)) => transfer,
+            payload => panic!("Unexpected payload: {payload:?}"),
+        }
+    }
+
+    #[test]
+    fn test_decode_transfer() {
+        let tokens = vec![amount_token(1_000_000_000), address_token("sender", OWNER)];
+        let transfer = transfer(&message("acceptTransfer", WALLET, WALLET, tokens));
+        assert_eq!(transfer.kind, TOKEN_TRANSFER);
+        assert_eq!(transfer.amount, BigUint::from(1_000_000_000u64));
+        assert_eq!(transfer.from_owner, Some(address(OWNER)));
+        // Known only from the wallet state
+        assert_eq!(transfer.token_root, None);
+        assert_eq!(transfer.to_owner, None);
+        assert_eq!(transfer.symbol, None);
+    }
+
+    #[test]
+    fn test_decode_mint_and_burn() {
+        let mint = transfer(&message("acceptMint", ROOT, WALLET, vec![amount_token(5)]));
+        assert_eq!(mint.kind, TOKEN_MINT);
+        assert_eq!(mint.token_root, Some(address(ROOT)));
+        assert_eq!(mint.from_owner, None);
+
+        let tokens = vec![amount_token(7), address_token("walletOwner", OWNER)];
+        let burn = transfer(&message("acceptBurn", WALLET, ROOT, tokens));
+        assert_eq!(burn.kind, TOKEN_BURN);
+        assert_eq!(burn.token_root, Some(address(ROOT)));
+        assert_eq!(burn.from_owner, Some(address(OWNER)));
+        assert_eq!(burn.to_owner, None);
+        assert_eq!(burn.amount, BigUint::from(7u32));
+    }
+
+    #[test]
+    fn test_decode_other_messages() {
+        let unknown = message("transfer", WALLET, WALLET, vec![amount_token(1)]);
+        assert_eq!(decode(&unknown, None).unwrap(), None);
+
+        // Amount is required
+        let tokens = vec![address_token("sender", OWNER)];
+        assert_eq!(decode(&message("acceptTransfer", WALLET, WALLET, tokens), None).unwrap(), None);
+    }
+
+    #[test]
+    fn test_abi() {
+        let abi = ton_abi::Contract::load(ABI).unwrap();
+        for name in ["acceptTransfer", "acceptMint", "acceptBurn"] {
+            assert!(abi.function(name).is_ok(), "{name}");
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::sync::{Mutex, OnceLock};
+
+use anyhow::{Context, Result};
+use rustc_hash::FxHashMap;
+use ton_abi::{Token, TokenValue};
+use ton_block::MsgAddressInt;
+use ton_indexer::utils::ShardStateStuff;
+use ton_types::UInt256;
+
+use crate::filter::account_key;
+use crate::filter::getters::run_get_method;
+
+/// The cache is cleared once it holds this many roots
+const CACHE_CAPACITY: usize = 10_000;
+
+/// Responsible get-methods of TIP-3.1 token roots
+const ROOT_ABI: &str = r#"{
+    "ABI version": 2,
+    "version": "2.2",
+    "header": ["pubkey", "time", "expire"],
+    "functions": [
+        {
+            "name": "symbol",
+            "inputs": [{"name": "answerId", "type": "uint32"}],
+            "outputs": [{"name": "value0", "type": "string"}]
+        },
+        {
+            "name": "decimals",
+            "inputs": [{"name": "answerId", "type": "uint32"}],
+            "outputs": [{"name": "value0", "type": "uint8"}]
+        }
+    ],
+    "data": [],
+    "events": []
+}"#;
+
+static ROOT_FUNCTIONS: OnceLock<Option<RootFunctions>> = OnceLock::new();
+/// Metadata by root, `None` for accounts which are not token roots
+static CACHE: OnceLock<Mutex<FxHashMap<(i32, UInt256), Option<TokenMetadata>>>> = OnceLock::new();
+
+struct RootFunctions {
+    symbol: ton_abi::Function,
+    decimals: ton_abi::Function,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenMetadata {
+    pub symbol: String,
+    pub decimals: u8,
+}
+
+/// Symbol and decimals of the token root, read once from the state of its shard.
+/// `None` until a block of the root shard is handled with its state, or if it is not a token root
+pub fn token_metadata(state: &ShardStateStuff, root: &MsgAddressInt, now: u32) -> Option<TokenMetadata> {
+    let key = account_key(root.workchain_id(), &root.address());
+    let cache = CACHE.get_or_init(Default::default);
+    if let Some(metadata) = cache.lock().unwrap().get(&key) {
+        return metadata.clone();
+    }
+
+    // Roots of other shards are not in the state
+    let shard = state.shard();
+    if shard.workchain_id() != root.workchain_id() || !shard.contains_account(root.address()).unwrap_or(false) {
+        return None;
+    }
+    let metadata = match read_metadata(state, root, now) {
+        Ok(Some(metadata)) => Some(metadata),
+        // Not deployed yet
+        Ok(None) => return None,
+        Err(error) => {
+            tracing::debug!("Token metadata of {}: {:?}", root, error);
+            None
+        }
+    };
+
+    let mut cache = cache.lock().unwrap();
+    if cache.len() >= CACHE_CAPACITY {
+        cache.clear();
+    }
+    cache.insert(key, metadata.clone());
+    metadata
+}
+
+fn read_metadata(state: &ShardStateStuff, root: &MsgAddressInt, now: u32) -> Result<Option<TokenMetadata>> {
+    let functions = ROOT_FUNCTIONS
+        .get_or_init(|| {
+            root_functions()
+                .map_err(|error| tracing::error!("Loading token root ABI: {:?}", error))
+                .ok()
+        })
+        .as_ref()
+        .context("No token root ABI")?;
+
+    let Some(symbol) = run_get_method(&functions.symbol, true, state, root, now)? else {
+        return Ok(None);
+    };
+    let Some(decimals) = run_get_method(&functions.decimals, true, state, root, now)? else {
+        return Ok(None);
+    };
+    parse_metadata(&symbol, &decimals).map(Some)
+}
+
+/// Metadata from the outputs of the `symbol` and `decimals` get-methods
+fn parse_metadata(symbol: &[Token], decimals: &[Token]) -> Result<TokenMetadata> {
+    let symbol = match symbol.first().map(|token| &token.value) {
+        Some(TokenValue::String(symbol)) => symbol.clone(),
+        _ => anyhow::bail!("Invalid symbol output"),
+    };
+    let decimals = match decimals.first().map(|token| &token.value) {
+        Some(TokenValue::Uint(decimals)) => u8::try_from(&decimals.number).context("Invalid decimals")?,
+        _ => anyhow::bail!("Invalid decimals output"),
+    };
+    Ok(TokenMetadata { symbol, decimals })
+}
+
+fn root_functions() -> Result<RootFunctions> {
+    let abi = ton_abi::Contract::load(ROOT_ABI)?;
+    Ok(RootFunctions {
+        symbol: abi.function("symbol")?.clone(),
+        decimals: abi.function("decimals")?.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use ton_abi::Uint;
+
+    use super::*;
+
+    fn output(value: TokenValue) -> Vec<Token> {
+        vec![Token::new("value0", value)]
+    }
+
+    #[test]
+    fn test_root_functions() {
+        let functions = root_functions().unwrap();
+        assert_eq!(functions.symbol.outputs.len(), 1);
+        assert_eq!(functions.decimals.outputs.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_metadata() {
+        let symbol = output(TokenValue::String("WVENOM".to_string()));
+        let decimals = output(TokenValue::Uint(Uint::new(9, 8)));
+        let expected = TokenMetadata {
+            symbol: "WVENOM".to_string(),
+            decimals: 9,
+        };
+        assert_eq!(parse_metadata(&symbol, &decimals).unwrap(), expected);
+
+        assert!(parse_metadata(&[], &decimals).is_err());
+        assert!(parse_metadata(&decimals, &decimals).is_err());
+        let too_many = output(TokenValue::Uint(Uint::new(256, 16)));
+        assert!(parse_metadata(&symbol, &too_many).is_err());
+    }
+}
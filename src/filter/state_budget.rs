@@ -0,0 +1,173 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use rustc_hash::FxHashMap;
+use serde::Deserialize;
+use ton_block::BlockIdExt;
+use ton_indexer::utils::ShardStateStuff;
+
+/// Time budget of the shard state reads of code hash filters in a block
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StateReadBudget {
+    /// Reads of a block are skipped once they took this long. Default: 200
+    pub block_budget_ms: u64,
+    /// The breaker opens after this many blocks in a row ran out of the budget. Default: 3
+    pub trip_after_blocks: u32,
+    /// While open, state reads are skipped. Default: 60
+    pub open_sec: u64,
+}
+
+impl Default for StateReadBudget {
+    fn default() -> Self {
+        Self {
+            block_budget_ms: 200,
+            trip_after_blocks: 3,
+            open_sec: 60,
+        }
+    }
+}
+
+static BUDGET: OnceLock<Option<StateReadBudget>> = OnceLock::new();
+static BREAKER: OnceLock<Breaker> = OnceLock::new();
+
+#[derive(Default)]
+struct Breaker {
+    /// Read time of the blocks being processed, by the block of the state
+    spent: Mutex<FxHashMap<BlockIdExt, BlockSpend>>,
+    /// Blocks in a row which ran out of the budget
+    exhausted_blocks: AtomicU32,
+    open_until: Mutex<Option<Instant>>,
+    open: AtomicBool,
+    skipped_reads: AtomicU64,
+    trips: AtomicU64,
+}
+
+#[derive(Default)]
+struct BlockSpend {
+    spent: Duration,
+    exhausted: bool,
+}
+
+/// Counters of the degraded code hash filters
+#[derive(Debug, Clone, Copy)]
+pub struct StateBudgetStats {
+    pub open: bool,
+    pub skipped_reads: u64,
+    pub trips: u64,
+}
+
+pub(super) fn init(budget: Option<StateReadBudget>) -> Result<()> {
+    BUDGET
+        .set(budget)
+        .map_err(|_| anyhow::anyhow!("Unable to initialize state read budget"))
+}
+
+fn breaker() -> &'static Breaker {
+    BREAKER.get_or_init(Default::default)
+}
+
+/// Runs a read of the state after the block unless the breaker is open or the block ran out
+/// of its budget. Returns `None` if the read was skipped
+pub(super) fn read_state<T>(block_id: &BlockIdExt, read: impl FnOnce() -> Result<T>) -> Result<Option<T>> {
+    let Some(budget) = BUDGET.get().copied().flatten() else {
+        return read().map(Some);
+    };
+    let breaker = breaker();
+    if breaker.is_open() {
+        breaker.skipped_reads.fetch_add(1, Ordering::Relaxed);
+        return Ok(None);
+    }
+    let block_budget = Duration::from_millis(budget.block_budget_ms);
+    let exhausted = breaker
+        .spent
+        .lock()
+        .unwrap()
+        .get(block_id)
+        .map_or(false, |block| block.exhausted);
+    if exhausted {
+        breaker.skipped_reads.fetch_add(1, Ordering::Relaxed);
+        return Ok(None);
+    }
+
+    let started = Instant::now();
+    let result = read();
+    let elapsed = started.elapsed();
+
+    let mut spent = breaker.spent.lock().unwrap();
+    let block = spent.entry(block_id.clone()).or_default();
+    block.spent += elapsed;
+    if block.spent > block_budget && !block.exhausted {
+        block.exhausted = true;
+        tracing::warn!(
+            "State reads of block {} took {:?}, code hash filters are skipped for the rest of it",
+            block_id,
+            block.spent
+        );
+    }
+    result.map(Some)
+}
+
+/// Ends the accounting of the block with this state, opens the breaker if blocks
+/// keep running out of the budget
+pub fn finish_block(state: &ShardStateStuff) {
+    let Some(budget) = BUDGET.get().copied().flatten() else {
+        return;
+    };
+    let breaker = breaker();
+    let exhausted = breaker
+        .spent
+        .lock()
+        .unwrap()
+        .remove(state.block_id())
+        .map_or(false, |block| block.exhausted);
+    if !exhausted {
+        breaker.exhausted_blocks.store(0, Ordering::Relaxed);
+        return;
+    }
+
+    let exhausted_blocks = breaker.exhausted_blocks.fetch_add(1, Ordering::Relaxed) + 1;
+    if exhausted_blocks >= budget.trip_after_blocks.max(1) {
+        // One more exhausted block after the breaker closes opens it again
+        breaker
+            .exhausted_blocks
+            .store(budget.trip_after_blocks.saturating_sub(1), Ordering::Relaxed);
+        *breaker.open_until.lock().unwrap() = Some(Instant::now() + Duration::from_secs(budget.open_sec));
+        breaker.open.store(true, Ordering::Relaxed);
+        breaker.trips.fetch_add(1, Ordering::Relaxed);
+        tracing::warn!(
+            "{} blocks in a row ran out of the state read budget, code hash filters are degraded for {} s",
+            exhausted_blocks,
+            budget.open_sec
+        );
+    }
+}
+
+impl Breaker {
+    fn is_open(&self) -> bool {
+        if !self.open.load(Ordering::Relaxed) {
+            return false;
+        }
+        let mut open_until = self.open_until.lock().unwrap();
+        match *open_until {
+            Some(until) if Instant::now() < until => true,
+            _ => {
+                *open_until = None;
+                self.open.store(false, Ordering::Relaxed);
+                tracing::warn!("State reads are resumed, code hash filters are restored");
+                false
+            }
+        }
+    }
+}
+
+pub fn state_budget_stats() -> StateBudgetStats {
+    let breaker = breaker();
+    StateBudgetStats {
+        open: breaker.open.load(Ordering::Relaxed),
+        skipped_reads: breaker.skipped_reads.load(Ordering::Relaxed),
+        trips: breaker.trips.load(Ordering::Relaxed),
+    }
+}
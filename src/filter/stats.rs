@@ -0,0 +1,47 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::parser::try_get_parsers;
+
+/// Number of messages emitted by a filter entry
+#[derive(Debug, Default)]
+pub struct MatchCounter(AtomicU64);
+
+impl MatchCounter {
+    pub fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Clone for MatchCounter {
+    fn clone(&self) -> Self {
+        Self(AtomicU64::new(self.get()))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FilterStats {
+    pub tenant: Option<String>,
+    pub contract_name: String,
+    pub filter_name: String,
+    pub matched: u64,
+}
+
+/// Returns the number of emitted messages for every filter entry
+pub fn match_stats() -> Vec<FilterStats> {
+    try_get_parsers()
+        .into_iter()
+        .flatten()
+        .flat_map(|parser| {
+            parser.filters.iter().map(|filter| FilterStats {
+                tenant: filter.tenant.clone(),
+                contract_name: parser.name.clone(),
+                filter_name: filter.name.clone(),
+                matched: filter.matched.get(),
+            })
+        })
+        .collect()
+}
@@ -1,6 +1,8 @@
 use std::str::FromStr;
 
 use serde::Deserialize;
+use ton_indexer::utils::ShardStateStuff;
+use ton_types::{AccountId, Cell, SliceData, UInt256};
 
 pub fn deserialize_from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
 where 
@@ -11,3 +13,68 @@ where
     let s: String = Deserialize::deserialize(deserializer)?;
     FromStr::from_str(&s).map_err(serde::de::Error::custom)
 }
+
+pub fn deserialize_optional_from_str<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    let s: Option<String> = Deserialize::deserialize(deserializer)?;
+    s.map(|s| FromStr::from_str(&s).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// Read state and get account's code hash
+pub fn account_code_hash(
+    state: &ShardStateStuff,
+    account_id: &AccountId,
+) -> anyhow::Result<Option<UInt256>> {
+    let shard_accounts = state.state().read_accounts()?;
+    let Some(account) = shard_accounts.account(account_id)? else {
+        tracing::trace!(
+            "account_code_hash: account not found in the shard: {}",
+            state.shard()
+        );
+        return Ok(None);
+    };
+    let account = account.read_account()?;
+    Ok(account.get_code_hash())
+}
+
+/// Read state and get account's data
+pub fn account_data(
+    state: &ShardStateStuff,
+    account_id: &AccountId,
+) -> anyhow::Result<Option<Cell>> {
+    let shard_accounts = state.state().read_accounts()?;
+    let Some(account) = shard_accounts.account(account_id)? else {
+        return Ok(None);
+    };
+    Ok(account.read_account()?.get_data())
+}
+
+/// Upper bound on cells in a body tree, shared subtrees are counted each time
+const MAX_TREE_CELLS: usize = 8192;
+
+/// Generic JSON tree of the slice: `data` as hex, number of `bits` and nested `refs`
+pub fn cell_tree(slice: &SliceData) -> anyhow::Result<serde_json::Value> {
+    let mut cells = 0;
+    slice_tree(slice, &mut cells)
+}
+
+fn slice_tree(slice: &SliceData, cells: &mut usize) -> anyhow::Result<serde_json::Value> {
+    *cells += 1;
+    anyhow::ensure!(*cells <= MAX_TREE_CELLS, "Cell tree has more than {MAX_TREE_CELLS} cells");
+
+    let mut refs = Vec::with_capacity(slice.remaining_references());
+    for index in 0..slice.remaining_references() {
+        let cell = slice.reference(index)?;
+        refs.push(slice_tree(&SliceData::load_cell(cell)?, cells)?);
+    }
+    Ok(serde_json::json!({
+        "data": hex::encode(slice.get_bytestring(0)),
+        "bits": slice.remaining_bits(),
+        "refs": refs,
+    }))
+}
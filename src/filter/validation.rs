@@ -0,0 +1,185 @@
+use std::collections::HashSet;
+
+use anyhow::Context;
+use ton_types::UInt256;
+
+use super::abi::ContractAbi;
+use super::config::{AddressOrCodeHash, FilterConfig, FilterEntry, FilterRecord, FilterType};
+use super::parser::{ACCOUNT_DELETED, ACCOUNT_DEPLOYED, ACCOUNT_FROZEN, ACCOUNT_UNFROZEN};
+use super::semantic::SemanticKind;
+
+/// Checks the filter config without initializing parsers and returns all found problems.
+/// Remote ABIs must be fetched beforehand
+pub fn validate_filters(config: &FilterConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    validate_records(None, &config.message_filters, &mut problems);
+
+    let mut tenant_names = HashSet::new();
+    let mut destinations = config
+        .message_filters
+        .iter()
+        .flat_map(|record| &record.entries)
+        .filter_map(|entry| entry.destination.as_deref())
+        .collect::<HashSet<_>>();
+    for tenant in &config.tenants {
+        if !tenant_names.insert(tenant.name.as_str()) {
+            problems.push(format!("Duplicate tenant name: {}", tenant.name));
+        }
+        if tenant.destination.is_empty() {
+            problems.push(format!("{}: empty destination", tenant.name));
+        } else if !destinations.insert(tenant.destination.as_str()) {
+            problems.push(format!("{}: destination {} is already used", tenant.name, tenant.destination));
+        }
+        for entry in tenant.message_filters.iter().flat_map(|record| &record.entries) {
+            if entry.destination.is_some() {
+                problems.push(format!("{} / {}: destination is set by the tenant", tenant.name, entry.name));
+            }
+        }
+        validate_records(Some(&tenant.name), &tenant.message_filters, &mut problems);
+    }
+
+    for filter in &config.account_filters {
+        validate_address_or_code_hash(&filter.name, "account", &filter.account, &mut problems);
+    }
+    for filter in &config.account_change_filters {
+        for account in &filter.accounts {
+            validate_address_or_code_hash(&filter.name, "accounts", account, &mut problems);
+        }
+    }
+
+    let filter_names = config
+        .message_filters
+        .iter()
+        .chain(config.tenants.iter().flat_map(|tenant| &tenant.message_filters))
+        .flat_map(|record| &record.entries)
+        .map(|entry| entry.name.as_str())
+        .collect::<HashSet<_>>();
+    for rule in &config.alerts {
+        if !filter_names.contains(rule.filter.as_str()) {
+            problems.push(format!("{}: unknown filter `{}`", rule.name, rule.filter));
+        }
+    }
+
+    let range = &config.range;
+    if matches!((range.start_utime, range.end_utime), (Some(start), Some(end)) if start > end) {
+        problems.push("range: start_utime is greater than end_utime".to_string());
+    }
+    if matches!((range.start_lt, range.end_lt), (Some(start), Some(end)) if start > end) {
+        problems.push("range: start_lt is greater than end_lt".to_string());
+    }
+
+    problems
+}
+
+/// Checks message filters of the node or a tenant, contract names are unique within them
+fn validate_records(tenant: Option<&str>, records: &[FilterRecord], problems: &mut Vec<String>) {
+    let scoped = |name: &str| match tenant {
+        Some(tenant) => format!("{tenant} / {name}"),
+        None => name.to_string(),
+    };
+    let mut contract_names = HashSet::new();
+    for record in records {
+        let (record_name, known_names) = match &record.filter_type {
+            FilterType::Contract { name, abi, .. } => {
+                if !contract_names.insert(name.as_str()) {
+                    problems.push(format!("Duplicate contract name: {}", scoped(name)));
+                }
+                let mut names = HashSet::new();
+                let versions = abi.clone().context("No ABI").and_then(ContractAbi::into_versions);
+                let versions = versions.unwrap_or_else(|error| {
+                    problems.push(format!("{}: {error:#}", scoped(name)));
+                    Vec::new()
+                });
+                for (index, version) in versions.into_iter().enumerate() {
+                    let contract = version
+                        .abi
+                        .read()
+                        .and_then(|abi| Ok(ton_abi::Contract::load(&abi)?));
+                    match contract {
+                        Ok(contract) => {
+                            names.extend(contract.functions.into_keys());
+                            names.extend(contract.events.into_keys());
+                        }
+                        Err(error) => {
+                            problems.push(format!("{}: failed to load ABI version {index}: {error:#}", scoped(name)))
+                        }
+                    }
+                }
+                (name.clone(), names)
+            }
+            FilterType::AccountStatusChange => (
+                "account_status_change".to_string(),
+                to_names(&[ACCOUNT_DEPLOYED, ACCOUNT_FROZEN, ACCOUNT_UNFROZEN, ACCOUNT_DELETED]),
+            ),
+            FilterType::Tip3Transfer => ("tip3_transfer".to_string(), to_names(SemanticKind::Tip3.message_names())),
+            FilterType::Multisig => ("multisig".to_string(), to_names(SemanticKind::Multisig.message_names())),
+            FilterType::Depool => ("depool".to_string(), to_names(SemanticKind::Depool.message_names())),
+            FilterType::NftTransfer => ("nft_transfer".to_string(), to_names(SemanticKind::Nft.message_names())),
+            // Messages of these filters have no names
+            FilterType::NativeTransfer => ("native_transfer".to_string(), HashSet::new()),
+            FilterType::AnyMessage => ("any_message".to_string(), HashSet::new()),
+        };
+        let record_name = scoped(&record_name);
+
+        for entry in &record.entries {
+            validate_entry(&record_name, entry, &known_names, problems);
+            if entry.body_tree && !matches!(record.filter_type, FilterType::AnyMessage) {
+                problems.push(format!("{record_name} / {}: body_tree is supported only by any_message filters", entry.name));
+            }
+        }
+    }
+}
+
+fn validate_entry(
+    record_name: &str,
+    entry: &FilterEntry,
+    known_names: &HashSet<String>,
+    problems: &mut Vec<String>,
+) {
+    let name = format!("{record_name} / {}", entry.name);
+
+    if let Some(message) = &entry.message {
+        if !known_names.contains(&message.message_name) {
+            problems.push(format!("{name}: unknown message `{}`", message.message_name));
+        }
+    }
+    if let Some(sender) = &entry.sender {
+        validate_address_or_code_hash(&name, "sender", sender, problems);
+    }
+    if let Some(receiver) = &entry.receiver {
+        validate_address_or_code_hash(&name, "receiver", receiver, problems);
+    }
+    if let Some(sample_rate) = entry.sample_rate {
+        if !(0.0..=1.0).contains(&sample_rate) {
+            problems.push(format!("{name}: sample_rate must be within 0.0 and 1.0"));
+        }
+    }
+    if entry.aggregate.as_ref().map_or(false, |aggregation| aggregation.window_sec == 0) {
+        problems.push(format!("{name}: aggregate window_sec must be positive"));
+    }
+}
+
+fn validate_address_or_code_hash(
+    name: &str,
+    field: &str,
+    value: &AddressOrCodeHash,
+    problems: &mut Vec<String>,
+) {
+    match value {
+        AddressOrCodeHash::Address(address) => {
+            if !matches!(address.workchain_id(), 0 | -1) {
+                problems.push(format!("{name}: {field} has unknown workchain: {address}"));
+            }
+        }
+        AddressOrCodeHash::CodeHash(code_hash) => {
+            if code_hash == &UInt256::default() {
+                problems.push(format!("{name}: {field} has empty code hash"));
+            }
+        }
+    }
+}
+
+fn to_names(names: &[&str]) -> HashSet<String> {
+    names.iter().map(|name| name.to_string()).collect()
+}
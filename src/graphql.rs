@@ -0,0 +1,336 @@
+//! GraphQL server with a `messages` subscription and a `status` query
+
+use anyhow::Result;
+
+use crate::blocks_handler::BlocksHandler;
+use crate::config::GraphqlConfig;
+
+/// Starts the server and subscribes it to the filtered messages of the handler
+#[cfg(feature = "graphql")]
+pub fn serve(handler: BlocksHandler, config: &GraphqlConfig) -> Result<BlocksHandler> {
+    let (sender, _) = tokio::sync::broadcast::channel(config.capacity);
+    let status = std::sync::Arc::new(server::Status::new(handler.producer.clone()));
+    server::start(config.listen_address, sender.clone(), status.clone());
+
+    Ok(handler.with_observer(std::sync::Arc::new(move |message: crate::types::SerializeMessage| {
+        status.published.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        // Fails only without subscriptions
+        sender.send(std::sync::Arc::new(message)).ok();
+    })))
+}
+
+#[cfg(not(feature = "graphql"))]
+pub fn serve(_: BlocksHandler, config: &GraphqlConfig) -> Result<BlocksHandler> {
+    anyhow::bail!(
+        "Unable to start GraphQL server on {}: built without `graphql` feature",
+        config.listen_address
+    )
+}
+
+#[cfg(feature = "graphql")]
+mod server {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    use async_graphql::http::{GraphiQLSource, WebSocket, WebSocketProtocols, WsMessage};
+    use async_graphql::{Context, EmptyMutation, InputObject, Json, Object, Schema, Subscription};
+    use futures_util::{future, SinkExt, Stream, StreamExt};
+    use hyper::header::{self, HeaderValue};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Method, Request, Response, Server, StatusCode};
+    use tokio::sync::broadcast::Sender;
+    use tokio_stream::wrappers::BroadcastStream;
+    use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+    use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame, Role};
+    use tokio_tungstenite::tungstenite::Message as WsFrame;
+    use tokio_tungstenite::WebSocketStream;
+
+    use crate::producer::Producer;
+    use crate::types::SerializeMessage;
+
+    type MessageSender = Sender<Arc<SerializeMessage>>;
+    type FusionSchema = Schema<Query, EmptyMutation, Subscription>;
+
+    pub struct Status {
+        producer: Producer,
+        started_at: Instant,
+        /// Messages passed to the subscriptions
+        pub published: AtomicU64,
+    }
+
+    impl Status {
+        pub fn new(producer: Producer) -> Self {
+            Self {
+                producer,
+                started_at: Instant::now(),
+                published: AtomicU64::new(0),
+            }
+        }
+    }
+
+    pub fn start(listen_address: SocketAddr, sender: MessageSender, status: Arc<Status>) {
+        let schema = Schema::build(Query, EmptyMutation, Subscription)
+            .data(sender)
+            .data(status)
+            .finish();
+
+        tokio::spawn(async move {
+            tracing::info!("Starting GraphQL server on: {}", listen_address);
+
+            let make_service = make_service_fn(move |_| {
+                let schema = schema.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req| {
+                        let schema = schema.clone();
+                        async move { Ok::<_, Infallible>(handle_request(schema, req).await) }
+                    }))
+                }
+            });
+            if let Err(error) = Server::bind(&listen_address).serve(make_service).await {
+                tracing::error!("GraphQL server: {}", error);
+            }
+        });
+    }
+
+    async fn handle_request(schema: FusionSchema, req: Request<Body>) -> Response<Body> {
+        if req.uri().path() != "/graphql" {
+            return response(StatusCode::NOT_FOUND, Body::empty());
+        }
+        match *req.method() {
+            Method::GET if req.headers().contains_key(header::SEC_WEBSOCKET_KEY) => subscribe(schema, req),
+            Method::GET => {
+                let page = GraphiQLSource::build()
+                    .endpoint("/graphql")
+                    .subscription_endpoint("/graphql")
+                    .finish();
+                response(StatusCode::OK, Body::from(page))
+            }
+            Method::POST => {
+                let request = hyper::body::to_bytes(req.into_body())
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|body| Ok(serde_json::from_slice::<async_graphql::Request>(&body)?));
+                match request {
+                    Ok(request) => {
+                        let result = schema.execute(request).await;
+                        let body = serde_json::to_vec(&result).unwrap_or_default();
+                        response(StatusCode::OK, Body::from(body))
+                    }
+                    Err(error) => response(StatusCode::BAD_REQUEST, Body::from(format!("{error:?}"))),
+                }
+            }
+            _ => response(StatusCode::METHOD_NOT_ALLOWED, Body::empty()),
+        }
+    }
+
+    /// Upgrades the request to a websocket running `graphql-ws` or `graphql-transport-ws`
+    fn subscribe(schema: FusionSchema, req: Request<Body>) -> Response<Body> {
+        let protocol = req
+            .headers()
+            .get(header::SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| {
+                value
+                    .split(',')
+                    .find_map(|protocol| WebSocketProtocols::from_str(protocol.trim()).ok())
+            });
+        let Some(protocol) = protocol else {
+            return response(StatusCode::BAD_REQUEST, Body::from("Unsupported websocket protocol"));
+        };
+        let Some(key) = req.headers().get(header::SEC_WEBSOCKET_KEY) else {
+            return response(StatusCode::BAD_REQUEST, Body::empty());
+        };
+        let accept = derive_accept_key(key.as_bytes());
+
+        tokio::spawn(async move {
+            let upgraded = match hyper::upgrade::on(req).await {
+                Ok(upgraded) => upgraded,
+                Err(error) => {
+                    tracing::warn!("GraphQL websocket upgrade: {}", error);
+                    return;
+                }
+            };
+            let socket = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+            let (mut sink, stream) = socket.split();
+
+            let input = stream
+                .take_while(|frame| future::ready(frame.is_ok()))
+                .filter_map(|frame| {
+                    future::ready(match frame {
+                        Ok(WsFrame::Text(text)) => Some(text.into_bytes()),
+                        Ok(WsFrame::Binary(data)) => Some(data),
+                        _ => None,
+                    })
+                });
+            let mut output = WebSocket::new(schema, input, protocol).map(|message| match message {
+                WsMessage::Text(text) => WsFrame::Text(text),
+                WsMessage::Close(code, reason) => WsFrame::Close(Some(CloseFrame {
+                    code: CloseCode::from(code),
+                    reason: reason.into(),
+                })),
+            });
+            while let Some(frame) = output.next().await {
+                if sink.send(frame).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut response = response(StatusCode::SWITCHING_PROTOCOLS, Body::empty());
+        let headers = response.headers_mut();
+        headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+        headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+        headers.insert(header::SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(protocol.sec_websocket_protocol()));
+        if let Ok(accept) = HeaderValue::from_str(&accept) {
+            headers.insert(header::SEC_WEBSOCKET_ACCEPT, accept);
+        }
+        response
+    }
+
+    fn response(status: StatusCode, body: Body) -> Response<Body> {
+        let mut response = Response::new(body);
+        *response.status_mut() = status;
+        response
+    }
+
+    pub struct Query;
+
+    #[Object]
+    impl Query {
+        /// Producer version, uptime and delivery counters
+        async fn status(&self, ctx: &Context<'_>) -> ProducerStatus {
+            let status = ctx.data_unchecked::<Arc<Status>>();
+            let sender = ctx.data_unchecked::<MessageSender>();
+            ProducerStatus {
+                version: env!("CARGO_PKG_VERSION").to_owned(),
+                uptime_sec: status.started_at.elapsed().as_secs(),
+                published_messages: status.published.load(Ordering::Relaxed),
+                subscriptions: sender.receiver_count(),
+                http_subscribers: status.producer.subscribers().map(|subscribers| subscribers.len()),
+            }
+        }
+    }
+
+    #[derive(async_graphql::SimpleObject)]
+    pub struct ProducerStatus {
+        version: String,
+        uptime_sec: u64,
+        /// Messages passed to the GraphQL subscriptions
+        published_messages: u64,
+        /// Open GraphQL subscriptions
+        subscriptions: usize,
+        /// Open subscriptions of the http/2 transport
+        http_subscribers: Option<usize>,
+    }
+
+    pub struct Subscription;
+
+    #[Subscription]
+    impl Subscription {
+        /// Filtered messages, optionally narrowed down further.
+        /// Messages are skipped if the subscription lags behind
+        async fn messages(
+            &self,
+            ctx: &Context<'_>,
+            filter: Option<MessageFilterInput>,
+        ) -> impl Stream<Item = MessageRecord> {
+            let receiver = ctx.data_unchecked::<MessageSender>().subscribe();
+            let filter = filter.unwrap_or_default();
+            BroadcastStream::new(receiver).filter_map(move |message| {
+                let message = message.ok().filter(|message| filter.matches(message));
+                future::ready(message.map(MessageRecord))
+            })
+        }
+    }
+
+    /// Conditions on the message, all of the specified ones must hold
+    #[derive(Default, InputObject)]
+    pub struct MessageFilterInput {
+        contract_name: Option<String>,
+        filter_name: Option<String>,
+        /// Sender address, e.g. `0:...`
+        src: Option<String>,
+        /// Receiver address, e.g. `0:...`
+        dst: Option<String>,
+    }
+
+    impl MessageFilterInput {
+        fn matches(&self, message: &SerializeMessage) -> bool {
+            let src = message.message.src_ref().map(ToString::to_string);
+            let dst = message.message.dst_ref().map(ToString::to_string);
+            matches_field(&self.contract_name, Some(&message.contract_name))
+                && matches_field(&self.filter_name, Some(&message.filter_name))
+                && matches_field(&self.src, src.as_ref())
+                && matches_field(&self.dst, dst.as_ref())
+        }
+    }
+
+    fn matches_field(expected: &Option<String>, value: Option<&String>) -> bool {
+        expected.is_none() || expected.as_ref() == value
+    }
+
+    pub struct MessageRecord(Arc<SerializeMessage>);
+
+    #[Object]
+    impl MessageRecord {
+        async fn message_hash(&self) -> String {
+            self.0.message_hash.to_hex_string()
+        }
+
+        async fn message_type(&self) -> String {
+            serde_json::to_value(&self.0.message_type)
+                .ok()
+                .and_then(|value| value.as_str().map(ToOwned::to_owned))
+                .unwrap_or_default()
+        }
+
+        async fn contract_name(&self) -> &str {
+            &self.0.contract_name
+        }
+
+        async fn filter_name(&self) -> &str {
+            &self.0.filter_name
+        }
+
+        async fn src(&self) -> Option<String> {
+            self.0.message.src_ref().map(ToString::to_string)
+        }
+
+        async fn dst(&self) -> Option<String> {
+            self.0.message.dst_ref().map(ToString::to_string)
+        }
+
+        async fn block_id(&self) -> String {
+            self.0.block_id.to_hex_string()
+        }
+
+        async fn workchain_id(&self) -> i32 {
+            self.0.workchain_id
+        }
+
+        async fn block_seqno(&self) -> u32 {
+            self.0.block_seqno
+        }
+
+        async fn mc_seqno(&self) -> Option<u32> {
+            self.0.mc_seqno
+        }
+
+        async fn transaction_id(&self) -> String {
+            self.0.transaction_id.to_hex_string()
+        }
+
+        async fn transaction_timestamp(&self) -> u32 {
+            self.0.transaction_timestamp
+        }
+
+        /// The whole record as in the JSON serializer
+        async fn record(&self) -> Json<serde_json::Value> {
+            Json(serde_json::to_value(&*self.0).unwrap_or_default())
+        }
+    }
+}
@@ -1,8 +1,18 @@
+pub mod address_book;
 pub mod archive;
 pub mod data_scanner;
 pub mod blocks_handler;
 pub mod config;
+pub mod control;
+pub mod error;
 pub mod filter;
+pub mod graphql;
+pub mod message_store;
+mod metrics;
 pub mod serializer;
 pub mod types;
 pub mod producer;
+pub mod service;
+pub mod testing;
+
+pub use service::{FusionProducer, FusionProducerBuilder, FusionProducerHandle};
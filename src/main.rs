@@ -1,25 +1,33 @@
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
 use argh::FromArgs;
 use broxus_util::alloc::profiling;
-use everscale_rpc_server::RpcState;
+use futures_util::future::{FusedFuture, FutureExt};
+use futures_util::Future;
 use is_terminal::IsTerminal;
-use pomfrit::formatter::*;
+use ton_block::{Deserializable, GetRepresentationHash};
+use ton_indexer::utils::ShardStateStuff;
 use tracing_subscriber::EnvFilter;
 
-use fusion_producer::filter::init_parsers;
+use fusion_producer::filter::{
+    config::TransactionRange, expression::tokens_to_value, filter_transaction, init_parsers, stats::match_stats,
+    validate_filters,
+};
 use fusion_producer::{
-    blocks_handler::BlocksHandler,
     config::*,
     data_scanner::{
-        archives_scanner::*,
+        account_snapshot::export_accounts,
         network_scanner::*,
-        s3_scanner::S3Scanner,
-        test_scanner::TestScanner
+        synthetic_state::shard_state_from_accounts,
+        test_scanner::fixture_transactions,
     },
-    producer::Producer,
+    producer::{read_frames, Producer, ResendFormat, Transport},
+    serializer::{Serializer, SCHEMAS},
+    FusionProducerBuilder,
 };
 
 #[global_allocator]
@@ -40,161 +48,376 @@ async fn main() -> Result<()> {
 
     let any_signal = broxus_util::any_signal(broxus_util::TERMINATION_SIGNALS);
 
-    let app = broxus_util::read_args_with_version!(_);
-    let run = run(app);
+    let app: App = broxus_util::read_args_with_version!(_);
+    if let Some(format) = &app.print_schema {
+        return print_schema(format);
+    }
+    anyhow::ensure!(app.prune || !app.prune_archives, "`--prune-archives` requires `--prune`");
+    let dry_run = app.dry_run;
+    let result = run(app, any_signal).await;
 
-    tokio::select! {
-        result = run => result,
-        signal = any_signal => {
-            if let Ok(signal) = signal {
-                tracing::warn!(?signal, "received termination signal, flushing state...");
-            }
-            // NOTE: engine future is safely dropped here so rocksdb method
-            // `rocksdb_close` is called in DB object destructor
-            Ok(())
-        }
+    if dry_run {
+        print_match_stats();
     }
+    result
 }
 
-async fn run(app: App) -> Result<()> {
+async fn run<S, E>(app: App, shutdown: impl Future<Output = Result<S, E>>) -> Result<()>
+where
+    S: std::fmt::Debug,
+{
     tracing::info!(version = env!("CARGO_PKG_VERSION"));
 
-    let config: AppConfig = broxus_util::read_config(app.config)?;
+    let mut config: AppConfig = broxus_util::read_config(&app.config)?;
+    if let Some(path) = &app.resend {
+        return resend(path, app.resend_format, config.serializer.is_json(), config.transport).await;
+    }
     countme::enable(true);
 
-    init_parsers(config.filter_config)?;
-    let serializer = config.serializer;
-    let producer = Producer::new(config.transport)?;
-    let handler = Arc::new(BlocksHandler::new(serializer, producer)?);
+    if app.validate_config || app.test_filter.is_some() || app.bench {
+        return check_filters(&app, config).await;
+    }
+
+    if app.prune {
+        if let Some(node_config) = config.scan_type.node_config_mut() {
+            node_config.retention = StorageRetention::Minimal;
+        }
+    }
+    let keep_serving = matches!(config.scan_type, ScanType::TestJson { .. });
+
+    let mut builder = FusionProducerBuilder::from_config(config);
+    if let Some(path) = &app.global_config {
+        let global_config =
+            ton_indexer::GlobalConfig::from_file(path).context("Failed to open global config")?;
+        builder = builder.global_config(global_config);
+    }
+    if app.dry_run {
+        tracing::warn!("dry run, data is not sent");
+        builder = builder.transport(Transport::Counting);
+    }
+    if let Some(from_seqno) = app.replay_from_seqno {
+        builder = builder.replay(from_seqno, app.to);
+    }
+    tracing::info!("initializing producer");
+    let producer = builder.build().await.context("Failed to initialize producer")?;
 
     tokio::spawn(memory_profiler());
-    match config.scan_type {
-        ScanType::FromNetwork { node_config } => {
-            let panicked = Arc::new(AtomicBool::default());
-            let orig_hook = std::panic::take_hook();
-            std::panic::set_hook({
-                let panicked = panicked.clone();
-                Box::new(move |panic_info| {
-                    panicked.store(true, Ordering::Release);
-                    orig_hook(panic_info);
-                })
-            });
-
-            let global_config = ton_indexer::GlobalConfig::from_file(
-                &app.global_config.context("Global config not found")?,
-            )
-            .context("Failed to open global config")?;
-
-            tracing::info!("initializing producer");
-
-            let rpc_state = config
-                .rpc_config
-                .map(RpcState::new)
-                .transpose()
-                .context("Failed to create server state")?
-                .map(Arc::new);
-
-            let engine = NetworkScanner::new(
-                node_config,
-                global_config,
-                handler,
-                rpc_state.clone(),
-            )
-            .await
-            .context("Failed to create engine")?;
-            if app.run_compaction {
-                tracing::warn!("compacting database");
-                engine.indexer().trigger_compaction().await;
-                return Ok(());
-            }
 
-            if app.print_memory_usage {
-                print_disk_usage_stats(&engine);
+    if app.run_compaction || app.prune || app.print_memory_usage || app.export_accounts.is_some() {
+        let engine = producer.open_node().await.context("Failed to create engine")?;
+        return maintain_node(&app, &engine).await;
+    }
 
-                return Ok(());
-            }
+    let mut shutdown = std::pin::pin!(async move {
+        if let Ok(signal) = shutdown.await {
+            tracing::warn!(?signal, "received termination signal, flushing state...");
+        }
+        // NOTE: engine future is safely dropped here so rocksdb method
+        // `rocksdb_close` is called in DB object destructor
+    }
+    .fuse());
+    producer.run_until(shutdown.as_mut()).await?;
 
-            let (_exporter, metrics_writer) =
-                pomfrit::create_exporter(config.metrics_settings).await?;
-
-            metrics_writer.spawn({
-                let rpc_state = rpc_state.clone();
-                let engine = engine.clone();
-                move |buf| {
-                    buf.write(Metrics {
-                        rpc_state: rpc_state.as_deref(),
-                        engine: &engine,
-                        panicked: &panicked,
-                    });
-                }
-            });
-            tracing::info!("initialized exporter");
-
-            engine.start().await.context("Failed to start engine")?;
-            tracing::info!("initialized engine");
-
-            if let Some(rpc_state) = rpc_state {
-                rpc_state.initialize(engine.indexer()).await?;
-                tokio::spawn(rpc_state.serve()?);
-                tracing::info!("initialized RPC");
-            }
+    if keep_serving && !shutdown.is_terminated() {
+        // Consumers can still read the test blocks from the transport
+        shutdown.await;
+    }
+    Ok(())
+}
 
-            tracing::info!("initialized producer");
-            futures_util::future::pending().await
+/// Loads the filters for `--validate-config`, `--test-filter` and `--bench`
+async fn check_filters(app: &App, config: AppConfig) -> Result<()> {
+    let mut filter_config = config.filter_config;
+    filter_config
+        .load_includes()
+        .context("Failed to load filter files")?;
+    filter_config
+        .fetch_remote_abis()
+        .await
+        .context("Failed to fetch remote ABIs")?;
+
+    if app.validate_config {
+        let problems = validate_filters(&filter_config);
+        if problems.is_empty() {
+            println!("Config is valid");
+            return Ok(());
         }
-        ScanType::FromArchives { list_path } => {
-            let scanner = ArchivesScanner::new(handler.clone(), list_path)
-                .context("Failed to create scanner")?;
+        for problem in &problems {
+            println!("{problem}");
+        }
+        anyhow::bail!("Found {} config problems", problems.len());
+    }
+
+    let range = filter_config.range;
+    init_parsers(filter_config)?;
 
-            scanner.run().await.context("Failed to scan archives")
+    if let Some(transaction) = &app.test_filter {
+        return test_filter(transaction, app.test_account.as_deref(), &range);
+    }
+    bench(app.bench_input.as_deref(), app.bench_iterations, &config.serializer, &range).await
+}
+
+/// Compacts, prunes, measures or exports the node database without following the network
+async fn maintain_node(app: &App, engine: &Arc<NetworkScanner>) -> Result<()> {
+    if app.run_compaction {
+        tracing::warn!("compacting database");
+        engine.indexer().trigger_compaction().await;
+        return Ok(());
+    }
+
+    if app.prune {
+        tracing::warn!("pruning database");
+        print_disk_usage_stats(engine);
+        prune_storage(engine.indexer(), app.prune_archives)
+            .await
+            .context("Failed to prune database")?;
+        engine.indexer().trigger_compaction().await;
+        print_disk_usage_stats(engine);
+        return Ok(());
+    }
+
+    if app.print_memory_usage {
+        print_disk_usage_stats(engine);
+        return Ok(());
+    }
+
+    if let Some(path) = &app.export_accounts {
+        let snapshot = export_accounts(engine.indexer()).await.context("Failed to export accounts")?;
+        let file = std::fs::File::create(path).context("Failed to create accounts file")?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), &snapshot)
+            .context("Failed to write accounts")?;
+        for filter in &snapshot.filters {
+            println!("{}: {} accounts", filter.filter_name, filter.accounts.len());
         }
-        ScanType::FromS3(scanner_config) => {
-            let scanner = S3Scanner::new(scanner_config, handler.clone())
-                .await
-                .context("Failed to create scanner")?;
+    }
+    Ok(())
+}
 
-            scanner.run().await.context("Failed to scan archives")
+/// Run filters over a single transaction and print the matched messages
+fn test_filter(transaction: &str, account: Option<&str>, range: &TransactionRange) -> Result<()> {
+    let transaction = ton_block::Transaction::construct_from_bytes(&read_boc(transaction)?)
+        .context("Failed to parse transaction")?;
+    let state = account
+        .map(|account| single_account_state(&transaction, account))
+        .transpose()
+        .context("Failed to build account state")?;
+
+    println!("Transaction: {}", transaction.hash()?.to_hex_string());
+    let messages = filter_transaction(transaction, state.as_ref(), range);
+    if messages.is_empty() {
+        println!("No filters matched");
+        return Ok(());
+    }
+
+    for message in messages {
+        let output = serde_json::json!({
+            "contract_name": message.contract_name,
+            "filter_name": message.filter_name,
+            "name": message.name,
+            "message_type": message.message_type,
+            "message_hash": message.message_hash.to_hex_string(),
+            "args": tokens_to_value(&message.tokens).to_json(),
+            "enrichment": message.enrichment,
+            "routing": message.routing,
+            "payload": message.payload,
+        });
+        println!("{}", serde_json::to_string_pretty(&output)?);
+    }
+    Ok(())
+}
+
+/// Test blocks used by `--bench` without `--bench-input`
+static BENCH_BLOCKS: &str = include_str!("../test/blocks/blocks.json");
+
+/// Measures the hot path: parsing, filtering, serialization and sending to a counting transport
+async fn bench(
+    input: Option<&Path>,
+    iterations: usize,
+    serializer: &Serializer,
+    range: &TransactionRange,
+) -> Result<()> {
+    let transactions = match input {
+        Some(path) if path.extension().map_or(false, |ext| ext == "json") => {
+            fixture_transactions(&std::fs::read_to_string(path)?)?
+        }
+        Some(path) => std::fs::read_to_string(path)?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| base64::decode(line.trim()).context("Invalid base64 BOC"))
+            .collect::<Result<Vec<_>>>()?,
+        None => fixture_transactions(BENCH_BLOCKS)?,
+    };
+    anyhow::ensure!(!transactions.is_empty(), "No transactions to benchmark");
+
+    let producer = Producer::new(Transport::Counting)?;
+    let mut parse = Duration::ZERO;
+    let mut filter = Duration::ZERO;
+    let mut serialize = Duration::ZERO;
+    let mut send = Duration::ZERO;
+    let mut messages = 0usize;
+    let started = Instant::now();
+    for _ in 0..iterations {
+        for boc in &transactions {
+            let now = Instant::now();
+            let transaction = ton_block::Transaction::construct_from_bytes(boc)
+                .context("Failed to parse transaction")?;
+            parse += now.elapsed();
+
+            let now = Instant::now();
+            let filtered = filter_transaction(transaction, None, range);
+            filter += now.elapsed();
+
+            for message in filtered {
+                let now = Instant::now();
+                let data = serializer.serialize_message(message.into())?;
+                serialize += now.elapsed();
+
+                let now = Instant::now();
+                producer.send_data(data).await?;
+                send += now.elapsed();
+                messages += 1;
+            }
         }
-        ScanType::TestJson { filename } => {
-            let scanner = TestScanner::new(handler.clone(), filename)
-                .context("Failed to create scanner")?;
+    }
+    let elapsed = started.elapsed();
 
-            scanner.run().await.context("Failed to scan block from json file")?;
-            futures_util::future::pending().await
+    let total_transactions = transactions.len() * iterations;
+    println!("Transactions: {total_transactions}, messages: {messages}, elapsed: {elapsed:?}");
+    println!(
+        "Transactions/s: {:.0}, messages/s: {:.0}",
+        total_transactions as f64 / elapsed.as_secs_f64(),
+        messages as f64 / elapsed.as_secs_f64(),
+    );
+    for (stage, time, count) in [
+        ("parse", parse, total_transactions),
+        ("filter", filter, total_transactions),
+        ("serialize", serialize, messages),
+        ("send", send, messages),
+    ] {
+        let average = time.checked_div(count as u32).unwrap_or_default();
+        println!("{stage:>9}: total {time:?}, average {average:?}");
+    }
+    Ok(())
+}
+
+/// Reads BOC from a file (raw or base64) or from a base64 argument
+fn read_boc(value: &str) -> Result<Vec<u8>> {
+    match std::fs::read(value) {
+        Ok(data) => {
+            let decoded = std::str::from_utf8(&data)
+                .ok()
+                .and_then(|data| base64::decode(data.trim()).ok());
+            Ok(decoded.unwrap_or(data))
+        }
+        Err(_) => base64::decode(value.trim()).context("Invalid base64 BOC"),
+    }
+}
+
+/// Builds a fake shard state with the only account to check code hash filters
+fn single_account_state(transaction: &ton_block::Transaction, account: &str) -> Result<ShardStateStuff> {
+    let account = ton_block::Account::construct_from_bytes(&read_boc(account)?)
+        .context("Failed to parse account")?;
+
+    let workchain_id = account.get_addr().map(|addr| addr.workchain_id()).unwrap_or_default();
+    shard_state_from_accounts(workchain_id, [(&account, transaction.hash()?, transaction.lt)])
+}
+
+fn print_match_stats() {
+    let stats = match_stats();
+    let names = stats
+        .iter()
+        .map(|s| match &s.tenant {
+            Some(tenant) => format!("{} / {} / {}", tenant, s.contract_name, s.filter_name),
+            None => format!("{} / {}", s.contract_name, s.filter_name),
+        })
+        .collect::<Vec<_>>();
+    let longest_name = names.iter().map(String::len).max().unwrap_or_default();
+    println!("{}", "=".repeat(80));
+    for (name, stat) in names.iter().zip(&stats) {
+        println!("{name:longest_name$} MATCHED: {:12}", stat.matched);
+    }
+    println!("{}", "=".repeat(80));
+    let mut tenants = BTreeMap::<&str, u64>::new();
+    for stat in &stats {
+        if let Some(tenant) = &stat.tenant {
+            *tenants.entry(tenant).or_default() += stat.matched;
         }
     }
+    for (tenant, matched) in tenants {
+        println!("TENANT {tenant} MATCHED: {matched}");
+    }
+    println!("TOTAL MATCHED: {}", stats.iter().map(|s| s.matched).sum::<u64>());
+}
+
+/// Removes what the minimal retention doesn't keep: shard states before the last
+/// applied masterchain block and, if confirmed, all archives
+async fn prune_storage(engine: &Arc<ton_indexer::Engine>, remove_archives: bool) -> Result<()> {
+    let last = engine
+        .load_last_applied_mc_block_id()
+        .context("Failed to load last masterchain block")?;
+    let storage = engine.storage();
+
+    tracing::warn!(mc_seqno = last.seq_no, "removing outdated states");
+    storage.shard_state_storage().remove_outdated_states(last.seq_no).await?;
+
+    if !remove_archives {
+        tracing::warn!("archives are kept, pass `--prune-archives` to remove them");
+        return Ok(());
+    }
+    tracing::warn!(mc_seqno = last.seq_no, "removing all archives, blocks before it can't be replayed");
+    storage.block_storage().remove_outdated_archives(last.seq_no).await?;
+    Ok(())
 }
 
 fn print_disk_usage_stats(engine: &Arc<NetworkScanner>) {
-    let stats = engine.indexer().db_usage_stats().unwrap();
-    let longest_table_name = stats
+    let usage = engine.db_usage().unwrap();
+    let longest_table_name = usage
+        .tables
         .iter()
-        .map(|s| s.cf_name.len())
+        .map(|table| table.name.len())
         .max()
         .unwrap_or_default();
     println!("{}", "=".repeat(80));
-    for stat in &stats {
-        let padded_name = stat
-            .cf_name
+    for table in &usage.tables {
+        let padded_name = table
+            .name
             .chars()
-            .chain(std::iter::repeat(' ').take(longest_table_name - stat.cf_name.len()))
+            .chain(std::iter::repeat(' ').take(longest_table_name - table.name.len()))
             .collect::<String>();
         println!(
-            "{padded_name} KEYS: {:12} VALUES: {:12} SUM: {:12}",
-            stat.keys_total,
-            stat.values_total,
-            stat.keys_total + stat.values_total
+            "{padded_name} KEYS: {:12} VALUES: {:12} SUM: {:12} GROWTH: {}",
+            bytesize::ByteSize(table.keys_bytes),
+            bytesize::ByteSize(table.values_bytes),
+            bytesize::ByteSize(table.keys_bytes + table.values_bytes),
+            format_growth(table.growth_bytes_per_day),
         );
     }
-    let total_keys = stats.iter().map(|s| s.keys_total.as_u64()).sum::<_>();
-    let total_values = stats.iter().map(|s| s.values_total.as_u64()).sum::<_>();
+    let total_keys = usage.tables.iter().map(|table| table.keys_bytes).sum::<u64>();
+    let total_values = usage.tables.iter().map(|table| table.values_bytes).sum::<u64>();
     println!("{}", "=".repeat(80));
     println!(
-        "TOTAL KEYS: {} TOTAL VALUES: {} TOTAL: {}",
+        "TOTAL KEYS: {} TOTAL VALUES: {} TOTAL: {} GROWTH: {}",
         bytesize::to_string(total_keys, true),
         bytesize::to_string(total_values, true),
-        bytesize::to_string(total_keys + total_values, true)
+        bytesize::to_string(usage.total_bytes, true),
+        format_growth(usage.growth_bytes_per_day),
     );
+    if let Some(free) = usage.free_bytes {
+        let days_to_full = usage
+            .days_to_full
+            .map(|days| format!("{days:.1} days"))
+            .unwrap_or_else(|| "unknown".to_owned());
+        println!("FREE: {} FULL IN: {days_to_full}", bytesize::to_string(free, true));
+    }
+}
+
+/// Growth per day, unknown until the usage history covers an hour
+fn format_growth(bytes_per_day: Option<f64>) -> String {
+    match bytes_per_day {
+        Some(bytes) if bytes < 0.0 => format!("-{}/day", bytesize::to_string(-bytes as u64, true)),
+        Some(bytes) => format!("+{}/day", bytesize::to_string(bytes as u64, true)),
+        None => "unknown".to_owned(),
+    }
 }
 
 #[derive(Debug, FromArgs)]
@@ -212,245 +435,99 @@ struct App {
     #[argh(switch)]
     run_compaction: bool,
 
-    /// print memory usage statistics and exit
+    /// remove outdated states, compact database, print its size and exit
     #[argh(switch)]
-    print_memory_usage: bool,
-}
-
-struct Metrics<'a> {
-    rpc_state: Option<&'a RpcState>,
-    engine: &'a NetworkScanner,
-    panicked: &'a AtomicBool,
-}
-
-impl std::fmt::Display for Metrics<'_> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let panicked = self.panicked.load(Ordering::Acquire) as u8;
-        f.begin_metric("panicked").value(panicked)?;
+    prune: bool,
 
-        let indexer = self.engine.indexer();
-
-        // TON indexer
-        let indexer_metrics = indexer.metrics();
+    /// also remove all archives with `--prune`, blocks stored before can't be replayed
+    #[argh(switch)]
+    prune_archives: bool,
 
-        let last_mc_utime = indexer_metrics.last_mc_utime.load(Ordering::Acquire);
-        if last_mc_utime > 0 {
-            f.begin_metric("ton_indexer_mc_time_diff")
-                .value(indexer_metrics.mc_time_diff.load(Ordering::Acquire))?;
-            f.begin_metric("ton_indexer_sc_time_diff").value(
-                indexer_metrics
-                    .shard_client_time_diff
-                    .load(Ordering::Acquire),
-            )?;
+    /// print memory usage statistics and exit
+    #[argh(switch)]
+    print_memory_usage: bool,
 
-            f.begin_metric("ton_indexer_last_mc_utime")
-                .value(last_mc_utime)?;
-        }
+    /// run with the real filters, but count matches instead of sending data
+    #[argh(switch)]
+    dry_run: bool,
 
-        let last_mc_block_seqno = indexer_metrics.last_mc_block_seqno.load(Ordering::Acquire);
-        if last_mc_block_seqno > 0 {
-            f.begin_metric("ton_indexer_last_mc_block_seqno")
-                .value(last_mc_block_seqno)?;
-        }
+    /// check config, ABIs and filters, print all problems and exit
+    #[argh(switch)]
+    validate_config: bool,
 
-        let last_shard_client_mc_block_seqno = indexer_metrics
-            .last_shard_client_mc_block_seqno
-            .load(Ordering::Acquire);
-        if last_shard_client_mc_block_seqno > 0 {
-            f.begin_metric("ton_indexer_last_sc_block_seqno")
-                .value(last_shard_client_mc_block_seqno)?;
-        }
+    /// run filters over a transaction (BOC file or base64) and exit
+    #[argh(option)]
+    test_filter: Option<String>,
 
-        f.begin_metric("ton_indexer_block_broadcasts_total").value(
-            indexer_metrics
-                .block_broadcasts
-                .total
-                .load(Ordering::Acquire),
-        )?;
-        f.begin_metric("ton_indexer_block_broadcasts_invalid")
-            .value(
-                indexer_metrics
-                    .block_broadcasts
-                    .invalid
-                    .load(Ordering::Acquire),
-            )?;
-
-        macro_rules! downloader_metrics {
-            ($f:ident, $metrics:ident.$name:ident) => {
-                $f.begin_metric(concat!("ton_indexer_", stringify!($name), "_total"))
-                    .value($metrics.$name.total.load(Ordering::Acquire))?;
-                $f.begin_metric(concat!("ton_indexer_", stringify!($name), "_errors"))
-                    .value($metrics.$name.errors.load(Ordering::Acquire))?;
-                $f.begin_metric(concat!("ton_indexer_", stringify!($name), "_timeouts"))
-                    .value($metrics.$name.timeouts.load(Ordering::Acquire))?;
-            };
-        }
+    /// account state BOC (file or base64) for code hash filters, used with `--test-filter`
+    #[argh(option)]
+    test_account: Option<String>,
 
-        downloader_metrics!(f, indexer_metrics.download_next_block_requests);
-        downloader_metrics!(f, indexer_metrics.download_block_requests);
-        downloader_metrics!(f, indexer_metrics.download_block_proof_requests);
-
-        // Internal metrics
-        let internal_metrics = indexer.internal_metrics();
-
-        f.begin_metric("ton_indexer_shard_states_operations_len")
-            .value(internal_metrics.shard_states_operations_len)?;
-        f.begin_metric("ton_indexer_block_applying_operations_len")
-            .value(internal_metrics.block_applying_operations_len)?;
-        f.begin_metric("ton_indexer_next_block_applying_operations_len")
-            .value(internal_metrics.next_block_applying_operations_len)?;
-        f.begin_metric("ton_indexer_download_block_operations")
-            .value(internal_metrics.download_block_operations_len)?;
-
-        // TON indexer network
-        let network_metrics = indexer.network_metrics();
-
-        f.begin_metric("network_adnl_peer_count")
-            .value(network_metrics.adnl.peer_count)?;
-        f.begin_metric("network_adnl_channels_by_id_len")
-            .value(network_metrics.adnl.channels_by_peers_len)?;
-        f.begin_metric("network_adnl_channels_by_peers_len")
-            .value(network_metrics.adnl.channels_by_peers_len)?;
-        f.begin_metric("network_adnl_incoming_transfers_len")
-            .value(network_metrics.adnl.incoming_transfers_len)?;
-        f.begin_metric("network_adnl_query_count")
-            .value(network_metrics.adnl.query_count)?;
-
-        f.begin_metric("network_dht_peers_cache_len")
-            .value(network_metrics.dht.known_peers_len)?;
-        f.begin_metric("network_dht_bucket_peer_count")
-            .value(network_metrics.dht.bucket_peer_count)?;
-        f.begin_metric("network_dht_storage_len")
-            .value(network_metrics.dht.storage_len)?;
-        f.begin_metric("network_dht_storage_total_size")
-            .value(network_metrics.dht.storage_total_size)?;
-
-        f.begin_metric("network_rldp_peer_count")
-            .value(network_metrics.rldp.peer_count)?;
-        f.begin_metric("network_rldp_transfers_cache_len")
-            .value(network_metrics.rldp.transfers_cache_len)?;
-
-        const OVERLAY_ID: &str = "overlay_id";
-
-        for (overlay_id, neighbour_metrics) in indexer.network_neighbour_metrics() {
-            f.begin_metric("overlay_peer_search_task_count")
-                .label(OVERLAY_ID, overlay_id)
-                .value(neighbour_metrics.peer_search_task_count)?;
-        }
+    /// write accounts of the code hash filters with balances from the latest node states
+    /// to the JSON file and exit
+    #[argh(option)]
+    export_accounts: Option<PathBuf>,
 
-        for (overlay_id, overlay_metrics) in indexer.network_overlay_metrics() {
-            let overlay_id = base64::encode(overlay_id.as_slice());
-
-            f.begin_metric("overlay_owned_broadcasts_len")
-                .label(OVERLAY_ID, &overlay_id)
-                .value(overlay_metrics.owned_broadcasts_len)?;
-            f.begin_metric("overlay_finished_broadcasts_len")
-                .label(OVERLAY_ID, &overlay_id)
-                .value(overlay_metrics.finished_broadcasts_len)?;
-            f.begin_metric("overlay_node_count")
-                .label(OVERLAY_ID, &overlay_id)
-                .value(overlay_metrics.node_count)?;
-            f.begin_metric("overlay_known_peers_len")
-                .label(OVERLAY_ID, &overlay_id)
-                .value(overlay_metrics.known_peers)?;
-            f.begin_metric("overlay_neighbours")
-                .label(OVERLAY_ID, &overlay_id)
-                .value(overlay_metrics.neighbours)?;
-            f.begin_metric("overlay_received_broadcasts_data_len")
-                .label(OVERLAY_ID, &overlay_id)
-                .value(overlay_metrics.received_broadcasts_data_len)?;
-            f.begin_metric("overlay_received_broadcasts_barrier_count")
-                .label(OVERLAY_ID, &overlay_id)
-                .value(overlay_metrics.received_broadcasts_barrier_count)?;
-        }
+    /// process blocks from the local node database starting from the masterchain seqno and exit
+    #[argh(option)]
+    replay_from_seqno: Option<u32>,
 
-        // RPC
+    /// last masterchain seqno to replay, used with `--replay-from-seqno`
+    #[argh(option)]
+    to: Option<u32>,
 
-        f.begin_metric("jrpc_enabled")
-            .value(self.rpc_state.is_some() as u8)?;
+    /// run transactions through filters, serialization and a null transport, print timings and exit
+    #[argh(switch)]
+    bench: bool,
+
+    /// transactions for `--bench`: blocks json as for TestJson, or base64 BOCs one per line.
+    /// Bundled test blocks by default
+    #[argh(option)]
+    bench_input: Option<PathBuf>,
+
+    /// number of passes over the transactions for `--bench`. Default: 10
+    #[argh(option, default = "10")]
+    bench_iterations: usize,
+
+    /// print the record schema of the serializer (`proto` or `json`) built into the binary and exit
+    #[argh(option)]
+    print_schema: Option<String>,
+
+    /// send the frames of a file written by the Stdio transport, or of an overflow queue
+    /// directory, through the configured transport and exit
+    #[argh(option)]
+    resend: Option<PathBuf>,
+
+    /// layout of the `--resend` frames: the Stdio framing (`separators`, `json_lines`,
+    /// `length_prefixed`, `hex`) or `elasticsearch` for its overflow queue. Default: separators
+    #[argh(option, default = "ResendFormat::default()")]
+    resend_format: ResendFormat,
+}
 
-        if let Some(state) = &self.rpc_state {
-            let jrpc = state.jrpc_metrics();
-            f.begin_metric("jrpc_total").value(jrpc.total)?;
-            f.begin_metric("jrpc_errors").value(jrpc.errors)?;
-            f.begin_metric("jrpc_not_found").value(jrpc.not_found)?;
+fn print_schema(format: &str) -> Result<()> {
+    let Some((_, schema)) = SCHEMAS.iter().find(|(name, _)| *name == format) else {
+        let formats = SCHEMAS.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+        anyhow::bail!("Unknown schema format `{format}`, available: {}", formats.join(", "));
+    };
+    print!("{schema}");
+    Ok(())
+}
 
-            let proto = state.proto_metrics();
-            f.begin_metric("proto_total").value(proto.total)?;
-            f.begin_metric("proto_errors").value(proto.errors)?;
-            f.begin_metric("proto_not_found").value(proto.not_found)?;
-        }
+async fn resend(path: &Path, format: ResendFormat, json: bool, transport: Transport) -> Result<()> {
+    let frames = read_frames(path, format, json).with_context(|| format!("Failed to read {}", path.display()))?;
+    let producer = Producer::new(transport)?;
+    let destinations = frames.iter().filter_map(|(destination, _)| destination.as_deref());
+    for destination in destinations.collect::<BTreeSet<_>>() {
+        producer.add_destination(destination)?;
+    }
 
-        // jemalloc
-
-        let profiling::JemallocStats {
-            allocated,
-            active,
-            metadata,
-            resident,
-            mapped,
-            retained,
-            dirty,
-            fragmentation,
-        } = profiling::fetch_stats().map_err(|e| {
-            tracing::error!("failed to fetch allocator stats: {e:?}");
-            std::fmt::Error
-        })?;
-
-        f.begin_metric("jemalloc_allocated_bytes")
-            .value(allocated)?;
-        f.begin_metric("jemalloc_active_bytes").value(active)?;
-        f.begin_metric("jemalloc_metadata_bytes").value(metadata)?;
-        f.begin_metric("jemalloc_resident_bytes").value(resident)?;
-        f.begin_metric("jemalloc_mapped_bytes").value(mapped)?;
-        f.begin_metric("jemalloc_retained_bytes").value(retained)?;
-        f.begin_metric("jemalloc_dirty_bytes").value(dirty)?;
-        f.begin_metric("jemalloc_fragmentation_bytes")
-            .value(fragmentation)?;
-
-        // DB
-        let db = indexer.get_db_metrics();
-        f.begin_metric("db_shard_state_storage_max_new_mc_cell_count")
-            .value(db.shard_state_storage.max_new_mc_cell_count)?;
-        f.begin_metric("db_shard_state_storage_max_new_sc_cell_count")
-            .value(db.shard_state_storage.max_new_sc_cell_count)?;
-
-        // RocksDB
-
-        let ton_indexer::RocksdbStats {
-            whole_db_stats,
-            block_cache_usage,
-            block_cache_pined_usage,
-        } = indexer.get_memory_usage_stats().map_err(|e| {
-            tracing::error!("failed to fetch rocksdb stats: {e:?}");
-            std::fmt::Error
-        })?;
-
-        f.begin_metric("rocksdb_block_cache_usage_bytes")
-            .value(block_cache_usage)?;
-        f.begin_metric("rocksdb_block_cache_pined_usage_bytes")
-            .value(block_cache_pined_usage)?;
-        f.begin_metric("rocksdb_memtable_total_size_bytes")
-            .value(whole_db_stats.mem_table_total)?;
-        f.begin_metric("rocksdb_memtable_unflushed_size_bytes")
-            .value(whole_db_stats.mem_table_unflushed)?;
-        f.begin_metric("rocksdb_memtable_cache_bytes")
-            .value(whole_db_stats.cache_total)?;
-
-        let cells_cache_stats = internal_metrics.cells_cache_stats;
-        f.begin_metric("cells_cache_hits")
-            .value(cells_cache_stats.hits)?;
-        f.begin_metric("cells_cache_requests")
-            .value(cells_cache_stats.requests)?;
-        f.begin_metric("cells_cache_occupied")
-            .value(cells_cache_stats.occupied)?;
-        f.begin_metric("cells_cache_hits_ratio")
-            .value(cells_cache_stats.hits_ratio)?;
-        f.begin_metric("cells_cache_size_bytes")
-            .value(cells_cache_stats.size_bytes)?;
-
-        Ok(())
+    let count = frames.len();
+    for (destination, data) in frames {
+        producer.send_to_destination(destination.as_deref(), data).await?;
     }
+    producer.close().await?;
+    println!("Resent {count} frames");
+    Ok(())
 }
 
 async fn memory_profiler() {
@@ -0,0 +1,238 @@
+//! Short-term store of the sent messages with a cursor-paginated pull API
+
+use std::convert::Infallible;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::Serialize;
+
+use crate::blocks_handler::BlocksHandler;
+use crate::config::MessageStoreConfig;
+
+const MESSAGES_CF: &str = "messages";
+
+/// Upper bound of `limit`
+const MAX_LIMIT: usize = 1000;
+const DEFAULT_LIMIT: usize = 100;
+/// Entries read per request while looking for messages of a filter
+const MAX_SCANNED: usize = 100_000;
+
+/// Opens the store, starts the pull API and stores the messages sent by the handler
+pub fn serve(handler: BlocksHandler, config: &MessageStoreConfig) -> Result<BlocksHandler> {
+    let store = Arc::new(MessageStore::open(&config.path, Duration::from_secs(config.ttl_sec))?);
+    start(config.listen_address, store.clone(), handler.serializer.is_json());
+    Ok(handler.with_message_store(store))
+}
+
+/// Serialized messages keyed by a sequential cursor.
+/// Entries older than the TTL are removed on compaction
+pub struct MessageStore {
+    db: rocksdb::DB,
+    next_cursor: AtomicU64,
+}
+
+impl MessageStore {
+    pub fn open(path: &Path, ttl: Duration) -> Result<Self> {
+        let mut options = rocksdb::Options::default();
+        options.create_if_missing(true);
+        options.create_missing_column_families(true);
+        let cf = rocksdb::ColumnFamilyDescriptor::new(MESSAGES_CF, rocksdb::Options::default());
+        let db = rocksdb::DB::open_cf_descriptors_with_ttl(&options, path, [cf], ttl)
+            .with_context(|| format!("Failed to open message store at {}", path.display()))?;
+
+        // Cursors continue after the restart
+        let next_cursor = {
+            let cf = db.cf_handle(MESSAGES_CF).context("No messages column family")?;
+            let mut iter = db.raw_iterator_cf(&cf);
+            iter.seek_to_last();
+            iter.key().and_then(parse_cursor).map_or(0, |cursor| cursor + 1)
+        };
+        tracing::info!("Opened message store at {}, next cursor: {}", path.display(), next_cursor);
+
+        Ok(Self {
+            db,
+            next_cursor: AtomicU64::new(next_cursor),
+        })
+    }
+
+    /// Stores the frame of a message matched by the filter
+    pub fn push(&self, filter_name: &str, frame: &[u8]) -> Result<()> {
+        let cursor = self.next_cursor.fetch_add(1, Ordering::Relaxed);
+        let filter_name = filter_name.as_bytes();
+        let mut value = Vec::with_capacity(2 + filter_name.len() + frame.len());
+        value.extend_from_slice(&(filter_name.len() as u16).to_be_bytes());
+        value.extend_from_slice(filter_name);
+        value.extend_from_slice(frame);
+
+        let cf = self.db.cf_handle(MESSAGES_CF).context("No messages column family")?;
+        self.db.put_cf(&cf, cursor.to_be_bytes(), value)?;
+        Ok(())
+    }
+
+    /// Up to `limit` messages after the cursor, optionally of a single filter,
+    /// and the cursor of the last read entry
+    pub fn read(
+        &self,
+        after: Option<u64>,
+        limit: usize,
+        filter: Option<&str>,
+    ) -> Result<(Vec<StoredMessage>, Option<u64>)> {
+        let cf = self.db.cf_handle(MESSAGES_CF).context("No messages column family")?;
+        let mut iter = self.db.raw_iterator_cf(&cf);
+        match after {
+            Some(cursor) => match cursor.checked_add(1) {
+                Some(next) => iter.seek(next.to_be_bytes()),
+                None => return Ok((Vec::new(), after)),
+            },
+            None => iter.seek_to_first(),
+        }
+
+        let mut messages = Vec::new();
+        let mut last_cursor = after;
+        for _ in 0..MAX_SCANNED {
+            if messages.len() >= limit {
+                break;
+            }
+            let (Some(key), Some(value)) = (iter.key(), iter.value()) else {
+                break;
+            };
+            let cursor = parse_cursor(key).context("Invalid cursor")?;
+            let message = parse_message(cursor, value).context("Invalid stored message")?;
+            if filter.map_or(true, |filter| filter == message.filter_name) {
+                messages.push(message);
+            }
+            last_cursor = Some(cursor);
+            iter.next();
+        }
+        iter.status()?;
+        Ok((messages, last_cursor))
+    }
+}
+
+pub struct StoredMessage {
+    pub cursor: u64,
+    pub filter_name: String,
+    pub frame: Vec<u8>,
+}
+
+fn parse_cursor(key: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(key.try_into().ok()?))
+}
+
+/// Value is the filter name length as u16, the filter name and the frame
+fn parse_message(cursor: u64, value: &[u8]) -> Option<StoredMessage> {
+    let len = u16::from_be_bytes(value.get(..2)?.try_into().ok()?) as usize;
+    let filter_name = value.get(2..2 + len)?;
+    Some(StoredMessage {
+        cursor,
+        filter_name: String::from_utf8(filter_name.to_vec()).ok()?,
+        frame: value[2 + len..].to_vec(),
+    })
+}
+
+fn start(listen_address: std::net::SocketAddr, store: Arc<MessageStore>, json_frames: bool) {
+    tokio::spawn(async move {
+        tracing::info!("Starting message store API on: {}", listen_address);
+
+        let make_service = make_service_fn(move |_| {
+            let store = store.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let store = store.clone();
+                    async move { Ok::<_, Infallible>(handle_request(&store, req, json_frames).await) }
+                }))
+            }
+        });
+        if let Err(error) = Server::bind(&listen_address).serve(make_service).await {
+            tracing::error!("Message store API: {}", error);
+        }
+    });
+}
+
+#[derive(Serialize)]
+struct MessagesPage {
+    messages: Vec<MessageEntry>,
+    /// Cursor to pass as `after` for the next page. Skipped messages of other filters
+    /// are not read again
+    next_cursor: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct MessageEntry {
+    cursor: u64,
+    filter_name: String,
+    /// The JSON record, or the base64 encoded protobuf frame
+    data: serde_json::Value,
+}
+
+async fn handle_request(store: &MessageStore, req: Request<Body>, json_frames: bool) -> Response<Body> {
+    if req.uri().path() != "/messages" {
+        return response(StatusCode::NOT_FOUND, Body::empty());
+    }
+    if req.method() != Method::GET {
+        return response(StatusCode::METHOD_NOT_ALLOWED, Body::empty());
+    }
+
+    let mut after = None;
+    let mut limit = DEFAULT_LIMIT;
+    let mut filter = None;
+    let query = req.uri().query().unwrap_or_default();
+    for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+        let parsed = match key.as_ref() {
+            "after" => value.parse().map(|cursor| after = Some(cursor)).is_ok(),
+            "limit" => value.parse::<usize>().map(|value| limit = value.min(MAX_LIMIT)).is_ok(),
+            "filter" => {
+                filter = Some(value.into_owned());
+                true
+            }
+            _ => true,
+        };
+        if !parsed {
+            return response(StatusCode::BAD_REQUEST, Body::from(format!("Invalid `{key}`")));
+        }
+    }
+
+    let result = tokio::task::block_in_place(|| store.read(after, limit, filter.as_deref()));
+    let (messages, next_cursor) = match result {
+        Ok(page) => page,
+        Err(error) => {
+            tracing::error!("Reading message store: {:?}", error);
+            return response(StatusCode::INTERNAL_SERVER_ERROR, Body::empty());
+        }
+    };
+
+    let messages = messages
+        .into_iter()
+        .map(|message| MessageEntry {
+            cursor: message.cursor,
+            filter_name: message.filter_name,
+            data: frame_data(&message.frame, json_frames),
+        })
+        .collect();
+    let body = serde_json::to_vec(&MessagesPage { messages, next_cursor }).unwrap_or_default();
+    let mut response = response(StatusCode::OK, Body::from(body));
+    response.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
+fn frame_data(frame: &[u8], json_frames: bool) -> serde_json::Value {
+    let record = frame
+        .get(std::mem::size_of::<u32>()..)
+        .filter(|_| json_frames)
+        .and_then(|record| serde_json::from_slice(record).ok());
+    record.unwrap_or_else(|| serde_json::Value::String(base64::encode(frame)))
+}
+
+fn response(status: StatusCode, body: Body) -> Response<Body> {
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    response
+}
@@ -0,0 +1,441 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use anyhow::Result;
+use broxus_util::alloc::profiling;
+use everscale_rpc_server::RpcState;
+use pomfrit::formatter::*;
+
+use crate::blocks_handler::counters::filter_counters;
+use crate::blocks_handler::latency::{delivery_latencies, stale_drops, LATENCY_BUCKETS};
+use crate::blocks_handler::supervisor::component_stats;
+use crate::data_scanner::backfill_stats::BackfillStats;
+use crate::data_scanner::network_scanner::NetworkScanner;
+use crate::filter::stats::match_stats;
+use crate::filter::state_budget::state_budget_stats;
+use crate::producer::Producer;
+
+/// Exports the node, filter and delivery metrics of the network scanner.
+/// The exporter stops when the returned value is dropped
+pub(crate) async fn spawn_exporter(
+    metrics_settings: Option<pomfrit::Config>,
+    rpc_state: Option<Arc<RpcState>>,
+    engine: Arc<NetworkScanner>,
+    producer: Producer,
+    panicked: Arc<AtomicBool>,
+) -> Result<impl Sized> {
+    let (exporter, metrics_writer) = pomfrit::create_exporter(metrics_settings).await?;
+    metrics_writer.spawn(move |buf| {
+        buf.write(Metrics {
+            rpc_state: rpc_state.as_deref(),
+            engine: &engine,
+            producer: &producer,
+            panicked: &panicked,
+        });
+    });
+    Ok(exporter)
+}
+
+/// Exports the progress of the archives scan with the filter and delivery metrics
+pub(crate) async fn spawn_backfill_exporter(
+    metrics_settings: Option<pomfrit::Config>,
+    stats: Arc<BackfillStats>,
+    producer: Producer,
+) -> Result<impl Sized> {
+    let (exporter, metrics_writer) = pomfrit::create_exporter(metrics_settings).await?;
+    metrics_writer.spawn(move |buf| {
+        buf.write(BackfillMetrics { stats: &stats, producer: &producer });
+    });
+    Ok(exporter)
+}
+
+struct BackfillMetrics<'a> {
+    stats: &'a BackfillStats,
+    producer: &'a Producer,
+}
+
+impl std::fmt::Display for BackfillMetrics<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.begin_metric("backfill_archives_processed")
+            .value(self.stats.archives_processed.load(Ordering::Acquire))?;
+        f.begin_metric("backfill_blocks_processed")
+            .value(self.stats.blocks_processed.load(Ordering::Acquire))?;
+        f.begin_metric("backfill_current_utime")
+            .value(self.stats.current_utime.load(Ordering::Acquire))?;
+        if let Some(eta) = self.stats.eta_sec() {
+            f.begin_metric("backfill_eta_seconds").value(eta)?;
+        }
+
+        write_latency_metrics(f)?;
+        write_filter_metrics(f)?;
+        write_subscriber_metrics(f, self.producer)?;
+        write_component_metrics(f)
+    }
+}
+
+fn write_latency_metrics(f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    for (contract_name, filter_name, histogram) in delivery_latencies() {
+        for (count, bound) in histogram.buckets().into_iter().zip(LATENCY_BUCKETS) {
+            f.begin_metric("delivery_latency_seconds_bucket")
+                .label("contract", &contract_name)
+                .label("filter", &filter_name)
+                .label("le", bound)
+                .value(count)?;
+        }
+        f.begin_metric("delivery_latency_seconds_bucket")
+            .label("contract", &contract_name)
+            .label("filter", &filter_name)
+            .label("le", "+Inf")
+            .value(histogram.count())?;
+        f.begin_metric("delivery_latency_seconds_sum")
+            .label("contract", &contract_name)
+            .label("filter", &filter_name)
+            .value(histogram.sum_seconds())?;
+        f.begin_metric("delivery_latency_seconds_count")
+            .label("contract", &contract_name)
+            .label("filter", &filter_name)
+            .value(histogram.count())?;
+    }
+    for (contract_name, filter_name, count) in stale_drops() {
+        f.begin_metric("messages_dropped_stale")
+            .label("contract", &contract_name)
+            .label("filter", &filter_name)
+            .value(count)?;
+    }
+    for counters in filter_counters() {
+        f.begin_metric("messages_emitted")
+            .label("filter", &counters.filter_name)
+            .value(counters.messages)?;
+        f.begin_metric("bytes_emitted")
+            .label("filter", &counters.filter_name)
+            .value(counters.bytes)?;
+    }
+
+    Ok(())
+}
+
+fn write_filter_metrics(f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    for stat in match_stats() {
+        f.begin_metric("filter_messages_matched")
+            .label("tenant", stat.tenant.as_deref().unwrap_or_default())
+            .label("contract", &stat.contract_name)
+            .label("filter", &stat.filter_name)
+            .value(stat.matched)?;
+    }
+    let state_budget = state_budget_stats();
+    f.begin_metric("state_read_breaker_open").value(state_budget.open as u8)?;
+    f.begin_metric("state_read_breaker_trips").value(state_budget.trips)?;
+    f.begin_metric("state_reads_skipped").value(state_budget.skipped_reads)?;
+
+    Ok(())
+}
+
+fn write_subscriber_metrics(f: &mut std::fmt::Formatter<'_>, producer: &Producer) -> std::fmt::Result {
+    for subscriber in producer.subscribers().unwrap_or_default() {
+        let peer = subscriber.peer.to_string();
+        let stream = subscriber.stream.name();
+        f.begin_metric("http2_subscriber_delivered")
+            .label("peer", &peer)
+            .label("stream", stream)
+            .value(subscriber.delivered.load(Ordering::Relaxed))?;
+        f.begin_metric("http2_subscriber_dropped")
+            .label("peer", &peer)
+            .label("stream", stream)
+            .value(subscriber.dropped.load(Ordering::Relaxed))?;
+        f.begin_metric("http2_subscriber_queue_depth")
+            .label("peer", &peer)
+            .label("stream", stream)
+            .value(subscriber.queue_depth.load(Ordering::Relaxed))?;
+    }
+
+    Ok(())
+}
+
+fn write_component_metrics(f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    for (component, stats) in component_stats() {
+        f.begin_metric("component_panics")
+            .label("component", component)
+            .value(stats.panics.load(Ordering::Relaxed))?;
+        f.begin_metric("component_restarts")
+            .label("component", component)
+            .value(stats.restarts.load(Ordering::Relaxed))?;
+    }
+
+    Ok(())
+}
+
+struct Metrics<'a> {
+    rpc_state: Option<&'a RpcState>,
+    engine: &'a NetworkScanner,
+    producer: &'a Producer,
+    panicked: &'a AtomicBool,
+}
+
+impl std::fmt::Display for Metrics<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let panicked = self.panicked.load(Ordering::Acquire) as u8;
+        f.begin_metric("panicked").value(panicked)?;
+
+        write_latency_metrics(f)?;
+        write_filter_metrics(f)?;
+        write_subscriber_metrics(f, self.producer)?;
+        write_component_metrics(f)?;
+
+        match self.engine.db_usage() {
+            Ok(usage) => {
+                for table in &usage.tables {
+                    f.begin_metric("db_table_bytes")
+                        .label("table", &table.name)
+                        .value(table.keys_bytes + table.values_bytes)?;
+                    if let Some(growth) = table.growth_bytes_per_day {
+                        f.begin_metric("db_table_growth_bytes_per_day")
+                            .label("table", &table.name)
+                            .value(growth)?;
+                    }
+                }
+                f.begin_metric("db_total_bytes").value(usage.total_bytes)?;
+                if let Some(growth) = usage.growth_bytes_per_day {
+                    f.begin_metric("db_growth_bytes_per_day").value(growth)?;
+                }
+                if let Some(free) = usage.free_bytes {
+                    f.begin_metric("db_disk_free_bytes").value(free)?;
+                }
+                if let Some(days) = usage.days_to_full {
+                    f.begin_metric("db_days_to_disk_full").value(days)?;
+                }
+            }
+            Err(error) => tracing::warn!("Database usage stats: {:?}", error),
+        }
+
+        let gaps = self.engine.gap_metrics();
+        f.begin_metric("gaps_detected")
+            .value(gaps.detected.load(Ordering::Acquire))?;
+        f.begin_metric("gaps_repaired")
+            .value(gaps.repaired.load(Ordering::Acquire))?;
+        f.begin_metric("forks_detected")
+            .value(gaps.forks.load(Ordering::Acquire))?;
+
+        let indexer = self.engine.indexer();
+
+        // TON indexer
+        let indexer_metrics = indexer.metrics();
+
+        let last_mc_utime = indexer_metrics.last_mc_utime.load(Ordering::Acquire);
+        if last_mc_utime > 0 {
+            f.begin_metric("ton_indexer_mc_time_diff")
+                .value(indexer_metrics.mc_time_diff.load(Ordering::Acquire))?;
+            f.begin_metric("ton_indexer_sc_time_diff").value(
+                indexer_metrics
+                    .shard_client_time_diff
+                    .load(Ordering::Acquire),
+            )?;
+
+            f.begin_metric("ton_indexer_last_mc_utime")
+                .value(last_mc_utime)?;
+        }
+
+        let last_mc_block_seqno = indexer_metrics.last_mc_block_seqno.load(Ordering::Acquire);
+        if last_mc_block_seqno > 0 {
+            f.begin_metric("ton_indexer_last_mc_block_seqno")
+                .value(last_mc_block_seqno)?;
+        }
+
+        let last_shard_client_mc_block_seqno = indexer_metrics
+            .last_shard_client_mc_block_seqno
+            .load(Ordering::Acquire);
+        if last_shard_client_mc_block_seqno > 0 {
+            f.begin_metric("ton_indexer_last_sc_block_seqno")
+                .value(last_shard_client_mc_block_seqno)?;
+        }
+
+        f.begin_metric("ton_indexer_block_broadcasts_total").value(
+            indexer_metrics
+                .block_broadcasts
+                .total
+                .load(Ordering::Acquire),
+        )?;
+        f.begin_metric("ton_indexer_block_broadcasts_invalid")
+            .value(
+                indexer_metrics
+                    .block_broadcasts
+                    .invalid
+                    .load(Ordering::Acquire),
+            )?;
+
+        macro_rules! downloader_metrics {
+            ($f:ident, $metrics:ident.$name:ident) => {
+                $f.begin_metric(concat!("ton_indexer_", stringify!($name), "_total"))
+                    .value($metrics.$name.total.load(Ordering::Acquire))?;
+                $f.begin_metric(concat!("ton_indexer_", stringify!($name), "_errors"))
+                    .value($metrics.$name.errors.load(Ordering::Acquire))?;
+                $f.begin_metric(concat!("ton_indexer_", stringify!($name), "_timeouts"))
+                    .value($metrics.$name.timeouts.load(Ordering::Acquire))?;
+            };
+        }
+
+        downloader_metrics!(f, indexer_metrics.download_next_block_requests);
+        downloader_metrics!(f, indexer_metrics.download_block_requests);
+        downloader_metrics!(f, indexer_metrics.download_block_proof_requests);
+
+        // Internal metrics
+        let internal_metrics = indexer.internal_metrics();
+
+        f.begin_metric("ton_indexer_shard_states_operations_len")
+            .value(internal_metrics.shard_states_operations_len)?;
+        f.begin_metric("ton_indexer_block_applying_operations_len")
+            .value(internal_metrics.block_applying_operations_len)?;
+        f.begin_metric("ton_indexer_next_block_applying_operations_len")
+            .value(internal_metrics.next_block_applying_operations_len)?;
+        f.begin_metric("ton_indexer_download_block_operations")
+            .value(internal_metrics.download_block_operations_len)?;
+
+        // TON indexer network
+        let network_metrics = indexer.network_metrics();
+
+        f.begin_metric("network_adnl_peer_count")
+            .value(network_metrics.adnl.peer_count)?;
+        f.begin_metric("network_adnl_channels_by_id_len")
+            .value(network_metrics.adnl.channels_by_peers_len)?;
+        f.begin_metric("network_adnl_channels_by_peers_len")
+            .value(network_metrics.adnl.channels_by_peers_len)?;
+        f.begin_metric("network_adnl_incoming_transfers_len")
+            .value(network_metrics.adnl.incoming_transfers_len)?;
+        f.begin_metric("network_adnl_query_count")
+            .value(network_metrics.adnl.query_count)?;
+
+        f.begin_metric("network_dht_peers_cache_len")
+            .value(network_metrics.dht.known_peers_len)?;
+        f.begin_metric("network_dht_bucket_peer_count")
+            .value(network_metrics.dht.bucket_peer_count)?;
+        f.begin_metric("network_dht_storage_len")
+            .value(network_metrics.dht.storage_len)?;
+        f.begin_metric("network_dht_storage_total_size")
+            .value(network_metrics.dht.storage_total_size)?;
+
+        f.begin_metric("network_rldp_peer_count")
+            .value(network_metrics.rldp.peer_count)?;
+        f.begin_metric("network_rldp_transfers_cache_len")
+            .value(network_metrics.rldp.transfers_cache_len)?;
+
+        const OVERLAY_ID: &str = "overlay_id";
+
+        for (overlay_id, neighbour_metrics) in indexer.network_neighbour_metrics() {
+            f.begin_metric("overlay_peer_search_task_count")
+                .label(OVERLAY_ID, overlay_id)
+                .value(neighbour_metrics.peer_search_task_count)?;
+        }
+
+        for (overlay_id, overlay_metrics) in indexer.network_overlay_metrics() {
+            let overlay_id = base64::encode(overlay_id.as_slice());
+
+            f.begin_metric("overlay_owned_broadcasts_len")
+                .label(OVERLAY_ID, &overlay_id)
+                .value(overlay_metrics.owned_broadcasts_len)?;
+            f.begin_metric("overlay_finished_broadcasts_len")
+                .label(OVERLAY_ID, &overlay_id)
+                .value(overlay_metrics.finished_broadcasts_len)?;
+            f.begin_metric("overlay_node_count")
+                .label(OVERLAY_ID, &overlay_id)
+                .value(overlay_metrics.node_count)?;
+            f.begin_metric("overlay_known_peers_len")
+                .label(OVERLAY_ID, &overlay_id)
+                .value(overlay_metrics.known_peers)?;
+            f.begin_metric("overlay_neighbours")
+                .label(OVERLAY_ID, &overlay_id)
+                .value(overlay_metrics.neighbours)?;
+            f.begin_metric("overlay_received_broadcasts_data_len")
+                .label(OVERLAY_ID, &overlay_id)
+                .value(overlay_metrics.received_broadcasts_data_len)?;
+            f.begin_metric("overlay_received_broadcasts_barrier_count")
+                .label(OVERLAY_ID, &overlay_id)
+                .value(overlay_metrics.received_broadcasts_barrier_count)?;
+        }
+
+        // RPC
+
+        f.begin_metric("jrpc_enabled")
+            .value(self.rpc_state.is_some() as u8)?;
+
+        if let Some(state) = &self.rpc_state {
+            let jrpc = state.jrpc_metrics();
+            f.begin_metric("jrpc_total").value(jrpc.total)?;
+            f.begin_metric("jrpc_errors").value(jrpc.errors)?;
+            f.begin_metric("jrpc_not_found").value(jrpc.not_found)?;
+
+            let proto = state.proto_metrics();
+            f.begin_metric("proto_total").value(proto.total)?;
+            f.begin_metric("proto_errors").value(proto.errors)?;
+            f.begin_metric("proto_not_found").value(proto.not_found)?;
+        }
+
+        // jemalloc
+
+        let profiling::JemallocStats {
+            allocated,
+            active,
+            metadata,
+            resident,
+            mapped,
+            retained,
+            dirty,
+            fragmentation,
+        } = profiling::fetch_stats().map_err(|e| {
+            tracing::error!("failed to fetch allocator stats: {e:?}");
+            std::fmt::Error
+        })?;
+
+        f.begin_metric("jemalloc_allocated_bytes")
+            .value(allocated)?;
+        f.begin_metric("jemalloc_active_bytes").value(active)?;
+        f.begin_metric("jemalloc_metadata_bytes").value(metadata)?;
+        f.begin_metric("jemalloc_resident_bytes").value(resident)?;
+        f.begin_metric("jemalloc_mapped_bytes").value(mapped)?;
+        f.begin_metric("jemalloc_retained_bytes").value(retained)?;
+        f.begin_metric("jemalloc_dirty_bytes").value(dirty)?;
+        f.begin_metric("jemalloc_fragmentation_bytes")
+            .value(fragmentation)?;
+
+        // DB
+        let db = indexer.get_db_metrics();
+        f.begin_metric("db_shard_state_storage_max_new_mc_cell_count")
+            .value(db.shard_state_storage.max_new_mc_cell_count)?;
+        f.begin_metric("db_shard_state_storage_max_new_sc_cell_count")
+            .value(db.shard_state_storage.max_new_sc_cell_count)?;
+
+        // RocksDB
+
+        let ton_indexer::RocksdbStats {
+            whole_db_stats,
+            block_cache_usage,
+            block_cache_pined_usage,
+        } = indexer.get_memory_usage_stats().map_err(|e| {
+            tracing::error!("failed to fetch rocksdb stats: {e:?}");
+            std::fmt::Error
+        })?;
+
+        f.begin_metric("rocksdb_block_cache_usage_bytes")
+            .value(block_cache_usage)?;
+        f.begin_metric("rocksdb_block_cache_pined_usage_bytes")
+            .value(block_cache_pined_usage)?;
+        f.begin_metric("rocksdb_memtable_total_size_bytes")
+            .value(whole_db_stats.mem_table_total)?;
+        f.begin_metric("rocksdb_memtable_unflushed_size_bytes")
+            .value(whole_db_stats.mem_table_unflushed)?;
+        f.begin_metric("rocksdb_memtable_cache_bytes")
+            .value(whole_db_stats.cache_total)?;
+
+        let cells_cache_stats = internal_metrics.cells_cache_stats;
+        f.begin_metric("cells_cache_hits")
+            .value(cells_cache_stats.hits)?;
+        f.begin_metric("cells_cache_requests")
+            .value(cells_cache_stats.requests)?;
+        f.begin_metric("cells_cache_occupied")
+            .value(cells_cache_stats.occupied)?;
+        f.begin_metric("cells_cache_hits_ratio")
+            .value(cells_cache_stats.hits_ratio)?;
+        f.begin_metric("cells_cache_size_bytes")
+            .value(cells_cache_stats.size_bytes)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,44 @@
+use std::sync::{Arc, Mutex};
+
+use super::{Stream, TransportData};
+
+/// Frame sent to the capturing transport
+#[derive(Debug, Clone)]
+pub struct CapturedFrame {
+    pub stream: Stream,
+    /// Filter destination of a message
+    pub destination: Option<String>,
+    pub data: TransportData,
+}
+
+/// Frames kept by the capturing transport in the order they were sent
+#[derive(Debug, Clone, Default)]
+pub struct CapturedFrames(Arc<Mutex<Vec<CapturedFrame>>>);
+
+impl CapturedFrames {
+    pub(super) fn push(&self, stream: Stream, destination: Option<&str>, data: TransportData) {
+        self.0.lock().unwrap().push(CapturedFrame {
+            stream,
+            destination: destination.map(ToOwned::to_owned),
+            data,
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copies of the captured frames
+    pub fn frames(&self) -> Vec<CapturedFrame> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Removes and returns the captured frames
+    pub fn take(&self) -> Vec<CapturedFrame> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
@@ -0,0 +1,281 @@
+use std::fmt::Write;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use tokio::sync::{mpsc, watch};
+
+use super::overflow::{OverflowConfig, OverflowSender, Spillable};
+use super::resilient::{ResilienceConfig, ResilientSink, SinkClient};
+use super::TransportData;
+
+/// Attempts to index a batch rejected with 429
+const MAX_ATTEMPTS: u32 = 8;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ElasticsearchConfig {
+    /// Cluster url, e.g. `http://127.0.0.1:9200`
+    pub url: String,
+    /// Index of the messages, strftime patterns are filled from the transaction time.
+    /// Default: `fusion-messages-%Y.%m.%d`
+    #[serde(default = "default_index")]
+    pub index: String,
+    /// Messages per bulk request. Default: 500
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// Partial batches are sent after this time. Default: 1000
+    #[serde(default = "default_flush_interval_ms")]
+    pub flush_interval_ms: u64,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Reconnects and buffering while the cluster is unreachable
+    #[serde(default)]
+    pub resilience: ResilienceConfig,
+    /// Disk queue of messages while the indexing task falls behind
+    #[serde(default)]
+    pub overflow: Option<OverflowConfig>,
+}
+
+fn default_index() -> String {
+    "fusion-messages-%Y.%m.%d".to_owned()
+}
+
+fn default_batch_size() -> usize {
+    500
+}
+
+fn default_flush_interval_ms() -> u64 {
+    1000
+}
+
+/// Message with the index pattern of its filter destination, if any
+pub type IndexedData = (Option<String>, TransportData);
+
+/// Spilled as the u16 length of the destination, the destination and the data
+impl Spillable for IndexedData {
+    fn into_bytes(self) -> Result<Vec<u8>, Self> {
+        let (destination, data) = self;
+        let destination_bytes = destination.as_deref().unwrap_or_default().as_bytes();
+        let Ok(len) = u16::try_from(destination_bytes.len()) else {
+            return Err((destination, data));
+        };
+        let mut bytes = Vec::with_capacity(2 + destination_bytes.len() + data.len());
+        bytes.extend_from_slice(&len.to_be_bytes());
+        bytes.extend_from_slice(destination_bytes);
+        bytes.extend_from_slice(&data);
+        Ok(bytes)
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        let len = bytes.get(..2).context("Truncated item")?;
+        let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+        let destination = bytes.get(2..2 + len).context("Truncated item")?;
+        let destination = match destination.is_empty() {
+            true => None,
+            false => Some(String::from_utf8(destination.to_vec())?),
+        };
+        Ok((destination, TransportData::copy_from_slice(&bytes[2 + len..])))
+    }
+}
+
+/// Spawns the bulk indexing task, messages are queued up to two batches.
+/// The returned flag is set once the task stops after all senders are dropped
+pub fn start(config: ElasticsearchConfig) -> Result<(OverflowSender<IndexedData>, watch::Receiver<bool>)> {
+    check_index_pattern(&config.index)?;
+    let (sender, receiver) = mpsc::channel(config.batch_size.max(1) * 2);
+    let sender = OverflowSender::new(sender, config.overflow.as_ref())?;
+    let (stopped_tx, stopped) = watch::channel(false);
+    tokio::spawn(async move {
+        run(config, receiver).await;
+        stopped_tx.send(true).ok();
+    });
+    Ok((sender, stopped))
+}
+
+/// Destinations are index patterns too
+pub fn check_index_pattern(pattern: &str) -> Result<()> {
+    let invalid = chrono::format::StrftimeItems::new(pattern).any(|item| item == chrono::format::Item::Error);
+    anyhow::ensure!(!invalid, "Invalid index pattern: {pattern}");
+    Ok(())
+}
+
+async fn run(config: ElasticsearchConfig, mut receiver: mpsc::Receiver<IndexedData>) {
+    tracing::info!("Starting Elasticsearch transport to: {}", config.url);
+
+    let client = BulkClient {
+        client: reqwest::Client::new(),
+        config: config.clone(),
+    };
+    let mut sink = match ResilientSink::new(client, config.resilience.clone()).await {
+        Ok(sink) => sink,
+        Err(error) => {
+            tracing::error!("Starting Elasticsearch transport: {:?}", error);
+            return;
+        }
+    };
+    let mut batch = Vec::with_capacity(config.batch_size);
+    let mut interval = tokio::time::interval(Duration::from_millis(config.flush_interval_ms));
+    loop {
+        let closed = tokio::select! {
+            data = receiver.recv() => match data {
+                Some((index, data)) => {
+                    let index = index.as_deref().unwrap_or(&config.index);
+                    match document(index, &data) {
+                        Ok(Some(document)) => batch.push(document.into_bytes()),
+                        Ok(None) => {}
+                        Err(error) => tracing::error!("Elasticsearch document: {:?}", error),
+                    }
+                    if batch.len() < config.batch_size {
+                        continue;
+                    }
+                    false
+                }
+                None => true,
+            },
+            _ = interval.tick() => false,
+        };
+
+        if batch.is_empty() {
+            sink.drain().await;
+        } else {
+            sink.send(std::mem::take(&mut batch)).await;
+        }
+        if closed {
+            break;
+        }
+    }
+    if sink.buffered() > 0 {
+        tracing::warn!("Elasticsearch transport stopped with {} undelivered batches", sink.buffered());
+    }
+}
+
+struct BulkClient {
+    client: reqwest::Client,
+    config: ElasticsearchConfig,
+}
+
+impl BulkClient {
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let url = format!("{}{path}", self.config.url.trim_end_matches('/'));
+        let request = self.client.request(method, url);
+        match &self.config.username {
+            Some(username) => request.basic_auth(username, self.config.password.as_ref()),
+            None => request,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl SinkClient for BulkClient {
+    async fn send(&mut self, batch: &[Vec<u8>]) -> Result<()> {
+        bulk(self, batch.to_vec()).await
+    }
+
+    async fn health_check(&mut self) -> Result<()> {
+        self.request(reqwest::Method::GET, "/_cluster/health")
+            .send()
+            .await
+            .context("Failed to send request")?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Fields of a message record used for indexing
+#[derive(Deserialize)]
+struct RecordKeys {
+    message_hash: String,
+    transaction_timestamp: i64,
+}
+
+/// Bulk action and source lines of a message record. Control frames are skipped
+fn document(index_pattern: &str, data: &[u8]) -> Result<Option<String>> {
+    let json = data
+        .get(std::mem::size_of::<u32>()..)
+        .context("Truncated frame")?;
+    let json = std::str::from_utf8(json).context("Elasticsearch transport requires the Json serializer")?;
+    let Ok(keys) = serde_json::from_str::<RecordKeys>(json) else {
+        return Ok(None);
+    };
+
+    let time = chrono::NaiveDateTime::from_timestamp_opt(keys.transaction_timestamp, 0)
+        .context("Invalid transaction timestamp")?;
+    let mut index = String::new();
+    write!(index, "{}", time.format(index_pattern))?;
+
+    let action = serde_json::json!({ "index": { "_index": index, "_id": keys.message_hash } });
+    Ok(Some(format!("{action}\n{json}\n")))
+}
+
+/// Sends the documents, retrying the ones rejected with 429. Connection and server errors
+/// are returned to retry the batch, requests rejected otherwise are dropped.
+/// The message hash is the document id, so repeated messages overwrite each other
+async fn bulk(client: &BulkClient, mut documents: Vec<Vec<u8>>) -> Result<()> {
+    let mut backoff = INITIAL_BACKOFF;
+    for attempt in 1..=MAX_ATTEMPTS {
+        let response = client
+            .request(reqwest::Method::POST, "/_bulk")
+            .header(reqwest::header::CONTENT_TYPE, "application/x-ndjson")
+            .body(documents.concat())
+            .send()
+            .await
+            .context("Failed to send request")?;
+
+        let status = response.status();
+        let rejected = if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            documents
+        } else if status.is_server_error() {
+            anyhow::bail!("Bulk request failed with {status}");
+        } else if !status.is_success() {
+            tracing::error!("Elasticsearch rejected a bulk request of {} documents: {}", documents.len(), status);
+            return Ok(());
+        } else {
+            let result: BulkResponse = response.json().await.context("Invalid response")?;
+            if !result.errors {
+                return Ok(());
+            }
+            let mut rejected = Vec::new();
+            for (document, item) in documents.into_iter().zip(result.items) {
+                match item.index.status {
+                    429 => rejected.push(document),
+                    status if status >= 300 => {
+                        tracing::error!("Elasticsearch rejected document {:?}: {}", item.index.id, status)
+                    }
+                    _ => {}
+                }
+            }
+            rejected
+        };
+        if rejected.is_empty() {
+            return Ok(());
+        }
+
+        tracing::warn!("Elasticsearch is overloaded, retrying {} documents (attempt {})", rejected.len(), attempt);
+        documents = rejected;
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+    }
+    anyhow::bail!("{} documents are still rejected after {} attempts", documents.len(), MAX_ATTEMPTS)
+}
+
+#[derive(Deserialize)]
+struct BulkResponse {
+    errors: bool,
+    items: Vec<BulkItem>,
+}
+
+#[derive(Deserialize)]
+struct BulkItem {
+    index: BulkItemResult,
+}
+
+#[derive(Deserialize)]
+struct BulkItemResult {
+    #[serde(rename = "_id")]
+    id: Option<String>,
+    status: u16,
+}
@@ -1,24 +1,197 @@
 use std::{
-    net::SocketAddr,
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
     pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    sync::{Arc, Mutex, RwLock, Weak},
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
 use anyhow::Result;
-use futures_util::Future;
-use hyper::{service::Service, Body, Request, Response, Server, StatusCode};
-use tokio::sync::broadcast::Receiver;
-use tokio_stream::wrappers::BroadcastStream;
+use bytes::Bytes;
+use futures_util::{Future, StreamExt};
+use hyper::{header, server::conn::AddrStream, service::Service, Body, Request, Response, Server, StatusCode};
+use rustc_hash::FxHashMap;
+use serde::Deserialize;
+use tokio::sync::broadcast::{error::RecvError, Receiver, Sender};
 
-use super::TransportData;
+use crate::filter::limiter::RateLimit;
 
-pub fn start_producer_service(receiver: Receiver<TransportData>, listen_address: SocketAddr) {
+use super::sse::SseHistory;
+use super::{Stream, TransportData};
+
+type Receivers = FxHashMap<Stream, Receiver<TransportData>>;
+
+/// Channels of the filter destinations, served at `/messages/<destination>/data`
+pub type Destinations = Arc<RwLock<FxHashMap<String, Sender<TransportData>>>>;
+
+/// Channels of the filters sent to the messages stream, merged in `/messages/data`
+pub type FilterChannels = Arc<RwLock<FxHashMap<String, FilterChannel>>>;
+
+/// Separate channel of a filter, so lagging subscribers skip messages
+/// of chatty filters without losing the others
+#[derive(Debug)]
+pub struct FilterChannel {
+    pub sender: Sender<TransportData>,
+    pub capacity: usize,
+}
+
+/// Sent to subscribers before the stream data
+#[derive(Debug)]
+pub struct Handshake {
+    /// First frame of each subscription
+    pub start_frame: TransportData,
+    /// Response of `/schema`
+    pub schema: &'static str,
+    /// Frames are length prefixed JSON, sent as is to SSE clients instead of base64
+    pub json_frames: bool,
+}
+
+pub type SharedHandshake = Arc<RwLock<Option<Handshake>>>;
+
+/// Limits of the subscription endpoint, nothing is limited by default
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SubscriberLimits {
+    /// Open subscriptions of all connections
+    pub max_subscribers: Option<usize>,
+    /// Open subscriptions of a single connection
+    pub max_subscribers_per_connection: Option<usize>,
+    /// New connections per second of all peers
+    pub max_connections_per_second: Option<RateLimit>,
+    /// Peers allowed to connect, any peer if empty
+    pub allowed_ips: Vec<IpAddr>,
+}
+
+impl SubscriberLimits {
+    fn allows(&self, ip: IpAddr) -> bool {
+        // IPv4 peers of a dual-stack listener have mapped addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        self.allowed_ips.is_empty() || self.allowed_ips.contains(&ip)
+    }
+}
+
+/// Detection of dead connections and stalled subscribers
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeepaliveConfig {
+    /// Interval of HTTP/2 pings and TCP keepalive probes, disabled if zero. Default: 30
+    pub interval_sec: u64,
+    /// The connection is closed if a ping is not acknowledged in this time. Default: 20
+    pub timeout_sec: u64,
+    /// A subscription which doesn't accept data for this long is closed. Disabled if not set
+    pub idle_timeout_sec: Option<u64>,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            interval_sec: 30,
+            timeout_sec: 20,
+            idle_timeout_sec: None,
+        }
+    }
+}
+
+/// A subscriber is reported as slow after its queue stays over half full for this long
+const SLOW_CONSUMER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Delivery counters of a single subscription
+#[derive(Debug)]
+pub struct SubscriberStats {
+    pub peer: SocketAddr,
+    pub stream: Stream,
+    pub delivered: AtomicU64,
+    pub dropped: AtomicU64,
+    /// Messages waiting in the channel for this subscriber
+    pub queue_depth: AtomicU64,
+}
+
+/// Stats of the open subscriptions
+#[derive(Debug, Default)]
+pub struct Subscribers {
+    subscribers: Mutex<Vec<Weak<SubscriberStats>>>,
+}
+
+impl Subscribers {
+    /// Registers a subscription, `None` if it exceeds the limits
+    fn register(&self, peer: SocketAddr, stream: Stream, limits: &SubscriberLimits) -> Option<Arc<SubscriberStats>> {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|stats| stats.strong_count() > 0);
+
+        if matches!(limits.max_subscribers, Some(max) if subscribers.len() >= max) {
+            tracing::warn!("Rejected {} subscriber {}: too many subscribers", stream.name(), peer);
+            return None;
+        }
+        if let Some(max) = limits.max_subscribers_per_connection {
+            let connection_subscribers = subscribers
+                .iter()
+                .filter_map(Weak::upgrade)
+                .filter(|stats| stats.peer == peer)
+                .count();
+            if connection_subscribers >= max {
+                tracing::warn!("Rejected {} subscriber {}: too many subscriptions of the connection", stream.name(), peer);
+                return None;
+            }
+        }
+
+        let stats = Arc::new(SubscriberStats {
+            peer,
+            stream,
+            delivered: Default::default(),
+            dropped: Default::default(),
+            queue_depth: Default::default(),
+        });
+        subscribers.push(Arc::downgrade(&stats));
+        Some(stats)
+    }
+
+    pub fn active(&self) -> Vec<Arc<SubscriberStats>> {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|stats| stats.strong_count() > 0);
+        subscribers.iter().filter_map(Weak::upgrade).collect()
+    }
+}
+
+pub fn start_producer_service(
+    receivers: Receivers,
+    listen_address: SocketAddr,
+    capacity: usize,
+    subscribers: Arc<Subscribers>,
+    handshake: SharedHandshake,
+    destinations: Destinations,
+    filters: FilterChannels,
+    sse: Arc<SseHistory>,
+    limits: SubscriberLimits,
+    keepalive: KeepaliveConfig,
+) {
     tokio::spawn(async move {
         tracing::info!("Starting http/2 transport server on: {}", &listen_address);
 
+        let keepalive_interval = (keepalive.interval_sec > 0).then(|| Duration::from_secs(keepalive.interval_sec));
+        let idle_timeout = keepalive.idle_timeout_sec.map(Duration::from_secs);
+
+        // HTTP/1 is accepted for SSE clients, http/2 with prior knowledge is detected
+        let limits = Arc::new(limits);
         let server = Server::bind(&listen_address)
-            .http2_only(true)
-            .serve(MakeProducerService { receiver });
+            .tcp_keepalive(keepalive_interval)
+            .http2_keep_alive_interval(keepalive_interval)
+            .http2_keep_alive_timeout(Duration::from_secs(keepalive.timeout_sec))
+            .serve(MakeProducerService {
+            receivers,
+            capacity,
+            subscribers,
+            handshake,
+            destinations,
+            filters,
+            sse,
+            limits,
+            idle_timeout,
+        });
 
         if let Err(error) = server.await {
             tracing::error!("Http2 producer: {}", error);
@@ -27,7 +200,118 @@ pub fn start_producer_service(receiver: Receiver<TransportData>, listen_address:
 }
 
 struct ProducerService {
-    messages_receiver: Receiver<TransportData>,
+    receivers: Receivers,
+    peer: SocketAddr,
+    capacity: usize,
+    subscribers: Arc<Subscribers>,
+    handshake: SharedHandshake,
+    destinations: Destinations,
+    filters: FilterChannels,
+    sse: Arc<SseHistory>,
+    limits: Arc<SubscriberLimits>,
+    idle_timeout: Option<Duration>,
+}
+
+impl ProducerService {
+    /// Streams the handshake frame and then the data of the channels.
+    /// Channels are merged, each one skips messages independently.
+    /// `None` if the subscription exceeds the limits
+    fn subscribe(&self, stream: Stream, receivers: Vec<(Receiver<TransportData>, usize)>, name: &str) -> Option<Body> {
+        let stats = self.subscribers.register(self.peer, stream, &self.limits)?;
+        tracing::info!("New {} subscriber: {}", name, self.peer);
+        let start_frame = self
+            .handshake
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|handshake| handshake.start_frame.clone());
+        let channels = receivers
+            .into_iter()
+            .map(|(receiver, capacity)| subscription(receiver, stats.clone(), capacity).boxed());
+        let stream = futures_util::stream::iter(start_frame.map(Ok))
+            .chain(futures_util::stream::select_all(channels));
+        Some(self.body(stream))
+    }
+
+    /// Streams the messages as server-sent events numbered by `id`, starting after
+    /// `last_id` if it is still kept. A lagging client is disconnected to resume
+    fn subscribe_sse(&self, last_id: Option<u64>) -> Option<Body> {
+        let stats = self.subscribers.register(self.peer, Stream::Messages, &self.limits)?;
+        tracing::info!("New messages SSE subscriber: {}", self.peer);
+        let (start_frame, json_frames) = match &*self.handshake.read().unwrap() {
+            Some(handshake) => (Some(handshake.start_frame.clone()), handshake.json_frames),
+            None => (None, false),
+        };
+        let (missed, receiver) = self.sse.subscribe(last_id);
+
+        // Resumed clients already have the start frame
+        let start_frame = start_frame.filter(|_| last_id.is_none());
+        let head = start_frame
+            .map(|frame| sse_event(None, &frame, json_frames))
+            .into_iter()
+            .chain(missed.into_iter().map(|(id, data)| sse_event(Some(id), &data, json_frames)));
+
+        let peer = self.peer;
+        let live = futures_util::stream::unfold(receiver, move |mut receiver| async move {
+            match receiver.recv().await {
+                Ok((id, data)) => Some((sse_event(Some(id), &data, json_frames), receiver)),
+                Err(RecvError::Lagged(count)) => {
+                    tracing::warn!("Slow messages SSE subscriber {}: {} events behind, disconnected", peer, count);
+                    None
+                }
+                Err(RecvError::Closed) => None,
+            }
+        });
+
+        let stream = futures_util::stream::iter(head).chain(live).map(move |event| {
+            stats.delivered.fetch_add(1, Ordering::Relaxed);
+            Ok::<_, Infallible>(event)
+        });
+        Some(self.body(stream))
+    }
+
+    /// Response body of a subscription. With an idle timeout, the subscription is closed
+    /// when the peer doesn't accept data in time, e.g. it crashed without closing the connection
+    fn body<S>(&self, stream: S) -> Body
+    where
+        S: futures_util::Stream<Item = Result<TransportData, Infallible>> + Send + 'static,
+    {
+        let Some(idle_timeout) = self.idle_timeout else {
+            return Body::wrap_stream(stream);
+        };
+
+        let (mut sender, body) = Body::channel();
+        let peer = self.peer;
+        tokio::spawn(async move {
+            futures_util::pin_mut!(stream);
+            while let Some(Ok(data)) = stream.next().await {
+                match tokio::time::timeout(idle_timeout, sender.send_data(data)).await {
+                    Ok(Ok(())) => {}
+                    // The peer closed the subscription
+                    Ok(Err(_)) => return,
+                    Err(_) => {
+                        tracing::warn!("Subscriber {} didn't accept data for {:?}, closed", peer, idle_timeout);
+                        sender.abort();
+                        return;
+                    }
+                }
+            }
+        });
+        body
+    }
+}
+
+/// Formats a frame as an SSE event, JSON records are sent without the length prefix
+fn sse_event(id: Option<u64>, data: &[u8], json_frames: bool) -> Bytes {
+    let payload = match json_frames {
+        true => String::from_utf8_lossy(data.get(std::mem::size_of::<u32>()..).unwrap_or_default()).into_owned(),
+        false => base64::encode(data),
+    };
+    let event = match id {
+        Some(id) => format!("id: {id}\ndata: {payload}\n\n"),
+        None => format!("data: {payload}\n\n"),
+    };
+    Bytes::from(event)
 }
 
 impl Service<Request<Body>> for ProducerService {
@@ -46,16 +330,69 @@ impl Service<Request<Body>> for ProducerService {
         fn response_error(status: StatusCode) -> Result<Response<Body>, hyper::Error> {
             Ok(Response::builder().status(status).body(Body::empty()).unwrap())
         }
+        fn subscription_response(body: Option<Body>) -> Result<Response<Body>, hyper::Error> {
+            match body {
+                Some(body) => Ok(Response::new(body)),
+                None => response_error(StatusCode::TOO_MANY_REQUESTS),
+            }
+        }
 
-        let res = match req.uri().path() {
-            "/" => ok_response("Subscribe to one of the streams".to_string()),
-            "/messages/data" => {
-                // TODO: This might discard some messages (look up resubscribe)
-                let mut receiver = self.messages_receiver.resubscribe();
-                std::mem::swap(&mut self.messages_receiver, &mut receiver);
-                let stream = BroadcastStream::new(receiver);
-                let body: Body = Body::wrap_stream(stream);
-                Ok(Response::new(body))
+        let path = req.uri().path();
+        let stream = Stream::ALL
+            .into_iter()
+            .find(|stream| path.strip_prefix('/').and_then(|p| p.strip_suffix("/data")) == Some(stream.name()));
+
+        if path == "/messages/sse" {
+            let last_id = req
+                .headers()
+                .get("last-event-id")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok());
+            let response = match self.subscribe_sse(last_id) {
+                Some(body) => Ok(Response::builder()
+                    .header(header::CONTENT_TYPE, "text/event-stream")
+                    .header(header::CACHE_CONTROL, "no-cache")
+                    .body(body)
+                    .unwrap()),
+                None => response_error(StatusCode::TOO_MANY_REQUESTS),
+            };
+            return Box::pin(async { response });
+        }
+
+        let destination = path
+            .strip_prefix("/messages/")
+            .and_then(|p| p.strip_suffix("/data"))
+            .and_then(|name| {
+                let receiver = self.destinations.read().unwrap().get(name)?.subscribe();
+                Some((name, receiver))
+            });
+        if let Some((name, receiver)) = destination {
+            let body = self.subscribe(Stream::Messages, vec![(receiver, self.capacity)], &format!("messages/{name}"));
+            let response = subscription_response(body);
+            return Box::pin(async { response });
+        }
+
+        let res = match (path, stream) {
+            ("/", _) => ok_response("Subscribe to one of the streams".to_string()),
+            ("/schema", _) => match &*self.handshake.read().unwrap() {
+                Some(handshake) => ok_response(handshake.schema.to_string()),
+                None => response_error(StatusCode::NOT_FOUND),
+            },
+            (_, Some(stream)) => match self.receivers.get_mut(&stream) {
+                Some(receiver) => {
+                    // TODO: This might discard some messages (look up resubscribe)
+                    let mut new_receiver = receiver.resubscribe();
+                    std::mem::swap(receiver, &mut new_receiver);
+                    let mut receivers = vec![(new_receiver, self.capacity)];
+                    if stream == Stream::Messages {
+                        let filters = self.filters.read().unwrap();
+                        receivers.extend(
+                            filters.values().map(|channel| (channel.sender.subscribe(), channel.capacity)),
+                        );
+                    }
+                    subscription_response(self.subscribe(stream, receivers, stream.name()))
+                }
+                None => response_error(StatusCode::NOT_FOUND),
             },
             _ => response_error(StatusCode::NOT_FOUND),
         };
@@ -64,28 +401,114 @@ impl Service<Request<Body>> for ProducerService {
     }
 }
 
-struct MakeProducerService {
+/// Streams the channel to a subscriber, skipping messages it lagged behind
+fn subscription(
     receiver: Receiver<TransportData>,
+    stats: Arc<SubscriberStats>,
+    capacity: usize,
+) -> impl futures_util::Stream<Item = Result<TransportData, Infallible>> {
+    let state = (receiver, stats, None::<Instant>);
+    futures_util::stream::unfold(state, move |(mut receiver, stats, mut lagging_since)| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(data) => {
+                    let queue_depth = receiver.len();
+                    stats.delivered.fetch_add(1, Ordering::Relaxed);
+                    stats.queue_depth.store(queue_depth as u64, Ordering::Relaxed);
+
+                    if queue_depth * 2 < capacity {
+                        lagging_since = None;
+                    } else {
+                        let since = *lagging_since.get_or_insert_with(Instant::now);
+                        if since.elapsed() >= SLOW_CONSUMER_TIMEOUT {
+                            tracing::warn!(
+                                "Slow {} subscriber {}: {} messages queued",
+                                stats.stream.name(), stats.peer, queue_depth,
+                            );
+                            lagging_since = Some(Instant::now());
+                        }
+                    }
+
+                    return Some((Ok(data), (receiver, stats, lagging_since)));
+                }
+                Err(RecvError::Lagged(count)) => {
+                    stats.dropped.fetch_add(count, Ordering::Relaxed);
+                    tracing::warn!(
+                        "Slow {} subscriber {}: dropped {} messages",
+                        stats.stream.name(), stats.peer, count,
+                    );
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+struct MakeProducerService {
+    receivers: Receivers,
+    capacity: usize,
+    subscribers: Arc<Subscribers>,
+    handshake: SharedHandshake,
+    destinations: Destinations,
+    filters: FilterChannels,
+    sse: Arc<SseHistory>,
+    limits: Arc<SubscriberLimits>,
+    idle_timeout: Option<Duration>,
 }
 
-impl<T> Service<T> for MakeProducerService {
+impl<'a> Service<&'a AddrStream> for MakeProducerService {
     type Response = ProducerService;
-    type Error = hyper::Error;
+    /// The connection is closed on error
+    type Error = anyhow::Error;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
     fn poll_ready(&mut self, _: &mut Context) -> Poll<Result<(), Self::Error>> {
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, _: T) -> Self::Future {
-        let mut receiver = self.receiver.resubscribe();
-        std::mem::swap(&mut self.receiver, &mut receiver);
+    fn call(&mut self, conn: &'a AddrStream) -> Self::Future {
+        let peer = conn.remote_addr();
+        if !self.limits.allows(peer.ip()) {
+            tracing::warn!("Rejected connection of {}: not in `allowed_ips`", peer);
+            return Box::pin(async move { Err(anyhow::anyhow!("Peer {peer} is not allowed")) });
+        }
+        if let Some(rate_limit) = &self.limits.max_connections_per_second {
+            if !rate_limit.try_acquire() {
+                tracing::debug!("Rejected connection of {}: connection rate limit", peer);
+                return Box::pin(async move { Err(anyhow::anyhow!("Connection rate limit exceeded")) });
+            }
+        }
+        let receivers = self
+            .receivers
+            .iter_mut()
+            .map(|(stream, receiver)| {
+                let mut new_receiver = receiver.resubscribe();
+                std::mem::swap(receiver, &mut new_receiver);
+                (*stream, new_receiver)
+            })
+            .collect();
+        let capacity = self.capacity;
+        let subscribers = self.subscribers.clone();
+        let handshake = self.handshake.clone();
+        let destinations = self.destinations.clone();
+        let filters = self.filters.clone();
+        let sse = self.sse.clone();
+        let limits = self.limits.clone();
+        let idle_timeout = self.idle_timeout;
         let fut = async move {
             Ok(ProducerService {
-                messages_receiver: receiver,
-            }) 
+                receivers,
+                peer,
+                capacity,
+                subscribers,
+                handshake,
+                destinations,
+                filters,
+                sse,
+                limits,
+                idle_timeout,
+            })
         };
         Box::pin(fut)
     }
 }
-
@@ -1,12 +1,38 @@
-use std::{net::SocketAddr, io, io::Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use anyhow::Result;
+use bytes::Bytes;
+use rustc_hash::FxHashMap;
 use serde::Deserialize;
 use tokio::sync::broadcast::{channel, Sender};
+use tokio::sync::{mpsc, oneshot, watch};
 
-use self::http2::start_producer_service;
+use crate::error::{FusionError, FusionResult};
 
+use self::elasticsearch::IndexedData;
+use self::overflow::OverflowSender;
+use self::http2::{start_producer_service, Destinations, FilterChannel, FilterChannels, Handshake, SharedHandshake, Subscribers};
+pub use self::http2::{KeepaliveConfig, SubscriberLimits};
+pub use self::stdio::StdioFraming;
+use self::sse::SseHistory;
+use self::stdio::StdioCommand;
+
+pub use self::capture::{CapturedFrame, CapturedFrames};
+pub use self::elasticsearch::ElasticsearchConfig;
+pub use self::overflow::OverflowConfig;
+pub use self::resend::{read_frames, ResendFormat};
+pub use self::resilient::ResilienceConfig;
+pub use self::http2::SubscriberStats;
+
+mod capture;
+mod elasticsearch;
 mod http2;
+mod overflow;
+mod resend;
+mod resilient;
+mod sse;
+mod stdio;
 
 #[derive(Debug, Clone)]
 pub struct Producer {
@@ -14,7 +40,8 @@ pub struct Producer {
     inner: TransportInner,
 }
 
-type TransportData = Vec<u8>;
+/// Frames are shared by all subscribers without copying
+type TransportData = Bytes;
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(tag = "kind", deny_unknown_fields)]
@@ -22,60 +49,529 @@ pub enum Transport {
     Http2 {
         capacity: usize,
         listen_address: Option<SocketAddr>,
+        /// Subscriber and connection limits
+        #[serde(default)]
+        limits: SubscriberLimits,
+        /// Pings and timeouts reaping dead connections
+        #[serde(default)]
+        keepalive: KeepaliveConfig,
     },
-    Stdio,
+    /// Write frames to stdout
+    Stdio {
+        #[serde(default)]
+        framing: StdioFraming,
+        /// Disk queue of frames while stdout is not read fast enough
+        #[serde(default)]
+        overflow: Option<OverflowConfig>,
+    },
+    /// Count sent data without delivering it
+    Counting,
+    /// Bulk index messages, requires the Json serializer
+    Elasticsearch(ElasticsearchConfig),
+    /// Keep sent frames in memory for tests, see [`crate::testing`]
+    #[serde(skip)]
+    Capture,
+}
+
+/// Logical output stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Stream {
+    /// Filtered messages
+    Messages,
+    /// States of the filtered accounts
+    AccountStates,
+    /// Per-block metadata
+    Blocks,
+    /// Masterchain config param changes
+    ConfigParams,
+    /// Statuses of the external messages sent via the message consumer
+    MessageStatuses,
+    /// Bounces of the matched messages
+    Bounces,
+    /// Per-block changed and deleted accounts of the account change filters
+    AccountChanges,
+    /// Message traces of the matched transactions
+    Traces,
+    /// Window summaries of the matched messages
+    Aggregates,
+}
+
+impl Stream {
+    pub const ALL: [Stream; 9] = [
+        Stream::Messages,
+        Stream::AccountStates,
+        Stream::Blocks,
+        Stream::ConfigParams,
+        Stream::MessageStatuses,
+        Stream::Bounces,
+        Stream::AccountChanges,
+        Stream::Traces,
+        Stream::Aggregates,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Messages => "messages",
+            Self::AccountStates => "accounts",
+            Self::Blocks => "blocks",
+            Self::ConfigParams => "config",
+            Self::MessageStatuses => "statuses",
+            Self::Bounces => "bounces",
+            Self::AccountChanges => "account_changes",
+            Self::Traces => "traces",
+            Self::Aggregates => "aggregates",
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 enum TransportInner {
     Http2 {
-        messages: Sender<TransportData>,
+        streams: FxHashMap<Stream, Sender<TransportData>>,
+        subscribers: Arc<Subscribers>,
+        handshake: SharedHandshake,
+        destinations: Destinations,
+        /// Channels of the filters in the messages stream
+        filters: FilterChannels,
+        capacity: usize,
+        /// Numbered messages stream for SSE clients
+        sse: Arc<SseHistory>,
+    },
+    Stdio {
+        framing: StdioFraming,
+        sender: OverflowSender<StdioCommand>,
+    },
+    Counting {
+        counters: Arc<FxHashMap<Stream, StreamCounter>>,
     },
-    Stdio,
+    Elasticsearch {
+        sender: OverflowSender<IndexedData>,
+        /// Set once the indexing task stops
+        stopped: watch::Receiver<bool>,
+    },
+    Capture {
+        frames: CapturedFrames,
+    },
+}
+
+/// Amount of data sent to a stream
+#[derive(Debug, Default)]
+pub struct StreamCounter {
+    pub messages: AtomicU64,
+    pub bytes: AtomicU64,
 }
 
 impl Producer {
-    pub fn new(transport: Transport) -> Result<Self> {
+    pub fn new(transport: Transport) -> FusionResult<Self> {
         match transport {
-            Transport::Http2 { capacity, listen_address } => {
+            Transport::Http2 { capacity, listen_address, ref limits, ref keepalive } => {
                 let listen_address = listen_address.unwrap_or(SocketAddr::from(([127, 0, 0, 1], 3000)));
-                let (messages_tx, messages_rx) = channel(capacity);
-                start_producer_service(messages_rx, listen_address);
+                let mut streams = FxHashMap::default();
+                let mut receivers = FxHashMap::default();
+                for stream in Stream::ALL {
+                    let (tx, rx) = channel(capacity);
+                    streams.insert(stream, tx);
+                    receivers.insert(stream, rx);
+                }
+                let subscribers = Arc::new(Subscribers::default());
+                let handshake = SharedHandshake::default();
+                let destinations = Destinations::default();
+                let filters = FilterChannels::default();
+                let sse = Arc::new(SseHistory::new(capacity));
+                start_producer_service(
+                    receivers,
+                    listen_address,
+                    capacity,
+                    subscribers.clone(),
+                    handshake.clone(),
+                    destinations.clone(),
+                    filters.clone(),
+                    sse.clone(),
+                    limits.clone(),
+                    keepalive.clone(),
+                );
                 Ok(Producer {
                     transport,
-                    inner: TransportInner::Http2 { messages: messages_tx }
+                    inner: TransportInner::Http2 { streams, subscribers, handshake, destinations, filters, capacity, sse }
                 })
             },
-            Transport::Stdio => Ok(Producer {
+            Transport::Stdio { framing, ref overflow } => {
+                let sender = stdio::start(overflow.as_ref()).map_err(FusionError::config)?;
+                Ok(Producer {
+                    transport,
+                    inner: TransportInner::Stdio { framing, sender },
+                })
+            }
+            Transport::Counting => {
+                let counters = Stream::ALL
+                    .into_iter()
+                    .map(|stream| (stream, StreamCounter::default()))
+                    .collect();
+                Ok(Producer {
+                    transport,
+                    inner: TransportInner::Counting {
+                        counters: Arc::new(counters),
+                    },
+                })
+            }
+            Transport::Elasticsearch(ref config) => {
+                let (sender, stopped) = elasticsearch::start(config.clone()).map_err(FusionError::config)?;
+                Ok(Producer {
+                    transport,
+                    inner: TransportInner::Elasticsearch { sender, stopped },
+                })
+            }
+            Transport::Capture => Ok(Producer {
                 transport,
-                inner: TransportInner::Stdio,
+                inner: TransportInner::Capture {
+                    frames: CapturedFrames::default(),
+                },
             }),
         }
     }
 
-    pub async fn send_data(&self, data: TransportData) -> Result<()> {
+    /// Returns the number of sent messages and bytes per stream for the counting transport
+    pub fn counters(&self) -> Option<Vec<(Stream, u64, u64)>> {
+        let TransportInner::Counting { counters } = &self.inner else {
+            return None;
+        };
+        let counters = Stream::ALL
+            .into_iter()
+            .filter_map(|stream| {
+                let counter = counters.get(&stream)?;
+                Some((
+                    stream,
+                    counter.messages.load(Ordering::Relaxed),
+                    counter.bytes.load(Ordering::Relaxed),
+                ))
+            })
+            .collect();
+        Some(counters)
+    }
+
+    /// Returns the frames kept by the capturing transport
+    pub fn captured(&self) -> Option<CapturedFrames> {
+        let TransportInner::Capture { frames } = &self.inner else {
+            return None;
+        };
+        Some(frames.clone())
+    }
+
+    /// Returns delivery stats of the open subscriptions for the http/2 transport
+    pub fn subscribers(&self) -> Option<Vec<Arc<SubscriberStats>>> {
+        let TransportInner::Http2 { subscribers, .. } = &self.inner else {
+            return None;
+        };
+        Some(subscribers.active())
+    }
+
+    /// Sets the first frame of each http/2 subscription and the `/schema` response.
+    /// Ignored by other transports
+    pub fn set_handshake(&self, start_frame: TransportData, schema: &'static str, json_frames: bool) {
+        if let TransportInner::Http2 { handshake, .. } = &self.inner {
+            *handshake.write().unwrap() = Some(Handshake { start_frame, schema, json_frames });
+        }
+    }
+
+    /// Adds a separate output for messages of the filters with this destination.
+    /// The http/2 transport serves it at `/messages/<destination>/data`,
+    /// Elasticsearch uses it as the index pattern
+    pub fn add_destination(&self, destination: &str) -> FusionResult<()> {
         match &self.inner {
-            TransportInner::Http2 { messages: tx } => tx.send(data)
-                .map(|_count| ())
-                .map_err(Into::into),
-            TransportInner::Stdio => self.send_data_sync(data),
+            TransportInner::Http2 { destinations, capacity, .. } => {
+                destinations
+                    .write()
+                    .unwrap()
+                    .entry(destination.to_owned())
+                    .or_insert_with(|| channel(*capacity).0);
+            }
+            TransportInner::Elasticsearch { .. } => {
+                elasticsearch::check_index_pattern(destination).map_err(FusionError::config)?;
+            }
+            TransportInner::Stdio { .. } | TransportInner::Counting { .. } | TransportInner::Capture { .. } => {}
         }
+        Ok(())
     }
 
-    pub fn send_data_sync(&self, data: TransportData) -> Result<()> {
-        match self.inner {
-            TransportInner::Http2 { messages: _ } => unimplemented!("Http producer does not support blocking send"),
-            TransportInner::Stdio => {
-                static PREFIX: &[u8] = ("-----\n").as_bytes();
-                static POSTFIX: &[u8] = ("\n-----\n").as_bytes();
+    /// Adds a separate channel of the filter to the messages stream, with the transport
+    /// capacity if not specified. Ignored by other transports
+    pub fn add_filter_channel(&self, filter_name: &str, capacity: Option<usize>) {
+        if let TransportInner::Http2 { filters, capacity: default_capacity, .. } = &self.inner {
+            let capacity = capacity.unwrap_or(*default_capacity);
+            filters
+                .write()
+                .unwrap()
+                .entry(filter_name.to_owned())
+                .or_insert_with(|| FilterChannel { sender: channel(capacity).0, capacity });
+        }
+    }
+
+    /// Whether the transport reads the records, so only the Json serializer
+    /// without signing is supported
+    pub fn requires_json(&self) -> bool {
+        matches!(
+            self.inner,
+            TransportInner::Elasticsearch { .. } | TransportInner::Stdio { framing: StdioFraming::JsonLines, .. }
+        )
+    }
+
+    /// Whether signed frames can be delivered
+    pub fn supports_signing(&self) -> bool {
+        !matches!(self.inner, TransportInner::Elasticsearch { .. })
+    }
+
+    /// Waits until the queued frames are written, only the Stdio transport buffers them
+    pub async fn flush(&self) -> FusionResult<()> {
+        if let TransportInner::Stdio { sender, .. } = &self.inner {
+            let (done, flushed) = oneshot::channel();
+            if sender.send_direct(StdioCommand::Flush(done)).await.is_ok() {
+                flushed.await.ok();
+            }
+        }
+        Ok(())
+    }
 
-                let mut output = PREFIX.to_vec();
-                output.extend(data);
-                output.extend_from_slice(POSTFIX);
+    /// Waits until the queued data is delivered. Elasticsearch indexes it once the clones
+    /// of the producer are dropped too
+    pub async fn close(self) -> FusionResult<()> {
+        self.flush().await?;
+        if let TransportInner::Elasticsearch { sender, mut stopped } = self.inner {
+            drop(sender);
+            while !*stopped.borrow() {
+                if stopped.changed().await.is_err() {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
 
-                io::stdout().write_all(&output)?;
+    /// Send data to all streams. The http/2 transport also sends it to every destination
+    /// and filter channel, so `/messages/data` subscribers get a copy per merged channel,
+    /// after the messages queued in that channel
+    pub async fn broadcast(&self, data: TransportData) -> FusionResult<()> {
+        match &self.inner {
+            TransportInner::Stdio { framing, sender } => return write_stdout(sender, framing.frame(None, &data)).await,
+            TransportInner::Http2 { destinations, filters, .. } => {
+                // Fails only without subscribers, the data is dropped then
+                for tx in destinations.read().unwrap().values() {
+                    tx.send(data.clone()).ok();
+                }
+                for channel in filters.read().unwrap().values() {
+                    channel.sender.send(data.clone()).ok();
+                }
+            }
+            _ => {}
+        }
+        for stream in Stream::ALL {
+            self.send_to(stream, data.clone()).await?;
+        }
+        Ok(())
+    }
+
+    /// Send data to the messages stream
+    pub async fn send_data(&self, data: TransportData) -> FusionResult<()> {
+        self.send_to(Stream::Messages, data).await
+    }
+
+    /// Send data to the filter destination, or to the messages stream if not specified
+    pub async fn send_to_destination(&self, destination: Option<&str>, data: TransportData) -> FusionResult<()> {
+        let Some(destination) = destination else {
+            return self.send_data(data).await;
+        };
+        match &self.inner {
+            TransportInner::Http2 { destinations, .. } => {
+                let tx = destinations.read().unwrap().get(destination).cloned();
+                match tx {
+                    // Fails only without subscribers, the data is dropped then
+                    Some(tx) => {
+                        tx.send(data).ok();
+                        Ok(())
+                    }
+                    None => self.send_data(data).await,
+                }
+            }
+            TransportInner::Stdio { framing, sender } => {
+                write_stdout(sender, framing.frame(Some(destination), &data)).await
+            }
+            TransportInner::Counting { .. } => self.send_data(data).await,
+            TransportInner::Elasticsearch { sender, .. } => index(sender, Some(destination), data).await,
+            TransportInner::Capture { frames } => {
+                frames.push(Stream::Messages, Some(destination), data);
                 Ok(())
+            }
+        }
+    }
+
+    /// Send a message of the filter to its destination or to the filter channel.
+    /// Without subscribers of `/messages/data` the message is dropped without an error,
+    /// as the messages stream keeps only the latest ones for the next subscriber
+    pub async fn send_message(
+        &self,
+        filter_name: &str,
+        destination: Option<&str>,
+        data: TransportData,
+    ) -> FusionResult<()> {
+        if let (TransportInner::Http2 { filters, sse, .. }, None) = (&self.inner, destination) {
+            let tx = filters.read().unwrap().get(filter_name).map(|channel| channel.sender.clone());
+            if let Some(tx) = tx {
+                sse.push(data.clone());
+                // Fails only without subscribers, the data is dropped then
+                tx.send(data).ok();
+                return Ok(());
+            }
+        }
+        self.send_to_destination(destination, data).await
+    }
+
+    pub async fn send_to(&self, stream: Stream, data: TransportData) -> FusionResult<()> {
+        match &self.inner {
+            TransportInner::Http2 { streams, sse, .. } => match streams.get(&stream) {
+                Some(tx) if stream == Stream::Messages => {
+                    sse.push(data.clone());
+                    tx.send(data).map(|_count| ()).map_err(FusionError::transport)
+                }
+                Some(tx) => tx.send(data)
+                    .map(|_count| ())
+                    .map_err(FusionError::transport),
+                None => Ok(()),
             },
+            TransportInner::Stdio { framing, sender } => write_stdout(sender, framing.frame(None, &data)).await,
+            TransportInner::Counting { counters } => {
+                count(counters, stream, &data);
+                Ok(())
+            }
+            // Other streams are not indexed
+            TransportInner::Elasticsearch { sender, .. } if stream == Stream::Messages => {
+                index(sender, None, data).await
+            }
+            TransportInner::Elasticsearch { .. } => Ok(()),
+            TransportInner::Capture { frames } => {
+                frames.push(stream, None, data);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn send_data_sync(&self, data: TransportData) -> FusionResult<()> {
+        match self.inner {
+            TransportInner::Http2 { .. } => unimplemented!("Http producer does not support blocking send"),
+            TransportInner::Stdio { framing, ref sender } => sender
+                .try_send(StdioCommand::Write(framing.frame(None, &data)))
+                .map_err(FusionError::transport),
+            TransportInner::Counting { ref counters } => {
+                count(counters, Stream::Messages, &data);
+                Ok(())
+            }
+            TransportInner::Elasticsearch { ref sender, .. } => {
+                sender.try_send((None, data)).map_err(FusionError::transport)
+            }
+            TransportInner::Capture { ref frames } => {
+                frames.push(Stream::Messages, None, data);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Queues the message for the next bulk request, waits while the queue is full unless it overflows to disk
+async fn index(sender: &OverflowSender<IndexedData>, destination: Option<&str>, data: TransportData) -> FusionResult<()> {
+    sender
+        .send((destination.map(ToOwned::to_owned), data))
+        .await
+        .map_err(|_| FusionError::transport(anyhow::anyhow!("Elasticsearch transport is stopped")))
+}
+
+/// Queues the frame for the stdout writer, waits while the queue is full unless it overflows to disk
+async fn write_stdout(sender: &OverflowSender<StdioCommand>, frame: Vec<u8>) -> FusionResult<()> {
+    sender
+        .send(StdioCommand::Write(frame))
+        .await
+        .map_err(|_| FusionError::transport(anyhow::anyhow!("Stdio transport is stopped")))
+}
+
+fn count(counters: &FxHashMap<Stream, StreamCounter>, stream: Stream, data: &TransportData) {
+    if let Some(counter) = counters.get(&stream) {
+        counter.messages.fetch_add(1, Ordering::Relaxed);
+        counter.bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+
+    use super::{Producer, Transport};
+
+    /// Http2 producer listening on the local port
+    pub(crate) fn http2_producer(port: u16) -> Producer {
+        Producer::new(Transport::Http2 {
+            capacity: 16,
+            listen_address: Some(SocketAddr::from(([127, 0, 0, 1], port))),
+            limits: Default::default(),
+            keepalive: Default::default(),
+        })
+        .unwrap()
+    }
+
+    /// Subscribes over HTTP/1, retrying until the server is listening
+    pub(crate) async fn subscribe(port: u16, path: &str) -> reqwest::Response {
+        let url = format!("http://127.0.0.1:{port}{path}");
+        for _ in 0..100 {
+            if let Ok(response) = reqwest::get(&url).await {
+                assert!(response.status().is_success(), "{path}: {}", response.status());
+                return response;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("No server listening on {port}");
+    }
+
+    /// Reads at least `len` bytes of the subscription
+    pub(crate) async fn read(subscription: &mut reqwest::Response, len: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        while data.len() < len {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), subscription.chunk())
+                .await
+                .expect("No data within 5 s")
+                .unwrap()
+                .expect("The subscription is closed");
+            data.extend_from_slice(&chunk);
         }
+        data
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_to_destination() {
+        let producer = http2_producer(31001);
+        producer.add_destination("transfers").unwrap();
+        let mut subscription = subscribe(31001, "/messages/transfers/data").await;
+
+        producer.send_to_destination(Some("transfers"), Bytes::from_static(b"<message>")).await.unwrap();
+        producer.broadcast(Bytes::from_static(b"<revoked>")).await.unwrap();
+
+        let expected = b"<message><revoked>";
+        assert_eq!(read(&mut subscription, expected.len()).await, expected);
+    }
+
+    #[tokio::test]
+    async fn test_filter_channels() {
+        let producer = http2_producer(31002);
+        producer.add_filter_channel("a", None);
+        producer.add_filter_channel("b", None);
+        // Dropped without subscribers
+        producer.send_message("a", None, Bytes::from_static(b"<dropped>")).await.unwrap();
+
+        let mut subscription = subscribe(31002, "/messages/data").await;
+        producer.send_message("a", None, Bytes::from_static(b"<a>")).await.unwrap();
+        let expected = b"<a>";
+        assert_eq!(read(&mut subscription, expected.len()).await, expected);
+
+        producer.send_message("b", None, Bytes::from_static(b"<b>")).await.unwrap();
+        let expected = b"<b>";
+        assert_eq!(read(&mut subscription, expected.len()).await, expected);
     }
 }
@@ -0,0 +1,472 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Notify};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OverflowConfig {
+    /// Directory of the queue segments. Items left from a previous run are sent first
+    pub path: PathBuf,
+    /// Items are dropped when the queue reaches this size. Default: 1024
+    #[serde(default = "default_max_size_mb")]
+    pub max_size_mb: u64,
+    /// Default: 64
+    #[serde(default = "default_segment_size_mb")]
+    pub segment_size_mb: u64,
+}
+
+fn default_max_size_mb() -> u64 {
+    1024
+}
+
+fn default_segment_size_mb() -> u64 {
+    64
+}
+
+/// Item of a transport queue which can be written to the disk queue
+pub trait Spillable: Sized + Send + 'static {
+    /// Encoded item, or the item itself if it can't be spilled
+    fn into_bytes(self) -> Result<Vec<u8>, Self>;
+
+    fn from_bytes(data: Vec<u8>) -> Result<Self>;
+}
+
+/// Sender of a transport queue. With an overflow queue, items are written to disk
+/// instead of waiting while the queue is full, and are moved back as it frees up
+#[derive(Debug)]
+pub struct OverflowSender<T> {
+    sender: mpsc::Sender<T>,
+    overflow: Option<Arc<Overflow>>,
+}
+
+impl<T> Clone for OverflowSender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            overflow: self.overflow.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Overflow {
+    log: Mutex<SegmentLog>,
+    spilled: Notify,
+}
+
+/// Sent item could not be queued
+#[derive(Debug, thiserror::Error)]
+pub enum OverflowError {
+    #[error("Queue is closed")]
+    Closed,
+    #[error("Queue is full")]
+    Full,
+}
+
+impl<T: Spillable> OverflowSender<T> {
+    /// Starts moving spilled items back to the queue, if the overflow queue is configured
+    pub fn new(sender: mpsc::Sender<T>, config: Option<&OverflowConfig>) -> Result<Self> {
+        let Some(config) = config else {
+            return Ok(Self { sender, overflow: None });
+        };
+        let log = SegmentLog::open(config)?;
+        if !log.is_empty() {
+            tracing::warn!("Sending {} MB of spilled data from {}", log.size() >> 20, config.path.display());
+        }
+        let overflow = Arc::new(Overflow {
+            log: Mutex::new(log),
+            spilled: Notify::new(),
+        });
+        tokio::spawn(drain(sender.clone(), Arc::downgrade(&overflow)));
+        Ok(Self {
+            sender,
+            overflow: Some(overflow),
+        })
+    }
+
+    /// Queues the item. Without an overflow queue it waits while the queue is full
+    pub async fn send(&self, item: T) -> Result<(), OverflowError> {
+        match &self.overflow {
+            Some(overflow) => self.spill_or_send(overflow, item),
+            None => self.sender.send(item).await.map_err(|_| OverflowError::Closed),
+        }
+    }
+
+    /// Queues the item without waiting
+    pub fn try_send(&self, item: T) -> Result<(), OverflowError> {
+        match &self.overflow {
+            Some(overflow) => self.spill_or_send(overflow, item),
+            None => self.sender.try_send(item).map_err(|error| match error {
+                TrySendError::Full(_) => OverflowError::Full,
+                TrySendError::Closed(_) => OverflowError::Closed,
+            }),
+        }
+    }
+
+    /// Sends the item bypassing the overflow queue, e.g. a control command
+    pub async fn send_direct(&self, item: T) -> Result<(), OverflowError> {
+        self.sender.send(item).await.map_err(|_| OverflowError::Closed)
+    }
+
+    /// Items go to disk while the queue is full and until the disk queue is drained, keeping the order
+    fn spill_or_send(&self, overflow: &Overflow, item: T) -> Result<(), OverflowError> {
+        let mut log = overflow.log.lock().unwrap();
+        let item = if log.is_empty() {
+            match self.sender.try_send(item) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Closed(_)) => return Err(OverflowError::Closed),
+                Err(TrySendError::Full(item)) => {
+                    tracing::warn!("Transport queue is full, spilling to disk");
+                    item
+                }
+            }
+        } else {
+            item
+        };
+        let data = match item.into_bytes() {
+            Ok(data) => data,
+            // Not spillable items wait for the queue
+            Err(item) => {
+                drop(log);
+                return self.sender.try_send(item).map_err(|_| OverflowError::Full);
+            }
+        };
+        let result = log.push(&data);
+        drop(log);
+        overflow.spilled.notify_one();
+        result.map_err(|error| {
+            tracing::error!("Spilling to disk: {:?}", error);
+            OverflowError::Full
+        })
+    }
+}
+
+/// Moves spilled items back to the queue as it frees up
+async fn drain<T: Spillable>(sender: mpsc::Sender<T>, overflow: std::sync::Weak<Overflow>) {
+    loop {
+        let Some(overflow) = overflow.upgrade() else {
+            return;
+        };
+        let empty = overflow.log.lock().unwrap().is_empty();
+        if empty {
+            // Notified on every spill, the timeout covers a missed notification
+            tokio::time::timeout(Duration::from_secs(1), overflow.spilled.notified()).await.ok();
+            continue;
+        }
+
+        let Ok(permit) = sender.reserve().await else {
+            return;
+        };
+        let mut log = overflow.log.lock().unwrap();
+        match log.pop() {
+            Ok(Some(data)) => match T::from_bytes(data) {
+                Ok(item) => permit.send(item),
+                Err(error) => tracing::error!("Decoding spilled item: {:?}", error),
+            },
+            Ok(None) => {}
+            Err(error) => tracing::error!("Reading spilled item: {:?}", error),
+        }
+        if log.is_empty() {
+            tracing::info!("Spilled data is drained");
+        }
+    }
+}
+
+/// Items of the queue in the directory in order, without removing them
+pub fn read_items(dir: &Path) -> Result<Vec<Vec<u8>>> {
+    let mut items = Vec::new();
+    for segment in list_segments(dir)? {
+        let path = SegmentLog::path(dir, segment.seqno);
+        let data = std::fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut data = data.as_slice();
+        while !data.is_empty() {
+            let (len, rest) = data.split_at(data.len().min(4));
+            let len = u32::from_be_bytes(len.try_into().context("Truncated segment")?) as usize;
+            anyhow::ensure!(rest.len() >= len, "Truncated segment {}", path.display());
+            let (item, rest) = rest.split_at(len);
+            items.push(item.to_vec());
+            data = rest;
+        }
+    }
+    Ok(items)
+}
+
+fn list_segments(dir: &Path) -> Result<Vec<Segment>> {
+    let mut segments = Vec::new();
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let entry = entry?;
+        let seqno = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_suffix(".log"))
+            .and_then(|seqno| seqno.parse::<u64>().ok());
+        if let Some(seqno) = seqno {
+            segments.push(Segment {
+                seqno,
+                size: entry.metadata()?.len(),
+            });
+        }
+    }
+    segments.sort_by_key(|segment| segment.seqno);
+    Ok(segments)
+}
+
+#[derive(Debug)]
+struct Segment {
+    seqno: u64,
+    size: u64,
+}
+
+/// Segment files of big-endian u32 length prefixed records. The last segment is appended,
+/// the first one is read and removed once read
+#[derive(Debug)]
+struct SegmentLog {
+    dir: PathBuf,
+    max_size: u64,
+    segment_size: u64,
+    segments: VecDeque<Segment>,
+    writer: Option<File>,
+    /// Reader of the first segment and its offset
+    reader: Option<(BufReader<File>, u64)>,
+}
+
+impl SegmentLog {
+    fn open(config: &OverflowConfig) -> Result<Self> {
+        let dir = config.path.clone();
+        std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        let segments = list_segments(&dir)?;
+        Ok(Self {
+            dir,
+            max_size: config.max_size_mb << 20,
+            segment_size: config.segment_size_mb.max(1) << 20,
+            segments: segments.into(),
+            writer: None,
+            reader: None,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Unread bytes
+    fn size(&self) -> u64 {
+        let read = self.reader.as_ref().map(|(_, offset)| *offset).unwrap_or_default();
+        self.segments.iter().map(|segment| segment.size).sum::<u64>() - read
+    }
+
+    fn path(dir: &Path, seqno: u64) -> PathBuf {
+        dir.join(format!("{seqno:020}.log"))
+    }
+
+    fn push(&mut self, data: &[u8]) -> Result<()> {
+        let len = data.len() as u64 + 4;
+        anyhow::ensure!(self.size() + len <= self.max_size, "Disk queue is full, dropping the item");
+
+        let rotate = match self.segments.back() {
+            Some(segment) => self.writer.is_none() || segment.size >= self.segment_size,
+            None => true,
+        };
+        if rotate {
+            let seqno = self.segments.back().map(|segment| segment.seqno + 1).unwrap_or_default();
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(Self::path(&self.dir, seqno))?;
+            self.segments.push_back(Segment { seqno, size: 0 });
+            self.writer = Some(file);
+        }
+        let writer = self.writer.as_mut().context("No segment writer")?;
+        let mut record = Vec::with_capacity(len as usize);
+        record.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        record.extend_from_slice(data);
+        writer.write_all(&record)?;
+        if let Some(segment) = self.segments.back_mut() {
+            segment.size += len;
+        }
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            let Some(segment) = self.segments.front() else {
+                return Ok(None);
+            };
+            let path = Self::path(&self.dir, segment.seqno);
+            let (reader, offset) = match &mut self.reader {
+                Some(reader) => reader,
+                None => self.reader.insert((BufReader::new(File::open(&path)?), 0)),
+            };
+
+            if *offset >= segment.size {
+                self.reader = None;
+                if self.segments.len() == 1 {
+                    self.writer = None;
+                }
+                self.segments.pop_front();
+                std::fs::remove_file(&path).ok();
+                continue;
+            }
+
+            let mut len = [0; 4];
+            let record = reader.read_exact(&mut len).and_then(|()| {
+                let mut data = vec![0; u32::from_be_bytes(len) as usize];
+                reader.read_exact(&mut data)?;
+                Ok(data)
+            });
+            match record {
+                Ok(data) => {
+                    *offset += data.len() as u64 + 4;
+                    return Ok(Some(data));
+                }
+                Err(error) => {
+                    // Skip the rest of a corrupted segment
+                    *offset = segment.size;
+                    return Err(error).with_context(|| format!("Corrupted segment {}", path.display()));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Empty directory for a queue, unique for the test and the process
+    pub(crate) fn queue_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("fusion-producer-{name}-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    fn config(dir: &Path, max_size_mb: u64) -> OverflowConfig {
+        OverflowConfig {
+            path: dir.to_owned(),
+            max_size_mb,
+            segment_size_mb: 1,
+        }
+    }
+
+    impl Spillable for Vec<u8> {
+        fn into_bytes(self) -> Result<Vec<u8>, Self> {
+            Ok(self)
+        }
+
+        fn from_bytes(data: Vec<u8>) -> Result<Self> {
+            Ok(data)
+        }
+    }
+
+    fn items(count: u8) -> Vec<Vec<u8>> {
+        (0..count).map(|i| vec![i; i as usize * 3]).collect()
+    }
+
+    #[test]
+    fn test_segment_log() {
+        let dir = queue_dir("segment-log");
+        let mut log = SegmentLog::open(&config(&dir, 1)).unwrap();
+        // A few records per segment
+        log.segment_size = 32;
+
+        for item in items(10) {
+            log.push(&item).unwrap();
+        }
+        assert!(list_segments(&dir).unwrap().len() > 1);
+        assert_eq!(read_items(&dir).unwrap(), items(10));
+
+        for item in &items(4) {
+            assert_eq!(log.pop().unwrap().as_ref(), Some(item));
+        }
+        log.push(b"tail").unwrap();
+
+        let mut expected = items(10).split_off(4);
+        expected.push(b"tail".to_vec());
+        let mut popped = Vec::new();
+        while let Some(item) = log.pop().unwrap() {
+            popped.push(item);
+        }
+        assert_eq!(popped, expected);
+        assert!(log.is_empty());
+        assert_eq!(log.size(), 0);
+        assert!(list_segments(&dir).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_segment_log_reopen() {
+        let dir = queue_dir("segment-log-reopen");
+        let mut log = SegmentLog::open(&config(&dir, 1)).unwrap();
+        for item in items(5) {
+            log.push(&item).unwrap();
+        }
+        drop(log);
+
+        // Items left from a previous run come first
+        let mut log = SegmentLog::open(&config(&dir, 1)).unwrap();
+        assert_eq!(log.size(), items(5).iter().map(|item| item.len() as u64 + 4).sum::<u64>());
+        log.push(b"new").unwrap();
+        let mut popped = Vec::new();
+        while let Some(item) = log.pop().unwrap() {
+            popped.push(item);
+        }
+        let mut expected = items(5);
+        expected.push(b"new".to_vec());
+        assert_eq!(popped, expected);
+    }
+
+    #[test]
+    fn test_segment_log_limit() {
+        let dir = queue_dir("segment-log-limit");
+        let mut log = SegmentLog::open(&config(&dir, 1)).unwrap();
+        let item = vec![0; 600 << 10];
+        log.push(&item).unwrap();
+        assert!(log.push(&item).is_err());
+
+        // Freed space is available again
+        assert_eq!(log.pop().unwrap(), Some(item.clone()));
+        log.push(&item).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_overflow_order() {
+        let dir = queue_dir("overflow-order");
+        let (sender, mut receiver) = mpsc::channel(2);
+        let sender = OverflowSender::new(sender, Some(&config(&dir, 1))).unwrap();
+
+        // Nothing is received yet, so most of the items are spilled
+        for item in items(20) {
+            sender.try_send(item).unwrap();
+        }
+        assert!(!list_segments(&dir).unwrap().is_empty());
+
+        let mut received = Vec::new();
+        while received.len() < 20 {
+            let item = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+                .await
+                .expect("No item within 5 s")
+                .unwrap();
+            received.push(item);
+        }
+        assert_eq!(received, items(20));
+    }
+
+    #[tokio::test]
+    async fn test_without_overflow() {
+        let (sender, mut receiver) = mpsc::channel(1);
+        let sender = OverflowSender::new(sender, None).unwrap();
+        sender.try_send(vec![1]).unwrap();
+        assert!(matches!(sender.try_send(vec![2]), Err(OverflowError::Full)));
+
+        assert_eq!(receiver.recv().await, Some(vec![1]));
+        drop(receiver);
+        assert!(matches!(sender.send(vec![3]).await, Err(OverflowError::Closed)));
+    }
+}
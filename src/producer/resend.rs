@@ -0,0 +1,254 @@
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+
+use super::elasticsearch::IndexedData;
+use super::overflow::{self, Spillable};
+use super::{StdioFraming, TransportData};
+
+/// Layout of the frames to re-send
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResendFormat {
+    /// Output of the Stdio transport or its overflow queue
+    Stdio(StdioFraming),
+    /// Overflow queue of the Elasticsearch transport
+    Elasticsearch,
+}
+
+impl Default for ResendFormat {
+    fn default() -> Self {
+        Self::Stdio(StdioFraming::default())
+    }
+}
+
+impl FromStr for ResendFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "separators" => Self::Stdio(StdioFraming::Separators),
+            "json_lines" => Self::Stdio(StdioFraming::JsonLines),
+            "length_prefixed" => Self::Stdio(StdioFraming::LengthPrefixed),
+            "hex" => Self::Stdio(StdioFraming::Hex),
+            "elasticsearch" => Self::Elasticsearch,
+            _ => {
+                return Err(format!(
+                    "Unknown format `{s}`, available: separators, json_lines, length_prefixed, hex, elasticsearch"
+                ))
+            }
+        })
+    }
+}
+
+/// Reads frames with their destinations from a file or an overflow queue directory.
+/// `json` tells how the frames are length prefixed: big-endian u32 for JSON, varint for protobuf
+pub fn read_frames(path: &Path, format: ResendFormat, json: bool) -> Result<Vec<IndexedData>> {
+    let framing = match format {
+        ResendFormat::Stdio(framing) => framing,
+        ResendFormat::Elasticsearch => {
+            anyhow::ensure!(path.is_dir(), "Elasticsearch frames are read from an overflow queue directory");
+            return overflow::read_items(path)?
+                .into_iter()
+                .map(IndexedData::from_bytes)
+                .collect();
+        }
+    };
+
+    let data = match path.is_dir() {
+        true => overflow::read_items(path)?.concat(),
+        false => std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?,
+    };
+    match framing {
+        StdioFraming::Separators => read_separated(&data, json),
+        StdioFraming::JsonLines => {
+            anyhow::ensure!(json, "json_lines frames require the Json serializer");
+            Ok(lines(&data)
+                .map(|line| {
+                    let mut frame = Vec::with_capacity(line.len() + 4);
+                    frame.extend_from_slice(&(line.len() as u32).to_be_bytes());
+                    frame.extend_from_slice(line);
+                    (None, TransportData::from(frame))
+                })
+                .collect())
+        }
+        StdioFraming::LengthPrefixed => {
+            let mut data = data.as_slice();
+            let mut frames = Vec::new();
+            while !data.is_empty() {
+                let (frame, rest) = split_frame(data, json)?;
+                frames.push((None, TransportData::copy_from_slice(frame)));
+                data = rest;
+            }
+            Ok(frames)
+        }
+        StdioFraming::Hex => lines(&data)
+            .map(|line| {
+                let line = std::str::from_utf8(line).context("Invalid hex frame")?;
+                let (destination, frame) = match line.split_once(' ') {
+                    Some((destination, frame)) => (Some(destination.to_owned()), frame),
+                    None => (None, line),
+                };
+                Ok((destination, TransportData::from(hex::decode(frame).context("Invalid hex frame")?)))
+            })
+            .collect(),
+    }
+}
+
+/// Frames between `-----` lines, the header line may contain the destination
+fn read_separated(mut data: &[u8], json: bool) -> Result<Vec<IndexedData>> {
+    let mut frames = Vec::new();
+    while !data.is_empty() {
+        let end = data.iter().position(|byte| *byte == b'\n').context("Truncated frame header")?;
+        let header = std::str::from_utf8(&data[..end]).context("Invalid frame header")?;
+        let destination = match header {
+            "-----" => None,
+            header => Some(header.strip_prefix("----- ").context("Invalid frame header")?.to_owned()),
+        };
+        let (frame, rest) = split_frame(&data[end + 1..], json)?;
+        data = rest.strip_prefix(b"\n-----\n").context("Missing frame separator")?;
+        frames.push((destination, TransportData::copy_from_slice(frame)));
+    }
+    Ok(frames)
+}
+
+/// Splits off one length prefixed frame
+fn split_frame(data: &[u8], json: bool) -> Result<(&[u8], &[u8])> {
+    let (prefix, len) = if json {
+        let len = data.get(..4).context("Truncated frame length")?;
+        (4, u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize)
+    } else {
+        let mut len = 0;
+        let mut prefix = 0;
+        loop {
+            let byte = *data.get(prefix).context("Truncated frame length")?;
+            anyhow::ensure!(prefix < 10, "Invalid frame length");
+            len |= ((byte & 0x7f) as usize) << (7 * prefix);
+            prefix += 1;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        (prefix, len)
+    };
+    anyhow::ensure!(data.len() >= prefix + len, "Truncated frame");
+    Ok(data.split_at(prefix + len))
+}
+
+fn lines(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    data.split(|byte| *byte == b'\n').filter(|line| !line.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::overflow::tests::queue_dir;
+    use super::*;
+
+    fn json_frame(json: &str) -> Vec<u8> {
+        let mut frame = (json.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(json.as_bytes());
+        frame
+    }
+
+    fn protobuf_frame(len: usize) -> Vec<u8> {
+        // Varint length of 300 takes two bytes
+        let mut frame = match len {
+            300 => vec![0xac, 0x02],
+            len => vec![len as u8],
+        };
+        frame.extend(std::iter::repeat(7).take(len));
+        frame
+    }
+
+    /// Writes the frames as the Stdio transport and reads them back
+    fn resend(name: &str, framing: StdioFraming, frames: &[(Option<&str>, Vec<u8>)], json: bool) -> Result<Vec<IndexedData>> {
+        let dir = queue_dir(name);
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("output");
+        let output = frames
+            .iter()
+            .flat_map(|(destination, data)| framing.frame(*destination, data))
+            .collect::<Vec<_>>();
+        std::fs::write(&path, output)?;
+        read_frames(&path, ResendFormat::Stdio(framing), json)
+    }
+
+    fn expected(frames: &[(Option<&str>, Vec<u8>)], destinations: bool) -> Vec<IndexedData> {
+        frames
+            .iter()
+            .map(|(destination, data)| {
+                let destination = destination.filter(|_| destinations).map(ToOwned::to_owned);
+                (destination, TransportData::from(data.clone()))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_resend_json() {
+        let frames = [
+            (None, json_frame(r#"{"a":1}"#)),
+            (Some("transfers"), json_frame("{\"b\":\"-----\\n\"}")),
+        ];
+        let read = |name, framing| resend(name, framing, &frames, true).unwrap();
+
+        assert_eq!(read("resend-separators", StdioFraming::Separators), expected(&frames, true));
+        assert_eq!(read("resend-json-lines", StdioFraming::JsonLines), expected(&frames, false));
+        assert_eq!(read("resend-length-prefixed", StdioFraming::LengthPrefixed), expected(&frames, false));
+        assert_eq!(read("resend-hex", StdioFraming::Hex), expected(&frames, true));
+    }
+
+    #[test]
+    fn test_resend_protobuf() {
+        let frames = [(None, protobuf_frame(3)), (Some("transfers"), protobuf_frame(300))];
+        let read = |name, framing| resend(name, framing, &frames, false).unwrap();
+
+        assert_eq!(read("resend-pb-separators", StdioFraming::Separators), expected(&frames, true));
+        assert_eq!(read("resend-pb-length-prefixed", StdioFraming::LengthPrefixed), expected(&frames, false));
+        assert!(resend("resend-pb-json-lines", StdioFraming::JsonLines, &frames, false).is_err());
+    }
+
+    #[test]
+    fn test_resend_truncated() {
+        let frames = [(None, json_frame(r#"{"a":1}"#))];
+        let dir = queue_dir("resend-truncated");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("output");
+
+        let output = StdioFraming::LengthPrefixed.frame(None, &frames[0].1);
+        std::fs::write(&path, &output[..output.len() - 1]).unwrap();
+        assert!(read_frames(&path, ResendFormat::Stdio(StdioFraming::LengthPrefixed), true).is_err());
+
+        let output = StdioFraming::Separators.frame(None, &frames[0].1);
+        std::fs::write(&path, &output[..output.len() - 3]).unwrap();
+        assert!(read_frames(&path, ResendFormat::Stdio(StdioFraming::Separators), true).is_err());
+    }
+
+    #[test]
+    fn test_resend_elasticsearch_queue() {
+        let items = vec![
+            (None, TransportData::from_static(b"{\"a\":1}")),
+            (Some("transfers".to_owned()), TransportData::from_static(b"{\"b\":2}")),
+        ];
+        let dir = queue_dir("resend-elasticsearch");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Segment of big-endian u32 length prefixed items
+        let mut segment = Vec::new();
+        for item in items.clone() {
+            let data = item.into_bytes().unwrap();
+            segment.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            segment.extend_from_slice(&data);
+        }
+        std::fs::write(dir.join(format!("{:020}.log", 0)), segment).unwrap();
+
+        assert_eq!(read_frames(&dir, ResendFormat::Elasticsearch, true).unwrap(), items);
+        assert!(read_frames(&dir.join("00000000000000000000.log"), ResendFormat::Elasticsearch, true).is_err());
+    }
+
+    #[test]
+    fn test_resend_format() {
+        assert_eq!("hex".parse::<ResendFormat>(), Ok(ResendFormat::Stdio(StdioFraming::Hex)));
+        assert_eq!("elasticsearch".parse::<ResendFormat>(), Ok(ResendFormat::Elasticsearch));
+        assert!("kafka".parse::<ResendFormat>().is_err());
+    }
+}
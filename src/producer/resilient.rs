@@ -0,0 +1,236 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+/// Items of a batch, e.g. documents or records
+pub type Batch = Vec<Vec<u8>>;
+
+/// Client of a network sink wrapped into [`ResilientSink`]
+#[async_trait::async_trait]
+pub trait SinkClient: Send {
+    /// Delivers the batch. An error means the sink is unreachable, the batch is retried later
+    async fn send(&mut self, batch: &[Vec<u8>]) -> Result<()>;
+
+    /// Checks whether the sink is reachable again before resending buffered batches
+    async fn health_check(&mut self) -> Result<()>;
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResilienceConfig {
+    /// Delay before the first reconnect attempt. Default: 500
+    pub initial_backoff_ms: u64,
+    /// Limit of the reconnect delay, which doubles after every failed attempt. Default: 30000
+    pub max_backoff_ms: u64,
+    /// Batches buffered in memory while the sink is unreachable. Default: 1000
+    pub buffer_batches: usize,
+    /// Directory for batches beyond the memory buffer, they are dropped if not specified.
+    /// Spilled batches are resent after a restart too
+    pub spill_dir: Option<PathBuf>,
+}
+
+impl Default for ResilienceConfig {
+    fn default() -> Self {
+        Self {
+            initial_backoff_ms: 500,
+            max_backoff_ms: 30000,
+            buffer_batches: 1000,
+            spill_dir: None,
+        }
+    }
+}
+
+/// Sends batches in order with reconnects and exponential backoff.
+/// While the sink is unreachable, batches are buffered in memory, then on disk
+pub struct ResilientSink<C> {
+    client: C,
+    config: ResilienceConfig,
+    buffer: VecDeque<Batch>,
+    spill: Option<Spill>,
+    connected: bool,
+    backoff: Duration,
+    retry_at: Instant,
+    dropped: u64,
+}
+
+impl<C: SinkClient> ResilientSink<C> {
+    pub async fn new(client: C, config: ResilienceConfig) -> Result<Self> {
+        let spill = match &config.spill_dir {
+            Some(dir) => Some(Spill::open(dir.clone()).await?),
+            None => None,
+        };
+        let spilled = spill.as_ref().map(Spill::len).unwrap_or_default();
+        if spilled > 0 {
+            tracing::warn!("Resending {} spilled batches", spilled);
+        }
+        Ok(Self {
+            backoff: Duration::from_millis(config.initial_backoff_ms),
+            client,
+            config,
+            buffer: VecDeque::new(),
+            spill,
+            connected: true,
+            retry_at: Instant::now(),
+            dropped: 0,
+        })
+    }
+
+    /// Sends the batch after the buffered ones, or buffers it while the sink is unreachable
+    pub async fn send(&mut self, batch: Batch) {
+        self.push(batch).await;
+        self.drain().await;
+    }
+
+    /// Resends buffered batches once the backoff has elapsed and the sink is healthy
+    pub async fn drain(&mut self) {
+        if !self.connected {
+            if Instant::now() < self.retry_at {
+                return;
+            }
+            if let Err(error) = self.client.health_check().await {
+                self.fail(error);
+                return;
+            }
+            tracing::info!("Sink is reachable, resending {} buffered batches", self.buffered());
+            self.connected = true;
+        }
+
+        while let Some(batch) = self.pop().await {
+            if let Err(error) = self.client.send(&batch).await {
+                self.buffer.push_front(batch);
+                self.fail(error);
+                return;
+            }
+            self.backoff = Duration::from_millis(self.config.initial_backoff_ms);
+        }
+    }
+
+    /// Number of batches waiting for the sink
+    pub fn buffered(&self) -> usize {
+        self.buffer.len() + self.spill.as_ref().map(Spill::len).unwrap_or_default()
+    }
+
+    fn fail(&mut self, error: anyhow::Error) {
+        tracing::warn!(
+            "Sink is unreachable, retrying in {:?} with {} buffered batches: {:?}",
+            self.backoff,
+            self.buffered(),
+            error
+        );
+        self.connected = false;
+        self.retry_at = Instant::now() + self.backoff;
+        self.backoff = (self.backoff * 2).min(Duration::from_millis(self.config.max_backoff_ms));
+    }
+
+    /// Batches go to disk once the memory buffer is full and until the disk is drained, keeping the order
+    async fn push(&mut self, batch: Batch) {
+        let spilling = self.buffer.len() >= self.config.buffer_batches.max(1)
+            || self.spill.as_ref().map(Spill::len).unwrap_or_default() > 0;
+        if !spilling {
+            self.buffer.push_back(batch);
+            return;
+        }
+        match &mut self.spill {
+            Some(spill) => {
+                if let Err(error) = spill.push(&batch).await {
+                    tracing::error!("Spilling batch to disk: {:?}", error);
+                    self.drop_batch();
+                }
+            }
+            None => self.drop_batch(),
+        }
+    }
+
+    async fn pop(&mut self) -> Option<Batch> {
+        if let Some(batch) = self.buffer.pop_front() {
+            return Some(batch);
+        }
+        let spill = self.spill.as_mut()?;
+        loop {
+            match spill.pop().await {
+                Ok(batch) => return batch,
+                Err(error) => tracing::error!("Reading spilled batch: {:?}", error),
+            }
+        }
+    }
+
+    fn drop_batch(&mut self) {
+        self.dropped += 1;
+        tracing::error!("Sink buffer is full, dropped {} batches", self.dropped);
+    }
+}
+
+/// Batches stored as `<seqno>.batch` files of big-endian u32 length prefixed items
+struct Spill {
+    dir: PathBuf,
+    first: u64,
+    next: u64,
+}
+
+impl Spill {
+    async fn open(dir: PathBuf) -> Result<Self> {
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .with_context(|| format!("Failed to create spill directory {}", dir.display()))?;
+        let mut seqnos = Vec::new();
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let seqno = name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".batch"))
+                .and_then(|seqno| seqno.parse::<u64>().ok());
+            seqnos.extend(seqno);
+        }
+        Ok(Self {
+            first: seqnos.iter().copied().min().unwrap_or_default(),
+            next: seqnos.iter().copied().max().map(|seqno| seqno + 1).unwrap_or_default(),
+            dir,
+        })
+    }
+
+    fn len(&self) -> usize {
+        (self.next - self.first) as usize
+    }
+
+    fn path(&self, seqno: u64) -> PathBuf {
+        self.dir.join(format!("{seqno:020}.batch"))
+    }
+
+    async fn push(&mut self, batch: &[Vec<u8>]) -> Result<()> {
+        let mut data = Vec::with_capacity(batch.iter().map(|item| item.len() + 4).sum());
+        for item in batch {
+            data.extend_from_slice(&(item.len() as u32).to_be_bytes());
+            data.extend_from_slice(item);
+        }
+        tokio::fs::write(self.path(self.next), data).await?;
+        self.next += 1;
+        Ok(())
+    }
+
+    /// Takes the oldest batch, a missing or corrupted file is skipped with an error
+    async fn pop(&mut self) -> Result<Option<Batch>> {
+        if self.first == self.next {
+            return Ok(None);
+        }
+        let path = self.path(self.first);
+        self.first += 1;
+        let data = tokio::fs::read(&path).await;
+        tokio::fs::remove_file(&path).await.ok();
+
+        let mut data = data.with_context(|| format!("Failed to read {}", path.display()))?.as_slice();
+        let mut batch = Vec::new();
+        while !data.is_empty() {
+            let (len, rest) = data.split_at(data.len().min(4));
+            let len = u32::from_be_bytes(len.try_into().context("Truncated batch")?) as usize;
+            anyhow::ensure!(rest.len() >= len, "Truncated batch {}", path.display());
+            let (item, rest) = rest.split_at(len);
+            batch.push(item.to_vec());
+            data = rest;
+        }
+        Ok(Some(batch))
+    }
+}
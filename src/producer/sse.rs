@@ -0,0 +1,62 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use tokio::sync::broadcast::{channel, Receiver, Sender};
+
+use super::TransportData;
+
+/// Numbered frame of the messages stream
+pub type SseEvent = (u64, TransportData);
+
+/// Latest frames of the messages stream with sequence numbers,
+/// so reconnecting SSE clients resume after `Last-Event-ID`
+#[derive(Debug)]
+pub struct SseHistory {
+    inner: Mutex<History>,
+    sender: Sender<SseEvent>,
+    capacity: usize,
+}
+
+#[derive(Debug, Default)]
+struct History {
+    next_id: u64,
+    events: VecDeque<SseEvent>,
+}
+
+impl SseHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Default::default(),
+            sender: channel(capacity).0,
+            capacity,
+        }
+    }
+
+    /// Numbers the frame and sends it to the open SSE subscriptions
+    pub fn push(&self, data: TransportData) {
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        if inner.events.len() >= self.capacity {
+            inner.events.pop_front();
+        }
+        inner.events.push_back((id, data.clone()));
+        // Sent under the lock to keep the order with `subscribe`. Fails only without subscribers
+        self.sender.send((id, data)).ok();
+    }
+
+    /// Returns kept frames after `last_id` and a receiver of the next ones
+    pub fn subscribe(&self, last_id: Option<u64>) -> (Vec<SseEvent>, Receiver<SseEvent>) {
+        let inner = self.inner.lock().unwrap();
+        let missed = match last_id {
+            Some(last_id) => {
+                if matches!(inner.events.front(), Some((first_id, _)) if *first_id > last_id + 1) {
+                    tracing::warn!("SSE subscriber resumes after {}, older events are gone", last_id);
+                }
+                inner.events.iter().filter(|(id, _)| *id > last_id).cloned().collect()
+            }
+            None => Vec::new(),
+        };
+        (missed, self.sender.subscribe())
+    }
+}
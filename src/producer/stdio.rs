@@ -0,0 +1,125 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::Deserialize;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::sync::{mpsc, oneshot};
+
+use super::overflow::{OverflowConfig, OverflowSender, Spillable};
+
+/// Frames waiting for the writer, senders wait while the queue is full
+const QUEUE_CAPACITY: usize = 4096;
+const BUFFER_SIZE: usize = 256 * 1024;
+/// Buffered frames are flushed at least this often
+const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Framing of the frames written to stdout
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StdioFraming {
+    /// Frames between `-----` lines, frames of destinations start with `----- <destination>`
+    #[default]
+    Separators,
+    /// JSON records one per line, requires the Json serializer. Destinations are not marked
+    JsonLines,
+    /// Frames as is: big-endian u32 length prefixed JSON or length delimited protobuf.
+    /// Destinations are not marked
+    LengthPrefixed,
+    /// Hex encoded frames one per line, frames of destinations are prefixed with `<destination> `
+    Hex,
+}
+
+impl StdioFraming {
+    pub fn frame(self, destination: Option<&str>, data: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(data.len() * 2 + 16);
+        match self {
+            Self::Separators => {
+                match destination {
+                    Some(destination) => frame.extend_from_slice(format!("----- {destination}\n").as_bytes()),
+                    None => frame.extend_from_slice(b"-----\n"),
+                }
+                frame.extend_from_slice(data);
+                frame.extend_from_slice(b"\n-----\n");
+            }
+            Self::JsonLines => {
+                frame.extend_from_slice(data.get(std::mem::size_of::<u32>()..).unwrap_or_default());
+                frame.push(b'\n');
+            }
+            Self::LengthPrefixed => frame.extend_from_slice(data),
+            Self::Hex => {
+                if let Some(destination) = destination {
+                    frame.extend_from_slice(destination.as_bytes());
+                    frame.push(b' ');
+                }
+                frame.extend_from_slice(hex::encode(data).as_bytes());
+                frame.push(b'\n');
+            }
+        }
+        frame
+    }
+}
+
+#[derive(Debug)]
+pub enum StdioCommand {
+    Write(Vec<u8>),
+    /// Flushes the written frames and notifies the sender
+    Flush(oneshot::Sender<()>),
+}
+
+/// Frames are spilled as is, flush requests always wait for the queue
+impl Spillable for StdioCommand {
+    fn into_bytes(self) -> Result<Vec<u8>, Self> {
+        match self {
+            Self::Write(frame) => Ok(frame),
+            command => Err(command),
+        }
+    }
+
+    fn from_bytes(data: Vec<u8>) -> Result<Self> {
+        Ok(Self::Write(data))
+    }
+}
+
+/// Spawns the stdout writer task
+pub fn start(overflow: Option<&OverflowConfig>) -> Result<OverflowSender<StdioCommand>> {
+    let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+    let sender = OverflowSender::new(sender, overflow)?;
+    tokio::spawn(run(tokio::io::stdout(), receiver));
+    Ok(sender)
+}
+
+/// Writes frames through a buffer, which is flushed periodically and on request
+async fn run<W: AsyncWrite + Unpin>(output: W, mut receiver: mpsc::Receiver<StdioCommand>) {
+    let mut output = BufWriter::with_capacity(BUFFER_SIZE, output);
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    let mut pending = false;
+    loop {
+        tokio::select! {
+            command = receiver.recv() => match command {
+                Some(StdioCommand::Write(frame)) => {
+                    if let Err(error) = output.write_all(&frame).await {
+                        tracing::error!("Writing to stdout: {}", error);
+                    }
+                    pending = true;
+                }
+                Some(StdioCommand::Flush(done)) => {
+                    flush(&mut output).await;
+                    pending = false;
+                    done.send(()).ok();
+                }
+                None => break,
+            },
+            _ = interval.tick(), if pending => {
+                flush(&mut output).await;
+                pending = false;
+            }
+        }
+    }
+    flush(&mut output).await;
+}
+
+async fn flush<W: AsyncWrite + Unpin>(output: &mut W) {
+    if let Err(error) = output.flush().await {
+        tracing::error!("Flushing stdout: {}", error);
+    }
+}
@@ -0,0 +1,36 @@
+use std::cell::RefCell;
+
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+
+/// Capacity of a fresh per-thread buffer
+const BUFFER_CAPACITY: usize = 1 << 20;
+
+/// Spare capacity reserved before writing a frame
+const FRAME_RESERVE: usize = 4 << 10;
+
+thread_local! {
+    /// Frames are split off the buffer. Once all frames of an allocation are dropped,
+    /// `reserve` reuses it instead of allocating
+    static BUFFER: RefCell<BytesMut> = RefCell::new(BytesMut::with_capacity(BUFFER_CAPACITY));
+}
+
+/// Writes a frame into the pooled buffer of the current thread and returns it without copying
+pub fn write_frame<F>(write: F) -> Result<Bytes>
+where
+    F: FnOnce(&mut BytesMut) -> Result<()>,
+{
+    BUFFER.with(|buffer| {
+        let mut buffer = buffer.borrow_mut();
+        if buffer.capacity() < FRAME_RESERVE {
+            buffer.reserve(BUFFER_CAPACITY);
+        }
+        match write(&mut buffer) {
+            Ok(()) => Ok(buffer.split().freeze()),
+            Err(error) => {
+                buffer.clear();
+                Err(error)
+            }
+        }
+    })
+}
@@ -1,39 +1,272 @@
 use std::mem::size_of;
 
 use anyhow::Result;
+use bytes::{BufMut, Bytes};
+use everscale_crypto::ed25519;
 use serde::Deserialize;
+use ton_block::MsgAddressInt;
 
-use crate::types::SerializeMessage;
+use crate::error::{FusionError, FusionResult};
+#[cfg(feature="serialize-json")]
+use crate::types::utils::with_address_format;
+use crate::types::{
+    ControlEvent, SerializeAccountChanges, SerializeAccountState, SerializeBlock, SerializeConfigParam, SerializeControl, SerializeMessage,
+    SerializeBounce, SerializeMessageStatus, SerializeTrace, SerializeAggregate,
+};
 
+mod buffer;
 mod protobuf;
 
+#[cfg(feature="serialize-protobuf")]
+const PROTOBUF_SCHEMA: &str = include_str!("data_producer.proto");
+#[cfg(feature="serialize-json")]
+const JSON_SCHEMA: &str = include_str!("data_producer.schema.json");
+
+/// Schema formats of the enabled serializers with their definitions
+pub const SCHEMAS: &[(&str, &str)] = &[
+    #[cfg(feature="serialize-protobuf")]
+    ("proto", PROTOBUF_SCHEMA),
+    #[cfg(feature="serialize-json")]
+    ("json", JSON_SCHEMA),
+];
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(tag = "kind", deny_unknown_fields)]
 pub enum Serializer {
     #[cfg(feature="serialize-protobuf")]
-    Protobuf,
+    Protobuf {
+        /// Also encode message value and fees as BOC bytes, like protocol version 1
+        #[serde(default)]
+        legacy_amounts: bool,
+        /// Also fill string forms of addresses, only BOC encoded addresses if not specified
+        #[serde(default)]
+        address_format: Option<AddressFormat>,
+        /// Also fill decimal amounts in whole tokens, e.g. `1.5`
+        #[serde(default)]
+        normalize_amounts: bool,
+    },
     #[cfg(feature="serialize-json")]
-    Json,
+    Json {
+        #[serde(default)]
+        address_format: AddressFormat,
+        #[serde(default)]
+        normalize_amounts: bool,
+    },
+}
+
+/// String form of addresses in records
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressFormat {
+    /// `workchain:hex`
+    #[default]
+    Raw,
+    /// Bounceable user-friendly base64url
+    Base64,
+    /// Both raw and base64url forms
+    Both,
+}
+
+impl AddressFormat {
+    /// Raw and base64url forms of the address, `None` for the forms not included in the format.
+    /// Addresses without a user-friendly form are formatted as raw
+    pub fn format(self, address: &MsgAddressInt) -> (Option<String>, Option<String>) {
+        let raw = matches!(self, Self::Raw | Self::Both).then(|| address.to_string());
+        let base64 = matches!(self, Self::Base64 | Self::Both).then(|| {
+            nekoton_utils::pack_std_smc_addr(true, address, true).unwrap_or_else(|_| address.to_string())
+        });
+        (raw, base64)
+    }
 }
 
 /// Prepend the array with a length
 #[cfg(feature="serialize-json")]
-pub fn write_json_with_prefix(message: SerializeMessage) -> Result<Vec<u8>> {
-    let mut json_vec = serde_json::to_vec(&message)?;
-    let len = json_vec.len();
-    let mut res = Vec::with_capacity(size_of::<u128>() + len);
-    res.extend((len as u32).to_be_bytes());
-    res.append(&mut json_vec);
-    Ok(res)
+pub fn write_json_with_prefix<T: serde::Serialize>(message: T) -> Result<Bytes> {
+    buffer::write_frame(|buffer| {
+        let start = buffer.len();
+        buffer.put_u32(0);
+        serde_json::to_writer(buffer.writer(), &message)?;
+        let len = buffer.len() - start - size_of::<u32>();
+        buffer[start..start + size_of::<u32>()].copy_from_slice(&(len as u32).to_be_bytes());
+        Ok(())
+    })
 }
 
+/// Signed envelope of a JSON record
+#[cfg(feature="serialize-json")]
+#[derive(serde::Serialize)]
+struct SignedJson<'a> {
+    /// Record JSON, the signature covers its bytes
+    payload: &'a str,
+    /// Base64 encoded ed25519 signature
+    signature: String,
+}
+
+/// Version of the stream format, increased on incompatible changes
+pub const PROTOCOL_VERSION: u32 = 2;
+
 impl Serializer {
-    pub fn serialize_message(&self, message: SerializeMessage) -> Result<Vec<u8>> {
+    /// Identifier of the format, sent in the stream start frame
+    pub fn name(&self) -> &'static str {
         match self {
             #[cfg(feature="serialize-protobuf")]
-            Self::Protobuf => protobuf::serialize_message(message),
+            Self::Protobuf { .. } => "protobuf",
             #[cfg(feature="serialize-json")]
-            Self::Json => write_json_with_prefix(message),
+            Self::Json { .. } => "json",
         }
     }
+
+    /// Whether messages get decimal amounts in whole tokens
+    pub fn normalizes_amounts(&self) -> bool {
+        match self {
+            #[cfg(feature="serialize-protobuf")]
+            Self::Protobuf { normalize_amounts, .. } => *normalize_amounts,
+            #[cfg(feature="serialize-json")]
+            Self::Json { normalize_amounts, .. } => *normalize_amounts,
+        }
+    }
+
+    /// Whether frames are length prefixed JSON
+    pub fn is_json(&self) -> bool {
+        match self {
+            #[cfg(feature="serialize-protobuf")]
+            Self::Protobuf { .. } => false,
+            #[cfg(feature="serialize-json")]
+            Self::Json { .. } => true,
+        }
+    }
+
+    /// Protobuf definitions or JSON schema of the records
+    pub fn schema(&self) -> &'static str {
+        match self {
+            #[cfg(feature="serialize-protobuf")]
+            Self::Protobuf { .. } => PROTOBUF_SCHEMA,
+            #[cfg(feature="serialize-json")]
+            Self::Json { .. } => JSON_SCHEMA,
+        }
+    }
+
+    pub fn serialize_message(&self, mut message: SerializeMessage) -> FusionResult<Bytes> {
+        if self.normalizes_amounts() {
+            message.normalize_amounts();
+        }
+        let result = match self {
+            #[cfg(feature="serialize-protobuf")]
+            Self::Protobuf { legacy_amounts, address_format, .. } => {
+                protobuf::serialize_message(message, *legacy_amounts, *address_format)
+            }
+            #[cfg(feature="serialize-json")]
+            Self::Json { address_format, .. } => with_address_format(*address_format, || write_json_with_prefix(message)),
+        };
+        result.map_err(FusionError::serialization)
+    }
+
+    pub fn serialize_account_state(&self, state: SerializeAccountState) -> FusionResult<Bytes> {
+        let result = match self {
+            #[cfg(feature="serialize-protobuf")]
+            Self::Protobuf { .. } => protobuf::serialize_account_state(state),
+            #[cfg(feature="serialize-json")]
+            Self::Json { .. } => write_json_with_prefix(state),
+        };
+        result.map_err(FusionError::serialization)
+    }
+
+    pub fn serialize_block(&self, block: SerializeBlock) -> FusionResult<Bytes> {
+        let result = match self {
+            #[cfg(feature="serialize-protobuf")]
+            Self::Protobuf { .. } => protobuf::serialize_block(block),
+            #[cfg(feature="serialize-json")]
+            Self::Json { .. } => write_json_with_prefix(block),
+        };
+        result.map_err(FusionError::serialization)
+    }
+
+    pub fn serialize_config_param(&self, param: SerializeConfigParam) -> FusionResult<Bytes> {
+        let result = match self {
+            #[cfg(feature="serialize-protobuf")]
+            Self::Protobuf { .. } => protobuf::serialize_config_param(param),
+            #[cfg(feature="serialize-json")]
+            Self::Json { .. } => write_json_with_prefix(param),
+        };
+        result.map_err(FusionError::serialization)
+    }
+
+    pub fn serialize_account_changes(&self, changes: SerializeAccountChanges) -> FusionResult<Bytes> {
+        let result = match self {
+            #[cfg(feature="serialize-protobuf")]
+            Self::Protobuf { .. } => protobuf::serialize_account_changes(changes),
+            #[cfg(feature="serialize-json")]
+            Self::Json { .. } => write_json_with_prefix(changes),
+        };
+        result.map_err(FusionError::serialization)
+    }
+
+    pub fn serialize_bounce(&self, bounce: SerializeBounce) -> FusionResult<Bytes> {
+        let result = match self {
+            #[cfg(feature="serialize-protobuf")]
+            Self::Protobuf { .. } => protobuf::serialize_bounce(bounce),
+            #[cfg(feature="serialize-json")]
+            Self::Json { .. } => write_json_with_prefix(bounce),
+        };
+        result.map_err(FusionError::serialization)
+    }
+
+    pub fn serialize_trace(&self, trace: SerializeTrace) -> FusionResult<Bytes> {
+        let result = match self {
+            #[cfg(feature="serialize-protobuf")]
+            Self::Protobuf { .. } => protobuf::serialize_trace(trace),
+            #[cfg(feature="serialize-json")]
+            Self::Json { .. } => write_json_with_prefix(trace),
+        };
+        result.map_err(FusionError::serialization)
+    }
+
+    pub fn serialize_aggregate(&self, aggregate: SerializeAggregate) -> FusionResult<Bytes> {
+        let result = match self {
+            #[cfg(feature="serialize-protobuf")]
+            Self::Protobuf { .. } => protobuf::serialize_aggregate(aggregate),
+            #[cfg(feature="serialize-json")]
+            Self::Json { .. } => write_json_with_prefix(aggregate),
+        };
+        result.map_err(FusionError::serialization)
+    }
+
+    pub fn serialize_message_status(&self, status: SerializeMessageStatus) -> FusionResult<Bytes> {
+        let result = match self {
+            #[cfg(feature="serialize-protobuf")]
+            Self::Protobuf { .. } => protobuf::serialize_message_status(status),
+            #[cfg(feature="serialize-json")]
+            Self::Json { .. } => write_json_with_prefix(status),
+        };
+        result.map_err(FusionError::serialization)
+    }
+
+    /// Serializes a control event, it can be sent to any stream
+    pub fn serialize_control(&self, event: ControlEvent) -> FusionResult<Bytes> {
+        let result = match self {
+            #[cfg(feature="serialize-protobuf")]
+            Self::Protobuf { .. } => protobuf::serialize_control(event),
+            #[cfg(feature="serialize-json")]
+            Self::Json { .. } => write_json_with_prefix(SerializeControl { control: event }),
+        };
+        result.map_err(FusionError::serialization)
+    }
+
+    /// Wraps a serialized frame into a signed envelope
+    pub fn sign(&self, frame: &[u8], keypair: &ed25519::KeyPair) -> FusionResult<Bytes> {
+        let result = match self {
+            #[cfg(feature="serialize-protobuf")]
+            Self::Protobuf { .. } => protobuf::sign_frame(frame, keypair),
+            #[cfg(feature="serialize-json")]
+            Self::Json { .. } => std::str::from_utf8(frame.get(size_of::<u32>()..).unwrap_or_default())
+                .map_err(anyhow::Error::from)
+                .and_then(|payload| {
+                    write_json_with_prefix(SignedJson {
+                        payload,
+                        signature: base64::encode(keypair.sign_raw(payload.as_bytes())),
+                    })
+                }),
+        };
+        result.map_err(FusionError::serialization)
+    }
 }
@@ -1,10 +1,21 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use everscale_crypto::ed25519;
+use num_bigint::BigUint;
 use prost::Message;
 
-use crate::types::{MessageType, SerializeMessage};
+use crate::types::{
+    AccountStatus, BlockBoundary, ControlEvent, FilterCounters, SerializeAccountChanges, MessageRouting, MessageStatus, MessageType, SerializeAccountState, SerializeBlock,
+    SerializeBounce, SerializeConfigParam, SerializeMessage, SerializeMessageStatus, SerializeTrace, SemanticPayload,
+    TraceTransaction, SerializeAggregate, AddressLabel,
+};
 
-use ton_types::serialize_toc;
-use ton_block::{CommonMsgInfo, Serializable, MsgAddressIntOrNone};
+use super::buffer::write_frame;
+use super::AddressFormat;
+
+use ton_abi::{Token, TokenValue};
+use ton_types::{serialize_toc, UInt256};
+use ton_block::{CommonMsgInfo, Serializable, MsgAddressInt, MsgAddressIntOrNone};
 
 mod bindings {
     // Generated protobuf bindings
@@ -22,66 +33,519 @@ impl From<MessageType> for bindings::MessageType {
     }
 }
 
-impl TryFrom<SerializeMessage> for bindings::Message {
-    type Error = anyhow::Error;
-
-    fn try_from(msg: SerializeMessage) -> Result<Self, Self::Error> {
-        let cell = msg.message.body().unwrap_or_default().into_cell();
-
-        let message_header = match msg.message.header() {
-            CommonMsgInfo::IntMsgInfo(header) =>
-                bindings::message::MessageHeader::Internal (
-                    bindings::InternalHeader {
-                        bounce: header.bounce,
-                        bounced: header.bounced,
-                        ihr_disabled: header.ihr_disabled,
-                        src: match header.src {
-                            MsgAddressIntOrNone::Some(ref msg) => msg.write_to_bytes()?,
-                            MsgAddressIntOrNone::None => Default::default()
-                        },
-                        dst: header.dst.write_to_bytes()?,
-                        value: header.value.grams.as_u128().write_to_bytes()?,
-                        ihr_fee: header.ihr_fee.as_u128().write_to_bytes()?,
-                        fwd_fee: header.fwd_fee.as_u128().write_to_bytes()?,
-                        created_at: header.created_at.as_u32(),
-                        created_lt: header.created_lt,
-                    }
-                ),
-            CommonMsgInfo::ExtInMsgInfo(header) =>
-                bindings::message::MessageHeader::ExtInbound(
-                    bindings::ExternalInboundHeader {
-                        dst: header.dst.write_to_bytes()?
-                    }
-                ),
-            CommonMsgInfo::ExtOutMsgInfo(header) =>
-                bindings::message::MessageHeader::ExtOutbound(
-                    bindings::ExternalOutboudHeader {
-                        src: match header.src {
-                            MsgAddressIntOrNone::Some(ref msg) => msg.write_to_bytes()?,
-                            MsgAddressIntOrNone::None => Default::default()
-                        },
-                        created_at: header.created_at.as_u32(),
-                        created_lt: header.created_lt,
-                    }
-                ),
+impl From<AccountStatus> for bindings::AccountStatus {
+    fn from(value: AccountStatus) -> Self {
+        match value {
+            AccountStatus::Uninit => Self::Uninit,
+            AccountStatus::Frozen => Self::Frozen,
+            AccountStatus::Active => Self::Active,
+            AccountStatus::Nonexist => Self::Nonexist,
+        }
+    }
+}
+
+impl From<SerializeAccountState> for bindings::AccountState {
+    fn from(state: SerializeAccountState) -> Self {
+        Self {
+            filter_name: state.filter_name,
+            block_id: state.block_id.into_vec(),
+            workchain_id: state.workchain_id,
+            account_id: state.account_id.into_vec(),
+            status: bindings::AccountStatus::from(state.status).into(),
+            balance: state.balance.to_string(),
+            last_transaction_hash: state.last_transaction_hash.into_vec(),
+            last_transaction_lt: state.last_transaction_lt,
+            code_hash: state.code_hash.map(UInt256::into_vec).unwrap_or_default(),
+            data_boc: state.data_boc.unwrap_or_default(),
+            control: None,
+        }
+    }
+}
+
+impl From<SerializeBlock> for bindings::Block {
+    fn from(block: SerializeBlock) -> Self {
+        Self {
+            id: block.block_id.into_vec(),
+            file_hash: block.file_hash.into_vec(),
+            workchain_id: block.workchain_id,
+            shard: block.shard,
+            seqno: block.seqno,
+            gen_utime: block.gen_utime,
+            transaction_count: block.transaction_count,
+            message_count: block.message_count,
+            control: None,
+        }
+    }
+}
+
+impl From<SerializeConfigParam> for bindings::ConfigParam {
+    fn from(param: SerializeConfigParam) -> Self {
+        Self {
+            filter_name: param.filter_name,
+            block_id: param.block_id.into_vec(),
+            seqno: param.seqno,
+            gen_utime: param.gen_utime,
+            param: param.param,
+            data_boc: param.data_boc.unwrap_or_default(),
+            control: None,
+        }
+    }
+}
+
+impl From<SerializeAccountChanges> for bindings::AccountChanges {
+    fn from(changes: SerializeAccountChanges) -> Self {
+        Self {
+            filter_name: changes.filter_name,
+            block_id: changes.block_id.into_vec(),
+            workchain_id: changes.workchain_id,
+            shard: changes.shard,
+            seqno: changes.seqno,
+            gen_utime: changes.gen_utime,
+            changed_accounts: changes.changed_accounts.into_iter().map(UInt256::into_vec).collect(),
+            deleted_accounts: changes.deleted_accounts.into_iter().map(UInt256::into_vec).collect(),
+            control: None,
+        }
+    }
+}
+
+impl From<SerializeBounce> for bindings::Bounce {
+    fn from(bounce: SerializeBounce) -> Self {
+        Self {
+            original_message_hash: bounce.original_message_hash.into_vec(),
+            bounced_message_hash: bounce.bounced_message_hash.into_vec(),
+            block_id: bounce.block_id.into_vec(),
+            transaction_id: bounce.transaction_id.into_vec(),
+            transaction_timestamp: bounce.transaction_timestamp,
+            contract_name: bounce.contract_name,
+            filter_name: bounce.filter_name,
+            control: None,
+        }
+    }
+}
+
+impl From<TraceTransaction> for bindings::TraceTransaction {
+    fn from(transaction: TraceTransaction) -> Self {
+        Self {
+            transaction_id: transaction.transaction_id.into_vec(),
+            block_id: transaction.block_id.into_vec(),
+            workchain_id: transaction.workchain_id,
+            account_id: transaction.account_id.into_vec(),
+            lt: transaction.lt,
+            timestamp: transaction.timestamp,
+            in_message_hash: transaction.in_message_hash.map(UInt256::into_vec).unwrap_or_default(),
+            out_message_hashes: transaction.out_message_hashes.into_iter().map(UInt256::into_vec).collect(),
+            aborted: transaction.aborted,
+        }
+    }
+}
+
+impl From<SerializeTrace> for bindings::Trace {
+    fn from(trace: SerializeTrace) -> Self {
+        Self {
+            trace_id: trace.trace_id.into_vec(),
+            contract_name: trace.contract_name,
+            filter_name: trace.filter_name,
+            complete: trace.complete,
+            started_at: trace.started_at,
+            transactions: trace.transactions.into_iter().map(Into::into).collect(),
+            control: None,
+        }
+    }
+}
+
+impl From<SerializeAggregate> for bindings::Aggregate {
+    fn from(aggregate: SerializeAggregate) -> Self {
+        Self {
+            contract_name: aggregate.contract_name,
+            filter_name: aggregate.filter_name,
+            window_start: aggregate.window_start,
+            window_sec: aggregate.window_sec,
+            count: aggregate.count,
+            sum: aggregate.sum.unwrap_or_default(),
+            control: None,
+        }
+    }
+}
+
+impl From<AddressLabel> for bindings::AddressLabel {
+    fn from(label: AddressLabel) -> Self {
+        Self {
+            label: label.label,
+            tags: label.tags,
+        }
+    }
+}
+
+impl From<MessageStatus> for bindings::MessageStatusKind {
+    fn from(value: MessageStatus) -> Self {
+        match value {
+            MessageStatus::Accepted => Self::Accepted,
+            MessageStatus::Included => Self::Included,
+            MessageStatus::Expired => Self::Expired,
+        }
+    }
+}
+
+impl From<SerializeMessageStatus> for bindings::MessageStatus {
+    fn from(status: SerializeMessageStatus) -> Self {
+        Self {
+            message_hash: status.message_hash.into_vec(),
+            status: bindings::MessageStatusKind::from(status.status).into(),
+            block_id: status.block_id.map(UInt256::into_vec).unwrap_or_default(),
+            transaction_id: status.transaction_id.map(UInt256::into_vec).unwrap_or_default(),
+            control: None,
+        }
+    }
+}
+
+impl From<ControlEvent> for bindings::Control {
+    fn from(event: ControlEvent) -> Self {
+        let event = match event {
+            ControlEvent::Revoked(block) => bindings::control::Event::Revoked(bindings::Revoked {
+                block_id: block.block_id.into_vec(),
+                workchain_id: block.workchain_id,
+                shard: block.shard,
+                seqno: block.seqno,
+            }),
+            ControlEvent::StreamStart(start) => bindings::control::Event::StreamStart(bindings::StreamStart {
+                started_at: start.started_at,
+                version: start.version,
+                protocol_version: start.protocol_version,
+                serializer: start.serializer,
+            }),
+            ControlEvent::Heartbeat(heartbeat) => bindings::control::Event::Heartbeat(bindings::Heartbeat {
+                timestamp: heartbeat.timestamp,
+            }),
+            ControlEvent::StreamEnd => bindings::control::Event::StreamEnd(bindings::StreamEnd {}),
+            ControlEvent::BlockBegin(block) => bindings::control::Event::BlockBegin(block.into()),
+            ControlEvent::BlockEnd(block) => bindings::control::Event::BlockEnd(block.into()),
+            ControlEvent::Stats(stats) => bindings::control::Event::Stats(bindings::Stats {
+                timestamp: stats.timestamp,
+                filters: stats.filters.into_iter().map(Into::into).collect(),
+            }),
         };
+        Self { event: Some(event) }
+    }
+}
 
-        Ok(Self {
-            id: msg.message_hash.into_vec(),
-            body_boc: serialize_toc(&cell)?,
-            message_type: bindings::MessageType::from(msg.message_type).into(),
-            block_id: msg.block_id.into_vec(),
-            transaction_id: msg.transaction_id.into_vec(),
-            transaction_timestamp: msg.transaction_timestamp,
-            index_in_transaction: msg.index_in_transaction.into(),
-            contract_name: msg.contract_name,
-            filter_name: msg.filter_name,
-            message_header: Some(message_header)
-        })
+impl From<BlockBoundary> for bindings::BlockBoundary {
+    fn from(block: BlockBoundary) -> Self {
+        Self {
+            block_id: block.block_id.into_vec(),
+            workchain_id: block.workchain_id,
+            shard: block.shard,
+            seqno: block.seqno,
+            message_count: block.message_count,
+        }
+    }
+}
+
+impl From<FilterCounters> for bindings::FilterCounters {
+    fn from(counters: FilterCounters) -> Self {
+        Self {
+            filter_name: counters.filter_name,
+            messages: counters.messages,
+            bytes: counters.bytes,
+            last_emitted_at: counters.last_emitted_at,
+        }
     }
 }
 
-pub fn serialize_message(message: SerializeMessage) -> Result<Vec<u8>> {
-    let message: bindings::Message = message.try_into()?;
-    Ok(message.encode_length_delimited_to_vec())
+impl From<MessageRouting> for bindings::MessageRouting {
+    fn from(routing: MessageRouting) -> Self {
+        Self {
+            parent_message_hash: routing
+                .parent_message_hash
+                .map(UInt256::into_vec)
+                .unwrap_or_default(),
+            child_message_hashes: routing
+                .child_message_hashes
+                .into_iter()
+                .map(UInt256::into_vec)
+                .collect(),
+        }
+    }
+}
+
+fn optional_address_bytes(address: Option<MsgAddressInt>) -> Result<Vec<u8>> {
+    Ok(match address {
+        Some(address) => address.write_to_bytes()?,
+        None => Default::default(),
+    })
+}
+
+/// String forms of the address, only with an address format
+fn address_forms(address: Option<&MsgAddressInt>, format: Option<AddressFormat>) -> Option<bindings::Address> {
+    let (raw, base64) = format?.format(address?);
+    Some(bindings::Address {
+        raw: raw.unwrap_or_default(),
+        base64: base64.unwrap_or_default(),
+    })
+}
+
+fn optional_decimal(value: Option<BigUint>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+fn payload_to_proto(payload: SemanticPayload, format: Option<AddressFormat>) -> Result<bindings::message::Payload> {
+    use bindings::message::Payload;
+
+    Ok(match payload {
+        SemanticPayload::TokenTransfer(transfer) => Payload::TokenTransfer(bindings::TokenTransfer {
+            kind: transfer.kind.to_string(),
+            token_root_address: address_forms(transfer.token_root.as_ref(), format),
+            from_owner_address: address_forms(transfer.from_owner.as_ref(), format),
+            to_owner_address: address_forms(transfer.to_owner.as_ref(), format),
+            token_root: optional_address_bytes(transfer.token_root)?,
+            from_owner: optional_address_bytes(transfer.from_owner)?,
+            to_owner: optional_address_bytes(transfer.to_owner)?,
+            amount: transfer.amount.to_string(),
+            symbol: transfer.symbol,
+            decimals: transfer.decimals.map(u32::from),
+            amount_normalized: transfer.amount_normalized,
+        }),
+        SemanticPayload::Multisig(transaction) => Payload::Multisig(bindings::MultisigTransaction {
+            action: transaction.action.to_string(),
+            transaction_id: transaction.transaction_id,
+            dest_address: address_forms(transaction.dest.as_ref(), format),
+            dest: optional_address_bytes(transaction.dest)?,
+            value: optional_decimal(transaction.value),
+        }),
+        SemanticPayload::Depool(operation) => Payload::Depool(bindings::DepoolOperation {
+            action: operation.action.to_string(),
+            participant_address: address_forms(operation.participant.as_ref(), format),
+            participant: optional_address_bytes(operation.participant)?,
+            amount: optional_decimal(operation.amount),
+            query_id: operation.query_id,
+        }),
+        SemanticPayload::Nft(transfer) => Payload::Nft(bindings::NftTransfer {
+            kind: transfer.kind.to_string(),
+            collection_address: address_forms(transfer.collection.as_ref(), format),
+            nft_address: address_forms(transfer.nft.as_ref(), format),
+            from_owner_address: address_forms(transfer.from_owner.as_ref(), format),
+            to_owner_address: address_forms(transfer.to_owner.as_ref(), format),
+            collection: optional_address_bytes(transfer.collection)?,
+            nft: optional_address_bytes(transfer.nft)?,
+            id: optional_decimal(transfer.id),
+            from_owner: optional_address_bytes(transfer.from_owner)?,
+            to_owner: optional_address_bytes(transfer.to_owner)?,
+        }),
+    })
+}
+
+/// BOC encoded amount of the protocol version 1
+fn abi_tokens(tokens: &[Token]) -> Result<Vec<bindings::AbiToken>> {
+    tokens
+        .iter()
+        .map(|token| {
+            Ok(bindings::AbiToken {
+                name: token.name.clone(),
+                value: Some(abi_value(&token.value)?),
+            })
+        })
+        .collect()
+}
+
+fn abi_value(value: &TokenValue) -> Result<bindings::AbiValue> {
+    use bindings::abi_value::Value;
+
+    let inner = match value {
+        TokenValue::Tuple(tokens) => Some(Value::Tuple(bindings::AbiTuple { fields: abi_tokens(tokens)? })),
+        TokenValue::Array(_, items) | TokenValue::FixedArray(_, items) => Some(Value::Array(bindings::AbiArray {
+            items: items.iter().map(abi_value).collect::<Result<_>>()?,
+        })),
+        TokenValue::Map(_, _, entries) => Some(Value::Map(bindings::AbiMap {
+            entries: entries
+                .iter()
+                .map(|(key, value)| {
+                    Ok(bindings::AbiMapEntry {
+                        key: key.to_string(),
+                        value: Some(abi_value(value)?),
+                    })
+                })
+                .collect::<Result<_>>()?,
+        })),
+        TokenValue::Bytes(bytes) | TokenValue::FixedBytes(bytes) => Some(Value::Bytes(bytes.clone())),
+        TokenValue::Cell(cell) => Some(Value::Bytes(serialize_toc(cell)?)),
+        TokenValue::Optional(_, value) => value.as_deref().map(abi_value).transpose()?.and_then(|value| value.value),
+        TokenValue::Ref(value) => abi_value(value)?.value,
+        _ => Some(Value::String(value.to_string())),
+    };
+    Ok(bindings::AbiValue {
+        r#type: value.get_param_type().type_signature(),
+        value: inner,
+    })
+}
+
+fn legacy_amount(amount: u128, legacy_amounts: bool) -> Result<Vec<u8>> {
+    Ok(match legacy_amounts {
+        true => amount.write_to_bytes()?,
+        false => Default::default(),
+    })
+}
+
+fn message_to_proto(
+    msg: SerializeMessage,
+    legacy_amounts: bool,
+    address_format: Option<AddressFormat>,
+) -> Result<bindings::Message> {
+    let cell = msg.message.body().unwrap_or_default().into_cell();
+
+    let message_header = match msg.message.header() {
+        CommonMsgInfo::IntMsgInfo(header) =>
+            bindings::message::MessageHeader::Internal (
+                bindings::InternalHeader {
+                    bounce: header.bounce,
+                    bounced: header.bounced,
+                    ihr_disabled: header.ihr_disabled,
+                    src: match header.src {
+                        MsgAddressIntOrNone::Some(ref msg) => msg.write_to_bytes()?,
+                        MsgAddressIntOrNone::None => Default::default()
+                    },
+                    dst: header.dst.write_to_bytes()?,
+                    src_address: address_forms(msg.src.as_ref(), address_format),
+                    dst_address: address_forms(msg.dst.as_ref(), address_format),
+                    value: legacy_amount(header.value.grams.as_u128(), legacy_amounts)?,
+                    ihr_fee: legacy_amount(header.ihr_fee.as_u128(), legacy_amounts)?,
+                    fwd_fee: legacy_amount(header.fwd_fee.as_u128(), legacy_amounts)?,
+                    value_decimal: header.value.grams.as_u128().to_string(),
+                    ihr_fee_decimal: header.ihr_fee.as_u128().to_string(),
+                    fwd_fee_decimal: header.fwd_fee.as_u128().to_string(),
+                    created_at: header.created_at.as_u32(),
+                    created_lt: header.created_lt,
+                }
+            ),
+        CommonMsgInfo::ExtInMsgInfo(header) =>
+            bindings::message::MessageHeader::ExtInbound(
+                bindings::ExternalInboundHeader {
+                    dst: header.dst.write_to_bytes()?,
+                    dst_address: address_forms(msg.dst.as_ref(), address_format),
+                }
+            ),
+        CommonMsgInfo::ExtOutMsgInfo(header) =>
+            bindings::message::MessageHeader::ExtOutbound(
+                bindings::ExternalOutboudHeader {
+                    src: match header.src {
+                        MsgAddressIntOrNone::Some(ref msg) => msg.write_to_bytes()?,
+                        MsgAddressIntOrNone::None => Default::default()
+                    },
+                    created_at: header.created_at.as_u32(),
+                    created_lt: header.created_lt,
+                    src_address: address_forms(msg.src.as_ref(), address_format),
+                }
+            ),
+    };
+
+    Ok(bindings::Message {
+        id: msg.message_hash.into_vec(),
+        body_boc: serialize_toc(&cell)?,
+        message_type: bindings::MessageType::from(msg.message_type).into(),
+        block_id: msg.block_id.into_vec(),
+        workchain_id: msg.workchain_id,
+        shard: msg.shard,
+        block_seqno: msg.block_seqno,
+        mc_seqno: msg.mc_seqno,
+        transaction_id: msg.transaction_id.into_vec(),
+        transaction_timestamp: msg.transaction_timestamp,
+        index_in_transaction: msg.index_in_transaction.into(),
+        contract_name: msg.contract_name,
+        filter_name: msg.filter_name,
+        matched_filters: msg.matched_filters,
+        historical: msg.historical,
+        message_header: Some(message_header),
+        enrichment: msg
+            .enrichment
+            .into_iter()
+            .map(|(key, value)| (key, value.to_string()))
+            .collect(),
+        routing: msg.routing.map(Into::into),
+        payload: msg
+            .payload
+            .map(|payload| payload_to_proto(payload, address_format))
+            .transpose()?,
+        decoded: abi_tokens(&msg.decoded)?,
+        src_label: msg.src_label.map(Into::into),
+        dst_label: msg.dst_label.map(Into::into),
+        value_normalized: msg.value_normalized,
+        external: msg.external.map(|external| bindings::ExternalInfo {
+            expire_at: external.expire_at,
+            function_id: external.function_id,
+            aborted: external.aborted,
+        }),
+        fees: Some(bindings::TransactionFees {
+            total_fees: msg.fees.total_fees.to_string(),
+            storage_fees: msg.fees.storage_fees.to_string(),
+            gas_used: msg.fees.gas_used,
+            gas_fees: msg.fees.gas_fees.to_string(),
+            fwd_fees: msg.fees.fwd_fees.to_string(),
+        }),
+        control: None,
+    })
+}
+
+pub fn serialize_message(
+    message: SerializeMessage,
+    legacy_amounts: bool,
+    address_format: Option<AddressFormat>,
+) -> Result<Bytes> {
+    let message = message_to_proto(message, legacy_amounts, address_format)?;
+    write_frame(|buffer| Ok(message.encode_length_delimited(buffer)?))
+}
+
+pub fn serialize_account_state(state: SerializeAccountState) -> Result<Bytes> {
+    let state = bindings::AccountState::from(state);
+    write_frame(|buffer| Ok(state.encode_length_delimited(buffer)?))
+}
+
+pub fn serialize_block(block: SerializeBlock) -> Result<Bytes> {
+    let block = bindings::Block::from(block);
+    write_frame(|buffer| Ok(block.encode_length_delimited(buffer)?))
+}
+
+pub fn serialize_config_param(param: SerializeConfigParam) -> Result<Bytes> {
+    let param = bindings::ConfigParam::from(param);
+    write_frame(|buffer| Ok(param.encode_length_delimited(buffer)?))
+}
+
+pub fn serialize_account_changes(changes: SerializeAccountChanges) -> Result<Bytes> {
+    let changes = bindings::AccountChanges::from(changes);
+    write_frame(|buffer| Ok(changes.encode_length_delimited(buffer)?))
+}
+
+pub fn serialize_bounce(bounce: SerializeBounce) -> Result<Bytes> {
+    let bounce = bindings::Bounce::from(bounce);
+    write_frame(|buffer| Ok(bounce.encode_length_delimited(buffer)?))
+}
+
+pub fn serialize_trace(trace: SerializeTrace) -> Result<Bytes> {
+    let trace = bindings::Trace::from(trace);
+    write_frame(|buffer| Ok(trace.encode_length_delimited(buffer)?))
+}
+
+pub fn serialize_aggregate(aggregate: SerializeAggregate) -> Result<Bytes> {
+    let aggregate = bindings::Aggregate::from(aggregate);
+    write_frame(|buffer| Ok(aggregate.encode_length_delimited(buffer)?))
+}
+
+pub fn serialize_message_status(status: SerializeMessageStatus) -> Result<Bytes> {
+    let status = bindings::MessageStatus::from(status);
+    write_frame(|buffer| Ok(status.encode_length_delimited(buffer)?))
+}
+
+pub fn serialize_control(event: ControlEvent) -> Result<Bytes> {
+    let frame = bindings::ControlFrame {
+        control: Some(event.into()),
+    };
+    write_frame(|buffer| Ok(frame.encode_length_delimited(buffer)?))
+}
+
+pub fn sign_frame(frame: &[u8], keypair: &ed25519::KeyPair) -> Result<Bytes> {
+    let mut data = frame;
+    let len = prost::decode_length_delimiter(&mut data)?;
+    let payload = data.get(..len).context("Truncated frame")?.to_vec();
+    let signed = bindings::Signed {
+        signature: keypair.sign_raw(&payload).to_vec(),
+        payload,
+    };
+    write_frame(|buffer| Ok(signed.encode_length_delimited(buffer)?))
 }
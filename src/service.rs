@@ -0,0 +1,509 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use everscale_rpc_server::RpcState;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::{
+    blocks_handler::{BlocksHandler, MessageSink, StateProvider},
+    config::{AddressBookConfig, AppConfig, ControlApiConfig, GraphqlConfig, HistoricalConfig, MessageStoreConfig, ScanType, SigningConfig},
+    data_scanner::{
+        archives_scanner::ArchivesScanner,
+        directory_scanner::DirectoryScanner,
+        handover::backfill,
+        liteserver_scanner::LiteServerScanner,
+        backfill_stats::BackfillStats,
+        network_scanner::NetworkScanner,
+        replay_scanner::ReplayScanner,
+        rpc_scanner::RpcScanner,
+        s3_scanner::S3Scanner,
+        state_provider,
+        test_scanner::TestScanner,
+    },
+    error::{FusionError, FusionResult},
+    filter::{config::FilterConfig, init_parsers},
+    metrics::{spawn_backfill_exporter, spawn_exporter},
+    producer::{Producer, Transport},
+    serializer::Serializer,
+    types::SerializeMessage,
+};
+
+/// Builds an embedded producer from filters, serializer, transport and a scanner source.
+///
+/// Filters are stored globally, so only one producer can be built per process
+#[derive(Default)]
+pub struct FusionProducerBuilder {
+    filter_config: Option<FilterConfig>,
+    serializer: Option<Serializer>,
+    transport: Option<Transport>,
+    scan_type: Option<ScanType>,
+    global_config: Option<ton_indexer::GlobalConfig>,
+    rpc_config: Option<everscale_rpc_server::Config>,
+    metrics_settings: Option<pomfrit::Config>,
+    fail_fast: bool,
+    replay: Option<(u32, Option<u32>)>,
+    blocks_stream: bool,
+    account_workers: usize,
+    atomic_blocks: bool,
+    max_message_age_sec: Option<u32>,
+    historical: Option<HistoricalConfig>,
+    sink: Option<MessageSink>,
+    state_provider: Option<Arc<dyn StateProvider>>,
+    heartbeat_interval: Option<Duration>,
+    signing: Option<SigningConfig>,
+    graphql: Option<GraphqlConfig>,
+    message_store: Option<MessageStoreConfig>,
+    control_api: Option<ControlApiConfig>,
+    address_book: Option<AddressBookConfig>,
+}
+
+impl FusionProducerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes all settings from the application config
+    pub fn from_config(config: AppConfig) -> Self {
+        Self {
+            filter_config: Some(config.filter_config),
+            serializer: Some(config.serializer),
+            transport: Some(config.transport),
+            scan_type: Some(config.scan_type),
+            global_config: None,
+            rpc_config: config.rpc_config,
+            metrics_settings: config.metrics_settings,
+            fail_fast: config.fail_fast,
+            replay: None,
+            blocks_stream: config.blocks_stream,
+            account_workers: config.account_workers,
+            atomic_blocks: config.atomic_blocks,
+            max_message_age_sec: config.max_message_age_sec,
+            historical: config.historical,
+            sink: None,
+            state_provider: config.state_provider.map(state_provider::from_config),
+            heartbeat_interval: config.control_frames.map(|config| config.heartbeat_interval()),
+            signing: config.signing,
+            graphql: config.graphql,
+            message_store: config.message_store,
+            control_api: config.control_api,
+            address_book: config.address_book,
+        }
+    }
+
+    pub fn filters(mut self, filter_config: FilterConfig) -> Self {
+        self.filter_config = Some(filter_config);
+        self
+    }
+
+    pub fn serializer(mut self, serializer: Serializer) -> Self {
+        self.serializer = Some(serializer);
+        self
+    }
+
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    pub fn scanner(mut self, scan_type: ScanType) -> Self {
+        self.scan_type = Some(scan_type);
+        self
+    }
+
+    /// Network global config, required for the `FromNetwork` scanner
+    pub fn global_config(mut self, global_config: ton_indexer::GlobalConfig) -> Self {
+        self.global_config = Some(global_config);
+        self
+    }
+
+    /// Serve states via RPC, only for the `FromNetwork` and `ArchivesThenNetwork` scanners.
+    /// `build` fails with `UnsupportedRpcConfig` for other scanners
+    pub fn rpc_config(mut self, rpc_config: everscale_rpc_server::Config) -> Self {
+        self.rpc_config = Some(rpc_config);
+        self
+    }
+
+    /// Export Prometheus metrics of the network, archives and S3 scanners
+    pub fn metrics(mut self, metrics_settings: pomfrit::Config) -> Self {
+        self.metrics_settings = Some(metrics_settings);
+        self
+    }
+
+    /// Abort the process on the first panic instead of restarting the failed component
+    pub fn fail_fast(mut self, enabled: bool) -> Self {
+        self.fail_fast = enabled;
+        self
+    }
+
+    /// Process blocks already stored in the node database from the masterchain seqno
+    /// instead of following the network. Account states are not produced
+    pub fn replay(mut self, from_seqno: u32, to_seqno: Option<u32>) -> Self {
+        self.replay = Some((from_seqno, to_seqno));
+        self
+    }
+
+    pub fn blocks_stream(mut self, enabled: bool) -> Self {
+        self.blocks_stream = enabled;
+        self
+    }
+
+    /// Threads processing the account blocks of a block, serial when 0 or 1
+    pub fn account_workers(mut self, workers: usize) -> Self {
+        self.account_workers = workers;
+        self
+    }
+
+    /// Send the messages of each block at once between block begin and end control frames
+    pub fn atomic_blocks(mut self, enabled: bool) -> Self {
+        self.atomic_blocks = enabled;
+        self
+    }
+
+    /// Drop messages of transactions older than `max_age_sec` instead of sending them
+    pub fn max_message_age(mut self, max_age_sec: u32) -> Self {
+        self.max_message_age_sec = Some(max_age_sec);
+        self
+    }
+
+    /// Tag messages of old blocks as historical, optionally routing them to a destination
+    pub fn historical(mut self, historical: HistoricalConfig) -> Self {
+        self.historical = Some(historical);
+        self
+    }
+
+    /// Pass filtered messages to the closure instead of serializing them.
+    /// Other streams still use the transport, which is counting only if not specified
+    pub fn message_sink<F>(mut self, sink: F) -> Self
+    where
+        F: Fn(SerializeMessage) + Send + Sync + 'static,
+    {
+        self.sink = Some(Arc::new(sink));
+        self
+    }
+
+    /// States for code hash filters in scanners without shard states
+    pub fn state_provider(mut self, state_provider: Arc<dyn StateProvider>) -> Self {
+        self.state_provider = Some(state_provider);
+        self
+    }
+
+    /// Send stream start, end and heartbeat control frames
+    pub fn control_frames(mut self, heartbeat_interval: Duration) -> Self {
+        self.heartbeat_interval = Some(heartbeat_interval);
+        self
+    }
+
+    /// Sign every sent frame
+    pub fn signing(mut self, signing: SigningConfig) -> Self {
+        self.signing = Some(signing);
+        self
+    }
+
+    /// Serve filtered messages over GraphQL, requires the `graphql` feature
+    pub fn graphql(mut self, graphql: GraphqlConfig) -> Self {
+        self.graphql = Some(graphql);
+        self
+    }
+
+    /// Keep sent messages for the `GET /messages` pull API
+    pub fn message_store(mut self, message_store: MessageStoreConfig) -> Self {
+        self.message_store = Some(message_store);
+        self
+    }
+
+    /// Serve the admin API pausing and resuming the message output
+    pub fn control_api(mut self, control_api: ControlApiConfig) -> Self {
+        self.control_api = Some(control_api);
+        self
+    }
+
+    /// Attach the labels of the message addresses from a file
+    pub fn address_book(mut self, address_book: AddressBookConfig) -> Self {
+        self.address_book = Some(address_book);
+        self
+    }
+
+    /// Loads ABIs, initializes filters and the transport
+    pub async fn build(self) -> FusionResult<FusionProducer> {
+        let missing = |field: &str| FusionError::config(anyhow!("`{field}` is not specified"));
+
+        let mut filter_config = self.filter_config.ok_or_else(|| missing("filters"))?;
+        let serializer = self.serializer.ok_or_else(|| missing("serializer"))?;
+        let transport = match (self.transport, &self.sink) {
+            (Some(transport), _) => transport,
+            (None, Some(_)) => Transport::Counting,
+            (None, None) => return Err(missing("transport")),
+        };
+        let scan_type = self.scan_type.unwrap_or_default();
+        scan_type
+            .check_rpc_config(self.rpc_config.as_ref())
+            .map_err(FusionError::config)?;
+        let needs_network = matches!(
+            scan_type,
+            ScanType::FromNetwork { .. } | ScanType::ArchivesThenNetwork(_)
+        );
+        if needs_network && self.global_config.is_none() {
+            return Err(missing("global_config"));
+        }
+        if self.replay.is_some() && !needs_network {
+            return Err(FusionError::config(anyhow!("Replay requires a scanner running a node")));
+        }
+
+        filter_config.load_includes()?;
+        filter_config.fetch_remote_abis().await?;
+        let range = filter_config.range;
+        init_parsers(filter_config)?;
+
+        let producer = Producer::new(transport)?;
+        let mut handler = BlocksHandler::new(serializer, producer, range, self.blocks_stream)?
+            .with_account_workers(self.account_workers)
+            .with_atomic_blocks(self.atomic_blocks);
+        if let Some(max_age) = self.max_message_age_sec {
+            handler = handler.with_max_message_age(max_age);
+        }
+        if let Some(historical) = self.historical {
+            handler = handler.with_historical(historical.realtime_threshold_sec, historical.destination)?;
+        }
+        if let Some(sink) = self.sink {
+            handler = handler.with_sink(sink);
+        }
+        if let Some(state_provider) = self.state_provider {
+            handler = handler.with_state_provider(state_provider);
+        }
+        if let Some(signing) = self.signing {
+            handler = handler.with_signer(signing.keypair().map_err(FusionError::config)?)?;
+        }
+        if let Some(graphql) = &self.graphql {
+            handler = crate::graphql::serve(handler, graphql).map_err(FusionError::config)?;
+        }
+        if let Some(message_store) = &self.message_store {
+            handler = crate::message_store::serve(handler, message_store).map_err(FusionError::config)?;
+        }
+        if let Some(control_api) = &self.control_api {
+            handler = crate::control::serve(handler, control_api).map_err(FusionError::config)?;
+        }
+        if let Some(address_book) = &self.address_book {
+            handler = crate::address_book::serve(handler, address_book).map_err(FusionError::config)?;
+        }
+        let handler = Arc::new(handler);
+
+        if self.fail_fast {
+            let orig_hook = std::panic::take_hook();
+            std::panic::set_hook(Box::new(move |panic_info| {
+                orig_hook(panic_info);
+                std::process::abort();
+            }));
+        }
+
+        Ok(FusionProducer {
+            handler,
+            heartbeat_interval: self.heartbeat_interval,
+            scan_type,
+            global_config: self.global_config,
+            rpc_config: self.rpc_config,
+            metrics_settings: self.metrics_settings,
+            replay: self.replay,
+        })
+    }
+}
+
+/// Producer ready to be started
+pub struct FusionProducer {
+    handler: Arc<BlocksHandler>,
+    heartbeat_interval: Option<Duration>,
+    scan_type: ScanType,
+    global_config: Option<ton_indexer::GlobalConfig>,
+    rpc_config: Option<everscale_rpc_server::Config>,
+    metrics_settings: Option<pomfrit::Config>,
+    replay: Option<(u32, Option<u32>)>,
+}
+
+impl FusionProducer {
+    pub fn handler(&self) -> &Arc<BlocksHandler> {
+        &self.handler
+    }
+
+    /// Spawns the scanner in the background
+    pub fn start(self) -> FusionProducerHandle {
+        let stop = Arc::new(Notify::new());
+        let task = tokio::spawn({
+            let stop = stop.clone();
+            async move { self.run_until(stop.notified()).await }
+        });
+        FusionProducerHandle { stop, task }
+    }
+
+    /// Runs the scanner until it completes or `shutdown` resolves, then ends the streams
+    pub async fn run_until(self, shutdown: impl Future<Output = ()>) -> FusionResult<()> {
+        let handler = self.handler.clone();
+        if let Some(heartbeat_interval) = self.heartbeat_interval {
+            handler.start_streams(heartbeat_interval).await?;
+        }
+        let result = tokio::select! {
+            result = self.run() => result.map_err(FusionError::scanner),
+            _ = shutdown => Ok(()),
+        };
+        if let Err(error) = handler.end_streams().await {
+            tracing::error!("Failed to end streams: {}", error);
+        }
+        result
+    }
+
+    /// Opens the node database of the scanner without following the network,
+    /// e.g. for maintenance
+    pub async fn open_node(mut self) -> FusionResult<Arc<NetworkScanner>> {
+        let node_config = self.scan_type.node_config_mut().map(std::mem::take).ok_or_else(|| {
+            FusionError::config(anyhow!("`{}` doesn't run a node", self.scan_type.name()))
+        })?;
+        let global_config = self
+            .global_config
+            .ok_or_else(|| FusionError::config(anyhow!("Global config not found")))?;
+        NetworkScanner::new(node_config, global_config, self.handler, None, None)
+            .await
+            .map_err(FusionError::scanner)
+    }
+
+    async fn run(self) -> anyhow::Result<()> {
+        if let Some((from_seqno, to_seqno)) = self.replay {
+            let handler = self.handler.clone();
+            let engine = self.open_node().await.context("Failed to create engine")?;
+            return ReplayScanner::new(engine.indexer().clone(), handler, from_seqno, to_seqno)
+                .run()
+                .await
+                .context("Failed to replay blocks");
+        }
+
+        let handler = self.handler;
+        let metrics_settings = self.metrics_settings;
+        let (scan_type, handover) = match self.scan_type {
+            ScanType::ArchivesThenNetwork(hybrid_config) => {
+                let (node_config, handover) = backfill(hybrid_config, handler.clone()).await?;
+                (ScanType::FromNetwork { node_config }, Some(handover))
+            }
+            scan_type => (scan_type, None),
+        };
+
+        match scan_type {
+            ScanType::FromNetwork { node_config } => {
+                let global_config = self.global_config.context("Global config not found")?;
+                let rpc_state = self
+                    .rpc_config
+                    .map(RpcState::new)
+                    .transpose()
+                    .context("Failed to create server state")?
+                    .map(Arc::new);
+
+                let panicked = Arc::new(AtomicBool::default());
+                if metrics_settings.is_some() {
+                    let orig_hook = std::panic::take_hook();
+                    let panicked = panicked.clone();
+                    std::panic::set_hook(Box::new(move |panic_info| {
+                        panicked.store(true, Ordering::Release);
+                        orig_hook(panic_info);
+                    }));
+                }
+
+                let producer = handler.producer.clone();
+                let engine = NetworkScanner::new(
+                    node_config,
+                    global_config,
+                    handler,
+                    rpc_state.clone(),
+                    handover,
+                )
+                    .await
+                    .context("Failed to create engine")?;
+                let _exporter =
+                    spawn_exporter(metrics_settings, rpc_state.clone(), engine.clone(), producer, panicked)
+                        .await?;
+
+                engine.start().await.context("Failed to start engine")?;
+                tracing::info!("initialized engine");
+
+                if let Some(rpc_state) = rpc_state {
+                    rpc_state.initialize(engine.indexer()).await?;
+                    tokio::spawn(rpc_state.serve()?);
+                    tracing::info!("initialized RPC");
+                }
+
+                futures_util::future::pending().await
+            }
+            ScanType::FromArchives { list_path, progress_path, pipeline, bounds } => {
+                let stats = Arc::new(BackfillStats::default());
+                let _exporter =
+                    spawn_backfill_exporter(metrics_settings, stats.clone(), handler.producer.clone()).await?;
+
+                ArchivesScanner::new(handler, list_path, progress_path)
+                    .context("Failed to create scanner")?
+                    .with_pipeline(pipeline)
+                    .with_bounds(bounds)
+                    .with_stats(stats)
+                    .run()
+                    .await
+            }
+            ScanType::FromDirectory(scanner_config) => {
+                DirectoryScanner::new(scanner_config, handler)
+                    .context("Failed to create scanner")?
+                    .run()
+                    .await
+            }
+            ScanType::FromS3(scanner_config) => {
+                let stats = Arc::new(BackfillStats::default());
+                let _exporter =
+                    spawn_backfill_exporter(metrics_settings, stats.clone(), handler.producer.clone()).await?;
+
+                S3Scanner::new(scanner_config, handler)
+                    .await
+                    .context("Failed to create scanner")?
+                    .with_stats(stats)
+                    .run()
+                    .await
+            }
+            ScanType::FromRpc(scanner_config) => {
+                RpcScanner::new(scanner_config, handler)
+                    .context("Failed to create scanner")?
+                    .run()
+                    .await
+            }
+            ScanType::FromLiteServer(scanner_config) => {
+                LiteServerScanner::new(scanner_config, handler)
+                    .context("Failed to create scanner")?
+                    .run()
+                    .await
+            }
+            ScanType::ArchivesThenNetwork(_) => unreachable!("replaced with FromNetwork after backfill"),
+            ScanType::TestJson { filename } => {
+                TestScanner::new(handler, filename)
+                    .context("Failed to create scanner")?
+                    .run()
+                    .await
+            }
+        }
+    }
+}
+
+/// Handle of the running producer
+pub struct FusionProducerHandle {
+    stop: Arc<Notify>,
+    task: JoinHandle<FusionResult<()>>,
+}
+
+impl FusionProducerHandle {
+    /// Requests the scanner to stop. Already sent data is not affected
+    pub fn stop(&self) {
+        self.stop.notify_one();
+    }
+
+    /// Waits until the scanner completes or is stopped
+    pub async fn join(self) -> FusionResult<()> {
+        self.task.await.map_err(FusionError::scanner)?
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+}
@@ -0,0 +1,170 @@
+//! Regression testing of filter configs and serializers: runs a handler over fixture blocks
+//! with the capturing transport and compares the rendered frames with golden files
+
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use ton_indexer::utils::{BlockStuff, ShardStateStuff};
+
+use crate::blocks_handler::BlocksHandler;
+use crate::data_scanner::test_scanner::TestScanner;
+use crate::error::{FusionError, FusionResult};
+use crate::filter::config::TransactionRange;
+use crate::producer::{Producer, Stream, Transport};
+use crate::serializer::Serializer;
+
+pub use crate::producer::{CapturedFrame, CapturedFrames};
+
+/// Frames are sent by detached tasks, the output is complete once none arrive for this period
+const SETTLE_PERIOD: Duration = Duration::from_millis(100);
+
+/// Set to write the actual output to the golden files instead of comparing it
+pub const UPDATE_GOLDEN_ENV: &str = "UPDATE_GOLDEN";
+
+/// Runs a handler with the capturing transport. Filters are global, so they must be
+/// initialized with [`crate::filter::init_parsers`] once per test process
+pub struct TestHarness {
+    handler: Arc<BlocksHandler>,
+    frames: CapturedFrames,
+    json: bool,
+}
+
+impl TestHarness {
+    /// Handler sending the messages of each block at once, so their order is stable
+    pub fn new(serializer: Serializer, range: TransactionRange) -> FusionResult<Self> {
+        let producer = Producer::new(Transport::Capture)?;
+        let handler = BlocksHandler::new(serializer, producer, range, false)?.with_atomic_blocks(true);
+        Self::from_handler(handler)
+    }
+
+    /// Uses a handler configured by the caller, its producer must use the capturing transport
+    pub fn from_handler(handler: BlocksHandler) -> FusionResult<Self> {
+        let frames = handler
+            .producer
+            .captured()
+            .ok_or_else(|| FusionError::config(anyhow::anyhow!("The handler must use the capturing transport")))?;
+        Ok(Self {
+            json: handler.serializer.is_json(),
+            handler: Arc::new(handler),
+            frames,
+        })
+    }
+
+    pub fn handler(&self) -> &BlocksHandler {
+        &self.handler
+    }
+
+    /// Handles the blocks of a fixture file or directory in the `TestScanner` format
+    /// and returns the sent frames
+    pub async fn run_fixture(&self, path: impl Into<PathBuf>) -> FusionResult<Vec<CapturedFrame>> {
+        let scanner = TestScanner::new(self.handler.clone(), path.into()).map_err(FusionError::scanner)?;
+        scanner.run().await.map_err(FusionError::scanner)?;
+        Ok(self.take_frames().await)
+    }
+
+    /// Handles a block and returns the sent frames
+    pub async fn handle_block(
+        &self,
+        block_stuff: &BlockStuff,
+        shard_state: Option<&ShardStateStuff>,
+    ) -> FusionResult<Vec<CapturedFrame>> {
+        self.handler.handle_block(block_stuff, shard_state).await?;
+        Ok(self.take_frames().await)
+    }
+
+    /// Renders the frames for a golden file
+    pub fn render(&self, frames: &[CapturedFrame]) -> String {
+        render_frames(frames, self.json)
+    }
+
+    /// Waits until the frames stop arriving and takes them
+    async fn take_frames(&self) -> Vec<CapturedFrame> {
+        let mut count = self.frames.len();
+        loop {
+            tokio::time::sleep(SETTLE_PERIOD).await;
+            let current = self.frames.len();
+            if current == count {
+                return self.frames.take();
+            }
+            count = current;
+        }
+    }
+}
+
+/// Renders the frames as text, one `--- <stream> [destination]` header per frame. JSON frames
+/// are pretty printed, Protobuf frames are hex encoded. Messages keep the order they were sent
+/// in, frames of other streams are sorted since their tasks race
+pub fn render_frames(frames: &[CapturedFrame], json: bool) -> String {
+    let mut rendered = frames
+        .iter()
+        .map(|frame| {
+            let stream = Stream::ALL.iter().position(|stream| *stream == frame.stream).unwrap_or_default();
+            let mut text = format!("--- {}", frame.stream.name());
+            if let Some(destination) = &frame.destination {
+                write!(text, " {destination}").ok();
+            }
+            writeln!(text, "\n{}", render_data(&frame.data, json)).ok();
+            (stream, text)
+        })
+        .collect::<Vec<_>>();
+    // Stable, so messages keep their order
+    rendered.sort_by(|(a, a_text), (b, b_text)| match a.cmp(b) {
+        std::cmp::Ordering::Equal if *a != 0 => a_text.cmp(b_text),
+        ordering => ordering,
+    });
+    rendered.into_iter().map(|(_, text)| text).collect()
+}
+
+fn render_data(data: &[u8], json: bool) -> String {
+    let value = data
+        .get(4..)
+        .filter(|_| json)
+        .and_then(|record| serde_json::from_slice::<serde_json::Value>(record).ok());
+    match value.and_then(|value| serde_json::to_string_pretty(&value).ok()) {
+        Some(text) => text,
+        None => hex::encode(data),
+    }
+}
+
+/// Compares the output with the golden file, panics showing the first difference.
+/// With the `UPDATE_GOLDEN` environment variable set, the file is written instead
+pub fn assert_golden(path: impl AsRef<Path>, actual: &str) {
+    let path = path.as_ref();
+    if std::env::var_os(UPDATE_GOLDEN_ENV).is_some() {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).ok();
+        }
+        if let Err(error) = std::fs::write(path, actual) {
+            panic!("Failed to write golden file {}: {}", path.display(), error);
+        }
+        return;
+    }
+
+    let expected = match std::fs::read_to_string(path) {
+        Ok(expected) => expected,
+        Err(error) => panic!(
+            "Failed to read golden file {}: {}. Set {} to create it",
+            path.display(),
+            error,
+            UPDATE_GOLDEN_ENV
+        ),
+    };
+    if expected == actual {
+        return;
+    }
+    let mismatch = expected
+        .lines()
+        .zip(actual.lines())
+        .position(|(expected, actual)| expected != actual)
+        .unwrap_or_else(|| expected.lines().count().min(actual.lines().count()));
+    panic!(
+        "Output differs from {} at line {}:\nexpected: {}\n  actual: {}\nSet {} to update it",
+        path.display(),
+        mismatch + 1,
+        expected.lines().nth(mismatch).unwrap_or("<end of file>"),
+        actual.lines().nth(mismatch).unwrap_or("<end of output>"),
+        UPDATE_GOLDEN_ENV
+    );
+}
@@ -1,10 +1,24 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
 use nekoton_abi::transaction_parser::Extracted;
 use serde::{Deserialize, Serialize};
-use ton_block::{CommonMsgInfo, Message, Transaction, MessageId, GetRepresentationHash};
-use ton_types::UInt256;
+use ton_abi::{Token, TokenValue};
+use num_bigint::BigUint;
+use ton_block::{
+    BlockIdExt, CommonMsgInfo, Message, Transaction, TransactionDescr, TrComputePhase, MessageId, GetRepresentationHash, ShardAccount,
+    MsgAddressInt,
+};
+use ton_types::{serialize_toc, UInt256};
+
+pub(crate) mod utils;
 
-mod utils;
-use utils::{serialize_ton_uint, serialize_message_as_display};
+/// Decimals of the native token
+pub const NATIVE_DECIMALS: u8 = 9;
+use utils::{
+    normalize_amount, serialize_ton_uint, serialize_message_as_display, serialize_optional_ton_uint, serialize_optional_base64,
+    serialize_ton_uint_list, serialize_optional_address, serialize_display, serialize_optional_display,
+};
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case", deny_unknown_fields)]
@@ -26,6 +40,17 @@ pub fn message_type_from(msg: &CommonMsgInfo, is_in_message: bool) -> MessageTyp
     }
 }
 
+impl MessageType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::InternalInbound => "internal_inbound",
+            Self::InternalOutbound => "internal_outbound",
+            Self::ExternalInbound => "external_inbound",
+            Self::ExternalOutbound => "external_outbound",
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct FilteredMessage {
     pub name: String,
@@ -35,7 +60,151 @@ pub struct FilteredMessage {
     pub tx: Transaction,
     pub index_in_transaction: u16, // The index of the message in the transaction
     pub contract_name: String,
-    pub filter_name: String
+    pub filter_name: String,
+    /// Output of the matched filter, the messages stream if not specified
+    pub destination: Option<String>,
+    /// Tenant of the matched filter, its messages stay in the tenant destination
+    pub tenant: Option<String>,
+    /// Names of all filters matching the message, filled only in the `all` match mode
+    pub matched_filters: Vec<String>,
+    /// Correlate bounces of the message, set by the matched filter
+    pub track_bounces: bool,
+    /// Assemble the trace of the transaction, set by the matched filter
+    pub trace: bool,
+    /// ABI function id, known only for contract filters
+    pub function_id: Option<u32>,
+    /// `expire` header of ABI external messages
+    pub expire_at: Option<u32>,
+    /// Decoded ABI tokens, empty for non-ABI parsers.
+    /// Kept after filtering only for filters with `include_decoded`
+    pub tokens: Vec<Token>,
+    /// Additional fields attached by filter plugins
+    pub enrichment: Enrichment,
+    /// Neighbour messages of the transaction, filled only if requested by the filter
+    pub routing: Option<MessageRouting>,
+    /// Normalized record, filled by the semantic decoders of standard contracts
+    pub payload: Option<SemanticPayload>,
+}
+
+/// Additional message fields attached during filtering
+pub type Enrichment = BTreeMap<String, serde_json::Value>;
+
+/// Links between the messages of a transaction, used to reconstruct message chains
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct MessageRouting {
+    /// Hash of the inbound message of the transaction
+    #[serde(serialize_with = "serialize_optional_ton_uint")]
+    pub parent_message_hash: Option<UInt256>,
+    /// Hashes of the outbound messages of the transaction in order
+    #[serde(serialize_with = "serialize_ton_uint_list")]
+    pub child_message_hashes: Vec<UInt256>,
+}
+
+/// Normalized TIP-3 token movement
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TokenTransfer {
+    /// One of: `transfer`, `mint`, `burn`
+    pub kind: &'static str,
+    #[serde(serialize_with = "serialize_optional_address")]
+    pub token_root: Option<MsgAddressInt>,
+    /// Owner of the sender wallet, empty for mints
+    #[serde(serialize_with = "serialize_optional_address")]
+    pub from_owner: Option<MsgAddressInt>,
+    /// Owner of the receiver wallet, empty for burns
+    #[serde(serialize_with = "serialize_optional_address")]
+    pub to_owner: Option<MsgAddressInt>,
+    #[serde(serialize_with = "serialize_display")]
+    pub amount: BigUint,
+    /// Metadata of the token root, known once it was read from the state of its shard
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub symbol: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decimals: Option<u8>,
+    /// Amount divided by `10^decimals`, set by serializers with `normalize_amounts`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount_normalized: Option<String>,
+}
+
+/// Normalized multisig wallet custodian call
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MultisigTransaction {
+    /// One of: `send`, `submit`, `confirm`
+    pub action: &'static str,
+    /// Confirmed transaction id
+    pub transaction_id: Option<u64>,
+    #[serde(serialize_with = "serialize_optional_address")]
+    pub dest: Option<MsgAddressInt>,
+    #[serde(serialize_with = "serialize_optional_display")]
+    pub value: Option<BigUint>,
+}
+
+/// Normalized DePool participant call or elector stake event
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DepoolOperation {
+    /// One of: `add_stake`, `withdraw`, `withdraw_all`, `round_stake_accepted`, `round_stake_rejected`
+    pub action: &'static str,
+    #[serde(serialize_with = "serialize_optional_address")]
+    pub participant: Option<MsgAddressInt>,
+    #[serde(serialize_with = "serialize_optional_display")]
+    pub amount: Option<BigUint>,
+    /// Elector query id of the stake events
+    pub query_id: Option<u64>,
+}
+
+/// Normalized TIP-4.1 NFT movement
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NftTransfer {
+    /// One of: `mint`, `transfer`, `burn`
+    pub kind: &'static str,
+    #[serde(serialize_with = "serialize_optional_address")]
+    pub collection: Option<MsgAddressInt>,
+    #[serde(serialize_with = "serialize_optional_address")]
+    pub nft: Option<MsgAddressInt>,
+    #[serde(serialize_with = "serialize_optional_display")]
+    pub id: Option<BigUint>,
+    #[serde(serialize_with = "serialize_optional_address")]
+    pub from_owner: Option<MsgAddressInt>,
+    #[serde(serialize_with = "serialize_optional_address")]
+    pub to_owner: Option<MsgAddressInt>,
+}
+
+/// Record produced by a semantic decoder
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SemanticPayload {
+    TokenTransfer(TokenTransfer),
+    Multisig(MultisigTransaction),
+    Depool(DepoolOperation),
+    Nft(NftTransfer),
+}
+
+impl SemanticPayload {
+    /// Normalized message name
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::TokenTransfer(transfer) => transfer.kind,
+            Self::Multisig(transaction) => transaction.action,
+            Self::Depool(operation) => operation.action,
+            Self::Nft(transfer) => transfer.kind,
+        }
+    }
+}
+
+impl MessageRouting {
+    pub fn from_transaction(tx: &Transaction) -> Result<Self> {
+        let parent_message_hash = tx.in_msg.as_ref().map(|message| message.hash());
+
+        let mut child_message_hashes = Vec::new();
+        tx.out_msgs.iterate_slices(|slice| {
+            child_message_hashes.push(slice.reference(0)?.repr_hash());
+            Ok(true)
+        })?;
+
+        Ok(Self {
+            parent_message_hash,
+            child_message_hashes,
+        })
+    }
 }
 
 impl<'a> From<&Extracted<'a>> for FilteredMessage {
@@ -49,7 +218,21 @@ impl<'a> From<&Extracted<'a>> for FilteredMessage {
             tx: ext.tx.clone(),
             index_in_transaction: ext.index_in_transaction,
             contract_name: Default::default(),
-            filter_name: Default::default()
+            filter_name: Default::default(),
+            destination: Default::default(),
+            tenant: None,
+            matched_filters: Vec::new(),
+            track_bounces: false,
+            trace: false,
+            function_id: Some(ext.function_id),
+            expire_at: ext.decoded_headers.iter().find_map(|header| match header.value {
+                TokenValue::Expire(expire_at) => Some(expire_at),
+                _ => None,
+            }),
+            tokens: ext.tokens.clone(),
+            enrichment: Default::default(),
+            routing: Default::default(),
+            payload: Default::default(),
         }
     }
 }
@@ -61,30 +244,518 @@ pub struct SerializeMessage {
     #[serde(serialize_with = "serialize_ton_uint")]
     pub message_hash: MessageId,
     pub message_type: MessageType,
+    /// Message source, not set for external inbound messages
+    #[serde(serialize_with = "serialize_optional_address", skip_serializing_if = "Option::is_none")]
+    pub src: Option<MsgAddressInt>,
+    /// Message destination, not set for external outbound messages
+    #[serde(serialize_with = "serialize_optional_address", skip_serializing_if = "Option::is_none")]
+    pub dst: Option<MsgAddressInt>,
     #[serde(serialize_with = "serialize_ton_uint")]
     pub block_id: UInt256,
+    pub workchain_id: i32,
+    /// Tagged shard prefix
+    pub shard: u64,
+    pub block_seqno: u32,
+    /// Masterchain block which committed the block, if known
+    pub mc_seqno: Option<u32>,
     #[serde(serialize_with = "serialize_ton_uint")]
     pub transaction_id: UInt256,
     pub transaction_timestamp: u32,
     pub index_in_transaction: u16,
     pub contract_name: String,
     pub filter_name: String,
+    /// Names of all filters matching the message, filled only in the `all` match mode
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub matched_filters: Vec<String>,
+    /// Block was generated before the realtime threshold, e.g. during catch-up
+    pub historical: bool,
+    /// Used by the transport, not serialized
+    #[serde(skip)]
+    pub destination: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub enrichment: Enrichment,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub routing: Option<MessageRouting>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload: Option<SemanticPayload>,
+    /// Decoded ABI parameters of filters with `include_decoded`, serialized only to protobuf
+    #[serde(skip)]
+    pub decoded: Vec<Token>,
+    /// Set for external inbound messages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub external: Option<ExternalInfo>,
+    /// Fees of the transaction
+    pub fees: TransactionFees,
+    /// Address book labels of the source and destination
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub src_label: Option<AddressLabel>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dst_label: Option<AddressLabel>,
+    /// Value of internal messages in native tokens, set by serializers with `normalize_amounts`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_normalized: Option<String>,
+}
+
+/// Human-readable name of an address
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AddressLabel {
+    pub label: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+/// Fees of a transaction in nano tokens, zero for phases which were skipped
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct TransactionFees {
+    pub total_fees: u128,
+    pub storage_fees: u128,
+    pub gas_used: u64,
+    pub gas_fees: u128,
+    /// Forward fees of the outbound messages
+    pub fwd_fees: u128,
+}
+
+impl TransactionFees {
+    pub fn from_transaction(tx: &Transaction) -> Result<Self> {
+        let mut fees = Self {
+            total_fees: tx.total_fees().grams.as_u128(),
+            ..Default::default()
+        };
+        if let TransactionDescr::Ordinary(description) = tx.read_description()? {
+            if let Some(storage) = &description.storage_ph {
+                fees.storage_fees = storage.storage_fees_collected.as_u128();
+            }
+            if let TrComputePhase::Vm(compute) = &description.compute_ph {
+                fees.gas_used = compute.gas_used.as_u64();
+                fees.gas_fees = compute.gas_fees.as_u128();
+            }
+            if let Some(action) = &description.action {
+                fees.fwd_fees = action.total_fwd_fees.as_ref().map(|fees| fees.as_u128()).unwrap_or_default();
+            }
+        }
+        Ok(fees)
+    }
+}
+
+/// Replay protection metadata of an external inbound message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ExternalInfo {
+    /// `expire` header, known only for contract filters with such a header
+    pub expire_at: Option<u32>,
+    /// ABI function id, known only for contract filters
+    pub function_id: Option<u32>,
+    /// Whether the transaction was aborted, e.g. the message was replayed or expired
+    pub aborted: bool,
+}
+
+pub(crate) fn transaction_aborted(tx: &Transaction) -> bool {
+    match tx.read_description() {
+        Ok(TransactionDescr::Ordinary(description)) => description.aborted,
+        _ => false,
+    }
+}
+
+impl SerializeMessage {
+    /// Sets the coordinates of the block containing the transaction
+    pub fn with_block(self, block_id: &BlockIdExt, mc_seqno: Option<u32>) -> Self {
+        Self {
+            block_id: block_id.root_hash,
+            workchain_id: block_id.shard().workchain_id(),
+            shard: block_id.shard().shard_prefix_with_tag(),
+            block_seqno: block_id.seq_no,
+            mc_seqno,
+            ..self
+        }
+    }
+
+    /// Fills the decimal amounts of the internal message value and the token transfer
+    /// with known decimals
+    pub fn normalize_amounts(&mut self) {
+        if let CommonMsgInfo::IntMsgInfo(header) = self.message.header() {
+            let value = BigUint::from(header.value.grams.as_u128());
+            self.value_normalized = Some(normalize_amount(&value, NATIVE_DECIMALS));
+        }
+        if let Some(SemanticPayload::TokenTransfer(transfer)) = &mut self.payload {
+            transfer.amount_normalized = transfer
+                .decimals
+                .map(|decimals| normalize_amount(&transfer.amount, decimals));
+        }
+    }
 }
 
 impl From<FilteredMessage> for SerializeMessage {
     fn from(msg: FilteredMessage) -> Self {
         let transaction_id = msg.tx.hash().unwrap_or_default();
+        let fees = TransactionFees::from_transaction(&msg.tx).unwrap_or_else(|error| {
+            tracing::error!("Reading transaction fees: {}", error);
+            Default::default()
+        });
+        let external = (msg.message_type == MessageType::ExternalInbound).then(|| ExternalInfo {
+            expire_at: msg.expire_at,
+            function_id: msg.function_id,
+            aborted: transaction_aborted(&msg.tx),
+        });
 
         SerializeMessage {
+            src: msg.message.src_ref().cloned(),
+            dst: msg.message.dst_ref().cloned(),
             message: msg.message,
             message_hash: msg.message_hash,
             message_type: msg.message_type,
             block_id: Default::default(),
+            workchain_id: Default::default(),
+            shard: Default::default(),
+            block_seqno: Default::default(),
+            mc_seqno: None,
             transaction_id,
             transaction_timestamp: msg.tx.now,
             index_in_transaction: msg.index_in_transaction,
             contract_name: msg.contract_name,
             filter_name: msg.filter_name,
+            matched_filters: msg.matched_filters,
+            historical: false,
+            destination: msg.destination,
+            enrichment: msg.enrichment,
+            routing: msg.routing,
+            payload: msg.payload,
+            decoded: msg.tokens,
+            external,
+            fees,
+            src_label: None,
+            dst_label: None,
+            value_normalized: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountStatus {
+    Uninit,
+    Frozen,
+    Active,
+    Nonexist,
+}
+
+impl From<&ton_block::AccountStatus> for AccountStatus {
+    fn from(status: &ton_block::AccountStatus) -> Self {
+        match status {
+            ton_block::AccountStatus::AccStateUninit => Self::Uninit,
+            ton_block::AccountStatus::AccStateFrozen => Self::Frozen,
+            ton_block::AccountStatus::AccStateActive => Self::Active,
+            ton_block::AccountStatus::AccStateNonexist => Self::Nonexist,
+        }
+    }
+}
+
+/// Account state after the last transaction in the block
+#[derive(Debug, Clone, Serialize)]
+pub struct SerializeAccountState {
+    pub filter_name: String,
+    #[serde(serialize_with = "serialize_ton_uint")]
+    pub block_id: UInt256,
+    pub workchain_id: i32,
+    #[serde(serialize_with = "serialize_ton_uint")]
+    pub account_id: UInt256,
+    pub status: AccountStatus,
+    pub balance: u128,
+    #[serde(serialize_with = "serialize_ton_uint")]
+    pub last_transaction_hash: UInt256,
+    pub last_transaction_lt: u64,
+    #[serde(serialize_with = "serialize_optional_ton_uint")]
+    pub code_hash: Option<UInt256>,
+    #[serde(serialize_with = "serialize_optional_base64")]
+    pub data_boc: Option<Vec<u8>>,
+}
+
+impl SerializeAccountState {
+    pub fn new(
+        filter_name: String,
+        block_id: UInt256,
+        workchain_id: i32,
+        account_id: UInt256,
+        shard_account: &ShardAccount,
+    ) -> Result<Self> {
+        let account = shard_account.read_account()?;
+        let data_boc = account
+            .get_data()
+            .map(|data| serialize_toc(&data))
+            .transpose()?;
+
+        Ok(Self {
+            filter_name,
+            block_id,
+            workchain_id,
+            account_id,
+            status: AccountStatus::from(&account.status()),
+            balance: account.balance().map(|balance| balance.grams.as_u128()).unwrap_or_default(),
+            last_transaction_hash: shard_account.last_trans_hash().clone(),
+            last_transaction_lt: shard_account.last_trans_lt(),
+            code_hash: account.get_code_hash(),
+            data_boc,
+        })
+    }
+
+    /// Tombstone of an account deleted in the block
+    pub fn deleted(
+        filter_name: String,
+        block_id: UInt256,
+        workchain_id: i32,
+        account_id: UInt256,
+        last_transaction_hash: UInt256,
+        last_transaction_lt: u64,
+    ) -> Self {
+        Self {
+            filter_name,
+            block_id,
+            workchain_id,
+            account_id,
+            status: AccountStatus::Nonexist,
+            balance: 0,
+            last_transaction_hash,
+            last_transaction_lt,
+            code_hash: None,
+            data_boc: None,
         }
     }
 }
+
+/// Accounts matching the code hash filters in the latest states of the node
+#[derive(Debug, Clone, Serialize)]
+pub struct AccountSnapshot {
+    /// Masterchain block of the states
+    pub mc_seqno: u32,
+    pub filters: Vec<CodeHashAccounts>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CodeHashAccounts {
+    /// Contract of a message filter, not set for account filters
+    pub contract_name: Option<String>,
+    pub filter_name: String,
+    #[serde(serialize_with = "serialize_ton_uint")]
+    pub code_hash: UInt256,
+    pub accounts: Vec<SnapshotAccount>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SnapshotAccount {
+    pub address: String,
+    pub balance: u128,
+}
+
+/// Per-block metadata record
+#[derive(Debug, Clone, Serialize)]
+pub struct SerializeBlock {
+    #[serde(serialize_with = "serialize_ton_uint")]
+    pub block_id: UInt256,
+    #[serde(serialize_with = "serialize_ton_uint")]
+    pub file_hash: UInt256,
+    pub workchain_id: i32,
+    pub shard: u64,
+    pub seqno: u32,
+    pub gen_utime: u32,
+    pub transaction_count: u32,
+    pub message_count: u32,
+}
+
+/// Bounced message returning a matched message of a filter with `track_bounces`
+#[derive(Debug, Clone, Serialize)]
+pub struct SerializeBounce {
+    #[serde(serialize_with = "serialize_ton_uint")]
+    pub original_message_hash: UInt256,
+    #[serde(serialize_with = "serialize_ton_uint")]
+    pub bounced_message_hash: UInt256,
+    /// Block of the transaction which bounced the message
+    #[serde(serialize_with = "serialize_ton_uint")]
+    pub block_id: UInt256,
+    #[serde(serialize_with = "serialize_ton_uint")]
+    pub transaction_id: UInt256,
+    pub transaction_timestamp: u32,
+    pub contract_name: String,
+    pub filter_name: String,
+}
+
+/// Transactions of the message chain started by a transaction with a matched message
+/// of a filter with `trace`
+#[derive(Debug, Clone, Serialize)]
+pub struct SerializeTrace {
+    /// Hash of the root transaction
+    #[serde(serialize_with = "serialize_ton_uint")]
+    pub trace_id: UInt256,
+    pub contract_name: String,
+    pub filter_name: String,
+    /// Not set if the trace timed out or grew too large before all its messages were processed
+    pub complete: bool,
+    /// Timestamp of the root transaction
+    pub started_at: u32,
+    /// Root transaction first, then in the order they were processed
+    pub transactions: Vec<TraceTransaction>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TraceTransaction {
+    #[serde(serialize_with = "serialize_ton_uint")]
+    pub transaction_id: UInt256,
+    #[serde(serialize_with = "serialize_ton_uint")]
+    pub block_id: UInt256,
+    pub workchain_id: i32,
+    #[serde(serialize_with = "serialize_ton_uint")]
+    pub account_id: UInt256,
+    pub lt: u64,
+    pub timestamp: u32,
+    #[serde(serialize_with = "serialize_optional_ton_uint")]
+    pub in_message_hash: Option<UInt256>,
+    #[serde(serialize_with = "serialize_ton_uint_list")]
+    pub out_message_hashes: Vec<UInt256>,
+    pub aborted: bool,
+}
+
+/// Summary of the matched messages of a filter with `aggregate` in a window of transaction time
+#[derive(Debug, Clone, Serialize)]
+pub struct SerializeAggregate {
+    pub contract_name: String,
+    pub filter_name: String,
+    pub window_start: u32,
+    pub window_sec: u32,
+    pub count: u64,
+    /// Decimal sum of the `sum` expression, set if it is configured
+    pub sum: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageStatus {
+    /// Broadcasted to the network
+    Accepted,
+    /// Processed by a transaction
+    Included,
+    /// Not included before the expiration time
+    Expired,
+}
+
+/// Status of an external message sent via the message consumer
+#[derive(Debug, Clone, Serialize)]
+pub struct SerializeMessageStatus {
+    #[serde(serialize_with = "serialize_ton_uint")]
+    pub message_hash: UInt256,
+    pub status: MessageStatus,
+    /// Set for included messages
+    #[serde(serialize_with = "serialize_optional_ton_uint")]
+    pub block_id: Option<UInt256>,
+    /// Set for included messages
+    #[serde(serialize_with = "serialize_optional_ton_uint")]
+    pub transaction_id: Option<UInt256>,
+}
+
+/// Producer event sent in the streams alongside the records
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlEvent {
+    /// Another block was applied at the same seqno, records of this block must be rolled back
+    Revoked(RevokedBlock),
+    /// First frame of the stream. A new `started_at` means the producer was restarted
+    StreamStart(StreamStart),
+    /// Sent periodically, so consumers can tell an idle stream from a dead producer
+    Heartbeat(Heartbeat),
+    /// The producer stopped, no more records follow
+    StreamEnd,
+    /// Messages of the block follow until the matching `BlockEnd`
+    BlockBegin(BlockBoundary),
+    /// All messages of the block were sent, so its effects can be applied
+    BlockEnd(BlockBoundary),
+    /// Running counters of the messages sent since the producer start, sent with heartbeats
+    Stats(StreamStats),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamStart {
+    /// Unix time of the producer start
+    pub started_at: u32,
+    pub version: String,
+    /// Consumers should not read streams of an unknown protocol version
+    pub protocol_version: u32,
+    /// Name of the serializer kind, e.g. `protobuf`
+    pub serializer: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Heartbeat {
+    pub timestamp: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RevokedBlock {
+    #[serde(serialize_with = "serialize_ton_uint")]
+    pub block_id: UInt256,
+    pub workchain_id: i32,
+    pub shard: u64,
+    pub seqno: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockBoundary {
+    #[serde(serialize_with = "serialize_ton_uint")]
+    pub block_id: UInt256,
+    pub workchain_id: i32,
+    pub shard: u64,
+    pub seqno: u32,
+    /// Messages of the block between the boundary frames
+    pub message_count: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamStats {
+    pub timestamp: u32,
+    pub filters: Vec<FilterCounters>,
+}
+
+/// Messages of a filter handed to the transport since the producer start
+#[derive(Debug, Clone, Serialize)]
+pub struct FilterCounters {
+    pub filter_name: String,
+    pub messages: u64,
+    /// Size of the serialized frames
+    pub bytes: u64,
+    /// Unix time of the last message
+    pub last_emitted_at: u32,
+}
+
+/// Wraps a control event, so it can be told apart from the stream records
+#[derive(Debug, Clone, Serialize)]
+pub struct SerializeControl {
+    pub control: ControlEvent,
+}
+
+/// Masterchain config param changed in a key block
+#[derive(Debug, Clone, Serialize)]
+pub struct SerializeConfigParam {
+    pub filter_name: String,
+    #[serde(serialize_with = "serialize_ton_uint")]
+    pub block_id: UInt256,
+    pub seqno: u32,
+    pub gen_utime: u32,
+    pub param: u32,
+    /// Param value, `None` if the param was removed
+    #[serde(serialize_with = "serialize_optional_base64")]
+    pub data_boc: Option<Vec<u8>>,
+}
+
+/// Accounts of an account change filter changed or deleted in a block
+#[derive(Debug, Clone, Serialize)]
+pub struct SerializeAccountChanges {
+    pub filter_name: String,
+    #[serde(serialize_with = "serialize_ton_uint")]
+    pub block_id: UInt256,
+    pub workchain_id: i32,
+    /// Tagged shard prefix
+    pub shard: u64,
+    pub seqno: u32,
+    pub gen_utime: u32,
+    /// Ids of the accounts with a new state
+    #[serde(serialize_with = "serialize_ton_uint_list")]
+    pub changed_accounts: Vec<UInt256>,
+    /// Ids of the deleted accounts
+    #[serde(serialize_with = "serialize_ton_uint_list")]
+    pub deleted_accounts: Vec<UInt256>,
+}
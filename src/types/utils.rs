@@ -1,7 +1,47 @@
+use std::cell::Cell;
+
+use num_bigint::BigUint;
 use serde::Serializer;
-use ton_block::Message;
+use ton_block::{Message, MsgAddressInt};
 use ton_types::UInt256;
 
+use crate::serializer::AddressFormat;
+
+thread_local! {
+    /// Format of addresses in JSON records, set by the serializer
+    static ADDRESS_FORMAT: Cell<AddressFormat> = Cell::new(AddressFormat::Raw);
+}
+
+/// Serializes addresses within `f` in the format
+#[cfg(feature="serialize-json")]
+pub fn with_address_format<T>(format: AddressFormat, f: impl FnOnce() -> T) -> T {
+    let previous = ADDRESS_FORMAT.with(|cell| cell.replace(format));
+    let result = f();
+    ADDRESS_FORMAT.with(|cell| cell.set(previous));
+    result
+}
+
+/// Decimal string of the amount divided by `10^decimals`, without trailing zeros
+pub fn normalize_amount(amount: &BigUint, decimals: u8) -> String {
+    let digits = amount.to_string();
+    let decimals = usize::from(decimals);
+    if decimals == 0 {
+        return digits;
+    }
+    let digits = format!("{digits:0>width$}", width = decimals + 1);
+    let (integer, fraction) = digits.split_at(digits.len() - decimals);
+    match fraction.trim_end_matches('0') {
+        "" => integer.to_string(),
+        fraction => format!("{integer}.{fraction}"),
+    }
+}
+
+#[derive(serde::Serialize)]
+struct AddressForms {
+    raw: String,
+    base64: String,
+}
+
 pub fn serialize_ton_uint<S>(id: &UInt256, s: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
@@ -16,3 +56,63 @@ where
     s.serialize_str(&format!("{}", message))
 }
 
+
+pub fn serialize_optional_ton_uint<S>(id: &Option<UInt256>, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match id {
+        Some(id) => s.serialize_some(&id.to_hex_string()),
+        None => s.serialize_none(),
+    }
+}
+
+pub fn serialize_ton_uint_list<S>(ids: &[UInt256], s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    s.collect_seq(ids.iter().map(UInt256::to_hex_string))
+}
+
+pub fn serialize_optional_base64<S>(data: &Option<Vec<u8>>, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match data {
+        Some(data) => s.serialize_some(&base64::encode(data)),
+        None => s.serialize_none(),
+    }
+}
+
+pub fn serialize_optional_address<S>(address: &Option<MsgAddressInt>, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let Some(address) = address else {
+        return s.serialize_none();
+    };
+    match ADDRESS_FORMAT.with(Cell::get).format(address) {
+        (Some(raw), Some(base64)) => s.serialize_some(&AddressForms { raw, base64 }),
+        (Some(address), None) | (None, Some(address)) => s.serialize_some(&address),
+        (None, None) => s.serialize_none(),
+    }
+}
+
+pub fn serialize_display<S, T>(value: &T, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    T: std::fmt::Display,
+{
+    s.collect_str(value)
+}
+
+pub fn serialize_optional_display<S, T>(value: &Option<T>, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    T: std::fmt::Display,
+{
+    match value {
+        Some(value) => s.serialize_some(&value.to_string()),
+        None => s.serialize_none(),
+    }
+}
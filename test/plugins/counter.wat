@@ -0,0 +1,17 @@
+;; Accepts `{}` unchanged, rejects `null` and attaches `{"calls":N}` to anything else,
+;; where N is the number of calls seen by the instance memory
+(module
+  (memory (export "memory") 1)
+  (data (i32.const 16) "{\22calls\22:0}")
+  (func (export "alloc") (param i32) (result i32)
+    (i32.const 1024))
+  (func (export "filter") (param $ptr i32) (param $len i32) (result i64)
+    ;; count calls at address 0 and write the digit into the output
+    (i32.store (i32.const 0) (i32.add (i32.load (i32.const 0)) (i32.const 1)))
+    (i32.store8 (i32.const 25) (i32.add (i32.const 48) (i32.load (i32.const 0))))
+    (if (i32.eq (local.get $len) (i32.const 2))
+      (then (return (i64.const 0))))
+    (if (i32.eq (local.get $len) (i32.const 4))
+      (then (return (i64.const -1))))
+    (i64.or (i64.shl (i64.const 16) (i64.const 32)) (i64.const 11)))
+)
@@ -0,0 +1,13 @@
+;; Traps on `{}`, points outside of the memory on `null` and returns invalid JSON otherwise
+(module
+  (memory (export "memory") 1)
+  (data (i32.const 16) "not a json")
+  (func (export "alloc") (param i32) (result i32)
+    (i32.const 1024))
+  (func (export "filter") (param $ptr i32) (param $len i32) (result i64)
+    (if (i32.eq (local.get $len) (i32.const 2))
+      (then unreachable))
+    (if (i32.eq (local.get $len) (i32.const 4))
+      (then (return (i64.or (i64.shl (i64.const 65536) (i64.const 32)) (i64.const 16)))))
+    (i64.or (i64.shl (i64.const 16) (i64.const 32)) (i64.const 10)))
+)
@@ -0,0 +1,6 @@
+;; Doesn't export `filter`
+(module
+  (memory (export "memory") 1)
+  (func (export "alloc") (param i32) (result i32)
+    (i32.const 1024))
+)